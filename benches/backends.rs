@@ -0,0 +1,60 @@
+//! Stack vs register backend benchmark
+//!
+//! Compiles one expression tree for both machines and reports how long each
+//! takes to evaluate it. The tree is balanced so neither the code generator
+//! nor the register allocator recurses more than a few dozen levels deep.
+//!
+//! Run with: cargo bench --bench backends
+
+use calculator::{CodeGenerator, Expr, UnaryOp, VirtualMachine};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const LEAVES: usize = 16_384;
+const RUNS: u32 = 20;
+
+/// Sum of `LEAVES` terms of the form sqrt(i) * 1.0000001 + 0.5
+fn build_expr(first: usize, count: usize) -> Expr {
+    if count == 1 {
+        let term = Expr::multiply(
+            Expr::unary(UnaryOp::Sqrt, Expr::number(first as f64)),
+            Expr::number(1.000_000_1),
+        );
+        return Expr::add(term, Expr::number(0.5));
+    }
+    let half = count / 2;
+    Expr::add(build_expr(first, half), build_expr(first + half, count - half))
+}
+
+fn report(label: &str, elapsed: Duration, instructions: usize) {
+    println!(
+        "{:<10} {:>10.3?} per run  {:>7} instructions",
+        label,
+        elapsed / RUNS,
+        instructions
+    );
+}
+
+fn main() {
+    let expr = build_expr(0, LEAVES);
+    let (chunk, _) = CodeGenerator::new().compile(&expr).expect("compile failed");
+    let registers = CodeGenerator::new().compile_registers(&expr).expect("compile failed");
+    chunk.instructions();
+    println!("{} leaves, {} runs, {} registers", LEAVES, RUNS, registers.register_count());
+
+    let mut vm = VirtualMachine::new();
+    let expected = vm.execute(&chunk).expect("execution failed");
+    assert_eq!(vm.execute_registers(&registers).expect("execution failed"), expected);
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(vm.execute(black_box(&chunk)).expect("execution failed"));
+    }
+    report("stack", start.elapsed(), chunk.instructions().len());
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(vm.execute_registers(black_box(&registers)).expect("execution failed"));
+    }
+    report("register", start.elapsed(), registers.len());
+}
@@ -0,0 +1,75 @@
+//! Dispatch loop micro-benchmark
+//!
+//! Builds a long straight-line chunk and reports how many instructions per
+//! second the VM retires, separating one-off decoding from steady-state
+//! dispatch over the cached instruction list. The fused run executes the
+//! same program after superinstruction fusion and is reported in terms of
+//! the original instruction count.
+//!
+//! Run with: cargo bench --bench dispatch
+
+use calculator::{Chunk, OpCode, Optimizer, VirtualMachine};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const TERMS: usize = 50_000;
+const RUNS: u32 = 20;
+
+/// x = 1; repeat { x = x * 1.0000001 + 0.5 }
+fn build_chunk() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_push(1.0);
+    for _ in 0..TERMS {
+        chunk.write_push(1.000_000_1);
+        chunk.write_op(OpCode::Mul);
+        chunk.write_push(0.5);
+        chunk.write_op(OpCode::Add);
+    }
+    chunk.write_op(OpCode::Halt);
+    chunk
+}
+
+fn report(label: &str, elapsed: Duration, instructions: usize) {
+    let per_run = elapsed / RUNS;
+    let rate = instructions as f64 * RUNS as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<10} {:>10.3?} per run  {:>8.1} M instructions/s",
+        label,
+        per_run,
+        rate / 1e6
+    );
+}
+
+fn main() {
+    let instructions = build_chunk().instructions().len();
+    println!("{} instructions per run, {} runs", instructions, RUNS);
+
+    // Decoding a freshly built chunk
+    let mut decode = Duration::ZERO;
+    for _ in 0..RUNS {
+        let chunk = build_chunk();
+        let start = Instant::now();
+        black_box(chunk.instructions());
+        decode += start.elapsed();
+    }
+    report("decode", decode, instructions);
+
+    // Executing with the decoded instructions already cached
+    let chunk = build_chunk();
+    chunk.instructions();
+    let mut vm = VirtualMachine::new();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(vm.execute(black_box(&chunk)).expect("execution failed"));
+    }
+    report("dispatch", start.elapsed(), instructions);
+
+    // Same program after superinstruction fusion (half the dispatches)
+    let fused = Optimizer::fuse_superinstructions(&chunk);
+    fused.instructions();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(vm.execute(black_box(&fused)).expect("execution failed"));
+    }
+    report("fused", start.elapsed(), instructions);
+}
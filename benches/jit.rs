@@ -0,0 +1,79 @@
+//! JIT vs interpreter benchmark
+//!
+//! Runs the same straight-line chunks on the interpreter and as native code.
+//! The "small" case is a typical calculator expression evaluated many times,
+//! where per-run interpreter setup dominates; the "large" case is the long
+//! chunk from the dispatch benchmark, where instruction dispatch dominates.
+//! Every operand there is a constant, so Cranelift folds much of it at
+//! compile time; the reported compile time is where that work went.
+//!
+//! Run with: cargo bench --features jit --bench jit
+
+use calculator::{evaluate, CodeGenerator, Chunk, OpCode, Parser, Tokenizer, VirtualMachine};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const SMALL_RUNS: u32 = 200_000;
+const LARGE_TERMS: usize = 50_000;
+const LARGE_RUNS: u32 = 20;
+
+fn compile(input: &str) -> Chunk {
+    let tokens = Tokenizer::new(input).tokenize().expect("tokenize failed");
+    let ast = Parser::new(tokens).parse().expect("parse failed");
+    CodeGenerator::new().compile(&ast).expect("compile failed").0
+}
+
+/// x = 1; repeat { x = x * 1.0000001 + 0.5 }
+fn build_large() -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_push(1.0);
+    for _ in 0..LARGE_TERMS {
+        chunk.write_push(1.000_000_1);
+        chunk.write_op(OpCode::Mul);
+        chunk.write_push(0.5);
+        chunk.write_op(OpCode::Add);
+    }
+    chunk.write_op(OpCode::Halt);
+    chunk
+}
+
+fn time(runs: u32, mut f: impl FnMut() -> f64) -> Duration {
+    let start = Instant::now();
+    for _ in 0..runs {
+        black_box(f());
+    }
+    start.elapsed() / runs
+}
+
+fn compare(label: &str, chunk: &Chunk, runs: u32) {
+    let mut vm = VirtualMachine::new();
+    assert_eq!(
+        vm.execute_jit(chunk).expect("execution failed"),
+        vm.execute(chunk).expect("execution failed")
+    );
+    assert!(chunk.jitted().is_ok(), "{} chunk did not compile", label);
+
+    let start = Instant::now();
+    let compiled = calculator::jit::compile(chunk).expect("compile failed");
+    let compile_time = start.elapsed();
+    drop(compiled);
+
+    let interpreted = time(runs, || vm.execute(black_box(chunk)).expect("execution failed"));
+    let native = time(runs, || vm.execute_jit(black_box(chunk)).expect("execution failed"));
+    println!(
+        "{:<6} interpreter {:>10.3?}  jit {:>10.3?}  ({:.1}x, compile {:.3?})",
+        label,
+        interpreted,
+        native,
+        interpreted.as_secs_f64() / native.as_secs_f64(),
+        compile_time
+    );
+}
+
+fn main() {
+    // Sanity check that the library path agrees
+    assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+
+    compare("small", &compile("(3.5 * 2 - 1) / 4 + sqrt(16) * (2 + 0.25)"), SMALL_RUNS);
+    compare("large", &build_large(), LARGE_RUNS);
+}
@@ -0,0 +1,217 @@
+# Deutsche Texte der Oberfläche
+#
+# Gleiches Format wie en.ftl; jede Nachricht dort braucht hier eine Übersetzung.
+
+app-title = Bytecode-Rechner
+
+## Titelleiste
+show-details = Details anzeigen
+show-trace = Ablauf anzeigen
+script-editor = Skript-Editor
+debugger = Debugger
+keyboard-shortcuts = Tastenkürzel
+details-layout = Anordnung der Details
+settings = Einstellungen
+mobile-calc = 🔢 Rechner
+mobile-details = 📋 Details
+mobile-history = 📜 Verlauf
+mobile-trace = Ablauf
+mobile-debug = Debug
+
+## Tastenkürzel
+shortcut-evaluate = Auswerten
+shortcut-evaluate-script = Auswerten (Skript-Editor)
+shortcut-clear = Eingabe löschen
+shortcut-recall = Vorherigen / nächsten Ausdruck abrufen
+shortcut-complete = Vervollständigung übernehmen
+shortcut-details = Details ein-/ausblenden
+
+## Einstellungen
+settings-theme = Design:
+settings-accent = Akzentfarbe:
+settings-font = Festbreitenschrift:
+settings-font-size = Schriftgröße:
+settings-ui-scale = Skalierung:
+settings-touch = Touch:
+settings-large-buttons = Große Tasten
+settings-language = Sprache:
+settings-e-notation = e nach einer Zahl:
+e-notation-scientific = Exponent (2e3 = 2000)
+e-notation-euler = Eulersche Zahl (2e3 = 2·e·3)
+settings-result-format = Ergebnisformat:
+settings-decimals = Nachkommastellen:
+settings-significant = Signifikante Stellen:
+settings-max-denominator = Größter Nenner:
+settings-locale = Trennzeichen:
+settings-trim-zeros = Nachkommanullen entfernen
+settings-exact-integers = Ganzzahlen exakt anzeigen
+settings-show-fraction = Nächsten Bruch nach dem Ergebnis anzeigen
+settings-preview = Vorschau:
+settings-reset = Zurücksetzen
+preset-dark = Dunkel
+preset-light = Hell
+preset-high-contrast = Hoher Kontrast
+notation-auto = Automatisch
+notation-fixed = Festkomma
+notation-scientific = Wissenschaftlich
+notation-engineering = Technisch
+notation-si-prefix = SI-Präfix
+notation-fraction = Bruch
+notation-exact = Exakt
+locale-plain = Schlicht (1234.5)
+locale-english = Englisch (1,234.5)
+locale-german = Deutsch (1.234,5)
+
+## Anordnung
+layout-reset = Anordnung zurücksetzen
+section-tokens = Tokens
+section-ast = Abstrakter Syntaxbaum
+section-bytecode = Bytecode-Disassemblierung
+section-hex-viewer = Hex-Ansicht
+section-optimizer = Optimierer
+section-register-machine = Registermaschine
+section-trace = Ausführungsablauf
+section-debugger = Debugger
+section-memory = Speicherstatistik
+
+## Rechner
+script-label = Skript (Strg+Enter zum Auswerten):
+expression-label = Ausdruck:
+result-label = Ergebnis:
+rewritten-label = Umgeformt:
+rewritten-use = Übernehmen
+error-prefix = Fehler: { $error }
+warning-prefix = Warnung: { $warning }
+button-factorial = Fakultät
+button-power = Potenz
+button-divide = Geteilt
+button-multiply = Mal
+button-minus = Minus
+button-decimal-point = Dezimalpunkt
+button-open-paren = Klammer auf
+button-plus = Plus
+button-close-paren = Klammer zu
+button-pi = Pi
+button-e = Eulersche Zahl
+button-modulo = Modulo
+button-memory-clear = Speicher löschen
+button-memory-recall = Speicher abrufen
+button-memory-add = Zum Speicher addieren
+button-memory-subtract = Vom Speicher abziehen
+button-backspace = Rücktaste
+button-clear = Löschen
+button-evaluate = Auswerten
+
+## Definitionen
+definitions = Variablen & Funktionen
+definitions-rename = Umbenennen
+definitions-cancel = Abbrechen
+definitions-insert = In den Ausdruck einfügen
+definitions-delete = Löschen
+definitions-insert-memory = Speicherwert einfügen
+definitions-clear-memory = Speicher löschen
+definitions-empty = Definieren mit x = 2 oder f(x) = x^2
+
+## Details
+no-tokens = Keine Tokens
+no-ast = Kein Syntaxbaum
+no-bytecode = Kein Bytecode erzeugt
+no-register-code = Kein Registercode erzeugt
+no-trace = Kein Ablauf verfügbar
+no-statistics = Keine Statistik verfügbar – zuerst eine Berechnung ausführen
+backend-stack = Stack
+backend-register = Register
+stat-instructions = Befehle:
+stat-executed = Ausgeführt:
+stat-storage = Speicher:
+stat-time = Zeit:
+stat-bytes = Bytes:
+storage-bytes = { $count } Bytes
+storage-registers = { $count } Register
+trace-ip = IP
+trace-opcode = Opcode
+trace-stack-before = Stack vorher
+trace-stack-after = Stack nachher
+memory-total = Insgesamt belegt:
+memory-current = Aktuell belegt:
+memory-peak = Höchststand:
+memory-reserved = Arena reserviert:
+memory-allocations = Allokationen:
+memory-collections = GC-Durchläufe:
+memory-freed = Freigegebene Objekte:
+memory-pauses = GC-Pausen:
+memory-pause-times = Pause min. / Ø / max.:
+memory-performance = Laufzeit:
+memory-phase-times = Tokenisieren { $tokenize } µs · Parsen { $parse } µs · Kompilieren { $compile } µs · Ausführen { $execute } µs
+memory-recent-pauses = Letzte Pausen:
+memory-by-size = Allokationen nach Größe:
+memory-no-allocations = [keine Allokationen]
+memory-by-site = Belegt nach Herkunft:
+memory-site-objects = { $live } lebend / { $total } angelegt
+optimizer-empty = Einen Ausdruck auswerten, um optimierten Bytecode zu vergleichen
+optimizer-generated = Erzeugt
+optimizer-optimized = Optimiert
+
+## Debugger
+debugger-empty = Einen Ausdruck auswerten, um ihn zu debuggen
+debugger-depth = Stacktiefe über { $steps } Schritte (max. { $max }):
+debugger-restart = ⟲ Neustart
+debugger-back = ◀ Zurück
+debugger-step = Schritt ▶
+debugger-pause = ⏸ Pause
+debugger-run = ▶ Ausführen
+debugger-finished = Beendet nach { $steps } Schritten
+debugger-running = Läuft…
+debugger-paused = Angehalten bei { $ip }, Schritt { $step }
+debugger-code = Code (● klicken, um einen Haltepunkt zu setzen):
+debugger-toggle-breakpoint = Haltepunkt bei { $offset } umschalten
+debugger-stack = Stack:
+debugger-globals = Globale Variablen:
+stack-empty = [leer]
+globals-empty = [keine]
+
+## Verlauf
+history-title = Rechenverlauf
+history-clear = 🗑 Verlauf löschen
+history-clear-hint = Angeheftete Einträge bleiben erhalten
+history-search = Verlauf durchsuchen
+history-clear-search = Suche löschen
+history-pin = Oben anheften
+history-unpin = Lösen
+history-load = In die Eingabe laden
+history-bytecode-show = ▶ Bytecode
+history-bytecode-hide = ▼ Bytecode
+history-empty = Noch keine Berechnungen
+history-no-matches = Keine passenden Berechnungen
+export-include-disassembly = Disassemblierung einschließen
+export-button = 📤 Exportieren…
+export-saved = { $path } gespeichert
+export-failed = Export fehlgeschlagen: { $error }
+export-copied = { $format } in die Zwischenablage kopiert
+
+## Teilen
+share-link = Link teilen
+share-include-format = Ergebnisformat mitsenden
+share-copy = 📋 Link kopieren
+share-copied = Link in die Zwischenablage kopiert
+
+## Abgelegte Dateien
+drop-hint = .calc-Datei hier ablegen, um sie zu laden
+file-error = { $file } konnte nicht geladen werden: { $error }
+file-not-script = nur .calc-Skripte können geladen werden
+file-unreadable = die Datei konnte nicht gelesen werden
+file-not-text = sie ist kein UTF-8-Text
+
+## Eigene Tasten
+custom-buttons = Eigene Tasten:
+custom-button-label = Beschriftung
+custom-button-snippet = Text, $0 = Cursor
+custom-button-add = ➕ Taste hinzufügen
+custom-button-hint = Die Tasten erscheinen unter den Funktionstasten; $0 im Text markiert, wohin Auswahl und Cursor kommen
+
+## Vergleich im Verlauf
+compare-title = Berechnungen vergleichen
+compare-toggle = Vergleichen
+compare-hint = Zwei Berechnungen markieren, um ihre Übersetzung zu vergleichen
+compare-ast = Syntaxbaum
+compare-changes = { $section } ({ $count } geändert)
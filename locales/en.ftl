@@ -0,0 +1,218 @@
+# English GUI strings
+#
+# One message per line: `key = value`. `{ $name }` is replaced with the
+# argument called `name`.
+
+app-title = Bytecode Calculator
+
+## Top bar
+show-details = Show Details
+show-trace = Show Trace
+script-editor = Script Editor
+debugger = Debugger
+keyboard-shortcuts = Keyboard shortcuts
+details-layout = Details layout
+settings = Settings
+mobile-calc = 🔢 Calc
+mobile-details = 📋 Details
+mobile-history = 📜 History
+mobile-trace = Trace
+mobile-debug = Debug
+
+## Shortcuts
+shortcut-evaluate = Evaluate
+shortcut-evaluate-script = Evaluate (script editor)
+shortcut-clear = Clear input
+shortcut-recall = Recall previous / next expression
+shortcut-complete = Accept completion
+shortcut-details = Toggle details
+
+## Settings
+settings-theme = Theme:
+settings-accent = Accent color:
+settings-font = Monospace font:
+settings-font-size = Font size:
+settings-ui-scale = UI scale:
+settings-touch = Touch:
+settings-large-buttons = Large buttons
+settings-language = Language:
+settings-e-notation = e after a number:
+e-notation-scientific = Exponent (2e3 = 2000)
+e-notation-euler = Euler's number (2e3 = 2·e·3)
+settings-result-format = Result format:
+settings-decimals = Decimals:
+settings-significant = Significant figures:
+settings-max-denominator = Max denominator:
+settings-locale = Separators:
+settings-trim-zeros = Trim trailing zeros
+settings-exact-integers = Show integers exactly
+settings-show-fraction = Show nearest fraction after the result
+settings-preview = Preview:
+settings-reset = Reset to defaults
+preset-dark = Dark
+preset-light = Light
+preset-high-contrast = High contrast
+notation-auto = Auto
+notation-fixed = Fixed
+notation-scientific = Scientific
+notation-engineering = Engineering
+notation-si-prefix = SI prefix
+notation-fraction = Fraction
+notation-exact = Exact
+locale-plain = Plain (1234.5)
+locale-english = English (1,234.5)
+locale-german = German (1.234,5)
+
+## Layout
+layout-reset = Reset layout
+section-tokens = Tokens
+section-ast = Abstract Syntax Tree
+section-bytecode = Bytecode Disassembly
+section-hex-viewer = Hex Viewer
+section-optimizer = Optimizer
+section-register-machine = Register Machine
+section-trace = Execution Trace
+section-debugger = Debugger
+section-memory = Memory Statistics
+
+## Calculator
+script-label = Script (Ctrl+Enter to evaluate):
+expression-label = Expression:
+result-label = Result:
+rewritten-label = Rewritten:
+rewritten-use = Use
+error-prefix = Error: { $error }
+warning-prefix = Warning: { $warning }
+button-factorial = Factorial
+button-power = Power
+button-divide = Divide
+button-multiply = Multiply
+button-minus = Minus
+button-decimal-point = Decimal point
+button-open-paren = Open parenthesis
+button-plus = Plus
+button-close-paren = Close parenthesis
+button-pi = Pi
+button-e = Euler's number
+button-modulo = Modulo
+button-memory-clear = Memory clear
+button-memory-recall = Memory recall
+button-memory-add = Memory add
+button-memory-subtract = Memory subtract
+button-backspace = Backspace
+button-clear = Clear
+button-evaluate = Evaluate
+
+## Definitions
+definitions = Variables & Functions
+definitions-rename = Rename
+definitions-cancel = Cancel
+definitions-insert = Insert into expression
+definitions-delete = Delete
+definitions-insert-memory = Insert memory value
+definitions-clear-memory = Clear memory
+definitions-empty = Define with x = 2 or f(x) = x^2
+
+## Details
+no-tokens = No tokens
+no-ast = No AST
+no-bytecode = No bytecode generated
+no-register-code = No register code generated
+no-trace = No trace available
+no-statistics = No statistics available - run a calculation first
+backend-stack = Stack
+backend-register = Register
+stat-instructions = Instructions:
+stat-executed = Executed:
+stat-storage = Storage:
+stat-time = Time:
+stat-bytes = Bytes:
+storage-bytes = { $count } bytes
+storage-registers = { $count } registers
+trace-ip = IP
+trace-opcode = Opcode
+trace-stack-before = Stack Before
+trace-stack-after = Stack After
+memory-total = Total Allocated:
+memory-current = Current Usage:
+memory-peak = Peak Usage:
+memory-reserved = Arena Reserved:
+memory-allocations = Allocations:
+memory-collections = GC Collections:
+memory-freed = Objects Freed:
+memory-pauses = GC Pauses:
+memory-pause-times = Min / Avg / Max Pause:
+memory-performance = Performance:
+memory-phase-times = tokenize { $tokenize } µs · parse { $parse } µs · compile { $compile } µs · execute { $execute } µs
+memory-recent-pauses = Recent pauses:
+memory-by-size = Allocations by size:
+memory-no-allocations = [no allocations]
+memory-by-site = Live memory by site:
+memory-site-objects = { $live } live / { $total } allocated
+optimizer-empty = Evaluate an expression to compare optimized bytecode
+optimizer-generated = Generated
+optimizer-optimized = Optimized
+
+## Debugger
+debugger-empty = Evaluate an expression to debug it
+debugger-depth = Stack depth over { $steps } steps (max { $max }):
+debugger-restart = ⟲ Restart
+debugger-back = ◀ Back
+debugger-step = Step ▶
+debugger-pause = ⏸ Pause
+debugger-run = ▶ Run
+debugger-finished = Finished after { $steps } steps
+debugger-running = Running…
+debugger-paused = Paused at { $ip }, step { $step }
+debugger-code = Code (click ● to toggle a breakpoint):
+debugger-toggle-breakpoint = Toggle breakpoint at { $offset }
+debugger-stack = Stack:
+debugger-globals = Globals:
+stack-empty = [empty]
+globals-empty = [none]
+
+## History
+history-title = Calculation History
+history-clear = 🗑 Clear History
+history-clear-hint = Pinned entries are kept
+history-search = Search history
+history-clear-search = Clear search
+history-pin = Pin to top
+history-unpin = Unpin
+history-load = Load into input
+history-bytecode-show = ▶ bytecode
+history-bytecode-hide = ▼ bytecode
+history-empty = No calculations yet
+history-no-matches = No matching calculations
+export-include-disassembly = Include disassembly
+export-button = 📤 Export…
+export-saved = Saved { $path }
+export-failed = Export failed: { $error }
+export-copied = Copied { $format } to clipboard
+
+## Sharing
+share-link = Share link
+share-include-format = Include result format
+share-copy = 📋 Copy link
+share-copied = Link copied to clipboard
+
+## Dropped files
+drop-hint = Drop a .calc file to load it
+file-error = Couldn't load { $file }: { $error }
+file-not-script = only .calc scripts can be loaded
+file-unreadable = the file couldn't be read
+file-not-text = it isn't UTF-8 text
+
+## Custom buttons
+custom-buttons = Custom buttons:
+custom-button-label = Label
+custom-button-snippet = Text, $0 = cursor
+custom-button-add = ➕ Add button
+custom-button-hint = Buttons appear below the function keys; $0 in the text marks where the selection and cursor go
+
+## History comparison
+compare-title = Compare calculations
+compare-toggle = Compare
+compare-hint = Tick two calculations to compare how they compile
+compare-ast = AST
+compare-changes = { $section } ({ $count } changed)
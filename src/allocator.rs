@@ -0,0 +1,190 @@
+//! Allocator backends - Where the VM heap gets its memory from
+//!
+//! `MemoryManager` carves objects out of blocks and asks an `Allocator`
+//! for the blocks themselves. By default that is `Global`, Rust's global
+//! allocator. Any `GlobalAlloc` works too, such as `std::alloc::System` or,
+//! with the `mimalloc` feature, `MiMalloc`; `FixedBuffer` hands out blocks
+//! from one region of memory given up front, for targets like WASM where
+//! the embedder wants the heap in a buffer of its own.
+
+use crate::prelude::*;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ops::Range;
+use core::ptr::NonNull;
+
+#[cfg(all(feature = "mimalloc", not(target_arch = "wasm32")))]
+pub use mimalloc::MiMalloc;
+
+/// A source of memory blocks for `MemoryManager`
+///
+/// A stand-in for the unstable `core::alloc::Allocator`.
+///
+/// # Safety
+///
+/// A block `allocate` returns must be valid for `layout` and stay so, not
+/// overlapping any other, until it is passed to `deallocate`.
+pub unsafe trait Allocator {
+    /// Allocate a block for `layout`, or `None` if there's no memory left
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Give back a block
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from `allocate` on this allocator with the same `layout`.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+unsafe impl<A: GlobalAlloc> Allocator for A {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        // SAFETY: blocks are never zero-sized
+        NonNull::new(unsafe { self.alloc(layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+/// Whatever `#[global_allocator]` the program uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        // SAFETY: blocks are never zero-sized
+        NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        alloc::alloc::dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+/// Blocks handed out first-fit from a fixed region of memory
+///
+/// Once the region is used up, allocations fail and the heap behaves as if
+/// it had hit a memory limit.
+pub struct FixedBuffer {
+    start: NonNull<u8>,
+    capacity: usize,
+    /// Unused ranges of offsets, sorted and never adjacent
+    free: RefCell<Vec<Range<usize>>>,
+}
+
+impl FixedBuffer {
+    /// Allocate blocks out of `buffer`
+    pub fn new(buffer: &'static mut [u8]) -> Self {
+        let capacity = buffer.len();
+        FixedBuffer {
+            start: NonNull::from(buffer).cast(),
+            capacity,
+            free: RefCell::new((capacity > 0).then_some(0..capacity).into_iter().collect()),
+        }
+    }
+
+    /// Size of the whole region in bytes
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes not handed out
+    pub fn available(&self) -> usize {
+        self.free.borrow().iter().map(|range| range.len()).sum()
+    }
+}
+
+unsafe impl Allocator for FixedBuffer {
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let base = self.start.as_ptr() as usize;
+        let mut free = self.free.borrow_mut();
+        let (index, offset) = free.iter().enumerate().find_map(|(index, range)| {
+            let offset = (base + range.start).checked_next_multiple_of(layout.align())? - base;
+            (offset.checked_add(layout.size())? <= range.end).then_some((index, offset))
+        })?;
+
+        // Whatever is left on either side of the block stays free
+        let range = free.remove(index);
+        let end = offset + layout.size();
+        if end < range.end {
+            free.insert(index, end..range.end);
+        }
+        if range.start < offset {
+            free.insert(index, range.start..offset);
+        }
+        // SAFETY: the block lies within the buffer
+        Some(unsafe { NonNull::new_unchecked(self.start.as_ptr().add(offset)) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let start = ptr.as_ptr() as usize - self.start.as_ptr() as usize;
+        let end = start + layout.size();
+        let mut free = self.free.borrow_mut();
+        let index = free.partition_point(|range| range.end <= start);
+
+        // Merge with the free ranges right before and after
+        let merges_next = free.get(index).is_some_and(|next| next.start == end);
+        let merges_previous = index > 0 && free[index - 1].end == start;
+        match (merges_previous, merges_next) {
+            (true, true) => {
+                let next = free.remove(index);
+                free[index - 1].end = next.end;
+            }
+            (true, false) => free[index - 1].end = end,
+            (false, true) => free[index].start = start,
+            (false, false) => free.insert(index, start..end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(size: usize) -> FixedBuffer {
+        FixedBuffer::new(Box::leak(vec![0u8; size].into_boxed_slice()))
+    }
+
+    #[test]
+    fn test_fixed_buffer() {
+        let buffer = buffer(1024);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let blocks: Vec<_> = (0..4).map(|_| buffer.allocate(layout).expect("Buffer has room")).collect();
+        assert_eq!(buffer.available(), 0);
+        assert!(buffer.allocate(layout).is_none());
+
+        // Freed blocks merge back into one range, whatever the order
+        unsafe {
+            buffer.deallocate(blocks[1], layout);
+            buffer.deallocate(blocks[3], layout);
+            buffer.deallocate(blocks[2], layout);
+        }
+        assert_eq!((buffer.free.borrow().len(), buffer.free.borrow()[0].clone()), (1, 256..1024));
+        let big = buffer.allocate(Layout::from_size_align(768, 8).unwrap()).expect("Freed space is reused");
+        assert_eq!(big, blocks[1]);
+        assert!(buffer.allocate(Layout::from_size_align(1, 1).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_fixed_buffer_alignment() {
+        let buffer = buffer(1024);
+        let small = buffer.allocate(Layout::from_size_align(8, 8).unwrap()).unwrap();
+        let aligned = buffer.allocate(Layout::from_size_align(64, 256).unwrap()).unwrap();
+        assert_eq!(aligned.as_ptr() as usize % 256, 0);
+        // The padding before the aligned block is still free
+        assert_eq!(buffer.available(), 1024 - 8 - 64);
+        unsafe {
+            buffer.deallocate(aligned, Layout::from_size_align(64, 256).unwrap());
+            buffer.deallocate(small, Layout::from_size_align(8, 8).unwrap());
+        }
+        assert_eq!((buffer.free.borrow().len(), buffer.available()), (1, 1024));
+    }
+
+    #[cfg(all(feature = "mimalloc", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_mimalloc() {
+        let mut mm = crate::MemoryManager::with_allocator(MiMalloc);
+        assert!(mm.allocate(64).is_some());
+    }
+}
@@ -1,9 +1,10 @@
 //! Abstract Syntax Tree - Parser output
 //!
 //! Represents the hierarchical structure of expressions
-//! Extended with arrays and more operations
+//! Extended with arrays and more operations, variables, and user functions
 
-use std::fmt;
+use crate::prelude::*;
+use core::fmt;
 
 /// Unary operations (single operand)
 #[derive(Debug, Clone, PartialEq)]
@@ -17,10 +18,16 @@ pub enum UnaryOp {
     Asin,
     Acos,
     Atan,
+    Sec,
+    Csc,
+    Cot,
     // Hyperbolic
     Sinh,
     Cosh,
     Tanh,
+    Asinh,
+    Acosh,
+    Atanh,
     // Mathematical
     Sqrt,
     Cbrt,
@@ -32,6 +39,8 @@ pub enum UnaryOp {
     Floor,
     Ceil,
     Round,
+    Trunc,
+    Frac,
     Sign,
     // Conversion
     ToRad,
@@ -42,6 +51,12 @@ pub enum UnaryOp {
     Min,
     Max,
     Len,
+    Multinomial,
+    // Combinatorics
+    Derange,
+    // Number theory
+    Totient,
+    PrimePi,
 }
 
 impl fmt::Display for UnaryOp {
@@ -55,9 +70,15 @@ impl fmt::Display for UnaryOp {
             UnaryOp::Asin => write!(f, "asin"),
             UnaryOp::Acos => write!(f, "acos"),
             UnaryOp::Atan => write!(f, "atan"),
+            UnaryOp::Sec => write!(f, "sec"),
+            UnaryOp::Csc => write!(f, "csc"),
+            UnaryOp::Cot => write!(f, "cot"),
             UnaryOp::Sinh => write!(f, "sinh"),
             UnaryOp::Cosh => write!(f, "cosh"),
             UnaryOp::Tanh => write!(f, "tanh"),
+            UnaryOp::Asinh => write!(f, "asinh"),
+            UnaryOp::Acosh => write!(f, "acosh"),
+            UnaryOp::Atanh => write!(f, "atanh"),
             UnaryOp::Sqrt => write!(f, "sqrt"),
             UnaryOp::Cbrt => write!(f, "cbrt"),
             UnaryOp::Log => write!(f, "log"),
@@ -68,6 +89,8 @@ impl fmt::Display for UnaryOp {
             UnaryOp::Floor => write!(f, "floor"),
             UnaryOp::Ceil => write!(f, "ceil"),
             UnaryOp::Round => write!(f, "round"),
+            UnaryOp::Trunc => write!(f, "trunc"),
+            UnaryOp::Frac => write!(f, "frac"),
             UnaryOp::Sign => write!(f, "sign"),
             UnaryOp::ToRad => write!(f, "rad"),
             UnaryOp::ToDeg => write!(f, "deg"),
@@ -76,6 +99,10 @@ impl fmt::Display for UnaryOp {
             UnaryOp::Min => write!(f, "min"),
             UnaryOp::Max => write!(f, "max"),
             UnaryOp::Len => write!(f, "len"),
+            UnaryOp::Multinomial => write!(f, "multinomial"),
+            UnaryOp::Derange => write!(f, "derange"),
+            UnaryOp::Totient => write!(f, "totient"),
+            UnaryOp::PrimePi => write!(f, "primepi"),
         }
     }
 }
@@ -94,6 +121,9 @@ pub enum BinaryOp {
     Lcm,
     Npr,        // Permutations
     Ncr,        // Combinations
+    Stirling2,  // Stirling numbers of the second kind
+    // Rounding
+    RoundTo,    // round(x, digits)
 }
 
 impl fmt::Display for BinaryOp {
@@ -109,6 +139,77 @@ impl fmt::Display for BinaryOp {
             BinaryOp::Lcm => write!(f, "lcm"),
             BinaryOp::Npr => write!(f, "nPr"),
             BinaryOp::Ncr => write!(f, "nCr"),
+            BinaryOp::Stirling2 => write!(f, "stirling2"),
+            BinaryOp::RoundTo => write!(f, "round"),
+        }
+    }
+}
+
+/// Builtins taking a fixed number of arguments, which may be arrays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NaryOp {
+    Linspace,   // linspace(a, b, n)
+    Zeros,      // zeros(n)
+    Ones,       // ones(n)
+    Repeat,     // repeat(v, n)
+    Hist,       // hist(array, bins)
+    HistEdges,  // histedges(array, bins)
+    Solve,      // solve(A, b)
+    Eigvals,    // eigvals(M)
+    Linfit,     // linfit(xs, ys)
+    NormPdf,    // normpdf(x, mu, sigma)
+    NormCdf,    // normcdf(x, mu, sigma)
+    InvNorm,    // invnorm(p, mu, sigma)
+    BinomPdf,   // binompdf(n, p, k)
+    PoissonPdf, // poissonpdf(lambda, k)
+    FromBase,   // frombase(digits, base)
+    ToBase,     // tobase(x, base)
+    Cfrac,      // cfrac(x, n)
+    Convergent, // convergent(x, n)
+    Sample,     // sample(array, k)
+    Shuffle,    // shuffle(array)
+    Map,        // map(f, array)
+    Filter,     // filter(f, array)
+}
+
+impl NaryOp {
+    /// Number of arguments the function takes
+    pub fn arity(self) -> usize {
+        match self {
+            NaryOp::Linspace | NaryOp::NormPdf | NaryOp::NormCdf | NaryOp::InvNorm | NaryOp::BinomPdf => 3,
+            NaryOp::Repeat | NaryOp::Hist | NaryOp::HistEdges | NaryOp::Solve | NaryOp::Linfit
+            | NaryOp::PoissonPdf | NaryOp::FromBase | NaryOp::ToBase | NaryOp::Cfrac | NaryOp::Convergent | NaryOp::Sample
+            | NaryOp::Map | NaryOp::Filter => 2,
+            NaryOp::Zeros | NaryOp::Ones | NaryOp::Eigvals | NaryOp::Shuffle => 1,
+        }
+    }
+}
+
+impl fmt::Display for NaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NaryOp::Linspace => write!(f, "linspace"),
+            NaryOp::Zeros => write!(f, "zeros"),
+            NaryOp::Ones => write!(f, "ones"),
+            NaryOp::Repeat => write!(f, "repeat"),
+            NaryOp::Hist => write!(f, "hist"),
+            NaryOp::HistEdges => write!(f, "histedges"),
+            NaryOp::Solve => write!(f, "solve"),
+            NaryOp::Eigvals => write!(f, "eigvals"),
+            NaryOp::Linfit => write!(f, "linfit"),
+            NaryOp::NormPdf => write!(f, "normpdf"),
+            NaryOp::NormCdf => write!(f, "normcdf"),
+            NaryOp::InvNorm => write!(f, "invnorm"),
+            NaryOp::BinomPdf => write!(f, "binompdf"),
+            NaryOp::PoissonPdf => write!(f, "poissonpdf"),
+            NaryOp::FromBase => write!(f, "frombase"),
+            NaryOp::ToBase => write!(f, "tobase"),
+            NaryOp::Cfrac => write!(f, "cfrac"),
+            NaryOp::Convergent => write!(f, "convergent"),
+            NaryOp::Sample => write!(f, "sample"),
+            NaryOp::Shuffle => write!(f, "shuffle"),
+            NaryOp::Map => write!(f, "map"),
+            NaryOp::Filter => write!(f, "filter"),
         }
     }
 }
@@ -136,8 +237,46 @@ pub enum Expr {
         left: Box<Expr>,
         right: Box<Expr>,
     },
+    /// Call of an n-ary builtin, `args` matching its arity
+    Nary {
+        op: NaryOp,
+        args: Vec<Expr>,
+    },
+    /// Variable reference (a global, or a parameter inside a function body)
+    Variable(String),
+    /// Assignment `name = value`; evaluates to the assigned value
+    Assign {
+        name: String,
+        value: Box<Expr>,
+    },
+    /// Definition `name(params) = body`; evaluates to 0
+    FunctionDef {
+        name: String,
+        params: Vec<String>,
+        body: Box<Expr>,
+    },
+    /// Call of a user-defined function, or of a function value by name
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+    /// Anonymous function `x -> body`, capturing the enclosing function's
+    /// parameters it uses
+    Lambda {
+        params: Vec<String>,
+        body: Box<Expr>,
+    },
+    /// Local binding `let name = value in body`; evaluates to the body
+    Let {
+        name: String,
+        value: Box<Expr>,
+        body: Box<Expr>,
+    },
+    /// Statements separated by `;`; evaluates to the last one
+    Sequence(Vec<Expr>),
 }
 
+#[allow(clippy::should_implement_trait)]
 impl Expr {
     pub fn number(value: f64) -> Self {
         Expr::Number(value)
@@ -201,6 +340,126 @@ impl Expr {
     pub fn modulo(left: Expr, right: Expr) -> Self {
         Self::binary(BinaryOp::Modulo, left, right)
     }
+
+    pub fn nary(op: NaryOp, args: Vec<Expr>) -> Self {
+        Expr::Nary { op, args }
+    }
+
+    pub fn variable(name: impl Into<String>) -> Self {
+        Expr::Variable(name.into())
+    }
+
+    pub fn assign(name: impl Into<String>, value: Expr) -> Self {
+        Expr::Assign {
+            name: name.into(),
+            value: Box::new(value),
+        }
+    }
+
+    pub fn function_def(name: impl Into<String>, params: Vec<String>, body: Expr) -> Self {
+        Expr::FunctionDef {
+            name: name.into(),
+            params,
+            body: Box::new(body),
+        }
+    }
+
+    pub fn call(name: impl Into<String>, args: Vec<Expr>) -> Self {
+        Expr::Call {
+            name: name.into(),
+            args,
+        }
+    }
+
+    pub fn lambda(params: Vec<String>, body: Expr) -> Self {
+        Expr::Lambda {
+            params,
+            body: Box::new(body),
+        }
+    }
+
+    pub fn let_in(name: impl Into<String>, value: Expr, body: Expr) -> Self {
+        Expr::Let {
+            name: name.into(),
+            value: Box::new(value),
+            body: Box::new(body),
+        }
+    }
+
+    /// Number of nodes on the longest path from here down to a leaf
+    ///
+    /// Walks the tree with an explicit stack, so it is safe to call on
+    /// trees too deep to recurse over.
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut pending = vec![(self, 1)];
+        while let Some((expr, depth)) = pending.pop() {
+            deepest = deepest.max(depth);
+            match expr {
+                Expr::Number(_) | Expr::Variable(_) => {}
+                Expr::Array(children)
+                | Expr::Nary { args: children, .. }
+                | Expr::Call { args: children, .. }
+                | Expr::Sequence(children) => {
+                    pending.extend(children.iter().map(|child| (child, depth + 1)));
+                }
+                Expr::UnaryOp { operand: child, .. }
+                | Expr::PostfixOp { operand: child, .. }
+                | Expr::Assign { value: child, .. }
+                | Expr::FunctionDef { body: child, .. }
+                | Expr::Lambda { body: child, .. } => pending.push((child, depth + 1)),
+                Expr::BinaryOp { left, right, .. } | Expr::Let { value: left, body: right, .. } => {
+                    pending.push((left, depth + 1));
+                    pending.push((right, depth + 1));
+                }
+            }
+        }
+        deepest
+    }
+
+    /// Direct subexpressions, in the order code is generated for them
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Number(_) | Expr::Variable(_) => Vec::new(),
+            Expr::Array(children)
+            | Expr::Nary { args: children, .. }
+            | Expr::Call { args: children, .. }
+            | Expr::Sequence(children) => children.iter().collect(),
+            Expr::UnaryOp { operand: child, .. }
+            | Expr::PostfixOp { operand: child, .. }
+            | Expr::Assign { value: child, .. }
+            | Expr::FunctionDef { body: child, .. }
+            | Expr::Lambda { body: child, .. } => vec![child],
+            Expr::BinaryOp { left, right, .. } | Expr::Let { value: left, body: right, .. } => vec![left, right],
+        }
+    }
+
+    /// Number of nodes in the tree, counted with an explicit stack like `depth`
+    pub fn size(&self) -> usize {
+        let mut size = 0;
+        let mut pending = vec![self];
+        while let Some(expr) = pending.pop() {
+            size += 1;
+            pending.extend(expr.children());
+        }
+        size
+    }
+}
+
+/// Take left-nested operations apart in a loop, as the parser builds a
+/// chain like 1+1+…+1, instead of recursing once per operator
+impl Drop for Expr {
+    fn drop(&mut self) {
+        let Expr::BinaryOp { left, .. } = self else { return };
+        if !matches!(**left, Expr::BinaryOp { .. }) {
+            return;
+        }
+        let mut next = core::mem::replace(&mut **left, Expr::Number(0.0));
+        while let Expr::BinaryOp { left, .. } = &mut next {
+            // The node replaced here is left with no chain to drop
+            next = core::mem::replace(&mut **left, Expr::Number(0.0));
+        }
+    }
 }
 
 impl fmt::Display for Expr {
@@ -234,12 +493,56 @@ impl fmt::Display for Expr {
             }
             Expr::BinaryOp { op, left, right } => {
                 match op {
-                    BinaryOp::Gcd | BinaryOp::Lcm | BinaryOp::Npr | BinaryOp::Ncr => {
+                    BinaryOp::Gcd | BinaryOp::Lcm | BinaryOp::Npr | BinaryOp::Ncr | BinaryOp::Stirling2 | BinaryOp::RoundTo => {
                         write!(f, "{}({}, {})", op, left, right)
                     }
                     _ => write!(f, "({} {} {})", left, op, right)
                 }
             }
+            Expr::Nary { op, args } => {
+                write!(f, "{}(", op)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Variable(name) => write!(f, "{}", name),
+            Expr::Assign { name, value } => write!(f, "{} = {}", name, value),
+            Expr::FunctionDef { name, params, body } => {
+                write!(f, "{}({}) = {}", name, params.join(", "), body)
+            }
+            Expr::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Lambda { params, body } => write_lambda(f, params, body),
+            Expr::Let { name, value, body } => write!(f, "(let {} = {} in {})", name, value, body),
+            Expr::Sequence(statements) => {
+                for (i, statement) in statements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", statement)?;
+                }
+                Ok(())
+            }
         }
     }
 }
+
+/// Write `x -> body`, or `(a, b) -> body` for any other number of parameters
+pub(crate) fn write_lambda(f: &mut fmt::Formatter<'_>, params: &[String], body: &dyn fmt::Display) -> fmt::Result {
+    match params {
+        [param] => write!(f, "{} -> {}", param, body),
+        _ => write!(f, "({}) -> {}", params.join(", "), body),
+    }
+}
@@ -0,0 +1,624 @@
+//! Bytecode Calculator - Command-Line Interface
+//!
+//! Evaluates the expression given as arguments, or starts an interactive
+//! REPL with line editing and history when there is none:
+//!
+//!   byte-calci-cli "2^10"                        -> 1024
+//!   byte-calci-cli --notation fraction 0.75      -> 3/4
+//!   byte-calci-cli -- -e                         -> -2.7182818285, not --emit
+//!   byte-calci-cli --emit tokens,ast "1 + 2"     -> each pipeline stage
+//!   cat formulas.txt | byte-calci-cli --batch    -> one result per line
+//!   byte-calci-cli --save f.bcal "x^2 + 1"       -> bytecode file, run with --load
+//!   byte-calci-cli                               -> REPL
+//!
+//! Variables and functions defined in the REPL or a batch last until it exits.
+
+use calculator::tokenizer::Token;
+use calculator::{
+    Chunk, CodeGenerator, Disassembler, Expr, Notation, NumberFormat, Optimizer, Parser, Tokenizer, VirtualMachine,
+};
+use rustyline::error::ReadlineError;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: byte-calci-cli [OPTIONS] [--] [EXPRESSION...]
+       byte-calci-cli --batch [OPTIONS] < FILE
+       byte-calci-cli --load FILE [OPTIONS]
+
+Evaluates EXPRESSION and prints the result, or starts a REPL without one.
+
+Options:
+  -e, --emit <STAGES>    Print these stages, comma-separated (default: result):
+                           tokens    one token per line
+                           ast       the parsed expression, fully parenthesized
+                           bytecode  the main chunk as hex bytes
+                           trace     ip, instruction, stack before and after, tab-separated
+                           result    the formatted result
+      --disasm           Print the disassembly with hex, like `--emit disasm`
+  -b, --batch            Evaluate each line of stdin; blank lines give empty results
+      --delimiter <SEP>  Separator between batch results (default: newline); \\t and \\n are escapes
+  -k, --continue         In a batch, print errors in place of results and keep going
+  -o, --save <FILE>      Compile EXPRESSION to a bytecode file instead of evaluating it
+  -l, --load <FILE>      Run a bytecode file written by --save; every stage but
+                         tokens and ast can be emitted
+  -n, --notation <NAME>  auto, fixed, scientific, engineering, si,
+                         fraction or exact
+  -d, --digits <N>       Decimals (fixed) or significant figures
+  -i, --integer          Require integers; inexact division and overflow are errors
+      --checked          Check each instruction against the stack, to debug bytecode
+  -h, --help             Print this help
+  -V, --version          Print the version
+      --                 Treat everything after it as the expression, e.g. `-- -e`";
+
+const REPL_HELP: &str = "\
+Enter an expression to evaluate it, e.g. `x = 2` then `f(y) = x * y` then `f(21)`.
+  :vars   List variables and functions
+  :help   Show this help
+  :quit   Exit (or Ctrl+D)";
+
+/// History file in the home directory, kept between REPL sessions
+const HISTORY_FILE: &str = ".byte-calci-history";
+
+/// A pipeline stage `--emit` can print
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Tokens,
+    Ast,
+    Bytecode,
+    Disasm,
+    Trace,
+    Result,
+}
+
+impl Stage {
+    const ALL: [Stage; 6] = [Stage::Tokens, Stage::Ast, Stage::Bytecode, Stage::Disasm, Stage::Trace, Stage::Result];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Tokens => "tokens",
+            Stage::Ast => "ast",
+            Stage::Bytecode => "bytecode",
+            Stage::Disasm => "disasm",
+            Stage::Trace => "trace",
+            Stage::Result => "result",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    Version,
+    /// Evaluate the expression and exit
+    Evaluate(String),
+    /// Evaluate each line of stdin
+    Batch,
+    /// Compile the expression into a bytecode file
+    Save { expression: String, path: PathBuf },
+    /// Run a bytecode file
+    Load(PathBuf),
+    Repl,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: Command,
+    format: NumberFormat,
+    /// Stages to print, in order
+    stages: Vec<Stage>,
+    /// Written between batch results
+    delimiter: String,
+    /// Whether a batch goes on past errors
+    keep_going: bool,
+    /// Evaluate in the VM's checked integer mode
+    integer: bool,
+    /// Check instructions against the stack as they run
+    checked: bool,
+}
+
+/// Parse the arguments after the program name
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Repl,
+        format: NumberFormat::default(),
+        stages: Vec::new(),
+        delimiter: String::from("\n"),
+        keep_going: false,
+        integer: false,
+        checked: false,
+    };
+    let mut batch = false;
+    let mut save = None;
+    let mut load = None;
+    let mut expression = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                options.command = Command::Help;
+                return Ok(options);
+            }
+            "-V" | "--version" => {
+                options.command = Command::Version;
+                return Ok(options);
+            }
+            "-e" | "--emit" => {
+                for name in value(&arg)?.split(',') {
+                    let stage = Stage::ALL
+                        .into_iter()
+                        .find(|stage| stage.name().eq_ignore_ascii_case(name.trim()))
+                        .ok_or_else(|| format!("Unknown stage '{}'", name))?;
+                    options.add_stage(stage);
+                }
+            }
+            "--disasm" => options.add_stage(Stage::Disasm),
+            "-n" | "--notation" => {
+                let name = value(&arg)?;
+                options.format.notation = Notation::ALL
+                    .into_iter()
+                    .find(|notation| notation.name().eq_ignore_ascii_case(&name))
+                    .ok_or_else(|| format!("Unknown notation '{}'", name))?;
+            }
+            "-d" | "--digits" => {
+                let digits = value(&arg)?;
+                options.format.digits = digits.parse().map_err(|_| format!("Invalid digit count '{}'", digits))?;
+            }
+            "-b" | "--batch" => batch = true,
+            "--delimiter" => options.delimiter = unescape(&value(&arg)?),
+            "-k" | "--continue" => options.keep_going = true,
+            "-i" | "--integer" => options.integer = true,
+            "--checked" => options.checked = true,
+            "-o" | "--save" => save = Some(PathBuf::from(value(&arg)?)),
+            "-l" | "--load" => load = Some(PathBuf::from(value(&arg)?)),
+            // Everything after `--` is expression text, even `-e`
+            "--" => {
+                expression.extend(args.by_ref());
+                break;
+            }
+            // Everything else, including negative numbers like "-3", is part of the expression
+            _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ => expression.push(arg),
+        }
+    }
+    options.command = match (batch, expression.is_empty()) {
+        (true, true) => Command::Batch,
+        (true, false) => return Err("--batch reads expressions from stdin, not arguments".into()),
+        (false, true) => Command::Repl,
+        (false, false) => Command::Evaluate(expression.join(" ")),
+    };
+    options.command = match (options.command, save, load) {
+        (command, None, None) => command,
+        (Command::Evaluate(expression), Some(path), None) => Command::Save { expression, path },
+        (Command::Repl, None, Some(path)) => Command::Load(path),
+        (_, Some(_), _) => return Err("--save needs an expression to compile".into()),
+        (_, None, Some(_)) => return Err("--load runs the file, not an expression or batch".into()),
+    };
+    if options.stages.is_empty() {
+        options.stages.push(Stage::Result);
+    }
+    Ok(options)
+}
+
+impl Options {
+    fn add_stage(&mut self, stage: Stage) {
+        if !self.stages.contains(&stage) {
+            self.stages.push(stage);
+        }
+    }
+}
+
+/// Expand the `\t`, `\n` and `\\` escapes a shell won't
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('t')) => unescaped.push('\t'),
+            ('\\', Some('n')) => unescaped.push('\n'),
+            ('\\', Some('\\')) => unescaped.push('\\'),
+            _ => {
+                unescaped.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    unescaped
+}
+
+/// Evaluates input against one VM, so definitions carry over
+struct Calculator {
+    vm: VirtualMachine,
+    format: NumberFormat,
+}
+
+impl Calculator {
+    fn new(format: NumberFormat) -> Self {
+        Calculator {
+            vm: VirtualMachine::new(),
+            format,
+        }
+    }
+
+    /// A calculator set up as `options` ask
+    fn from_options(options: &Options) -> Self {
+        let mut calculator = Calculator::new(options.format);
+        if options.integer {
+            calculator.vm.enable_integer_mode();
+        }
+        if options.checked {
+            calculator.vm.enable_checked_mode();
+        }
+        calculator
+    }
+
+    /// Compile `input` and render `stages`, headed by their names if there are several
+    ///
+    /// The program only runs when the result or trace is asked for, and then
+    /// once: traced as generated, like the GUI's trace, or else optimized.
+    /// Compiler warnings go to stderr.
+    fn run(&mut self, input: &str, stages: &[Stage]) -> Result<String, String> {
+        let tokens = Tokenizer::new(input).tokenize().map_err(|e| e.to_string())?;
+        let ast = Parser::new(tokens.clone()).parse().map_err(|e| e.to_string())?;
+        let (chunk, diagnostics) = CodeGenerator::new().with_source(input).compile(&ast).map_err(|e| e.to_string())?;
+        for diagnostic in diagnostics {
+            eprintln!("Warning: {}", diagnostic);
+        }
+        self.run_chunk(&chunk, Some((&tokens, &ast)), stages)
+    }
+
+    /// Run a bytecode file written by `save`, which keeps no tokens or AST
+    fn run_file(&mut self, path: &Path, stages: &[Stage]) -> Result<String, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let chunk = Chunk::from_bytes(&bytes).map_err(|e| e.to_string())?;
+        self.run_chunk(&chunk, None, stages)
+    }
+
+    /// Render `stages` of `chunk`, compiled from `source` if it's known
+    fn run_chunk(&mut self, chunk: &Chunk, source: Option<(&[Token], &Expr)>, stages: &[Stage]) -> Result<String, String> {
+        if source.is_none() {
+            if let Some(stage) = stages.iter().find(|stage| matches!(stage, Stage::Tokens | Stage::Ast)) {
+                return Err(format!("A bytecode file has no {} to emit", stage.name()));
+            }
+        }
+
+        let value = if stages.contains(&Stage::Trace) {
+            self.vm.enable_tracing();
+            let executed = self.vm.execute_value(chunk);
+            self.vm.disable_tracing();
+            Some(executed.map_err(|e| e.to_string())?)
+        } else if stages.contains(&Stage::Result) {
+            Some(self.vm.execute_value(&Optimizer::fuse_superinstructions(chunk)).map_err(|e| e.to_string())?)
+        } else {
+            None
+        };
+
+        let mut outputs = Vec::new();
+        for &stage in stages {
+            let output = match (stage, source) {
+                (Stage::Tokens, Some((tokens, _))) => {
+                    tokens.iter().map(|token| token.to_string()).collect::<Vec<_>>().join("\n")
+                }
+                (Stage::Ast, Some((_, ast))) => ast.to_string(),
+                (Stage::Tokens | Stage::Ast, None) => unreachable!("checked above"),
+                (Stage::Bytecode, _) => hex_dump(chunk),
+                (Stage::Disasm, _) => Disassembler::format_with_hex(chunk).trim_end().to_string(),
+                (Stage::Trace, _) => format_trace(&self.vm, &self.format),
+                (Stage::Result, Some((_, Expr::FunctionDef { name, params, .. }))) => {
+                    format!("defined {}({})", name, params.join(", "))
+                }
+                (Stage::Result, _) => value.as_ref().map_or_else(String::new, |value| self.format.format_value(value)),
+            };
+            outputs.push(if stages.len() > 1 { format!("# {}\n{}", stage.name(), output) } else { output });
+        }
+        Ok(outputs.join("\n\n"))
+    }
+
+    /// Variables and function signatures, one per line
+    fn definitions(&self) -> String {
+        let variables = self
+            .vm
+            .globals()
+            .map(|(name, value)| format!("{} = {}", name, self.format.format_value(&value.to_value())));
+        let functions = self.vm.functions().map(|f| f.to_string());
+        variables.chain(functions).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Compile `input`, optimized, into a bytecode file at `path`
+fn save(input: &str, path: &Path) -> Result<String, String> {
+    let tokens = Tokenizer::new(input).tokenize().map_err(|e| e.to_string())?;
+    let ast = Parser::new(tokens).parse().map_err(|e| e.to_string())?;
+    let chunk = Optimizer::fuse_superinstructions(&CodeGenerator::new().with_source(input).compile(&ast).map_err(|e| e.to_string())?.0);
+    let bytes = chunk.to_bytes();
+    std::fs::write(path, &bytes).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    Ok(format!("Wrote {} bytes to {}", bytes.len(), path.display()))
+}
+
+/// Code bytes of `chunk`, 16 to a row behind their offset
+fn hex_dump(chunk: &Chunk) -> String {
+    let mut output = String::new();
+    for (row, bytes) in chunk.code().chunks(16).enumerate() {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let _ = writeln!(output, "{:04X}  {}", row * 16, bytes.join(" "));
+    }
+    output.trim_end().to_string()
+}
+
+/// The VM's last trace, one tab-separated step per line
+///
+/// Operands are printed exactly, the stacks in the result format.
+fn format_trace(vm: &VirtualMachine, format: &NumberFormat) -> String {
+    let exact = NumberFormat::new(Notation::Exact);
+    let mut output = String::new();
+    for step in vm.trace() {
+        let instruction = match step.operand {
+            Some(operand) => format!("{} {}", step.opcode, exact.format(operand)),
+            None => step.opcode.to_string(),
+        };
+        let _ = writeln!(
+            output,
+            "0x{:02X}\t{}\t{}\t{}",
+            step.ip,
+            instruction,
+            format.format_list(&step.stack_before),
+            format.format_list(&step.stack_after)
+        );
+    }
+    output.trim_end().to_string()
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(HISTORY_FILE))
+}
+
+/// Evaluate each line of `input`, writing the results separated by the delimiter
+///
+/// Stops at the first error unless `keep_going` is set, in which case the
+/// error takes the place of that line's result. Returns whether every line
+/// succeeded.
+fn batch(calculator: &mut Calculator, options: &Options, input: impl BufRead, output: &mut impl Write) -> io::Result<bool> {
+    let mut succeeded = true;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        // Blank lines keep their row, so results line up with the input
+        let evaluated = if line.is_empty() { Ok(String::new()) } else { calculator.run(line, &options.stages) };
+        let record = match evaluated {
+            Ok(result) => result,
+            Err(e) if options.keep_going => {
+                succeeded = false;
+                format!("Error: {}", e)
+            }
+            Err(e) => {
+                writeln!(output)?;
+                output.flush()?;
+                eprintln!("Error on line {}: {}", index + 1, e);
+                return Ok(false);
+            }
+        };
+        if index > 0 {
+            output.write_all(options.delimiter.as_bytes())?;
+        }
+        output.write_all(record.as_bytes())?;
+    }
+    writeln!(output)?;
+    Ok(succeeded)
+}
+
+fn repl(mut calculator: Calculator, stages: &[Stage]) -> rustyline::Result<()> {
+    let mut editor = rustyline::DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing file just means a first run
+        let _ = editor.load_history(path);
+    }
+    println!("Bytecode Calculator {} - :help for help, Ctrl+D to exit", env!("CARGO_PKG_VERSION"));
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // Ctrl+C drops the line, Ctrl+D exits
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        match line {
+            ":quit" | ":q" | ":exit" => break,
+            ":help" | ":h" => println!("{}", REPL_HELP),
+            ":vars" => {
+                let definitions = calculator.definitions();
+                if !definitions.is_empty() {
+                    println!("{}", definitions);
+                }
+            }
+            _ => match calculator.run(line, stages) {
+                Ok(result) => println!("{}", result),
+                Err(e) => eprintln!("Error: {}", e),
+            },
+        }
+    }
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("Couldn't save history to {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match &options.command {
+        Command::Help => println!("{}", USAGE),
+        Command::Version => println!("byte-calci-cli {}", env!("CARGO_PKG_VERSION")),
+        Command::Evaluate(expression) => match Calculator::from_options(&options).run(expression, &options.stages) {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Save { expression, path } => match save(expression, path) {
+            Ok(message) => println!("{}", message),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Load(path) => match Calculator::from_options(&options).run_file(path, &options.stages) {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Batch => {
+            let mut calculator = Calculator::from_options(&options);
+            match batch(&mut calculator, &options, io::stdin().lock(), &mut io::stdout().lock()) {
+                Ok(true) => {}
+                Ok(false) => return ExitCode::FAILURE,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Command::Repl => {
+            if let Err(e) = repl(Calculator::from_options(&options), &options.stages) {
+                eprintln!("Error: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(args("").unwrap().command, Command::Repl);
+        assert_eq!(args("2 ^ 10").unwrap().command, Command::Evaluate("2 ^ 10".into()));
+        assert_eq!(args("-3 + 1").unwrap().command, Command::Evaluate("-3 + 1".into()));
+        assert_eq!(args("1 --help").unwrap().command, Command::Help);
+
+        let options = args("--notation Fixed -d 2 pi").unwrap();
+        assert_eq!(options.format, NumberFormat::new(Notation::Fixed).with_digits(2));
+        assert_eq!(options.command, Command::Evaluate("pi".into()));
+
+        assert!(args("--notation roman 1").is_err());
+        assert_eq!(args("-- -e").unwrap().command, Command::Evaluate("-e".into()));
+        assert_eq!(args("-n fraction -- -e --help").unwrap().command, Command::Evaluate("-e --help".into()));
+        assert_eq!(args("--").unwrap().command, Command::Repl);
+        assert!(args("-e").is_err());
+        assert!(args("--digits").is_err());
+        assert!(args("--frobnicate").is_err());
+
+        assert_eq!(args("1").unwrap().stages, [Stage::Result]);
+        assert_eq!(
+            args("--emit tokens,AST -e tokens --disasm 1").unwrap().stages,
+            [Stage::Tokens, Stage::Ast, Stage::Disasm]
+        );
+        assert!(args("--emit tokens,llvm 1").is_err());
+    }
+
+    fn run_batch(flags: &str, input: &str) -> (String, bool) {
+        let options = args(&format!("--batch {}", flags)).unwrap();
+        let mut calculator = Calculator::from_options(&options);
+        let mut output = Vec::new();
+        let succeeded = batch(&mut calculator, &options, input.as_bytes(), &mut output).unwrap();
+        (String::from_utf8(output).unwrap(), succeeded)
+    }
+
+    #[test]
+    fn test_batch() {
+        assert_eq!(args("--batch").unwrap().command, Command::Batch);
+        assert!(args("--batch 1 + 1").is_err());
+        assert_eq!(unescape(r"a\tb\\n\x"), "a\tb\\n\\x");
+
+        assert_eq!(run_batch("", "r = 2\n\npi * r^2\n"), ("2\n\n12.5663706144\n".into(), true));
+        assert_eq!(run_batch("--delimiter ,", "1\n2\n3"), ("1,2,3\n".into(), true));
+        assert_eq!(run_batch("", "tobase(255, 16)\n"), ("ff\n".into(), true));
+
+        // Stops at the first error, or carries on and reports it in place
+        assert_eq!(run_batch("", "1\n1 +\n3"), ("1\n".into(), false));
+        let (output, succeeded) = run_batch("-k --delimiter \\t", "1\n1 +\n3");
+        assert!(!succeeded);
+        assert!(output.starts_with("1\tError: ") && output.ends_with("\t3\n"));
+
+        let (output, _) = run_batch("-k --integer", "12 / 4\n7 / 2\n0.5");
+        assert_eq!(output, "3\nError: Math error: 7 is not divisible by 2\nError: Not an integer: 0.5\n");
+    }
+
+    #[test]
+    fn test_emit_stages() {
+        let mut calculator = Calculator::new(NumberFormat::default());
+        assert_eq!(calculator.run("1 + 2", &[Stage::Tokens]).unwrap(), "1\n+\n2");
+        assert_eq!(calculator.run("2 * (3 + 4)", &[Stage::Ast]).unwrap(), "(2 * (3 + 4))");
+        assert!(calculator.run("1", &[Stage::Bytecode]).unwrap().starts_with("0000  "));
+        assert!(calculator.run("1", &[Stage::Disasm]).unwrap().contains("HALT"));
+
+        let trace = calculator.run("1 + 2", &[Stage::Trace]).unwrap();
+        assert_eq!(trace.lines().count(), 4);
+        assert!(trace.lines().nth(2).unwrap().ends_with("\t[1, 2]\t[3]"));
+
+        let both = calculator.run("2^10", &[Stage::Ast, Stage::Result]).unwrap();
+        assert_eq!(both, "# ast\n(2 ^ 10)\n\n# result\n1024");
+        // Nothing runs unless asked, so runtime errors only show with the result
+        assert!(calculator.run("1 / 0", &[Stage::Ast]).is_ok());
+        // ...and it runs once for both the trace and the result
+        calculator.run("n = 0", &[Stage::Result]).unwrap();
+        assert!(calculator.run("n = n + 1", &[Stage::Trace, Stage::Result]).unwrap().ends_with("# result\n1"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("byte-calci-cli-test-{}.bcal", std::process::id()));
+        let command = args(&format!("--save {} f(x) = x + 1; f(2)", path.display())).unwrap().command;
+        assert_eq!(command, Command::Save { expression: "f(x) = x + 1; f(2)".into(), path: path.clone() });
+        assert!(args("--save out.bcal").is_err());
+        assert!(args("--load out.bcal 1 + 1").is_err());
+
+        assert!(save("f(x) = x + 1; f(2)", &path).unwrap().starts_with("Wrote "));
+        let mut calculator = Calculator::new(NumberFormat::default());
+        assert_eq!(calculator.run_file(&path, &[Stage::Result]).as_deref(), Ok("3"));
+        let disassembly = calculator.run_file(&path, &[Stage::Disasm]).unwrap();
+        assert!(disassembly.contains("Source: f(x) = x + 1; f(2)"), "{}", disassembly);
+        assert!(calculator.run_file(&path, &[Stage::Ast]).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(calculator.run_file(&path, &[Stage::Result]).is_err());
+    }
+
+    #[test]
+    fn test_definitions_persist() {
+        let mut calculator = Calculator::new(NumberFormat::default());
+        let mut evaluate = |input: &str| calculator.run(input, &[Stage::Result]);
+        assert_eq!(evaluate("x = 2").as_deref(), Ok("2"));
+        assert_eq!(evaluate("f(y) = x * y").as_deref(), Ok("defined f(y)"));
+        assert_eq!(evaluate("f(21)").as_deref(), Ok("42"));
+        assert_eq!(evaluate("linspace(0, x, 3)").as_deref(), Ok("[0, 1, 2]"));
+        assert!(evaluate("g(1)").is_err());
+        assert_eq!(calculator.definitions(), "x = 2\nf(y) = (x * y)");
+    }
+}
@@ -0,0 +1,201 @@
+//! Built-in function, constant and keyword registry
+//!
+//! One table lists every name the tokenizer recognizes, with the token it
+//! becomes, a signature and a one-line description. The tokenizer resolves
+//! names through `lookup`; the GUI's autocompletion lists `completions`.
+
+use crate::tokenizer::Token;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinKind {
+    Function,
+    Constant,
+    Keyword,
+}
+
+/// A name built into the language
+#[derive(Debug)]
+pub struct Builtin {
+    /// Name as written in completions
+    pub name: &'static str,
+    /// Other accepted spellings
+    pub aliases: &'static [&'static str],
+    pub token: Token,
+    pub kind: BuiltinKind,
+    /// e.g. "nCr(n, k)"
+    pub signature: &'static str,
+    pub description: &'static str,
+}
+
+macro_rules! function {
+    ($name:literal $(| $alias:literal)*, $token:ident, $signature:literal, $description:literal) => {
+        Builtin {
+            name: $name,
+            aliases: &[$($alias),*],
+            token: Token::$token,
+            kind: BuiltinKind::Function,
+            signature: $signature,
+            description: $description,
+        }
+    };
+}
+
+macro_rules! constant {
+    ($name:literal $(| $alias:literal)*, $token:ident, $description:literal) => {
+        Builtin {
+            name: $name,
+            aliases: &[$($alias),*],
+            token: Token::$token,
+            kind: BuiltinKind::Constant,
+            signature: $name,
+            description: $description,
+        }
+    };
+}
+
+macro_rules! keyword {
+    ($name:literal, $token:ident, $signature:literal, $description:literal) => {
+        Builtin {
+            name: $name,
+            aliases: &[],
+            token: Token::$token,
+            kind: BuiltinKind::Keyword,
+            signature: $signature,
+            description: $description,
+        }
+    };
+}
+
+/// Every built-in function, constant and keyword
+pub static BUILTINS: &[Builtin] = &[
+    // Trig functions
+    function!("sin", Sin, "sin(x)", "Sine of x in degrees"),
+    function!("cos", Cos, "cos(x)", "Cosine of x in degrees"),
+    function!("tan", Tan, "tan(x)", "Tangent of x in degrees"),
+    function!("asin" | "arcsin", Asin, "asin(x)", "Inverse sine, in degrees"),
+    function!("acos" | "arccos", Acos, "acos(x)", "Inverse cosine, in degrees"),
+    function!("atan" | "arctan", Atan, "atan(x)", "Inverse tangent, in degrees"),
+    function!("sec", Sec, "sec(x)", "Secant of x in degrees"),
+    function!("csc" | "cosec", Csc, "csc(x)", "Cosecant of x in degrees"),
+    function!("cot", Cot, "cot(x)", "Cotangent of x in degrees"),
+    // Hyperbolic
+    function!("sinh", Sinh, "sinh(x)", "Hyperbolic sine"),
+    function!("cosh", Cosh, "cosh(x)", "Hyperbolic cosine"),
+    function!("tanh", Tanh, "tanh(x)", "Hyperbolic tangent"),
+    function!("asinh" | "arsinh", Asinh, "asinh(x)", "Inverse hyperbolic sine"),
+    function!("acosh" | "arcosh", Acosh, "acosh(x)", "Inverse hyperbolic cosine"),
+    function!("atanh" | "artanh", Atanh, "atanh(x)", "Inverse hyperbolic tangent"),
+    // Math functions
+    function!("sqrt", Sqrt, "sqrt(x)", "Square root"),
+    function!("cbrt", Cbrt, "cbrt(x)", "Cube root"),
+    function!("log" | "log10", Log, "log(x)", "Base-10 logarithm"),
+    function!("log2", Log2, "log2(x)", "Base-2 logarithm"),
+    function!("ln", Ln, "ln(x)", "Natural logarithm"),
+    function!("exp", Exp, "exp(x)", "e raised to x"),
+    function!("abs", Abs, "abs(x)", "Absolute value"),
+    function!("floor", Floor, "floor(x)", "Round down to an integer"),
+    function!("ceil", Ceil, "ceil(x)", "Round up to an integer"),
+    function!("round", Round, "round(x, digits)", "Round to the nearest integer, or to digits decimals"),
+    function!("trunc", Trunc, "trunc(x)", "Round toward zero"),
+    function!("frac", Frac, "frac(x)", "Fractional part, with the sign of x"),
+    function!("sign" | "sgn", Sign, "sign(x)", "-1, 0 or 1 by the sign of x"),
+    // Array functions
+    function!("sum", Sum, "sum(array)", "Sum of the elements"),
+    function!("avg" | "mean" | "average", Avg, "avg(array)", "Mean of the elements"),
+    function!("min", Min, "min(array)", "Smallest element"),
+    function!("max", Max, "max(array)", "Largest element"),
+    function!("len" | "length" | "count", Len, "len(array)", "Number of elements"),
+    function!("multinomial", Multinomial, "multinomial([k1, k2, ...])", "(k1 + k2 + ...)! / (k1! k2! ...)"),
+    function!("linspace", Linspace, "linspace(a, b, n)", "n evenly spaced values from a to b"),
+    function!("zeros", Zeros, "zeros(n)", "n zeros"),
+    function!("ones", Ones, "ones(n)", "n ones"),
+    function!("repeat", Repeat, "repeat(v, n)", "n copies of v"),
+    function!("hist" | "histogram", Hist, "hist(array, bins)", "Counts in bins of equal width"),
+    function!("histedges", HistEdges, "histedges(array, bins)", "The bins+1 edges of hist's bins"),
+    function!("solve", Solve, "solve(A, b)", "Solution x of A x = b, A given as rows"),
+    function!("eigvals", Eigvals, "eigvals(M)", "Real eigenvalues of a square matrix, ascending"),
+    function!("linfit", Linfit, "linfit(xs, ys)", "Least-squares line: [slope, intercept, r2]"),
+    function!("normpdf", NormPdf, "normpdf(x, mu, sigma)", "Normal probability density"),
+    function!("normcdf", NormCdf, "normcdf(x, mu, sigma)", "Normal probability of at most x"),
+    function!("invnorm", InvNorm, "invnorm(p, mu, sigma)", "x with normcdf(x, mu, sigma) = p"),
+    function!("binompdf", BinomPdf, "binompdf(n, p, k)", "Probability of k successes in n trials"),
+    function!("poissonpdf", PoissonPdf, "poissonpdf(lambda, k)", "Probability of k events at rate lambda"),
+    function!("frombase", FromBase, "frombase(\"digits\", base)", "Number written in base 2 to 36"),
+    function!("tobase", ToBase, "tobase(x, base)", "Integer x written in base 2 to 36, as a string"),
+    function!("cfrac", Cfrac, "cfrac(x, n)", "First n continued fraction terms of x"),
+    function!("convergent", Convergent, "convergent(x, n)", "Fraction from the first n terms of cfrac(x, n)"),
+    function!("sample", Sample, "sample(array, k)", "k elements drawn at random without replacement"),
+    function!("shuffle", Shuffle, "shuffle(array)", "The elements in random order"),
+    // Higher-order
+    function!("map", Map, "map(f, array)", "f applied to each element, e.g. map(x -> x^2, a)"),
+    function!("filter", Filter, "filter(f, array)", "The elements for which f is non-zero"),
+    // Combinatorics
+    function!("gcd", Gcd, "gcd(a, b)", "Greatest common divisor"),
+    function!("lcm", Lcm, "lcm(a, b)", "Least common multiple"),
+    function!("nPr" | "perm", Npr, "nPr(n, k)", "Ordered selections of k from n"),
+    function!("nCr" | "comb" | "choose", Ncr, "nCr(n, k)", "Unordered selections of k from n"),
+    function!("derange" | "subfactorial", Derange, "derange(n)", "Permutations of n leaving nothing in place"),
+    function!("stirling2", Stirling2, "stirling2(n, k)", "Partitions of n items into k non-empty sets"),
+    // Number theory
+    function!("totient" | "eulerphi", Totient, "totient(n)", "Count of 1..n coprime to n"),
+    function!("primepi", PrimePi, "primepi(x)", "Count of primes up to x"),
+    // Symbolic rewrites
+    function!("expand", Expand, "expand(p)", "Polynomial p with its products multiplied out"),
+    function!("factor", Factor, "factor(p)", "Polynomial p split into factors"),
+    // Conversion
+    function!("rad" | "torad", ToRad, "rad(x)", "Degrees to radians"),
+    function!("deg" | "todeg", ToDeg, "deg(x)", "Radians to degrees"),
+    // Constants
+    constant!("pi", Pi, "π ≈ 3.14159"),
+    constant!("e", E, "Euler's number ≈ 2.71828"),
+    constant!("tau", Tau, "τ = 2π ≈ 6.28319"),
+    constant!("phi" | "golden", Phi, "Golden ratio φ ≈ 1.61803"),
+    // Keywords
+    keyword!("let", Let, "let x = v in body", "body with x bound to v"),
+    keyword!("in", In, "let x = v in body", "Ends the binding of a let"),
+];
+
+/// The built-in called `name` (or one of its aliases), ignoring case
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| {
+        builtin.name.eq_ignore_ascii_case(name) || builtin.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+/// The built-in the tokenizer reads as `token`
+pub fn for_token(token: &Token) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.token == *token)
+}
+
+/// Built-ins whose name starts with `prefix`, ignoring case, in table order
+pub fn completions(prefix: &str) -> impl Iterator<Item = &'static Builtin> + '_ {
+    BUILTINS.iter().filter(move |builtin| {
+        builtin.name.len() >= prefix.len()
+            && builtin.name.is_char_boundary(prefix.len())
+            && builtin.name[..prefix.len()].eq_ignore_ascii_case(prefix)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("SIN").map(|b| &b.token), Some(&Token::Sin));
+        assert_eq!(lookup("choose").map(|b| b.name), Some("nCr"));
+        assert!(lookup("sine").is_none());
+    }
+
+    #[test]
+    fn test_completions() {
+        let names: Vec<_> = completions("s").map(|b| b.name).collect();
+        assert_eq!(names, ["sin", "sec", "sinh", "sqrt", "sign", "sum", "solve", "sample", "shuffle", "stirling2"]);
+        assert_eq!(completions("NC").next().map(|b| b.signature), Some("nCr(n, k)"));
+        // Functions come before constants
+        let kinds: Vec<_> = completions("p").map(|b| b.kind).collect();
+        assert_eq!(kinds.last(), Some(&BuiltinKind::Constant));
+        assert!(kinds.is_sorted_by_key(|kind| *kind == BuiltinKind::Constant));
+    }
+}
@@ -4,8 +4,16 @@
 //!   - Each instruction is 1 byte opcode
 //!   - PUSH instruction followed by 8 bytes for f64 value
 //!   - PUSH_ARRAY followed by 8 bytes for count, then count * 8 bytes for values
+//!   - LOAD_GLOBAL/STORE_GLOBAL/LOAD_LOCAL/DEF_FUNC followed by a 2-byte index
+//!   - CALL followed by a 2-byte name index and a 1-byte argument count
 //!   - All other instructions are single byte
 //!
+//! Names of globals and functions are stored once in the chunk's name
+//! table, and user-defined functions are compiled into chunks of their own
+//! kept in its function table; instructions refer to both by index. Both
+//! tables are deduplicated as they are written, so an identical lambda
+//! written twice is compiled into one entry.
+//!
 //! Example bytecode for "sin(90) + 2^3":
 //!   0x00: PUSH 90.0     (9 bytes: opcode + f64)
 //!   0x09: SIN           (1 byte)
@@ -14,8 +22,25 @@
 //!   0x1C: POW           (1 byte)
 //!   0x1D: ADD           (1 byte)
 //!   0x1E: HALT          (1 byte)
+//!
+//! Each chunk carries a `SourceMap` from its bytecode back to the tokens
+//! every instruction was compiled from, when the compiler was given them.
+//!
+//! The VM does not interpret these raw bytes directly: a chunk is decoded
+//! once into a list of `Instruction`s, which is cached until the chunk is
+//! modified again.
 
-use std::fmt;
+use crate::prelude::*;
+use crate::intern::Symbol;
+use alloc::sync::Arc;
+use core::fmt;
+use core::ops::Range;
+use once_cell::race::OnceBox;
+#[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+use std::sync::OnceLock;
+
+#[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+use crate::jit::{JitError, JitFunction};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -35,6 +60,9 @@ pub enum OpCode {
     Neg = 0x15,       // Negate top of stack
     Mod = 0x16,       // Pop two, push modulo (second % first)
     Factorial = 0x17, // Pop one, push factorial
+    Derange = 0x18,   // Pop n, push the number of derangements of n
+    Totient = 0x19,   // Pop n, push Euler's totient of n
+    PrimePi = 0x1A,   // Pop x, push the number of primes up to x
 
     // Trigonometric functions (radians)
     Sin = 0x20,
@@ -46,6 +74,12 @@ pub enum OpCode {
     Sinh = 0x26,      // Hyperbolic sine
     Cosh = 0x27,      // Hyperbolic cosine
     Tanh = 0x28,      // Hyperbolic tangent
+    Sec = 0x29,       // Secant
+    Csc = 0x2A,       // Cosecant
+    Cot = 0x2B,       // Cotangent
+    Asinh = 0x2C,     // Inverse hyperbolic sine
+    Acosh = 0x2D,     // Inverse hyperbolic cosine
+    Atanh = 0x2E,     // Inverse hyperbolic tangent
 
     // Mathematical functions
     Sqrt = 0x30,
@@ -61,6 +95,8 @@ pub enum OpCode {
     Sign = 0x3A,      // Sign function (-1, 0, 1)
     ToRad = 0x3B,     // Degrees to radians
     ToDeg = 0x3C,     // Radians to degrees
+    Trunc = 0x3D,     // Round toward zero
+    Frac = 0x3E,      // Fractional part
 
     // Array operations
     Sum = 0x40,       // Sum of array
@@ -68,19 +104,95 @@ pub enum OpCode {
     Min = 0x42,       // Minimum of array
     Max = 0x43,       // Maximum of array
     Len = 0x44,       // Length of array
+    Multinomial = 0x4F, // Multinomial coefficient of the array's counts
+    Linspace = 0x45,  // Pop n, b, a, push n evenly spaced values from a to b
+    Zeros = 0x46,     // Pop n, push n zeros
+    Ones = 0x47,      // Pop n, push n ones
+    Repeat = 0x48,    // Pop n, v, push n copies of v
+    Hist = 0x49,      // Pop bins, array, push the count in each bin
+    HistEdges = 0x4A, // Pop bins, array, push the bin edges
+    Solve = 0x4B,     // Pop b, A, push the solution of A x = b
+    Eigvals = 0x4C,   // Pop a matrix, push its eigenvalues
+    Linfit = 0x4D,    // Pop ys, xs, push [slope, intercept, r2]
+    FromBase = 0x4E,  // Pop base, digit values, push the number they write
 
     // Binary functions (2-argument)
     Gcd = 0x50,       // Greatest common divisor
     Lcm = 0x51,       // Least common multiple
     Npr = 0x52,       // Permutations nPr
     Ncr = 0x53,       // Combinations nCr
+    RoundTo = 0x54,   // Pop two, push second rounded to first decimals
+    Stirling2 = 0x55, // Stirling number of the second kind S(second, first)
+
+    // Continued fractions
+    Cfrac = 0x56,      // Pop n, x, push the first n continued fraction terms of x
+    Convergent = 0x57, // Pop n, x, push the fraction those terms make
+
+    // Probability distributions
+    NormPdf = 0x58,    // Pop sigma, mu, x, push the normal density at x
+    NormCdf = 0x59,    // Pop sigma, mu, x, push the normal probability of at most x
+    InvNorm = 0x5A,    // Pop sigma, mu, p, push the normal quantile of p
+    BinomPdf = 0x5B,   // Pop k, p, n, push the binomial probability of k
+    PoissonPdf = 0x5C, // Pop k, lambda, push the Poisson probability of k
+
+    // Random
+    Sample = 0x5D,     // Pop k, array, push k of its elements drawn at random
+    Shuffle = 0x5E,    // Pop array, push its elements in random order
+
+    // Bases
+    ToBase = 0x5F,     // Pop base, x, push x written in that base as a string
+
+    // Superinstructions (fused common pairs)
+    PushAdd = 0x60,   // Add constant to top of stack (followed by 8 bytes f64)
+    PushMul = 0x61,   // Multiply top of stack by constant (followed by 8 bytes f64)
+    DupMul = 0x62,    // Square top of stack
+
+    // Variables and functions
+    LoadGlobal = 0x70,     // Push global variable (followed by u16 name index)
+    StoreGlobal = 0x71,    // Set global variable to top of stack, leaving it there (u16 name index)
+    LoadLocal = 0x72,      // Push parameter or let-binding of the current frame (followed by u16 slot)
+    DefineFunction = 0x73, // Bind a function from the function table (followed by u16 index)
+    Call = 0x74,           // Call function (followed by u16 name index, u8 argument count)
+    TailCall = 0x75,       // Call as the last thing a function does, reusing its frame (operands as CALL)
+    Closure = 0x76,        // Pop captured values, push a function value (u16 function index, u8 capture count)
+    LoadUpvalue = 0x77,    // Push a value captured by the running closure (followed by u16 slot)
+    CallValue = 0x78,      // Call the function value below the arguments (followed by u8 argument count)
+    Map = 0x79,            // Pop array, function value, push the function applied to each element
+    Filter = 0x7A,         // Pop array, function value, push the elements it returns non-zero for
+    StoreLocal = 0x7B,     // Pop into a let-binding slot of the current frame (followed by u16 slot)
+
+    // Extensions, run by handlers the host registers on the VM
+    Ext0 = 0xE0,
+    Ext1 = 0xE1,
+    Ext2 = 0xE2,
+    Ext3 = 0xE3,
+    Ext4 = 0xE4,
+    Ext5 = 0xE5,
+    Ext6 = 0xE6,
+    Ext7 = 0xE7,
+    Ext8 = 0xE8,
+    Ext9 = 0xE9,
+    Ext10 = 0xEA,
+    Ext11 = 0xEB,
+    Ext12 = 0xEC,
+    Ext13 = 0xED,
+    Ext14 = 0xEE,
+    Ext15 = 0xEF,
 
     // Control
     Halt = 0xFF,
 }
 
 impl OpCode {
-    pub fn from_byte(byte: u8) -> Option<OpCode> {
+    /// The extension opcodes 0xE0-0xEF, in order
+    pub const EXTENSIONS: [OpCode; 16] = [
+        OpCode::Ext0, OpCode::Ext1, OpCode::Ext2, OpCode::Ext3,
+        OpCode::Ext4, OpCode::Ext5, OpCode::Ext6, OpCode::Ext7,
+        OpCode::Ext8, OpCode::Ext9, OpCode::Ext10, OpCode::Ext11,
+        OpCode::Ext12, OpCode::Ext13, OpCode::Ext14, OpCode::Ext15,
+    ];
+
+    pub const fn from_byte(byte: u8) -> Option<OpCode> {
         match byte {
             0x01 => Some(OpCode::Push),
             0x02 => Some(OpCode::Pop),
@@ -94,6 +206,9 @@ impl OpCode {
             0x15 => Some(OpCode::Neg),
             0x16 => Some(OpCode::Mod),
             0x17 => Some(OpCode::Factorial),
+            0x18 => Some(OpCode::Derange),
+            0x19 => Some(OpCode::Totient),
+            0x1A => Some(OpCode::PrimePi),
             0x20 => Some(OpCode::Sin),
             0x21 => Some(OpCode::Cos),
             0x22 => Some(OpCode::Tan),
@@ -103,6 +218,12 @@ impl OpCode {
             0x26 => Some(OpCode::Sinh),
             0x27 => Some(OpCode::Cosh),
             0x28 => Some(OpCode::Tanh),
+            0x29 => Some(OpCode::Sec),
+            0x2A => Some(OpCode::Csc),
+            0x2B => Some(OpCode::Cot),
+            0x2C => Some(OpCode::Asinh),
+            0x2D => Some(OpCode::Acosh),
+            0x2E => Some(OpCode::Atanh),
             0x30 => Some(OpCode::Sqrt),
             0x31 => Some(OpCode::Log),
             0x32 => Some(OpCode::Ln),
@@ -116,15 +237,56 @@ impl OpCode {
             0x3A => Some(OpCode::Sign),
             0x3B => Some(OpCode::ToRad),
             0x3C => Some(OpCode::ToDeg),
+            0x3D => Some(OpCode::Trunc),
+            0x3E => Some(OpCode::Frac),
             0x40 => Some(OpCode::Sum),
             0x41 => Some(OpCode::Avg),
             0x42 => Some(OpCode::Min),
             0x43 => Some(OpCode::Max),
             0x44 => Some(OpCode::Len),
+            0x45 => Some(OpCode::Linspace),
+            0x46 => Some(OpCode::Zeros),
+            0x47 => Some(OpCode::Ones),
+            0x48 => Some(OpCode::Repeat),
+            0x49 => Some(OpCode::Hist),
+            0x4A => Some(OpCode::HistEdges),
+            0x4B => Some(OpCode::Solve),
+            0x4C => Some(OpCode::Eigvals),
+            0x4D => Some(OpCode::Linfit),
+            0x4E => Some(OpCode::FromBase),
+            0x4F => Some(OpCode::Multinomial),
             0x50 => Some(OpCode::Gcd),
             0x51 => Some(OpCode::Lcm),
             0x52 => Some(OpCode::Npr),
             0x53 => Some(OpCode::Ncr),
+            0x54 => Some(OpCode::RoundTo),
+            0x55 => Some(OpCode::Stirling2),
+            0x56 => Some(OpCode::Cfrac),
+            0x57 => Some(OpCode::Convergent),
+            0x58 => Some(OpCode::NormPdf),
+            0x59 => Some(OpCode::NormCdf),
+            0x5A => Some(OpCode::InvNorm),
+            0x5B => Some(OpCode::BinomPdf),
+            0x5C => Some(OpCode::PoissonPdf),
+            0x5D => Some(OpCode::Sample),
+            0x5E => Some(OpCode::Shuffle),
+            0x5F => Some(OpCode::ToBase),
+            0x60 => Some(OpCode::PushAdd),
+            0x61 => Some(OpCode::PushMul),
+            0x62 => Some(OpCode::DupMul),
+            0x70 => Some(OpCode::LoadGlobal),
+            0x71 => Some(OpCode::StoreGlobal),
+            0x72 => Some(OpCode::LoadLocal),
+            0x73 => Some(OpCode::DefineFunction),
+            0x74 => Some(OpCode::Call),
+            0x75 => Some(OpCode::TailCall),
+            0x76 => Some(OpCode::Closure),
+            0x77 => Some(OpCode::LoadUpvalue),
+            0x78 => Some(OpCode::CallValue),
+            0x79 => Some(OpCode::Map),
+            0x7A => Some(OpCode::Filter),
+            0x7B => Some(OpCode::StoreLocal),
+            0xE0..=0xEF => Some(OpCode::EXTENSIONS[(byte - 0xE0) as usize]),
             0xFF => Some(OpCode::Halt),
             _ => None,
         }
@@ -144,6 +306,9 @@ impl OpCode {
             OpCode::Neg => "NEG",
             OpCode::Mod => "MOD",
             OpCode::Factorial => "FACT",
+            OpCode::Derange => "DERANGE",
+            OpCode::Totient => "TOTIENT",
+            OpCode::PrimePi => "PRIME_PI",
             OpCode::Sin => "SIN",
             OpCode::Cos => "COS",
             OpCode::Tan => "TAN",
@@ -153,6 +318,12 @@ impl OpCode {
             OpCode::Sinh => "SINH",
             OpCode::Cosh => "COSH",
             OpCode::Tanh => "TANH",
+            OpCode::Sec => "SEC",
+            OpCode::Csc => "CSC",
+            OpCode::Cot => "COT",
+            OpCode::Asinh => "ASINH",
+            OpCode::Acosh => "ACOSH",
+            OpCode::Atanh => "ATANH",
             OpCode::Sqrt => "SQRT",
             OpCode::Log => "LOG",
             OpCode::Ln => "LN",
@@ -166,30 +337,117 @@ impl OpCode {
             OpCode::Sign => "SIGN",
             OpCode::ToRad => "TORAD",
             OpCode::ToDeg => "TODEG",
+            OpCode::Trunc => "TRUNC",
+            OpCode::Frac => "FRAC",
             OpCode::Sum => "SUM",
             OpCode::Avg => "AVG",
             OpCode::Min => "MIN",
             OpCode::Max => "MAX",
             OpCode::Len => "LEN",
+            OpCode::Multinomial => "MULTINOMIAL",
+            OpCode::Linspace => "LINSPACE",
+            OpCode::Zeros => "ZEROS",
+            OpCode::Ones => "ONES",
+            OpCode::Repeat => "REPEAT",
+            OpCode::Hist => "HIST",
+            OpCode::HistEdges => "HIST_EDGES",
+            OpCode::Solve => "SOLVE",
+            OpCode::Eigvals => "EIGVALS",
+            OpCode::Linfit => "LINFIT",
+            OpCode::FromBase => "FROM_BASE",
+            OpCode::ToBase => "TO_BASE",
             OpCode::Gcd => "GCD",
             OpCode::Lcm => "LCM",
             OpCode::Npr => "NPR",
             OpCode::Ncr => "NCR",
+            OpCode::RoundTo => "ROUND_TO",
+            OpCode::Stirling2 => "STIRLING2",
+            OpCode::Cfrac => "CFRAC",
+            OpCode::Convergent => "CONVERGENT",
+            OpCode::NormPdf => "NORM_PDF",
+            OpCode::NormCdf => "NORM_CDF",
+            OpCode::InvNorm => "INV_NORM",
+            OpCode::BinomPdf => "BINOM_PDF",
+            OpCode::PoissonPdf => "POISSON_PDF",
+            OpCode::Sample => "SAMPLE",
+            OpCode::Shuffle => "SHUFFLE",
+            OpCode::PushAdd => "PUSH_ADD",
+            OpCode::PushMul => "PUSH_MUL",
+            OpCode::DupMul => "DUP_MUL",
+            OpCode::LoadGlobal => "LOAD_GLOBAL",
+            OpCode::StoreGlobal => "STORE_GLOBAL",
+            OpCode::LoadLocal => "LOAD_LOCAL",
+            OpCode::DefineFunction => "DEF_FUNC",
+            OpCode::Call => "CALL",
+            OpCode::TailCall => "TAILCALL",
+            OpCode::Closure => "CLOSURE",
+            OpCode::LoadUpvalue => "LOAD_UPVALUE",
+            OpCode::CallValue => "CALL_VALUE",
+            OpCode::Map => "MAP",
+            OpCode::Filter => "FILTER",
+            OpCode::StoreLocal => "STORE_LOCAL",
+            OpCode::Ext0 => "EXT0",
+            OpCode::Ext1 => "EXT1",
+            OpCode::Ext2 => "EXT2",
+            OpCode::Ext3 => "EXT3",
+            OpCode::Ext4 => "EXT4",
+            OpCode::Ext5 => "EXT5",
+            OpCode::Ext6 => "EXT6",
+            OpCode::Ext7 => "EXT7",
+            OpCode::Ext8 => "EXT8",
+            OpCode::Ext9 => "EXT9",
+            OpCode::Ext10 => "EXT10",
+            OpCode::Ext11 => "EXT11",
+            OpCode::Ext12 => "EXT12",
+            OpCode::Ext13 => "EXT13",
+            OpCode::Ext14 => "EXT14",
+            OpCode::Ext15 => "EXT15",
             OpCode::Halt => "HALT",
         }
     }
 
+    /// Position of an extension opcode within `EXTENSIONS`
+    pub fn extension_slot(&self) -> Option<usize> {
+        let byte = *self as u8;
+        (0xE0..=0xEF).contains(&byte).then(|| (byte - 0xE0) as usize)
+    }
+
     /// Returns true if this opcode is followed by an operand
     pub fn has_operand(&self) -> bool {
-        matches!(self, OpCode::Push | OpCode::PushArray)
+        matches!(
+            self,
+            OpCode::Push
+                | OpCode::PushArray
+                | OpCode::PushAdd
+                | OpCode::PushMul
+                | OpCode::LoadGlobal
+                | OpCode::StoreGlobal
+                | OpCode::LoadLocal
+                | OpCode::StoreLocal
+                | OpCode::DefineFunction
+                | OpCode::Call
+                | OpCode::TailCall
+                | OpCode::Closure
+                | OpCode::LoadUpvalue
+                | OpCode::CallValue
+        )
     }
 
     /// Size in bytes of instruction including operand (only for fixed-size operands)
     pub fn size(&self) -> usize {
         match self {
-            OpCode::Push => 9, // 1 byte opcode + 8 bytes f64
+            OpCode::Push | OpCode::PushAdd | OpCode::PushMul => 9, // 1 byte opcode + 8 bytes f64
             // PushArray has variable size, returns minimum
             OpCode::PushArray => 9, // 1 byte opcode + 8 bytes count (values follow)
+            OpCode::LoadGlobal
+            | OpCode::StoreGlobal
+            | OpCode::LoadLocal
+            | OpCode::StoreLocal
+            | OpCode::DefineFunction
+            | OpCode::LoadUpvalue => 3,
+            OpCode::Call | OpCode::TailCall => 4, // 1 byte opcode + 2 bytes name + 1 byte argument count
+            OpCode::Closure => 4,                 // 1 byte opcode + 2 bytes function + 1 byte capture count
+            OpCode::CallValue => 2,               // 1 byte opcode + 1 byte argument count
             _ => 1,
         }
     }
@@ -201,50 +459,414 @@ impl fmt::Display for OpCode {
     }
 }
 
+/// Immediate operand of a decoded instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    None,
+    /// Constant pushed by PUSH
+    Number(f64),
+    /// Element count popped by PUSH_ARRAY, or argument count of CALL_VALUE
+    Count(u64),
+    /// Name, local or upvalue slot, or function table index
+    Index(u16),
+    /// Callee name index and argument count of CALL
+    Call { name: u16, argc: u8 },
+    /// Function table index and number of captured values of CLOSURE
+    Closure { function: u16, captures: u8 },
+}
+
+/// Instruction decoded from raw bytecode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    /// Byte offset of the opcode within the chunk
+    pub offset: usize,
+    pub opcode: OpCode,
+    pub operand: Operand,
+}
+
+impl Instruction {
+    /// Byte offset of the instruction following this one
+    pub fn next_offset(&self) -> usize {
+        self.offset + self.opcode.size()
+    }
+}
+
+/// Name of every lambda's function, which no identifier can clash with
+pub const LAMBDA_NAME: &str = "<lambda>";
+
+/// A compiled user-defined function
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    /// Body, reading parameters with LOAD_LOCAL and ending in HALT
+    pub chunk: Chunk,
+    /// The body as written back from the AST, e.g. "(x ^ 2)"
+    pub body: String,
+}
+
+impl Function {
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    /// Whether this is the body of a lambda rather than a named definition
+    pub fn is_lambda(&self) -> bool {
+        self.name == LAMBDA_NAME
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.params == other.params && self.chunk.code() == other.chunk.code()
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_lambda() {
+            return crate::ast::write_lambda(f, &self.params, &self.body);
+        }
+        write!(f, "{}({}) = {}", self.name, self.params.join(", "), self.body)
+    }
+}
+
+/// Where a chunk came from, saved along with its code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkMetadata {
+    /// Text the chunk was compiled from
+    pub source: Option<String>,
+    /// Name and version of the compiling crate, e.g. "calculator 0.1.0"
+    pub compiler: String,
+    /// Whether the superinstruction pass has run over the code
+    pub optimized: bool,
+    /// Compile time in seconds since the Unix epoch, where there is a clock
+    pub compiled_at: Option<u64>,
+}
+
+impl ChunkMetadata {
+    /// Metadata for a chunk this crate is compiling now
+    pub fn now(source: Option<String>) -> Self {
+        #[cfg(feature = "std")]
+        let compiled_at = web_time::SystemTime::now()
+            .duration_since(web_time::UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs());
+        #[cfg(not(feature = "std"))]
+        let compiled_at = None;
+        ChunkMetadata {
+            source,
+            compiler: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string(),
+            optimized: false,
+            compiled_at,
+        }
+    }
+}
+
+/// Size of a chunk's name and function tables, and what deduplication saved
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Entries in the name table
+    pub names: usize,
+    /// Entries in the function table
+    pub functions: usize,
+    /// Additions that reused an identical entry instead of storing another
+    pub deduplicated: usize,
+    /// Bytes of name text and function code those reuses didn't store again
+    pub bytes_saved: usize,
+}
+
+/// Maps bytecode offsets to the span of tokens they were compiled from
+///
+/// Stored as runs of consecutive bytes sharing a span, so a chunk costs an
+/// entry per instruction at most. Bytes written without a span aren't mapped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// Byte range and token range of each run, in order of offset
+    runs: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Map the byte at `offset`, which must follow every byte mapped so far, to `span`
+    pub fn add(&mut self, offset: usize, span: Range<usize>) {
+        match self.runs.last_mut() {
+            Some((bytes, last)) if bytes.end == offset && *last == span => bytes.end += 1,
+            _ => self.runs.push((offset..offset + 1, span)),
+        }
+    }
+
+    /// Token span the byte at `offset` was compiled from
+    pub fn span(&self, offset: usize) -> Option<Range<usize>> {
+        let index = self.runs.partition_point(|(bytes, _)| bytes.end <= offset);
+        self.runs
+            .get(index)
+            .filter(|(bytes, _)| bytes.contains(&offset))
+            .map(|(_, span)| span.clone())
+    }
+
+    /// Runs of bytes and the token span each was compiled from, in order of offset
+    pub fn runs(&self) -> &[(Range<usize>, Range<usize>)] {
+        &self.runs
+    }
+
+    /// Offset of the first byte compiled from the narrowest span containing
+    /// token `token`, the code most specifically produced by it
+    pub fn offset_of(&self, token: usize) -> Option<usize> {
+        self.runs
+            .iter()
+            .filter(|(_, span)| span.contains(&token))
+            .min_by_key(|(bytes, span)| (span.len(), bytes.start))
+            .map(|(bytes, _)| bytes.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+}
+
 /// Chunk of bytecode with associated data
 #[derive(Debug, Clone)]
 pub struct Chunk {
     code: Vec<u8>,
-    /// Source line numbers for debugging (maps bytecode offset to source line)
-    lines: Vec<usize>,
+    /// Token spans of the code, for relating instructions to the source
+    source_map: SourceMap,
+    /// Span recorded for the bytes written next
+    span: Option<Range<usize>>,
+    /// Global and function names referenced by index
+    names: Arc<Vec<Symbol>>,
+    /// Functions defined by DEF_FUNC
+    functions: Arc<Vec<Arc<Function>>>,
+    /// Set on chunks compiled from source; function bodies have none
+    metadata: Option<Arc<ChunkMetadata>>,
+    /// Deduplication counts, as the tables themselves are counted on demand
+    pool: PoolStats,
+    /// Decoded instructions, built on first use and dropped on every write
+    decoded: OnceBox<Vec<Instruction>>,
+    /// Native code, compiled on first `execute_jit` and dropped on every write
+    #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+    jitted: OnceLock<Result<Arc<JitFunction>, JitError>>,
 }
 
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
+            source_map: SourceMap::new(),
+            span: None,
+            names: Arc::default(),
+            functions: Arc::default(),
+            metadata: None,
+            pool: PoolStats::default(),
+            decoded: OnceBox::new(),
+            #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+            jitted: OnceLock::new(),
         }
     }
 
     /// Write a single byte
-    pub fn write_byte(&mut self, byte: u8, line: usize) {
+    pub fn write_byte(&mut self, byte: u8) {
+        if let Some(span) = &self.span {
+            self.source_map.add(self.code.len(), span.clone());
+        }
         self.code.push(byte);
-        self.lines.push(line);
+        self.decoded = OnceBox::new();
+        #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+        self.jitted.take();
     }
 
     /// Write an opcode
-    pub fn write_op(&mut self, op: OpCode, line: usize) {
-        self.write_byte(op as u8, line);
+    pub fn write_op(&mut self, op: OpCode) {
+        self.write_byte(op as u8);
     }
 
     /// Write a PUSH instruction with f64 constant
-    pub fn write_push(&mut self, value: f64, line: usize) {
-        self.write_op(OpCode::Push, line);
+    pub fn write_push(&mut self, value: f64) {
+        self.write_op_f64(OpCode::Push, value);
+    }
+
+    /// Write an instruction followed by an f64 operand (PUSH and fused PUSH_* ops)
+    pub fn write_op_f64(&mut self, op: OpCode, value: f64) {
+        self.write_op(op);
         let bytes = value.to_le_bytes();
         for byte in bytes {
-            self.write_byte(byte, line);
+            self.write_byte(byte);
+        }
+    }
+
+    /// Empty chunk sharing the name and function tables of `chunk`
+    ///
+    /// For passes that rewrite code but keep its operand indices valid.
+    pub fn with_tables_of(chunk: &Chunk) -> Self {
+        Chunk {
+            names: chunk.names.clone(),
+            functions: chunk.functions.clone(),
+            pool: chunk.pool,
+            ..Chunk::new()
+        }
+    }
+
+    /// Write an instruction followed by a u16 operand (name, slot, or function index)
+    pub fn write_op_u16(&mut self, op: OpCode, index: u16) {
+        self.write_op(op);
+        for byte in index.to_le_bytes() {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Write a CALL of the function named by `name` with `argc` arguments
+    pub fn write_call(&mut self, name: u16, argc: u8) {
+        self.write_op_u16(OpCode::Call, name);
+        self.write_byte(argc);
+    }
+
+    /// Write a CLOSURE of function `function` over the top `captures` values
+    pub fn write_closure(&mut self, function: u16, captures: u8) {
+        self.write_op_u16(OpCode::Closure, function);
+        self.write_byte(captures);
+    }
+
+    /// Write a CALL_VALUE with `argc` arguments
+    pub fn write_call_value(&mut self, argc: u8) {
+        self.write_op(OpCode::CallValue);
+        self.write_byte(argc);
+    }
+
+    /// Write a TAILCALL, a CALL whose result the function returns unchanged
+    pub fn write_tail_call(&mut self, name: u16, argc: u8) {
+        self.write_op_u16(OpCode::TailCall, name);
+        self.write_byte(argc);
+    }
+
+    /// Index of `name` in the name table, interning and adding it if needed
+    pub fn add_name(&mut self, name: &str) -> u16 {
+        self.try_add_name(name).expect("Too many names in one chunk")
+    }
+
+    /// Like `add_name`, but `None` once the table can't grow any further
+    pub fn try_add_name(&mut self, name: &str) -> Option<u16> {
+        let symbol = Symbol::intern(name);
+        match self.names.iter().position(|n| *n == symbol) {
+            Some(index) => {
+                self.pool.deduplicated += 1;
+                self.pool.bytes_saved += name.len();
+                u16::try_from(index).ok()
+            }
+            None => {
+                let index = u16::try_from(self.names.len()).ok()?;
+                Arc::make_mut(&mut self.names).push(symbol);
+                Some(index)
+            }
+        }
+    }
+
+    /// Name at `index` of the name table
+    pub fn name(&self, index: u16) -> Option<&str> {
+        self.names.get(index as usize).map(Symbol::as_str)
+    }
+
+    /// Interned name at `index` of the name table
+    pub fn symbol(&self, index: u16) -> Option<&Symbol> {
+        self.names.get(index as usize)
+    }
+
+    pub fn names(&self) -> &[Symbol] {
+        &self.names
+    }
+
+    /// Add a function to the function table, returning its index
+    ///
+    /// An identical function already in the table is reused instead.
+    pub fn add_function(&mut self, function: Function) -> u16 {
+        self.try_add_function(function).expect("Too many functions in one chunk")
+    }
+
+    /// Like `add_function`, but `None` once the table can't grow any further
+    pub fn try_add_function(&mut self, function: Function) -> Option<u16> {
+        match self.functions.iter().position(|existing| identical(existing, &function)) {
+            Some(index) => {
+                self.pool.deduplicated += 1;
+                self.pool.bytes_saved += function.chunk.len();
+                u16::try_from(index).ok()
+            }
+            None => {
+                let index = u16::try_from(self.functions.len()).ok()?;
+                Arc::make_mut(&mut self.functions).push(Arc::new(function));
+                Some(index)
+            }
+        }
+    }
+
+    /// Sizes of the name and function tables and what deduplicating them saved
+    pub fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            names: self.names.len(),
+            functions: self.functions.len(),
+            ..self.pool
+        }
+    }
+
+    /// Function at `index` of the function table
+    pub fn function(&self, index: u16) -> Option<&Arc<Function>> {
+        self.functions.get(index as usize)
+    }
+
+    pub fn functions(&self) -> &[Arc<Function>] {
+        &self.functions
+    }
+
+    pub fn metadata(&self) -> Option<&ChunkMetadata> {
+        self.metadata.as_deref()
+    }
+
+    pub fn set_metadata(&mut self, metadata: ChunkMetadata) {
+        self.metadata = Some(Arc::new(metadata));
+    }
+
+    /// Chunk of `code` with the given tables, as read back from a file
+    ///
+    /// Source maps aren't saved, so none of its code is mapped.
+    pub(crate) fn from_parts(
+        code: Vec<u8>,
+        names: Vec<String>,
+        functions: Vec<Arc<Function>>,
+        metadata: Option<ChunkMetadata>,
+    ) -> Self {
+        Chunk {
+            code,
+            names: Arc::new(names.iter().map(|name| Symbol::intern(name)).collect()),
+            functions: Arc::new(functions),
+            metadata: metadata.map(Arc::new),
+            ..Chunk::new()
         }
     }
 
+    /// Shared handles to the name and function tables, cheap to clone
+    pub(crate) fn tables(&self) -> (Arc<Vec<Symbol>>, Arc<Vec<Arc<Function>>>) {
+        (self.names.clone(), self.functions.clone())
+    }
+
     /// Get the bytecode
     pub fn code(&self) -> &[u8] {
         &self.code
     }
 
-    /// Get source line for bytecode offset
-    pub fn line(&self, offset: usize) -> usize {
-        self.lines.get(offset).copied().unwrap_or(0)
+    /// Set the token span of the bytes written from now on, returning the
+    /// previous one so a nested construct can restore it
+    pub fn set_span(&mut self, span: Option<Range<usize>>) -> Option<Range<usize>> {
+        core::mem::replace(&mut self.span, span)
+    }
+
+    /// Token span the byte at `offset` was compiled from
+    pub fn span(&self, offset: usize) -> Option<Range<usize>> {
+        self.source_map.span(offset)
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
     }
 
     /// Get length of bytecode
@@ -264,6 +886,78 @@ impl Chunk {
             .expect("Invalid f64 bytes");
         f64::from_le_bytes(bytes)
     }
+
+    /// Read u16 from bytecode at offset (after an index opcode)
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Read u64 from bytecode at offset (after PUSH_ARRAY opcode)
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        let bytes: [u8; 8] = self.code[offset..offset + 8]
+            .try_into()
+            .expect("Invalid u64 bytes");
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Decoded instructions of this chunk
+    ///
+    /// Decoding stops at the first invalid opcode or truncated operand, so
+    /// the last instruction's `next_offset()` is less than `len()` for a
+    /// malformed chunk.
+    pub fn instructions(&self) -> &[Instruction] {
+        self.decoded.get_or_init(|| Box::new(self.decode()))
+    }
+
+    /// Native code for this chunk, or why it can't be compiled
+    #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+    pub fn jitted(&self) -> Result<&JitFunction, &JitError> {
+        self.jitted
+            .get_or_init(|| crate::jit::compile(self).map(Arc::new))
+            .as_ref()
+            .map(|function| function.as_ref())
+    }
+
+    fn decode(&self) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+
+        while let Some(opcode) = self.code.get(offset).copied().and_then(OpCode::from_byte) {
+            if offset + opcode.size() > self.code.len() {
+                break;
+            }
+            let operand = match opcode {
+                OpCode::Push | OpCode::PushAdd | OpCode::PushMul => {
+                    Operand::Number(self.read_f64(offset + 1))
+                }
+                OpCode::PushArray => Operand::Count(self.read_u64(offset + 1)),
+                OpCode::LoadGlobal
+                | OpCode::StoreGlobal
+                | OpCode::LoadLocal
+                | OpCode::StoreLocal
+                | OpCode::DefineFunction
+                | OpCode::LoadUpvalue => Operand::Index(self.read_u16(offset + 1)),
+                OpCode::Closure => Operand::Closure {
+                    function: self.read_u16(offset + 1),
+                    captures: self.code[offset + 3],
+                },
+                OpCode::CallValue => Operand::Count(self.code[offset + 1] as u64),
+                OpCode::Call | OpCode::TailCall => Operand::Call {
+                    name: self.read_u16(offset + 1),
+                    argc: self.code[offset + 3],
+                },
+                _ => Operand::None,
+            };
+            instructions.push(Instruction {
+                offset,
+                opcode,
+                operand,
+            });
+            offset += opcode.size();
+        }
+
+        instructions
+    }
 }
 
 impl Default for Chunk {
@@ -271,3 +965,18 @@ impl Default for Chunk {
         Self::new()
     }
 }
+
+/// Whether two functions would behave alike everywhere, down to the names
+/// their bodies refer to
+///
+/// Source maps aren't compared, so a function merged into an earlier one
+/// keeps the spans of the first.
+fn identical(a: &Function, b: &Function) -> bool {
+    a.name == b.name
+        && a.params == b.params
+        && a.body == b.body
+        && a.chunk.code == b.chunk.code
+        && a.chunk.names == b.chunk.names
+        && a.chunk.functions.len() == b.chunk.functions.len()
+        && a.chunk.functions.iter().zip(b.chunk.functions.iter()).all(|(a, b)| identical(a, b))
+}
@@ -0,0 +1,335 @@
+//! Saved bytecode files
+//!
+//! `Chunk::to_bytes` writes a chunk with its name and function tables and
+//! its metadata, so a file says what it was compiled from and by what;
+//! `Chunk::from_bytes` reads one back. All integers are little-endian:
+//!
+//!   magic     "BCAL" and a version byte
+//!   metadata  flag byte, then source (flag, text), compiler (text),
+//!             optimized (byte) and compiled_at (flag, u64)
+//!   names     u32 count, then the texts
+//!   functions u32 count, then each name, u32 count of parameter names,
+//!             the parameters, the body text and the body chunk
+//!   code      u32 length, then the bytes
+//!
+//! Texts are a u32 byte length and UTF-8. Line numbers aren't saved.
+//!
+//! `LinkedProgram::to_bytes` writes a linked program as one file: magic
+//! "BCLP" and the version, the main chunk, then its symbol table in the
+//! same layout as a chunk's functions.
+
+use crate::prelude::*;
+use crate::bytecode::{Chunk, ChunkMetadata, Function};
+use crate::linker::LinkedProgram;
+use alloc::sync::Arc;
+use core::fmt;
+
+const MAGIC: &[u8; 4] = b"BCAL";
+const PROGRAM_MAGIC: &[u8; 4] = b"BCLP";
+
+/// Version of the format written by `to_bytes`
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Function bodies nested deeper than this are refused, so a crafted
+/// file can't exhaust the stack
+const MAX_NESTING: usize = 64;
+
+/// Why bytes couldn't be read as a chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The bytes don't start with the magic number
+    NotBytecode,
+    UnsupportedVersion(u8),
+    /// The bytes end partway through the chunk
+    Truncated,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a bytecode file"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "Bytecode format version {} is not supported (expected {})", version, FORMAT_VERSION)
+            }
+            LoadError::Truncated => write!(f, "Bytecode file is truncated"),
+            LoadError::Malformed(what) => write!(f, "Malformed bytecode file: {}", what),
+        }
+    }
+}
+
+impl Chunk {
+    /// The chunk as a self-contained bytecode file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        write_chunk(&mut bytes, self);
+        bytes
+    }
+
+    /// Read a chunk written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, LoadError> {
+        let mut reader = Reader::open(bytes, MAGIC)?;
+        let chunk = reader.chunk(0)?;
+        reader.finish()?;
+        Ok(chunk)
+    }
+}
+
+impl LinkedProgram {
+    /// The program, main chunk and symbol table, as one bytecode file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = PROGRAM_MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        write_chunk(&mut bytes, self.main());
+        let symbols: Vec<_> = self.symbols().collect();
+        write_u32(&mut bytes, symbols.len());
+        for function in symbols {
+            write_function(&mut bytes, function);
+        }
+        bytes
+    }
+
+    /// Read a program written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<LinkedProgram, LoadError> {
+        let mut reader = Reader::open(bytes, PROGRAM_MAGIC)?;
+        let main = reader.chunk(0)?;
+        let functions = reader.functions(0)?;
+        reader.finish()?;
+        Ok(LinkedProgram::from_parts(main, functions))
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("Bytecode tables are limited to u32 lengths");
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_text(bytes: &mut Vec<u8>, text: &str) {
+    write_u32(bytes, text.len());
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, chunk: &Chunk) {
+    match chunk.metadata() {
+        None => bytes.push(0),
+        Some(metadata) => {
+            bytes.push(1);
+            match &metadata.source {
+                None => bytes.push(0),
+                Some(source) => {
+                    bytes.push(1);
+                    write_text(bytes, source);
+                }
+            }
+            write_text(bytes, &metadata.compiler);
+            bytes.push(metadata.optimized as u8);
+            match metadata.compiled_at {
+                None => bytes.push(0),
+                Some(seconds) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&seconds.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    write_u32(bytes, chunk.names().len());
+    for name in chunk.names() {
+        write_text(bytes, name.as_str());
+    }
+
+    write_u32(bytes, chunk.functions().len());
+    for function in chunk.functions() {
+        write_function(bytes, function);
+    }
+
+    write_u32(bytes, chunk.len());
+    bytes.extend_from_slice(chunk.code());
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &Function) {
+    write_text(bytes, &function.name);
+    write_u32(bytes, function.params.len());
+    for param in &function.params {
+        write_text(bytes, param);
+    }
+    write_text(bytes, &function.body);
+    write_chunk(bytes, &function.chunk);
+}
+
+/// The unread rest of a bytecode file
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Check the magic number and version at the start of `bytes`
+    fn open(bytes: &'a [u8], magic: &[u8; 4]) -> Result<Self, LoadError> {
+        if bytes.get(..magic.len()) != Some(magic.as_slice()) {
+            return Err(LoadError::NotBytecode);
+        }
+        let mut reader = Reader { bytes: &bytes[magic.len()..] };
+        let version = reader.byte()?;
+        if version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        Ok(reader)
+    }
+
+    fn finish(&self) -> Result<(), LoadError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(LoadError::Malformed("bytes after the chunk"))
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoadError> {
+        if count > self.bytes.len() {
+            return Err(LoadError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool, LoadError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(LoadError::Malformed("flag is neither 0 nor 1")),
+        }
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn text(&mut self) -> Result<String, LoadError> {
+        let length = self.u32()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::Malformed("text is not UTF-8"))
+    }
+
+    /// A count of items at least `min_size` bytes each, checked against what's left
+    fn count(&mut self, min_size: usize) -> Result<usize, LoadError> {
+        let count = self.u32()?;
+        if count.saturating_mul(min_size) > self.bytes.len() {
+            return Err(LoadError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn chunk(&mut self, depth: usize) -> Result<Chunk, LoadError> {
+        if depth > MAX_NESTING {
+            return Err(LoadError::Malformed("functions nested too deeply"));
+        }
+        let metadata = if self.flag()? {
+            let source = if self.flag()? { Some(self.text()?) } else { None };
+            let compiler = self.text()?;
+            let optimized = self.flag()?;
+            let compiled_at = if self.flag()? { Some(self.u64()?) } else { None };
+            Some(ChunkMetadata { source, compiler, optimized, compiled_at })
+        } else {
+            None
+        };
+
+        let names = (0..self.count(4)?).map(|_| self.text()).collect::<Result<Vec<_>, _>>()?;
+        let functions = self.functions(depth)?;
+
+        let length = self.u32()?;
+        let code = self.take(length)?.to_vec();
+        Ok(Chunk::from_parts(code, names, functions, metadata))
+    }
+
+    /// A count, then the functions of a chunk at `depth`
+    fn functions(&mut self, depth: usize) -> Result<Vec<Arc<Function>>, LoadError> {
+        let mut functions = Vec::new();
+        for _ in 0..self.count(4)? {
+            let name = self.text()?;
+            let params = (0..self.count(4)?).map(|_| self.text()).collect::<Result<Vec<_>, _>>()?;
+            let body = self.text()?;
+            let chunk = self.chunk(depth + 1)?;
+            functions.push(Arc::new(Function { name, params, chunk, body }));
+        }
+        Ok(functions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CodeGenerator, Disassembler, Optimizer, Parser, Tokenizer, VirtualMachine};
+
+    fn compile(input: &str) -> Chunk {
+        let ast = Parser::new(Tokenizer::new(input).tokenize().unwrap()).parse().unwrap();
+        Optimizer::fuse_superinstructions(&CodeGenerator::new().with_source(input).compile(&ast).unwrap().0)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let chunk = compile("f(x) = x^2 + k; k = 1; f(3) + 2");
+        let loaded = Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+        assert_eq!(loaded.code(), chunk.code());
+        assert_eq!(loaded.names(), chunk.names());
+        assert_eq!(loaded.functions(), chunk.functions());
+        assert_eq!(loaded.functions()[0].body, chunk.functions()[0].body);
+        assert_eq!(loaded.metadata(), chunk.metadata());
+        assert!(loaded.functions()[0].chunk.metadata().is_none());
+
+        let metadata = loaded.metadata().unwrap();
+        assert_eq!(metadata.source.as_deref(), Some("f(x) = x^2 + k; k = 1; f(3) + 2"));
+        assert!(metadata.optimized);
+        assert!(metadata.compiler.starts_with("calculator "));
+        assert!(Disassembler::format(&loaded).contains("Source: f(x) = x^2 + k; k = 1; f(3) + 2"));
+
+        assert_eq!(VirtualMachine::new().execute(&loaded).unwrap(), 12.0);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let bytes = compile("[1, 2] * 3").to_bytes();
+        assert_eq!(Chunk::from_bytes(b"PK\x03\x04").unwrap_err(), LoadError::NotBytecode);
+        assert_eq!(Chunk::from_bytes(b"BCAL\x09").unwrap_err(), LoadError::UnsupportedVersion(9));
+        for end in [5, 12, bytes.len() - 1] {
+            assert_eq!(Chunk::from_bytes(&bytes[..end]).unwrap_err(), LoadError::Truncated, "cut at {}", end);
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(Chunk::from_bytes(&longer), Err(LoadError::Malformed(_))));
+        // A name count far beyond the file's size
+        let mut huge = b"BCAL\x01\x00".to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Chunk::from_bytes(&huge).unwrap_err(), LoadError::Truncated);
+    }
+
+    #[test]
+    fn test_program_round_trip() {
+        let program = crate::Linker::new(compile("area(2) + 1"))
+            .with_library(compile("area(r) = pi * sq(r); sq(x) = x * x"))
+            .link()
+            .unwrap();
+        let bytes = program.to_bytes();
+        assert!(bytes.starts_with(b"BCLP"));
+        assert_eq!(Chunk::from_bytes(&bytes).unwrap_err(), LoadError::NotBytecode);
+
+        let loaded = LinkedProgram::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.main().code(), program.main().code());
+        assert_eq!(loaded.symbols().collect::<Vec<_>>(), program.symbols().collect::<Vec<_>>());
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute_linked(&loaded).unwrap(), 4.0 * core::f64::consts::PI + 1.0);
+        assert_eq!(LinkedProgram::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), LoadError::Truncated);
+    }
+}
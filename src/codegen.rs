@@ -1,4 +1,4 @@
-//! Code Generator - Compiles AST to bytecode
+//! Code NaryOp - Compiles AST to bytecode
 //!
 //! Traverses the AST in post-order to generate stack-based bytecode.
 //! The generated code follows these conventions:
@@ -6,108 +6,676 @@
 //!   - Binary ops: left operand pushed first, then right
 //!   - Result of each operation remains on stack
 //!   - Arrays: elements pushed in order, then PUSH_ARRAY with count
+//!   - Statements of a sequence are separated by POP; only the last value remains
+//!   - Function bodies are compiled into their own chunk, bound at runtime
+//!     by DEF_FUNC; parameters are read with LOAD_LOCAL, anything else
+//!     with LOAD_GLOBAL
+//!
+//! Both backends apply exact strength reductions on the way: `x ^ 2`
+//! becomes `x * x` (DUP; MUL) and `x * 2` becomes `x + x` (DUP; ADD), each
+//! evaluating `x` once. `x / c` becomes `x * (1 / c)` only when asked with
+//! `with_reciprocal_division`, as the reciprocal is usually inexact.
+//!
+//! Given the token spans of the tree's nodes with `with_spans`, the stack
+//! backend records in the chunk's source map which node every instruction
+//! was generated for.
+//!
+//! The experimental register backend (`Backend::Register`) instead emits
+//! `RegInstr`s, allocating one register per level of expression depth.
+//!
+//! `compile` also returns `Diagnostic`s: operations on literals that are
+//! bound to fail when run, such as `x / 0` or `asin(2)`. They are warnings
+//! only; the code is generated all the same. What can't be encoded at all,
+//! such as a chunk with more names than an index operand addresses, is a
+//! `CompileError` instead.
+
+use crate::prelude::*;
+use crate::ast::{BinaryOp, Expr, NaryOp, UnaryOp};
+use crate::bytecode::{Chunk, ChunkMetadata, Function, OpCode, LAMBDA_NAME};
+use crate::intern::Symbol;
+use crate::register::{RegInstr, Register, RegisterChunk, Source};
+use alloc::sync::Arc;
+use core::fmt;
+use core::ops::Range;
+
+/// Target machine for code generation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Stack,
+    Register,
+}
+
+/// An operation on literals that will fail at run time, found while compiling
+///
+/// Each holds the offending expression as written back from the AST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// Division or modulo by a literal zero
+    DivisionByZero(String),
+    /// Factorial of a negative literal
+    NegativeFactorial(String),
+    /// `asin` or `acos` of a literal outside [-1, 1]
+    OutOfDomain(String),
+    /// `avg`, `min` or `max` of the empty array
+    EmptyAggregate(String),
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::DivisionByZero(expr) => write!(f, "Division by zero in {}", expr),
+            Diagnostic::NegativeFactorial(expr) => write!(f, "Factorial of a negative number in {}", expr),
+            Diagnostic::OutOfDomain(expr) => write!(f, "Argument outside [-1, 1] in {}", expr),
+            Diagnostic::EmptyAggregate(expr) => write!(f, "Aggregate of an empty array in {}", expr),
+        }
+    }
+}
 
-use crate::ast::{BinaryOp, Expr, UnaryOp};
-use crate::bytecode::{Chunk, OpCode};
+/// Why an expression couldn't be compiled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// More distinct names in one chunk than an index operand can address
+    TooManyNames,
+    /// More functions in one chunk than an index operand can address
+    TooManyFunctions,
+    /// A call with more arguments than its argument count can hold
+    TooManyArguments { name: String, count: usize },
+    /// A lambda capturing more variables than its capture count can hold
+    TooManyCaptures(usize),
+    /// More parameters and let-bindings in scope than a slot operand can address
+    TooManyLocals,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::TooManyNames => write!(f, "Too many names: at most {} fit in one chunk", u16::MAX as usize + 1),
+            CompileError::TooManyFunctions => {
+                write!(f, "Too many functions: at most {} fit in one chunk", u16::MAX as usize + 1)
+            }
+            CompileError::TooManyArguments { name, count } => {
+                write!(f, "{} is called with {} arguments, more than {}", name, count, u8::MAX)
+            }
+            CompileError::TooManyCaptures(count) => {
+                write!(f, "A lambda captures {} variables, more than {}", count, u8::MAX)
+            }
+            CompileError::TooManyLocals => {
+                write!(f, "Too many variables in scope: at most {}", u16::MAX as usize + 1)
+            }
+        }
+    }
+}
+
+/// Output of `CodeGenerator::compile_program`
+#[derive(Debug, Clone)]
+pub enum Program {
+    Stack(Chunk),
+    Register(RegisterChunk),
+}
 
 pub struct CodeGenerator {
     chunk: Chunk,
-    current_line: usize,
+    backend: Backend,
+    /// Parameters of the function being compiled, then the let-bindings in
+    /// scope, by LOAD_LOCAL slot
+    locals: Vec<String>,
+    /// Variables of enclosing functions a lambda captures, by LOAD_UPVALUE slot
+    upvalues: Vec<String>,
+    /// Input text, recorded in the chunk's metadata
+    source: Option<String>,
+    /// Divide by a constant by multiplying with its reciprocal
+    reciprocal_division: bool,
+    /// Token span of every node of the tree being compiled, in pre-order
+    spans: Arc<[Range<usize>]>,
+    /// Pre-order index of the next node `generate` visits
+    node: usize,
 }
 
 impl CodeGenerator {
     pub fn new() -> Self {
         CodeGenerator {
             chunk: Chunk::new(),
-            current_line: 1,
+            backend: Backend::Stack,
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            source: None,
+            reciprocal_division: false,
+            spans: Arc::new([]),
+            node: 0,
+        }
+    }
+
+    /// Record `source` as the text the chunk is compiled from
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Compile `x / c` for a constant `c` as `x * (1 / c)`
+    ///
+    /// Multiplying is cheaper, but the result can differ from the quotient
+    /// in the last bit unless `c` is a power of two, and integer mode's
+    /// divisibility check no longer applies.
+    pub fn with_reciprocal_division(mut self) -> Self {
+        self.reciprocal_division = true;
+        self
+    }
+
+    /// Map each instruction to the span of the node it was generated for,
+    /// given every node's span in pre-order as `Parser::parse_spanned` returns them
+    ///
+    /// Only the stack backend records spans.
+    pub fn with_spans(mut self, spans: Vec<Range<usize>>) -> Self {
+        self.spans = spans.into();
+        self
+    }
+
+    /// Create a generator targeting the given backend
+    pub fn with_backend(backend: Backend) -> Self {
+        CodeGenerator {
+            backend,
+            ..Self::new()
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Compile to a chunk stamped with this compiler, the time and any
+    /// source, with warnings about operations bound to fail
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err))]
+    pub fn compile(mut self, expr: &Expr) -> Result<(Chunk, Vec<Diagnostic>), CompileError> {
+        let metadata = ChunkMetadata::now(self.source.take());
+        let mut chunk = self.compile_code(expr)?;
+        chunk.set_metadata(metadata);
+        let diagnostics = diagnose(expr);
+        debug_event!(
+            instructions = chunk.instructions().len(),
+            bytes = chunk.len(),
+            warnings = diagnostics.len(),
+            "compiled"
+        );
+        Ok((chunk, diagnostics))
+    }
+
+    fn compile_code(mut self, expr: &Expr) -> Result<Chunk, CompileError> {
+        self.generate(expr)?;
+        self.chunk.write_op(OpCode::Halt);
+        Ok(self.chunk)
+    }
+
+    /// Compile a function body, turning a call it ends with into a TAILCALL
+    fn compile_body(mut self, body: &Expr) -> Result<Chunk, CompileError> {
+        match body {
+            Expr::Call { name, args } if !self.is_captured(name) => {
+                let outer = self.enter();
+                for arg in args {
+                    self.generate(arg)?;
+                }
+                let index = self.name_index(name)?;
+                let argc = Self::argument_count(name, args)?;
+                self.chunk.write_tail_call(index, argc);
+                self.chunk.set_span(outer);
+            }
+            _ => self.generate(body)?,
+        }
+        self.chunk.write_op(OpCode::Halt);
+        Ok(self.chunk)
+    }
+
+    /// Compile for the register machine
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err))]
+    pub fn compile_registers(&self, expr: &Expr) -> Result<RegisterChunk, CompileError> {
+        let mut chunk = RegisterChunk::new();
+        let result = self.generate_register(&mut chunk, expr, 0, &[])?;
+        chunk.emit(RegInstr::Return { src: result });
+        Ok(chunk)
+    }
+
+    /// Compile for whichever backend this generator was created with
+    pub fn compile_program(self, expr: &Expr) -> Result<Program, CompileError> {
+        Ok(match self.backend {
+            Backend::Stack => Program::Stack(self.compile(expr)?.0),
+            Backend::Register => Program::Register(self.compile_registers(expr)?),
+        })
+    }
+
+    /// Index of `name` in the name table
+    fn name_index(&mut self, name: &str) -> Result<u16, CompileError> {
+        self.chunk.try_add_name(name).ok_or(CompileError::TooManyNames)
+    }
+
+    /// Index of `function` in the function table
+    fn function_index(&mut self, function: Function) -> Result<u16, CompileError> {
+        self.chunk.try_add_function(function).ok_or(CompileError::TooManyFunctions)
+    }
+
+    /// Argument count operand of a call of `name`
+    fn argument_count(name: &str, args: &[Expr]) -> Result<u8, CompileError> {
+        u8::try_from(args.len()).map_err(|_| CompileError::TooManyArguments {
+            name: name.to_string(),
+            count: args.len(),
+        })
+    }
+
+    /// Generate code leaving the value of `expr` in `dst` or a constant
+    ///
+    /// Subexpressions only use registers at or above `dst`, so a left
+    /// operand held in `dst` survives evaluation of the right one in `dst + 1`.
+    /// `locals` are the let-bindings in scope, each held in a register below `dst`.
+    fn generate_register(
+        &self,
+        chunk: &mut RegisterChunk,
+        expr: &Expr,
+        dst: Register,
+        locals: &[(String, Register)],
+    ) -> Result<Source, CompileError> {
+        let local = |name: &str| locals.iter().rev().find(|(local, _)| local == name).map(|&(_, reg)| reg);
+        Ok(match expr {
+            Expr::Number(value) => Source::Const(*value),
+            Expr::Array(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    let slot = dst + i;
+                    let src = self.generate_register(chunk, element, slot, locals)?;
+                    if src != Source::Reg(slot) {
+                        chunk.emit(RegInstr::Move { dst: slot, src });
+                    }
+                }
+                chunk.emit(RegInstr::MakeArray { dst, start: dst, count: elements.len() });
+                Source::Reg(dst)
+            }
+            Expr::UnaryOp { op, operand } => {
+                let src = self.generate_register(chunk, operand, dst, locals)?;
+                chunk.emit(RegInstr::Unary { op: Self::unary_opcode(op), dst, src });
+                Source::Reg(dst)
+            }
+            Expr::PostfixOp { operand, .. } => {
+                // Factorial is the only postfix operation, as in the stack backend
+                let src = self.generate_register(chunk, operand, dst, locals)?;
+                chunk.emit(RegInstr::Unary { op: OpCode::Factorial, dst, src });
+                Source::Reg(dst)
+            }
+            Expr::BinaryOp { op, left, right } => {
+                if let Some((op, operand, constant)) = self.strength_reduce(op, left, right) {
+                    let lhs = self.generate_register(chunk, operand, dst, locals)?;
+                    let rhs = constant.map_or(lhs, Source::Const);
+                    chunk.emit(RegInstr::Binary { op, dst, lhs, rhs });
+                    return Ok(Source::Reg(dst));
+                }
+                // Walk down the left operands of a chain like 1+2+3 in a loop,
+                // as the parser built it, rather than recursing once per operator
+                let mut chain = vec![(op, right)];
+                let mut first = left;
+                while let Expr::BinaryOp { op, left, right } = &**first {
+                    if self.strength_reduce(op, left, right).is_some() {
+                        break;
+                    }
+                    chain.push((op, right));
+                    first = left;
+                }
+                let mut lhs = self.generate_register(chunk, first, dst, locals)?;
+                while let Some((op, right)) = chain.pop() {
+                    let rhs = self.generate_register(chunk, right, dst + 1, locals)?;
+                    chunk.emit(RegInstr::Binary { op: Self::binary_opcode(op), dst, lhs, rhs });
+                    lhs = Source::Reg(dst);
+                }
+                lhs
+            }
+            Expr::Variable(name) => match local(name) {
+                Some(reg) => Source::Reg(reg),
+                None => {
+                    chunk.emit(RegInstr::LoadGlobal { dst, name: Symbol::intern(name) });
+                    Source::Reg(dst)
+                }
+            },
+            Expr::Assign { name, value } => {
+                let src = self.generate_register(chunk, value, dst, locals)?;
+                chunk.emit(RegInstr::StoreGlobal { name: Symbol::intern(name), src });
+                src
+            }
+            Expr::FunctionDef { name, params, body } => {
+                // Function bodies always run on the stack machine
+                let function = self.without_spans().compile_function(name, params, body)?;
+                let index = chunk.add_function(function);
+                chunk.emit(RegInstr::Define { function: index });
+                Source::Const(0.0)
+            }
+            Expr::Lambda { params, body } => {
+                // Outside any function only let-bindings can be captured
+                let mut captures = Vec::new();
+                free_variables(body, params, &mut captures);
+                captures.retain(|name| local(name).is_some());
+                for (i, name) in captures.iter().enumerate() {
+                    let src = Source::Reg(local(name).expect("captures are let-bound"));
+                    chunk.emit(RegInstr::Move { dst: dst + i, src });
+                }
+                let count = captures.len();
+                let function = self.without_spans().compile_closure(LAMBDA_NAME, params, captures, body)?;
+                let index = chunk.add_function(function);
+                chunk.emit(RegInstr::Closure { dst, function: index, start: dst, count });
+                Source::Reg(dst)
+            }
+            Expr::Let { name, value, body } => {
+                // The binding holds `dst` while the body runs above it
+                let src = self.generate_register(chunk, value, dst, locals)?;
+                if src != Source::Reg(dst) {
+                    chunk.emit(RegInstr::Move { dst, src });
+                }
+                let mut inner = locals.to_vec();
+                inner.push((name.clone(), dst));
+                let result = self.generate_register(chunk, body, dst + 1, &inner)?;
+                chunk.emit(RegInstr::Move { dst, src: result });
+                Source::Reg(dst)
+            }
+            Expr::Nary { op, args } => {
+                for (i, arg) in args.iter().enumerate() {
+                    let slot = dst + i;
+                    let src = self.generate_register(chunk, arg, slot, locals)?;
+                    if src != Source::Reg(slot) {
+                        chunk.emit(RegInstr::Move { dst: slot, src });
+                    }
+                }
+                let op = Self::nary_opcode(*op);
+                chunk.emit(RegInstr::Nary { op, dst, start: dst, count: args.len() });
+                Source::Reg(dst)
+            }
+            Expr::Call { name, args } => {
+                // A let-bound function value is called with the stack machine's CALL_VALUE
+                let callee = local(name);
+                let first = dst + usize::from(callee.is_some());
+                if let Some(reg) = callee {
+                    chunk.emit(RegInstr::Move { dst, src: Source::Reg(reg) });
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    let slot = first + i;
+                    let src = self.generate_register(chunk, arg, slot, locals)?;
+                    if src != Source::Reg(slot) {
+                        chunk.emit(RegInstr::Move { dst: slot, src });
+                    }
+                }
+                if callee.is_some() {
+                    chunk.emit(RegInstr::Nary { op: OpCode::CallValue, dst, start: dst, count: args.len() + 1 });
+                } else {
+                    chunk.emit(RegInstr::Call { dst, name: Symbol::intern(name), start: dst, count: args.len() });
+                }
+                Source::Reg(dst)
+            }
+            Expr::Sequence(statements) => {
+                let mut result = Source::Const(0.0);
+                for statement in statements {
+                    result = self.generate_register(chunk, statement, dst, locals)?;
+                }
+                result
+            }
+        })
+    }
+
+    /// A generator with the same options but no spans, for the function
+    /// bodies the register backend compiles
+    fn without_spans(&self) -> CodeGenerator {
+        CodeGenerator {
+            reciprocal_division: self.reciprocal_division,
+            ..CodeGenerator::new()
+        }
+    }
+
+    /// Compile the body of `name(params) = body` into a function of its own
+    fn compile_function(&self, name: &str, params: &[String], body: &Expr) -> Result<Function, CompileError> {
+        self.compile_closure(name, params, Vec::new(), body)
+    }
+
+    /// Whether `name` is a parameter or let-binding of the function being
+    /// compiled, or one of the variables it captured, rather than a global
+    fn is_captured(&self, name: &str) -> bool {
+        self.locals.iter().chain(&self.upvalues).any(|local| local == name)
+    }
+
+    /// Compile a function body that reads `upvalues` with LOAD_UPVALUE
+    fn compile_closure(
+        &self,
+        name: &str,
+        params: &[String],
+        upvalues: Vec<String>,
+        body: &Expr,
+    ) -> Result<Function, CompileError> {
+        let generator = CodeGenerator {
+            locals: params.to_vec(),
+            upvalues,
+            reciprocal_division: self.reciprocal_division,
+            spans: self.spans.clone(),
+            node: self.node,
+            ..CodeGenerator::new()
+        };
+        Ok(Function {
+            name: name.to_string(),
+            params: params.to_vec(),
+            chunk: generator.compile_body(body)?,
+            body: body.to_string(),
+        })
+    }
+
+    /// A cheaper operation computing `left op right`, applied to one operand
+    /// and either itself or a constant
+    ///
+    /// An operand that is itself a number gains nothing from it, and is left
+    /// to superinstruction fusion.
+    fn strength_reduce<'e>(
+        &self,
+        op: &BinaryOp,
+        left: &'e Expr,
+        right: &'e Expr,
+    ) -> Option<(OpCode, &'e Expr, Option<f64>)> {
+        if matches!((left, right), (Expr::Number(_), Expr::Number(_))) {
+            return None;
+        }
+        match (op, left, right) {
+            (BinaryOp::Power, x, Expr::Number(2.0)) => Some((OpCode::Mul, x, None)),
+            (BinaryOp::Multiply, x, Expr::Number(2.0)) | (BinaryOp::Multiply, Expr::Number(2.0), x) => {
+                Some((OpCode::Add, x, None))
+            }
+            // Division by zero must still be reported
+            (BinaryOp::Divide, x, &Expr::Number(c)) if self.reciprocal_division && (1.0 / c).is_normal() => {
+                Some((OpCode::Mul, x, Some(1.0 / c)))
+            }
+            _ => None,
+        }
+    }
+
+    fn unary_opcode(op: &UnaryOp) -> OpCode {
+        match op {
+            UnaryOp::Negate => OpCode::Neg,
+            UnaryOp::Factorial => OpCode::Factorial,
+            UnaryOp::Sin => OpCode::Sin,
+            UnaryOp::Cos => OpCode::Cos,
+            UnaryOp::Tan => OpCode::Tan,
+            UnaryOp::Asin => OpCode::Asin,
+            UnaryOp::Acos => OpCode::Acos,
+            UnaryOp::Atan => OpCode::Atan,
+            UnaryOp::Sec => OpCode::Sec,
+            UnaryOp::Csc => OpCode::Csc,
+            UnaryOp::Cot => OpCode::Cot,
+            UnaryOp::Sinh => OpCode::Sinh,
+            UnaryOp::Cosh => OpCode::Cosh,
+            UnaryOp::Tanh => OpCode::Tanh,
+            UnaryOp::Asinh => OpCode::Asinh,
+            UnaryOp::Acosh => OpCode::Acosh,
+            UnaryOp::Atanh => OpCode::Atanh,
+            UnaryOp::Sqrt => OpCode::Sqrt,
+            UnaryOp::Cbrt => OpCode::Cbrt,
+            UnaryOp::Log => OpCode::Log,
+            UnaryOp::Log2 => OpCode::Log2,
+            UnaryOp::Ln => OpCode::Ln,
+            UnaryOp::Exp => OpCode::Exp,
+            UnaryOp::Abs => OpCode::Abs,
+            UnaryOp::Floor => OpCode::Floor,
+            UnaryOp::Ceil => OpCode::Ceil,
+            UnaryOp::Round => OpCode::Round,
+            UnaryOp::Trunc => OpCode::Trunc,
+            UnaryOp::Frac => OpCode::Frac,
+            UnaryOp::Sign => OpCode::Sign,
+            UnaryOp::ToRad => OpCode::ToRad,
+            UnaryOp::ToDeg => OpCode::ToDeg,
+            UnaryOp::Sum => OpCode::Sum,
+            UnaryOp::Avg => OpCode::Avg,
+            UnaryOp::Min => OpCode::Min,
+            UnaryOp::Max => OpCode::Max,
+            UnaryOp::Len => OpCode::Len,
+            UnaryOp::Multinomial => OpCode::Multinomial,
+            UnaryOp::Derange => OpCode::Derange,
+            UnaryOp::Totient => OpCode::Totient,
+            UnaryOp::PrimePi => OpCode::PrimePi,
         }
     }
 
-    pub fn compile(mut self, expr: &Expr) -> Chunk {
-        self.generate(expr);
-        self.chunk.write_op(OpCode::Halt, self.current_line);
-        self.chunk
+    fn nary_opcode(op: NaryOp) -> OpCode {
+        match op {
+            NaryOp::Linspace => OpCode::Linspace,
+            NaryOp::Zeros => OpCode::Zeros,
+            NaryOp::Ones => OpCode::Ones,
+            NaryOp::Repeat => OpCode::Repeat,
+            NaryOp::Hist => OpCode::Hist,
+            NaryOp::HistEdges => OpCode::HistEdges,
+            NaryOp::Solve => OpCode::Solve,
+            NaryOp::Eigvals => OpCode::Eigvals,
+            NaryOp::Linfit => OpCode::Linfit,
+            NaryOp::NormPdf => OpCode::NormPdf,
+            NaryOp::NormCdf => OpCode::NormCdf,
+            NaryOp::InvNorm => OpCode::InvNorm,
+            NaryOp::BinomPdf => OpCode::BinomPdf,
+            NaryOp::PoissonPdf => OpCode::PoissonPdf,
+            NaryOp::FromBase => OpCode::FromBase,
+            NaryOp::ToBase => OpCode::ToBase,
+            NaryOp::Cfrac => OpCode::Cfrac,
+            NaryOp::Convergent => OpCode::Convergent,
+            NaryOp::Sample => OpCode::Sample,
+            NaryOp::Shuffle => OpCode::Shuffle,
+            NaryOp::Map => OpCode::Map,
+            NaryOp::Filter => OpCode::Filter,
+        }
     }
 
-    fn generate(&mut self, expr: &Expr) {
+    fn binary_opcode(op: &BinaryOp) -> OpCode {
+        match op {
+            BinaryOp::Add => OpCode::Add,
+            BinaryOp::Subtract => OpCode::Sub,
+            BinaryOp::Multiply => OpCode::Mul,
+            BinaryOp::Divide => OpCode::Div,
+            BinaryOp::Power => OpCode::Pow,
+            BinaryOp::Modulo => OpCode::Mod,
+            BinaryOp::Gcd => OpCode::Gcd,
+            BinaryOp::Lcm => OpCode::Lcm,
+            BinaryOp::Npr => OpCode::Npr,
+            BinaryOp::Ncr => OpCode::Ncr,
+            BinaryOp::Stirling2 => OpCode::Stirling2,
+            BinaryOp::RoundTo => OpCode::RoundTo,
+        }
+    }
+
+    /// Move on to the next node, writing code with its span until the
+    /// returned previous span is restored
+    fn enter(&mut self) -> Option<Range<usize>> {
+        let span = self.spans.get(self.node).cloned();
+        self.node += 1;
+        self.chunk.set_span(span)
+    }
+
+    /// Move past the nodes of `expr` without generating code for it
+    fn skip(&mut self, expr: &Expr) {
+        self.node += expr.size();
+    }
+
+    /// Push the value of variable `name`
+    fn load(&mut self, name: &str) -> Result<(), CompileError> {
+        if let Some(slot) = self.locals.iter().rposition(|local| local == name) {
+            self.chunk.write_op_u16(OpCode::LoadLocal, slot as u16);
+        } else if let Some(slot) = self.upvalues.iter().position(|upvalue| upvalue == name) {
+            self.chunk.write_op_u16(OpCode::LoadUpvalue, slot as u16);
+        } else {
+            let index = self.name_index(name)?;
+            self.chunk.write_op_u16(OpCode::LoadGlobal, index);
+        }
+        Ok(())
+    }
+
+    fn generate(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        let outer = self.enter();
+        let result = self.generate_node(expr);
+        self.chunk.set_span(outer);
+        result
+    }
+
+    fn generate_node(&mut self, expr: &Expr) -> Result<(), CompileError> {
         match expr {
             Expr::Number(value) => {
-                self.chunk.write_push(*value, self.current_line);
+                self.chunk.write_push(*value);
             }
             Expr::Array(elements) => {
                 // Push all elements onto stack
                 for element in elements {
-                    self.generate(element);
+                    self.generate(element)?;
                 }
                 // Write PUSH_ARRAY with element count
-                self.chunk.write_op(OpCode::PushArray, self.current_line);
+                self.chunk.write_op(OpCode::PushArray);
                 let count_bytes = (elements.len() as u64).to_le_bytes();
                 for byte in count_bytes {
-                    self.chunk.write_byte(byte, self.current_line);
+                    self.chunk.write_byte(byte);
                 }
             }
             Expr::UnaryOp { op, operand } => {
                 // Generate operand first (post-order)
-                self.generate(operand);
+                self.generate(operand)?;
 
                 // Then apply operation
-                let opcode = match op {
-                    UnaryOp::Negate => OpCode::Neg,
-                    UnaryOp::Factorial => OpCode::Factorial,
-                    UnaryOp::Sin => OpCode::Sin,
-                    UnaryOp::Cos => OpCode::Cos,
-                    UnaryOp::Tan => OpCode::Tan,
-                    UnaryOp::Asin => OpCode::Asin,
-                    UnaryOp::Acos => OpCode::Acos,
-                    UnaryOp::Atan => OpCode::Atan,
-                    UnaryOp::Sinh => OpCode::Sinh,
-                    UnaryOp::Cosh => OpCode::Cosh,
-                    UnaryOp::Tanh => OpCode::Tanh,
-                    UnaryOp::Sqrt => OpCode::Sqrt,
-                    UnaryOp::Cbrt => OpCode::Cbrt,
-                    UnaryOp::Log => OpCode::Log,
-                    UnaryOp::Log2 => OpCode::Log2,
-                    UnaryOp::Ln => OpCode::Ln,
-                    UnaryOp::Exp => OpCode::Exp,
-                    UnaryOp::Abs => OpCode::Abs,
-                    UnaryOp::Floor => OpCode::Floor,
-                    UnaryOp::Ceil => OpCode::Ceil,
-                    UnaryOp::Round => OpCode::Round,
-                    UnaryOp::Sign => OpCode::Sign,
-                    UnaryOp::ToRad => OpCode::ToRad,
-                    UnaryOp::ToDeg => OpCode::ToDeg,
-                    UnaryOp::Sum => OpCode::Sum,
-                    UnaryOp::Avg => OpCode::Avg,
-                    UnaryOp::Min => OpCode::Min,
-                    UnaryOp::Max => OpCode::Max,
-                    UnaryOp::Len => OpCode::Len,
-                };
-                self.chunk.write_op(opcode, self.current_line);
+                let opcode = Self::unary_opcode(op);
+                self.chunk.write_op(opcode);
             }
             Expr::BinaryOp { op, left, right } => {
+                if let Some((op, operand, constant)) = self.strength_reduce(op, left, right) {
+                    // The operand left out still has its place in the node order
+                    for child in [left, right] {
+                        if core::ptr::eq(&**child, operand) {
+                            self.generate(operand)?;
+                        } else {
+                            self.skip(child);
+                        }
+                    }
+                    match constant {
+                        Some(value) => self.chunk.write_push(value),
+                        None => self.chunk.write_op(OpCode::Dup),
+                    }
+                    self.chunk.write_op(op);
+                    return Ok(());
+                }
+                // Walk down the left operands of a chain like 1+2+3 in a loop,
+                // entering each node as `generate` would, so a long chain
+                // costs no stack
+                let mut chain = vec![(op, right, None)];
+                let mut first = left;
+                while let Expr::BinaryOp { op, left, right } = &**first {
+                    if self.strength_reduce(op, left, right).is_some() {
+                        break;
+                    }
+                    let outer = self.enter();
+                    chain.push((op, right, Some(outer)));
+                    first = left;
+                }
                 // Generate left operand first
-                self.generate(left);
-                // Then right operand
-                self.generate(right);
-
-                // Apply binary operation
-                let opcode = match op {
-                    BinaryOp::Add => OpCode::Add,
-                    BinaryOp::Subtract => OpCode::Sub,
-                    BinaryOp::Multiply => OpCode::Mul,
-                    BinaryOp::Divide => OpCode::Div,
-                    BinaryOp::Power => OpCode::Pow,
-                    BinaryOp::Modulo => OpCode::Mod,
-                    BinaryOp::Gcd => OpCode::Gcd,
-                    BinaryOp::Lcm => OpCode::Lcm,
-                    BinaryOp::Npr => OpCode::Npr,
-                    BinaryOp::Ncr => OpCode::Ncr,
-                };
-                self.chunk.write_op(opcode, self.current_line);
+                self.generate(first)?;
+                while let Some((op, right, outer)) = chain.pop() {
+                    // Then right operand
+                    self.generate(right)?;
+                    // Apply binary operation
+                    self.chunk.write_op(Self::binary_opcode(op));
+                    if let Some(outer) = outer {
+                        self.chunk.set_span(outer);
+                    }
+                }
             }
             Expr::PostfixOp { op, operand } => {
                 // Generate operand first
-                self.generate(operand);
+                self.generate(operand)?;
                 
                 // Apply postfix operation (factorial only for now)
                 let opcode = match op {
@@ -115,10 +683,177 @@ impl CodeGenerator {
                     // Other unary ops shouldn't be used as postfix
                     _ => OpCode::Factorial,
                 };
-                self.chunk.write_op(opcode, self.current_line);
+                self.chunk.write_op(opcode);
+            }
+            Expr::Variable(name) => self.load(name)?,
+            Expr::Assign { name, value } => {
+                self.generate(value)?;
+                let index = self.name_index(name)?;
+                self.chunk.write_op_u16(OpCode::StoreGlobal, index);
+            }
+            Expr::FunctionDef { name, params, body } => {
+                let function = self.compile_function(name, params, body)?;
+                self.skip(body);
+                let index = self.function_index(function)?;
+                self.chunk.write_op_u16(OpCode::DefineFunction, index);
+                // A definition is a statement; give it a value like any other
+                self.chunk.write_push(0.0);
+            }
+            Expr::Nary { op, args } => {
+                for arg in args {
+                    self.generate(arg)?;
+                }
+                self.chunk.write_op(Self::nary_opcode(*op));
+            }
+            Expr::Call { name, args } => {
+                // A parameter or captured variable holds a function value
+                let by_value = self.is_captured(name);
+                if by_value {
+                    self.load(name)?;
+                }
+                for arg in args {
+                    self.generate(arg)?;
+                }
+                let argc = Self::argument_count(name, args)?;
+                if by_value {
+                    self.chunk.write_call_value(argc);
+                } else {
+                    let index = self.name_index(name)?;
+                    self.chunk.write_call(index, argc);
+                }
+            }
+            Expr::Lambda { params, body } => {
+                // Capture, by value, whatever the body uses of this function's variables
+                let mut captures = Vec::new();
+                free_variables(body, params, &mut captures);
+                captures.retain(|name| self.is_captured(name));
+                for name in &captures {
+                    self.load(name)?;
+                }
+                let count = u8::try_from(captures.len()).map_err(|_| CompileError::TooManyCaptures(captures.len()))?;
+                let function = self.compile_closure(LAMBDA_NAME, params, captures, body)?;
+                self.skip(body);
+                let index = self.function_index(function)?;
+                self.chunk.write_closure(index, count);
+            }
+            Expr::Let { name, value, body } => {
+                self.generate(value)?;
+                // Slots follow the parameters, and are reused once the binding ends
+                let slot = u16::try_from(self.locals.len()).map_err(|_| CompileError::TooManyLocals)?;
+                self.chunk.write_op_u16(OpCode::StoreLocal, slot);
+                self.locals.push(name.clone());
+                self.generate(body)?;
+                self.locals.pop();
+            }
+            Expr::Sequence(statements) => {
+                for (i, statement) in statements.iter().enumerate() {
+                    if i > 0 {
+                        self.chunk.write_op(OpCode::Pop);
+                    }
+                    self.generate(statement)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Operations in `expr` on literals that are bound to fail, in source order
+///
+/// Walks the tree with an explicit stack, like `Expr::depth`.
+fn diagnose(expr: &Expr) -> Vec<Diagnostic> {
+    // A literal, negated or not
+    let literal = |expr: &Expr| match expr {
+        Expr::Number(value) => Some(*value),
+        Expr::UnaryOp { op: UnaryOp::Negate, operand } => match **operand {
+            Expr::Number(value) => Some(-value),
+            _ => None,
+        },
+        _ => None,
+    };
+    let mut diagnostics = Vec::new();
+    let mut pending = vec![expr];
+    while let Some(expr) = pending.pop() {
+        let found = match expr {
+            Expr::BinaryOp { op: BinaryOp::Divide | BinaryOp::Modulo, right, .. } if literal(right) == Some(0.0) => {
+                Some(Diagnostic::DivisionByZero(expr.to_string()))
+            }
+            Expr::PostfixOp { op: UnaryOp::Factorial, operand } if literal(operand).is_some_and(|n| n < 0.0) => {
+                Some(Diagnostic::NegativeFactorial(expr.to_string()))
+            }
+            Expr::UnaryOp { op: UnaryOp::Asin | UnaryOp::Acos, operand }
+                if literal(operand).is_some_and(|x| !(-1.0..=1.0).contains(&x)) =>
+            {
+                Some(Diagnostic::OutOfDomain(expr.to_string()))
+            }
+            Expr::UnaryOp { op: UnaryOp::Avg | UnaryOp::Min | UnaryOp::Max, operand }
+                if matches!(&**operand, Expr::Array(elements) if elements.is_empty()) =>
+            {
+                Some(Diagnostic::EmptyAggregate(expr.to_string()))
+            }
+            _ => None,
+        };
+        diagnostics.extend(found);
+
+        // Children go on in reverse so they come off left to right
+        match expr {
+            Expr::Number(_) | Expr::Variable(_) => {}
+            Expr::Array(children)
+            | Expr::Nary { args: children, .. }
+            | Expr::Call { args: children, .. }
+            | Expr::Sequence(children) => pending.extend(children.iter().rev()),
+            Expr::UnaryOp { operand: child, .. }
+            | Expr::PostfixOp { operand: child, .. }
+            | Expr::Assign { value: child, .. }
+            | Expr::FunctionDef { body: child, .. }
+            | Expr::Lambda { body: child, .. } => pending.push(child),
+            Expr::BinaryOp { left, right, .. } | Expr::Let { value: left, body: right, .. } => {
+                pending.push(right);
+                pending.push(left);
             }
         }
     }
+    diagnostics
+}
+
+/// Add the names `expr` reads that aren't `bound`, in order of first use
+fn free_variables(expr: &Expr, bound: &[String], names: &mut Vec<String>) {
+    let free = |name: &String, names: &mut Vec<String>| {
+        if !bound.contains(name) && !names.contains(name) {
+            names.push(name.clone());
+        }
+    };
+    match expr {
+        Expr::Number(_) => {}
+        Expr::Variable(name) => free(name, names),
+        Expr::Call { name, args } => {
+            free(name, names);
+            for arg in args {
+                free_variables(arg, bound, names);
+            }
+        }
+        Expr::Array(children) | Expr::Nary { args: children, .. } | Expr::Sequence(children) => {
+            for child in children {
+                free_variables(child, bound, names);
+            }
+        }
+        Expr::UnaryOp { operand: child, .. }
+        | Expr::PostfixOp { operand: child, .. }
+        | Expr::Assign { value: child, .. } => free_variables(child, bound, names),
+        Expr::BinaryOp { left, right, .. } => {
+            free_variables(left, bound, names);
+            free_variables(right, bound, names);
+        }
+        Expr::FunctionDef { params, body, .. } | Expr::Lambda { params, body } => {
+            let bound: Vec<String> = bound.iter().chain(params).cloned().collect();
+            free_variables(body, &bound, names);
+        }
+        Expr::Let { name, value, body } => {
+            free_variables(value, bound, names);
+            let bound: Vec<String> = bound.iter().chain(core::iter::once(name)).cloned().collect();
+            free_variables(body, &bound, names);
+        }
+    }
 }
 
 impl Default for CodeGenerator {
@@ -130,12 +865,12 @@ impl Default for CodeGenerator {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::bytecode::OpCode;
+    use crate::bytecode::{OpCode, Operand};
 
     #[test]
     fn test_compile_number() {
         let expr = Expr::number(42.0);
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
 
         assert_eq!(chunk.code()[0], OpCode::Push as u8);
         assert_eq!(chunk.read_f64(1), 42.0);
@@ -145,7 +880,7 @@ mod tests {
     #[test]
     fn test_compile_addition() {
         let expr = Expr::add(Expr::number(1.0), Expr::number(2.0));
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
 
         // PUSH 1.0, PUSH 2.0, ADD, HALT
         assert_eq!(chunk.code()[0], OpCode::Push as u8);
@@ -159,7 +894,7 @@ mod tests {
     #[test]
     fn test_compile_sin() {
         let expr = Expr::unary(UnaryOp::Sin, Expr::number(90.0));
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
 
         assert_eq!(chunk.code()[0], OpCode::Push as u8);
         assert_eq!(chunk.read_f64(1), 90.0);
@@ -174,7 +909,7 @@ mod tests {
             Expr::number(2.0),
             Expr::number(3.0),
         ]);
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
 
         // PUSH 1.0, PUSH 2.0, PUSH 3.0, PUSH_ARRAY 3, HALT
         assert_eq!(chunk.code()[0], OpCode::Push as u8);
@@ -189,7 +924,7 @@ mod tests {
     #[test]
     fn test_compile_factorial() {
         let expr = Expr::factorial(Expr::number(5.0));
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
 
         assert_eq!(chunk.code()[0], OpCode::Push as u8);
         assert_eq!(chunk.read_f64(1), 5.0);
@@ -200,10 +935,241 @@ mod tests {
     #[test]
     fn test_compile_modulo() {
         let expr = Expr::modulo(Expr::number(10.0), Expr::number(3.0));
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
 
         assert_eq!(chunk.code()[0], OpCode::Push as u8);
         assert_eq!(chunk.code()[9], OpCode::Push as u8);
         assert_eq!(chunk.code()[18], OpCode::Mod as u8);
     }
+
+    #[test]
+    fn test_compile_function_definition() {
+        // f(x) = x * k
+        let expr = Expr::function_def(
+            "f",
+            vec!["x".to_string()],
+            Expr::multiply(Expr::variable("x"), Expr::variable("k")),
+        );
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
+
+        let opcodes: Vec<_> = chunk.instructions().iter().map(|i| i.opcode).collect();
+        assert_eq!(opcodes, [OpCode::DefineFunction, OpCode::Push, OpCode::Halt]);
+
+        let function = chunk.function(0).unwrap();
+        assert_eq!(function.params, ["x"]);
+        let body: Vec<_> = function.chunk.instructions().iter().map(|i| (i.opcode, i.operand)).collect();
+        assert_eq!(body, [
+            (OpCode::LoadLocal, Operand::Index(0)),
+            (OpCode::LoadGlobal, Operand::Index(0)),
+            (OpCode::Mul, Operand::None),
+            (OpCode::Halt, Operand::None),
+        ]);
+        assert_eq!(function.chunk.name(0), Some("k"));
+    }
+
+    #[test]
+    fn test_compile_let() {
+        // f(a) = let b = a in b: the binding takes the slot after the parameter
+        let ast = Expr::function_def(
+            "f",
+            vec!["a".to_string()],
+            Expr::let_in("b", Expr::variable("a"), Expr::variable("b")),
+        );
+        let (chunk, _) = CodeGenerator::new().compile(&ast).unwrap();
+        let ops: Vec<_> = chunk.function(0).unwrap().chunk.instructions().iter().map(|i| (i.opcode, i.operand)).collect();
+        assert_eq!(
+            ops,
+            [
+                (OpCode::LoadLocal, Operand::Index(0)),
+                (OpCode::StoreLocal, Operand::Index(1)),
+                (OpCode::LoadLocal, Operand::Index(1)),
+                (OpCode::Halt, Operand::None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_closure() {
+        // f(k) = x -> k * x captures k as upvalue 0
+        let ast = Expr::function_def(
+            "f",
+            vec!["k".to_string()],
+            Expr::lambda(vec!["x".to_string()], Expr::multiply(Expr::variable("k"), Expr::variable("x"))),
+        );
+        let (chunk, _) = CodeGenerator::new().compile(&ast).unwrap();
+        let outer = &chunk.function(0).unwrap().chunk;
+        let ops: Vec<_> = outer.instructions().iter().map(|i| (i.opcode, i.operand)).collect();
+        assert_eq!(ops[0], (OpCode::LoadLocal, Operand::Index(0)));
+        assert_eq!(ops[1], (OpCode::Closure, Operand::Closure { function: 0, captures: 1 }));
+        let lambda = outer.function(0).unwrap();
+        assert!(lambda.is_lambda());
+        assert_eq!(lambda.chunk.instructions().first().map(|i| i.opcode), Some(OpCode::LoadUpvalue));
+    }
+
+    #[test]
+    fn test_compile_tail_call() {
+        // f(n) = g(n + 1) ends in a call, h(n) = 3 * g(n) doesn't
+        let tail = Expr::function_def(
+            "f",
+            vec!["n".to_string()],
+            Expr::call("g", vec![Expr::add(Expr::variable("n"), Expr::number(1.0))]),
+        );
+        let inner = Expr::function_def(
+            "h",
+            vec!["n".to_string()],
+            Expr::multiply(Expr::number(3.0), Expr::call("g", vec![Expr::variable("n")])),
+        );
+        let last_call = |expr: &Expr| {
+            let (chunk, _) = CodeGenerator::new().compile(expr).unwrap();
+            let body = chunk.function(0).unwrap().chunk.instructions();
+            (body[body.len() - 2].opcode, body[body.len() - 2].operand)
+        };
+        assert_eq!(last_call(&tail), (OpCode::TailCall, Operand::Call { name: 0, argc: 1 }));
+        assert_eq!(last_call(&inner).0, OpCode::Mul);
+    }
+
+    #[test]
+    fn test_strength_reduction() {
+        let x = || Expr::variable("x");
+        let ops = |generator: CodeGenerator, expr: &Expr| -> Vec<_> {
+            generator.compile(expr).unwrap().0.instructions().iter().map(|i| (i.opcode, i.operand)).collect()
+        };
+        let load = (OpCode::LoadGlobal, Operand::Index(0));
+        let halt = (OpCode::Halt, Operand::None);
+
+        let square = Expr::power(x(), Expr::number(2.0));
+        assert_eq!(ops(CodeGenerator::new(), &square), [load, (OpCode::Dup, Operand::None), (OpCode::Mul, Operand::None), halt]);
+        let double = Expr::multiply(Expr::number(2.0), x());
+        assert_eq!(ops(CodeGenerator::new(), &double), [load, (OpCode::Dup, Operand::None), (OpCode::Add, Operand::None), halt]);
+
+        // Division only when asked, and never by zero
+        let quarter = Expr::divide(x(), Expr::number(4.0));
+        assert_eq!(ops(CodeGenerator::new(), &quarter)[2].0, OpCode::Div);
+        let reduced = ops(CodeGenerator::new().with_reciprocal_division(), &quarter);
+        assert_eq!(reduced, [load, (OpCode::Push, Operand::Number(0.25)), (OpCode::Mul, Operand::None), halt]);
+        let by_zero = Expr::divide(x(), Expr::number(0.0));
+        assert_eq!(ops(CodeGenerator::new().with_reciprocal_division(), &by_zero)[2].0, OpCode::Div);
+
+        let chunk = CodeGenerator::new().compile_registers(&square).unwrap();
+        assert_eq!(chunk.instructions()[1], RegInstr::Binary { op: OpCode::Mul, dst: 0, lhs: Source::Reg(0), rhs: Source::Reg(0) });
+    }
+
+    #[test]
+    fn test_source_map() {
+        use crate::{Optimizer, Parser, Tokenizer};
+        let compile = |input: &str| {
+            let (ast, spans) = Parser::new(Tokenizer::new(input).tokenize().unwrap()).parse_spanned().unwrap();
+            CodeGenerator::new().with_spans(spans).compile(&ast).unwrap().0
+        };
+        let spans = |chunk: &Chunk| -> Vec<_> {
+            chunk.instructions().iter().map(|i| (i.opcode, chunk.span(i.offset))).collect()
+        };
+
+        // Tokens: sqrt ( 4 ) + 1
+        let chunk = compile("sqrt(4) + 1");
+        assert_eq!(
+            spans(&chunk),
+            [
+                (OpCode::Push, Some(2..3)),
+                (OpCode::Sqrt, Some(0..4)),
+                (OpCode::Push, Some(5..6)),
+                (OpCode::Add, Some(0..6)),
+                (OpCode::Halt, None),
+            ]
+        );
+        assert_eq!(chunk.source_map().offset_of(1), Some(chunk.instructions()[1].offset));
+        // A fused instruction takes the span of the operation
+        assert_eq!(spans(&Optimizer::optimize(&chunk))[2], (OpCode::PushAdd, Some(0..6)));
+
+        // The 2 left out by strength reduction still counts as a node
+        let chunk = compile("x^2 + 1");
+        let opcodes: Vec<_> = spans(&chunk).into_iter().map(|(_, span)| span).collect();
+        assert_eq!(opcodes, [Some(0..1), Some(0..3), Some(0..3), Some(4..5), Some(0..5), None]);
+
+        // Function bodies are mapped too; tokens: f ( x ) = x + 1 ; f ( 2 )
+        let chunk = compile("f(x) = x + 1; f(2)");
+        let body = &chunk.function(0).unwrap().chunk;
+        assert_eq!(spans(body)[..3], [(OpCode::LoadLocal, Some(5..6)), (OpCode::Push, Some(7..8)), (OpCode::Add, Some(5..8))]);
+        assert!(spans(&chunk).contains(&(OpCode::Call, Some(9..13))));
+        assert!(spans(&chunk).contains(&(OpCode::Push, Some(11..12))));
+
+        // Without spans nothing is mapped
+        let ast = Parser::new(Tokenizer::new("1 + 2").tokenize().unwrap()).parse().unwrap();
+        assert!(CodeGenerator::new().compile(&ast).unwrap().0.source_map().is_empty());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        use crate::{Parser, Tokenizer};
+        let diagnostics = |input: &str| {
+            let ast = Parser::new(Tokenizer::new(input).tokenize().unwrap()).parse().unwrap();
+            CodeGenerator::new().compile(&ast).unwrap().1
+        };
+        assert_eq!(
+            diagnostics("f(x) = x / 0; (-3)! + asin(2) + max([]) + acos(-1)"),
+            [
+                Diagnostic::DivisionByZero("(x / 0)".into()),
+                Diagnostic::NegativeFactorial("((-3)!)".into()),
+                Diagnostic::OutOfDomain("asin(2)".into()),
+                Diagnostic::EmptyAggregate("max([])".into()),
+            ]
+        );
+        assert_eq!(diagnostics("5 % 0")[0].to_string(), "Division by zero in (5 % 0)");
+        assert!(diagnostics("x / 0.5 + sum([]) + 3! + x % y").is_empty());
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        // The parser stops at 255 arguments, but an AST built by hand needn't
+        let call = Expr::function_def("g", vec![], Expr::call("f", vec![Expr::number(1.0); 256]));
+        let error = CodeGenerator::new().compile(&call).unwrap_err();
+        assert_eq!(error, CompileError::TooManyArguments { name: "f".into(), count: 256 });
+        assert_eq!(error.to_string(), "f is called with 256 arguments, more than 255");
+        assert!(CodeGenerator::new().compile_registers(&call).is_err());
+        assert!(CodeGenerator::with_backend(Backend::Register).compile_program(&call).is_err());
+    }
+
+    #[test]
+    fn test_compile_registers_inlines_constants() {
+        // sin(90) + 2^3
+        let expr = Expr::add(
+            Expr::unary(UnaryOp::Sin, Expr::number(90.0)),
+            Expr::power(Expr::number(2.0), Expr::number(3.0)),
+        );
+        let chunk = CodeGenerator::new().compile_registers(&expr).unwrap();
+
+        assert_eq!(chunk.instructions(), &[
+            RegInstr::Unary { op: OpCode::Sin, dst: 0, src: Source::Const(90.0) },
+            RegInstr::Binary { op: OpCode::Pow, dst: 1, lhs: Source::Const(2.0), rhs: Source::Const(3.0) },
+            RegInstr::Binary { op: OpCode::Add, dst: 0, lhs: Source::Reg(0), rhs: Source::Reg(1) },
+            RegInstr::Return { src: Source::Reg(0) },
+        ]);
+        assert_eq!(chunk.register_count(), 2);
+    }
+
+    #[test]
+    fn test_compile_registers_array() {
+        let expr = Expr::unary(UnaryOp::Sum, Expr::array(vec![
+            Expr::number(1.0),
+            Expr::negate(Expr::number(2.0)),
+        ]));
+        let chunk = CodeGenerator::new().compile_registers(&expr).unwrap();
+
+        assert_eq!(chunk.instructions(), &[
+            RegInstr::Move { dst: 0, src: Source::Const(1.0) },
+            RegInstr::Unary { op: OpCode::Neg, dst: 1, src: Source::Const(2.0) },
+            RegInstr::MakeArray { dst: 0, start: 0, count: 2 },
+            RegInstr::Unary { op: OpCode::Sum, dst: 0, src: Source::Reg(0) },
+            RegInstr::Return { src: Source::Reg(0) },
+        ]);
+    }
+
+    #[test]
+    fn test_compile_program_selects_backend() {
+        let expr = Expr::number(1.0);
+        assert!(matches!(CodeGenerator::new().compile_program(&expr).unwrap(), Program::Stack(_)));
+        let generator = CodeGenerator::with_backend(Backend::Register);
+        assert_eq!(generator.backend(), Backend::Register);
+        assert!(matches!(generator.compile_program(&expr).unwrap(), Program::Register(_)));
+    }
 }
@@ -2,8 +2,11 @@
 //!
 //! Useful for debugging and displaying the compiled bytecode to users.
 
+use crate::prelude::*;
 use crate::bytecode::{Chunk, OpCode};
-use std::fmt::Write;
+use crate::format::{Notation, NumberFormat};
+use core::fmt::Write;
+use core::ops::Range;
 
 /// Disassembled instruction
 #[derive(Debug, Clone)]
@@ -12,9 +15,18 @@ pub struct DisassembledInstruction {
     pub opcode: OpCode,
     pub operand: Option<f64>,
     pub array_count: Option<u64>,
+    /// Resolved operand of variable and call instructions, e.g. "x" or "f/2"
+    pub argument: Option<String>,
     pub text: String,
 }
 
+impl DisassembledInstruction {
+    /// Bytes of the chunk this instruction was decoded from
+    pub fn byte_range(&self) -> Range<usize> {
+        self.offset..self.offset + self.opcode.size()
+    }
+}
+
 /// Disassembler for bytecode chunks
 pub struct Disassembler;
 
@@ -48,10 +60,11 @@ impl Disassembler {
         let byte = chunk.code()[offset];
         let opcode = OpCode::from_byte(byte)?;
 
+        let mut argument = None;
         let (operand, array_count, text, new_offset) = match opcode {
-            OpCode::Push => {
+            OpCode::Push | OpCode::PushAdd | OpCode::PushMul => {
                 let value = chunk.read_f64(offset + 1);
-                let text = format!("0x{:04X}: {} {}", offset, opcode.name(), value);
+                let text = format!("0x{:04X}: {} {}", offset, opcode.name(), NumberFormat::new(Notation::Exact).format(value));
                 (Some(value), None, text, offset + 9)
             }
             OpCode::PushArray => {
@@ -62,6 +75,48 @@ impl Disassembler {
                 let text = format!("0x{:04X}: {} count={}", offset, opcode.name(), count);
                 (None, Some(count), text, offset + 9)
             }
+            OpCode::LoadGlobal
+            | OpCode::StoreGlobal
+            | OpCode::LoadLocal
+            | OpCode::StoreLocal
+            | OpCode::DefineFunction
+            | OpCode::Call
+            | OpCode::TailCall
+            | OpCode::Closure
+            | OpCode::LoadUpvalue => {
+                if offset + opcode.size() > chunk.len() {
+                    return None;
+                }
+                let index = chunk.read_u16(offset + 1);
+                let text = match opcode {
+                    OpCode::LoadLocal | OpCode::StoreLocal | OpCode::LoadUpvalue => format!("{}", index),
+                    OpCode::Closure => {
+                        let captures = chunk.code()[offset + 3];
+                        match chunk.function(index) {
+                            Some(function) => format!("{} captures={}", function, captures),
+                            None => format!("#{} captures={}", index, captures),
+                        }
+                    }
+                    OpCode::DefineFunction => match chunk.function(index) {
+                        Some(function) => format!("{}/{}", function.name, function.arity()),
+                        None => format!("#{}", index),
+                    },
+                    OpCode::Call | OpCode::TailCall => {
+                        let name = chunk.name(index).unwrap_or("?");
+                        format!("{}/{}", name, chunk.code()[offset + 3])
+                    }
+                    _ => chunk.name(index).unwrap_or("?").to_string(),
+                };
+                argument = Some(text);
+                let text = format!("0x{:04X}: {} {}", offset, opcode.name(), argument.as_deref().unwrap_or(""));
+                (None, None, text, offset + opcode.size())
+            }
+            OpCode::CallValue => {
+                let argc = *chunk.code().get(offset + 1)?;
+                argument = Some(format!("argc={}", argc));
+                let text = format!("0x{:04X}: {} argc={}", offset, opcode.name(), argc);
+                (None, None, text, offset + 2)
+            }
             _ => {
                 let text = format!("0x{:04X}: {}", offset, opcode.name());
                 (None, None, text, offset + 1)
@@ -74,6 +129,7 @@ impl Disassembler {
                 opcode,
                 operand,
                 array_count,
+                argument,
                 text,
             },
             new_offset,
@@ -85,29 +141,72 @@ impl Disassembler {
         let mut output = String::new();
         let instructions = Self::disassemble(chunk);
 
-        writeln!(output, "=== Bytecode Disassembly ===").unwrap();
-        writeln!(output, "Size: {} bytes", chunk.len()).unwrap();
-        writeln!(output).unwrap();
+        Self::write_header(&mut output, chunk);
 
         for instr in instructions {
             writeln!(output, "  {}", instr.text).unwrap();
         }
 
+        for function in chunk.functions() {
+            writeln!(output).unwrap();
+            writeln!(output, "=== {} ===", function).unwrap();
+            for instr in Self::disassemble(&function.chunk) {
+                writeln!(output, "  {}", instr.text).unwrap();
+            }
+        }
+
         output
     }
 
     /// Format disassembly with hex dump
     pub fn format_with_hex(chunk: &Chunk) -> String {
         let mut output = String::new();
-        let instructions = Self::disassemble(chunk);
 
+        Self::write_header(&mut output, chunk);
+        writeln!(output, "Offset  Hex                      Instruction").unwrap();
+        writeln!(output, "------  -----------------------  -----------").unwrap();
+        Self::write_hex_rows(&mut output, chunk);
+
+        for function in chunk.functions() {
+            writeln!(output).unwrap();
+            writeln!(output, "=== {} ===", function).unwrap();
+            Self::write_hex_rows(&mut output, &function.chunk);
+        }
+
+        output
+    }
+
+    /// Title, size, table sizes and, for a chunk compiled from source, where it came from
+    fn write_header(output: &mut String, chunk: &Chunk) {
         writeln!(output, "=== Bytecode Disassembly ===").unwrap();
         writeln!(output, "Size: {} bytes", chunk.len()).unwrap();
+        let pool = chunk.pool_stats();
+        if pool.names + pool.functions > 0 {
+            write!(output, "Pool: {} names, {} functions", pool.names, pool.functions).unwrap();
+            if pool.deduplicated > 0 {
+                write!(output, ", {} duplicates merged saving {} bytes", pool.deduplicated, pool.bytes_saved).unwrap();
+            }
+            writeln!(output).unwrap();
+        }
+        if let Some(metadata) = chunk.metadata() {
+            if let Some(source) = &metadata.source {
+                writeln!(output, "Source: {}", source).unwrap();
+            }
+            write!(output, "Compiler: {}", metadata.compiler).unwrap();
+            if metadata.optimized {
+                write!(output, ", optimized").unwrap();
+            }
+            if let Some(seconds) = metadata.compiled_at {
+                write!(output, ", at {} s since 1970", seconds).unwrap();
+            }
+            writeln!(output).unwrap();
+        }
         writeln!(output).unwrap();
-        writeln!(output, "Offset  Hex                      Instruction").unwrap();
-        writeln!(output, "------  -----------------------  -----------").unwrap();
+    }
 
-        for instr in instructions {
+    /// Write one offset/hex/instruction row per instruction
+    fn write_hex_rows(output: &mut String, chunk: &Chunk) {
+        for instr in Self::disassemble(chunk) {
             let size = Self::instruction_size(&instr);
             let hex_bytes = Self::format_hex_bytes(chunk, instr.offset, size);
             writeln!(
@@ -119,17 +218,19 @@ impl Disassembler {
             )
             .unwrap();
         }
+    }
 
-        output
+    /// Index of the instruction that byte `offset` belongs to
+    ///
+    /// `instructions` must be in offset order, as returned by `disassemble`.
+    pub fn instruction_containing(instructions: &[DisassembledInstruction], offset: usize) -> Option<usize> {
+        let index = instructions.partition_point(|instr| instr.offset <= offset).checked_sub(1)?;
+        instructions[index].byte_range().contains(&offset).then_some(index)
     }
 
     /// Get the size of an instruction
     fn instruction_size(instr: &DisassembledInstruction) -> usize {
-        match instr.opcode {
-            OpCode::Push => 9,
-            OpCode::PushArray => 9, // opcode + count
-            _ => 1,
-        }
+        instr.opcode.size()
     }
 
     /// Format hex bytes for an instruction
@@ -149,9 +250,10 @@ impl Disassembler {
 
     /// Format instruction text
     fn format_instruction(instr: &DisassembledInstruction) -> String {
-        match (&instr.operand, &instr.array_count) {
-            (Some(value), _) => format!("{} {}", instr.opcode.name(), value),
-            (_, Some(count)) => format!("{} count={}", instr.opcode.name(), count),
+        match (&instr.operand, &instr.array_count, &instr.argument) {
+            (Some(value), _, _) => format!("{} {}", instr.opcode.name(), NumberFormat::new(Notation::Exact).format(*value)),
+            (_, Some(count), _) => format!("{} count={}", instr.opcode.name(), count),
+            (_, _, Some(argument)) => format!("{} {}", instr.opcode.name(), argument),
             _ => instr.opcode.name().to_string(),
         }
     }
@@ -166,7 +268,7 @@ mod tests {
     #[test]
     fn test_disassemble_simple() {
         let expr = Expr::add(Expr::number(1.0), Expr::number(2.0));
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
         let instructions = Disassembler::disassemble(&chunk);
 
         assert_eq!(instructions.len(), 4); // PUSH, PUSH, ADD, HALT
@@ -176,14 +278,47 @@ mod tests {
         assert_eq!(instructions[3].opcode, OpCode::Halt);
     }
 
+    #[test]
+    fn test_instruction_containing() {
+        // PUSH 1.0 (0..9), PUSH 2.0 (9..18), ADD (18), HALT (19)
+        let expr = Expr::add(Expr::number(1.0), Expr::number(2.0));
+        let (mut chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
+        chunk.write_byte(0x0F);
+        let instructions = Disassembler::disassemble(&chunk);
+
+        assert_eq!(instructions[1].byte_range(), 9..18);
+        assert_eq!(Disassembler::instruction_containing(&instructions, 0), Some(0));
+        assert_eq!(Disassembler::instruction_containing(&instructions, 17), Some(1));
+        assert_eq!(Disassembler::instruction_containing(&instructions, 18), Some(2));
+        assert_eq!(Disassembler::instruction_containing(&instructions, 19), Some(3));
+        // Undecodable trailing byte
+        assert_eq!(Disassembler::instruction_containing(&instructions, 20), None);
+    }
+
     #[test]
     fn test_format_output() {
         let expr = Expr::number(42.0);
-        let chunk = CodeGenerator::new().compile(&expr);
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
         let output = Disassembler::format(&chunk);
 
         assert!(output.contains("PUSH"));
         assert!(output.contains("42"));
         assert!(output.contains("HALT"));
+        assert!(!output.contains("Pool:"));
+    }
+
+    #[test]
+    fn test_pool_stats() {
+        use crate::{Parser, Tokenizer};
+        let source = "map([1, 2], x -> x * 2) + map([3], x -> x * 2) + map([4], x -> x * 3)";
+        let ast = Parser::new(Tokenizer::new(source).tokenize().unwrap()).parse().unwrap();
+        let (chunk, _) = CodeGenerator::new().compile(&ast).unwrap();
+
+        // The second `x -> x * 2` reuses the first's entry
+        let pool = chunk.pool_stats();
+        assert_eq!((pool.names, pool.functions, pool.deduplicated), (0, 2, 1));
+        assert_eq!(pool.bytes_saved, chunk.functions()[0].chunk.len());
+        let output = Disassembler::format(&chunk);
+        assert!(output.contains(&format!("Pool: 0 names, 2 functions, 1 duplicates merged saving {} bytes", pool.bytes_saved)));
     }
 }
@@ -0,0 +1,236 @@
+//! Session export
+//!
+//! Renders the calculation history as CSV, JSON or Markdown, optionally
+//! followed by the disassembly of the current expression. Timestamps are
+//! written as ISO 8601 in UTC.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// One evaluated expression in the history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub expression: String,
+    pub result: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Kept at the top of the history and through "Clear History"
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+impl HistoryEntry {
+    /// Entry stamped with the current time
+    pub fn new(expression: impl Into<String>, result: impl Into<String>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        HistoryEntry {
+            expression: expression.into(),
+            result: result.into(),
+            timestamp,
+            pinned: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [ExportFormat::Csv, ExportFormat::Json, ExportFormat::Markdown];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "CSV",
+            ExportFormat::Json => "JSON",
+            ExportFormat::Markdown => "Markdown",
+        }
+    }
+
+    /// File extension, without the dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+/// History exporter
+pub struct Exporter;
+
+impl Exporter {
+    /// Render `entries`, oldest first, and the optional disassembly
+    pub fn export(entries: &[HistoryEntry], disassembly: Option<&str>, format: ExportFormat) -> String {
+        match format {
+            ExportFormat::Csv => Self::csv(entries, disassembly),
+            ExportFormat::Json => Self::json(entries, disassembly),
+            ExportFormat::Markdown => Self::markdown(entries, disassembly),
+        }
+    }
+
+    fn csv(entries: &[HistoryEntry], disassembly: Option<&str>) -> String {
+        let mut output = String::from("expression,result,timestamp\n");
+        for entry in entries {
+            let _ = writeln!(
+                output,
+                "{},{},{}",
+                csv_field(&entry.expression),
+                csv_field(&entry.result),
+                format_timestamp(entry.timestamp)
+            );
+        }
+        if let Some(disassembly) = disassembly {
+            // CSV has no sections; the listing follows as a one-column table
+            output.push_str("\ndisassembly\n");
+            for line in disassembly.lines() {
+                let _ = writeln!(output, "{}", csv_field(line));
+            }
+        }
+        output
+    }
+
+    fn json(entries: &[HistoryEntry], disassembly: Option<&str>) -> String {
+        let history: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                serde_json::json!({
+                    "expression": entry.expression,
+                    "result": entry.result,
+                    "timestamp": format_timestamp(entry.timestamp),
+                })
+            })
+            .collect();
+        let mut document = serde_json::json!({ "history": history });
+        if let Some(disassembly) = disassembly {
+            document["disassembly"] = disassembly.into();
+        }
+        let mut output = serde_json::to_string_pretty(&document).expect("JSON values always serialize");
+        output.push('\n');
+        output
+    }
+
+    fn markdown(entries: &[HistoryEntry], disassembly: Option<&str>) -> String {
+        let mut output = String::from("# Calculation History\n\n");
+        output.push_str("| Expression | Result | Time |\n|---|---|---|\n");
+        for entry in entries {
+            let _ = writeln!(
+                output,
+                "| `{}` | {} | {} |",
+                markdown_cell(&entry.expression),
+                markdown_cell(&entry.result),
+                format_timestamp(entry.timestamp)
+            );
+        }
+        if let Some(disassembly) = disassembly {
+            let _ = write!(output, "\n## Disassembly\n\n```\n{}\n```\n", disassembly.trim_end());
+        }
+        output
+    }
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Keep a value inside one Markdown table cell
+fn markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
+
+/// Format seconds since the Unix epoch as e.g. "2023-11-14T22:13:20Z"
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+
+    // Civil date from days since 1970-01-01 (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<HistoryEntry> {
+        vec![
+            HistoryEntry {
+                expression: "gcd(12, 18)".into(),
+                result: "6".into(),
+                timestamp: 0,
+                pinned: false,
+            },
+            HistoryEntry {
+                expression: "f(x) = x | 1".into(),
+                result: "defined f(x)".into(),
+                timestamp: 1_700_000_000,
+                pinned: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = Exporter::export(&entries(), Some("0x00: HALT"), ExportFormat::Csv);
+        assert_eq!(
+            csv,
+            "expression,result,timestamp\n\
+             \"gcd(12, 18)\",6,1970-01-01T00:00:00Z\n\
+             f(x) = x | 1,defined f(x),2023-11-14T22:13:20Z\n\
+             \ndisassembly\n\
+             0x00: HALT\n"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let json = Exporter::export(&entries(), None, ExportFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["history"][1]["expression"], "f(x) = x | 1");
+        assert_eq!(value["history"][0]["timestamp"], "1970-01-01T00:00:00Z");
+        assert!(value.get("disassembly").is_none());
+    }
+
+    #[test]
+    fn test_markdown() {
+        let markdown = Exporter::export(&entries(), Some("0x00: HALT\n"), ExportFormat::Markdown);
+        assert!(markdown.contains("| `f(x) = x \\| 1` | defined f(x) | 2023-11-14T22:13:20Z |"));
+        assert!(markdown.ends_with("## Disassembly\n\n```\n0x00: HALT\n```\n"));
+    }
+}
@@ -0,0 +1,556 @@
+//! Result formatting
+//!
+//! `NumberFormat` turns numbers into display strings for the GUI (results,
+//! stack and trace), the CLI, the disassembler and the library's
+//! `evaluate_formatted`, so they all agree on how a number looks:
+//!
+//!   Auto              0.1 + 0.2  -> 0.3
+//!   Fixed, 4          pi         -> 3.1416
+//!   Scientific, 3     12345      -> 1.23e4
+//!   Engineering, 3    12345      -> 12.3e3
+//!   SI, 3             12345      -> 12.3 k
+//!   Fraction, 1000    0.75       -> 3/4
+//!   Exact             0.1 + 0.2  -> 0.30000000000000004
+//!
+//! On top of the notation, trailing zeros can be trimmed, integral values
+//! written without decimals, and the separators chosen by `Locale`.
+//! `format_result` can also follow a result with its nearest fraction,
+//! `0.75 = 3/4`, which `as_fraction` finds for library users.
+
+use crate::prelude::*;
+use crate::vm::Value;
+use serde::{Deserialize, Serialize};
+
+/// How results are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Notation {
+    /// Integers exactly, anything else to at most 10 decimals
+    Auto,
+    /// A fixed number of decimals
+    Fixed,
+    /// Mantissa and exponent, with `digits` significant figures
+    Scientific,
+    /// Like scientific, with the exponent a multiple of 3
+    Engineering,
+    /// Like engineering, with the exponent written as an SI prefix (`12.3 k`)
+    SiPrefix,
+    /// Nearest fraction with a denominator up to `max_denominator`
+    Fraction,
+    /// Shortest text that reads back as the same `f64`
+    Exact,
+}
+
+impl Notation {
+    pub const ALL: [Notation; 7] = [
+        Notation::Auto,
+        Notation::Fixed,
+        Notation::Scientific,
+        Notation::Engineering,
+        Notation::SiPrefix,
+        Notation::Fraction,
+        Notation::Exact,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Notation::Auto => "Auto",
+            Notation::Fixed => "Fixed",
+            Notation::Scientific => "Scientific",
+            Notation::Engineering => "Engineering",
+            Notation::SiPrefix => "SI",
+            Notation::Fraction => "Fraction",
+            Notation::Exact => "Exact",
+        }
+    }
+}
+
+/// Decimal and digit-group separators
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Locale {
+    /// 1234567.5, as the calculator reads numbers
+    #[default]
+    Plain,
+    /// 1,234,567.5
+    English,
+    /// 1.234.567,5
+    German,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 3] = [Locale::Plain, Locale::English, Locale::German];
+
+    /// Decimal separator and the separator between groups of three digits
+    fn separators(self) -> (char, Option<char>) {
+        match self {
+            Locale::Plain => ('.', None),
+            Locale::English => ('.', Some(',')),
+            Locale::German => (',', Some('.')),
+        }
+    }
+
+    /// Rewrite the numbers in `text`, written with a '.' and no grouping
+    fn apply(self, text: &str) -> String {
+        if self == Locale::Plain {
+            return text.to_string();
+        }
+        let (decimal, group) = self.separators();
+        let mut output = String::with_capacity(text.len() + text.len() / 3);
+        let mut digits = 0;
+        let mut in_fraction = false;
+        for (i, c) in text.char_indices() {
+            if c.is_ascii_digit() {
+                if digits == 0 && !in_fraction {
+                    // Digits left in this integer part, to place the group separators
+                    digits = text[i..].bytes().take_while(u8::is_ascii_digit).count();
+                }
+                output.push(c);
+                if !in_fraction {
+                    digits -= 1;
+                    if let Some(group) = group.filter(|_| digits > 0 && digits % 3 == 0) {
+                        output.push(group);
+                    }
+                }
+            } else if c == '.' {
+                output.push(decimal);
+                in_fraction = true;
+            } else {
+                output.push(c);
+                in_fraction = false;
+            }
+        }
+        output
+    }
+}
+
+/// Display settings for results
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NumberFormat {
+    pub notation: Notation,
+    /// Decimals for `Fixed`, significant figures for `Scientific`, `Engineering` and `SiPrefix`
+    pub digits: usize,
+    /// Largest denominator tried by `Fraction`
+    pub max_denominator: u64,
+    /// Drop trailing zeros after the decimal point (`2.50` -> `2.5`)
+    pub trim_zeros: bool,
+    /// Write integral values below 10^15 without decimals or exponent, as `Auto` does
+    pub exact_integers: bool,
+    pub locale: Locale,
+    /// Follow results from `format_result` with their nearest fraction
+    pub show_fraction: bool,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            notation: Notation::Auto,
+            digits: 6,
+            max_denominator: 1000,
+            trim_zeros: false,
+            exact_integers: false,
+            locale: Locale::Plain,
+            show_fraction: false,
+        }
+    }
+}
+
+impl NumberFormat {
+    /// Format with `notation` and otherwise default settings
+    pub fn new(notation: Notation) -> Self {
+        NumberFormat {
+            notation,
+            ..NumberFormat::default()
+        }
+    }
+
+    pub fn with_digits(mut self, digits: usize) -> Self {
+        self.digits = digits;
+        self
+    }
+
+    pub fn with_max_denominator(mut self, max_denominator: u64) -> Self {
+        self.max_denominator = max_denominator.max(1);
+        self
+    }
+
+    pub fn with_trim_zeros(mut self, trim_zeros: bool) -> Self {
+        self.trim_zeros = trim_zeros;
+        self
+    }
+
+    pub fn with_exact_integers(mut self, exact_integers: bool) -> Self {
+        self.exact_integers = exact_integers;
+        self
+    }
+
+    pub fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    pub fn with_show_fraction(mut self, show_fraction: bool) -> Self {
+        self.show_fraction = show_fraction;
+        self
+    }
+
+    /// Format a single number
+    pub fn format(&self, value: f64) -> String {
+        if !value.is_finite() {
+            return value.to_string();
+        }
+        let text = if self.exact_integers && is_integer(value) {
+            format!("{}", value as i64)
+        } else {
+            match self.notation {
+                Notation::Auto => auto(value),
+                Notation::Fixed => format!("{:.*}", self.digits, value),
+                Notation::Scientific => scientific(value, self.digits),
+                Notation::Engineering => engineering(value, self.digits),
+                Notation::SiPrefix => si_prefix(value, self.digits),
+                Notation::Fraction => fraction(value, self.max_denominator),
+                Notation::Exact => exact(value),
+            }
+        };
+        let text = if self.trim_zeros { trim_zeros(&text) } else { text };
+        self.locale.apply(&text)
+    }
+
+    /// Format a result, followed by its nearest fraction if `show_fraction`
+    /// is set and the value isn't an integer: `0.75 = 3/4`, `3.1415926536 ≈ 355/113`
+    pub fn format_result(&self, value: f64) -> String {
+        let text = self.format(value);
+        if !self.show_fraction || self.notation == Notation::Fraction {
+            return text;
+        }
+        match as_fraction(value, self.max_denominator) {
+            Some((_, denominator)) if denominator > 1 => {
+                let fraction = fraction(value, self.max_denominator);
+                let fraction = fraction.strip_prefix("≈ ").map_or(format!("= {}", fraction), |f| format!("≈ {}", f));
+                format!("{} {}", text, self.locale.apply(&fraction))
+            }
+            _ => text,
+        }
+    }
+
+    /// `format_result` for a number, `format_value` for anything else
+    pub fn format_result_value(&self, value: &Value) -> String {
+        match value {
+            Value::Scalar(v) => self.format_result(*v),
+            _ => self.format_value(value),
+        }
+    }
+
+    /// Format a list of numbers as `[a, b, c]`
+    pub fn format_list(&self, values: &[f64]) -> String {
+        let elements: Vec<String> = values.iter().map(|&v| self.format(v)).collect();
+        format!("[{}]", elements.join(", "))
+    }
+
+    /// Format a result of any kind; functions and strings are written as they are
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::Scalar(v) => self.format(*v),
+            Value::Array(elements) => self.format_list(elements),
+            Value::Function(source) | Value::Text(source) => source.clone(),
+        }
+    }
+}
+
+/// Integral, and small enough that every neighbouring integer is representable
+fn is_integer(value: f64) -> bool {
+    value.fract() == 0.0 && value.abs() < 1e15
+}
+
+/// Remove zeros after the decimal point, and the point if nothing is left
+fn trim_zeros(text: &str) -> String {
+    let (mantissa, exponent) = match text.find(['e', ' ']) {
+        Some(at) => text.split_at(at),
+        None => (text, ""),
+    };
+    if !mantissa.contains('.') {
+        return text.to_string();
+    }
+    let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+    format!("{}{}", mantissa, exponent)
+}
+
+fn auto(value: f64) -> String {
+    if is_integer(value) {
+        format!("{}", value as i64)
+    } else {
+        format!("{:.10}", value)
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+/// Round to `digits` significant figures, returning the mantissa digits and exponent
+fn round_significant(value: f64, digits: usize) -> (f64, i32) {
+    let text = format!("{:.*e}", digits.max(1) - 1, value);
+    let (mantissa, exponent) = text.split_once('e').expect("`{:e}` always writes an exponent");
+    (
+        mantissa.parse().expect("`{:e}` writes a valid mantissa"),
+        exponent.parse().expect("`{:e}` writes a valid exponent"),
+    )
+}
+
+fn scientific(value: f64, digits: usize) -> String {
+    let (mantissa, exponent) = round_significant(value, digits);
+    format!("{:.*}e{}", digits.max(1) - 1, mantissa, exponent)
+}
+
+/// Mantissa text and exponent, a multiple of 3, with `digits` significant figures
+fn engineering_parts(value: f64, digits: usize) -> (String, i32) {
+    let digits = digits.max(1);
+    let (mantissa, exponent) = round_significant(value, digits);
+    let shift = exponent.rem_euclid(3);
+    let decimals = (digits - 1).saturating_sub(shift as usize);
+    (format!("{:.*}", decimals, mantissa * 10f64.powi(shift)), exponent - shift)
+}
+
+fn engineering(value: f64, digits: usize) -> String {
+    let (mantissa, exponent) = engineering_parts(value, digits);
+    format!("{}e{}", mantissa, exponent)
+}
+
+/// SI prefixes from 10^-24 to 10^24, in steps of 10^3
+const SI_PREFIXES: [&str; 17] = ["y", "z", "a", "f", "p", "n", "µ", "m", "", "k", "M", "G", "T", "P", "E", "Z", "Y"];
+
+/// Engineering notation with a prefix for the exponent, or an exponent
+/// outside the range of prefixes
+fn si_prefix(value: f64, digits: usize) -> String {
+    let (mantissa, exponent) = engineering_parts(value, digits);
+    let index = usize::try_from((exponent + 24) / 3).ok();
+    match index.and_then(|index| SI_PREFIXES.get(index)) {
+        Some(&"") => mantissa,
+        Some(prefix) => format!("{} {}", mantissa, prefix),
+        None => format!("{}e{}", mantissa, exponent),
+    }
+}
+
+/// Rust's shortest round-trip digits, with an exponent where the plain
+/// form would be long
+fn exact(value: f64) -> String {
+    let magnitude = value.abs();
+    if magnitude == 0.0 || (1e-5..1e16).contains(&magnitude) {
+        format!("{}", value)
+    } else {
+        format!("{:e}", value)
+    }
+}
+
+/// `value` written in `base`, 2 to 36, with lowercase letters for digits past 9
+///
+/// `None` for other bases and for values that aren't integers below 2^53.
+pub fn to_base(value: f64, base: u32) -> Option<String> {
+    if !(2..=36).contains(&base) || value.fract() != 0.0 || value.abs() >= 9007199254740992.0 {
+        return None;
+    }
+    let mut magnitude = value.abs() as u64;
+    let mut digits = Vec::new();
+    loop {
+        digits.push(char::from_digit((magnitude % base as u64) as u32, base)?);
+        magnitude /= base as u64;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    if value < 0.0 {
+        digits.push('-');
+    }
+    Some(digits.iter().rev().collect())
+}
+
+/// Nearest fraction to `value` with a denominator of at most `max_denominator`,
+/// as `(numerator, denominator)`
+///
+/// `None` for infinities, NaN and values beyond the range of `i64`.
+pub fn as_fraction(value: f64, max_denominator: u64) -> Option<(i64, u64)> {
+    if !value.is_finite() || value.abs() >= i64::MAX as f64 {
+        None
+    } else if value.abs() >= 1e15 {
+        // Already an integer as far as f64 can tell
+        Some((value as i64, 1))
+    } else {
+        Some(best_fraction(value, max_denominator))
+    }
+}
+
+fn fraction(value: f64, max_denominator: u64) -> String {
+    if value.abs() >= 1e15 {
+        return auto(value);
+    }
+    let (numerator, denominator) = best_fraction(value, max_denominator);
+    let approximate = (numerator as f64 / denominator as f64 - value).abs() > value.abs() * 1e-12;
+    let prefix = if approximate { "≈ " } else { "" };
+    if denominator == 1 {
+        format!("{}{}", prefix, numerator)
+    } else {
+        format!("{}{}/{}", prefix, numerator, denominator)
+    }
+}
+
+/// Closest fraction to `value` whose denominator is at most `max_denominator`
+///
+/// Walks the continued fraction expansion, then checks the best
+/// semiconvergent once the next convergent would be too large.
+fn best_fraction(value: f64, max_denominator: u64) -> (i64, u64) {
+    let max_denominator = max_denominator.max(1) as i128;
+    let (mut h0, mut h1) = (0i128, 1i128);
+    let (mut k0, mut k1) = (1i128, 0i128);
+    let mut x = value;
+    loop {
+        let a = x.floor();
+        if a.abs() > i64::MAX as f64 {
+            break;
+        }
+        let a = a as i128;
+        let k2 = a * k1 + k0;
+        if k2 > max_denominator {
+            // Largest semiconvergent that fits, if it beats the last convergent
+            let t = (max_denominator - k0) / k1;
+            let (h, k) = (t * h1 + h0, t * k1 + k0);
+            if (h as f64 / k as f64 - value).abs() < (h1 as f64 / k1 as f64 - value).abs() {
+                (h1, k1) = (h, k);
+            }
+            break;
+        }
+        (h0, h1) = (h1, a * h1 + h0);
+        (k0, k1) = (k1, k2);
+        let rest = x - a as f64;
+        if rest.abs() < 1e-12 {
+            break;
+        }
+        x = 1.0 / rest;
+    }
+    (h1.clamp(i64::MIN as i128, i64::MAX as i128) as i64, k1 as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_and_fixed() {
+        let auto = NumberFormat::default();
+        assert_eq!(auto.format(0.1 + 0.2), "0.3");
+        assert_eq!(auto.format(1024.0), "1024");
+        assert_eq!(auto.format(f64::NAN), "NaN");
+        assert_eq!(NumberFormat::new(Notation::Fixed).with_digits(4).format(core::f64::consts::PI), "3.1416");
+        assert_eq!(NumberFormat::new(Notation::Fixed).with_digits(0).format(2.4), "2");
+    }
+
+    #[test]
+    fn test_scientific_and_engineering() {
+        let scientific = NumberFormat::new(Notation::Scientific).with_digits(3);
+        assert_eq!(scientific.format(12345.0), "1.23e4");
+        assert_eq!(scientific.format(-0.000999), "-9.99e-4");
+        assert_eq!(scientific.format(9999.0), "1.00e4");
+
+        let engineering = NumberFormat::new(Notation::Engineering).with_digits(3);
+        assert_eq!(engineering.format(12345.0), "12.3e3");
+        assert_eq!(engineering.format(0.00047), "470e-6");
+        assert_eq!(engineering.format(999_999.0), "1.00e6");
+        assert_eq!(engineering.format(0.0), "0.00e0");
+    }
+
+    #[test]
+    fn test_si_prefix() {
+        let si = NumberFormat::new(Notation::SiPrefix).with_digits(3);
+        assert_eq!(si.format(12400.0), "12.4 k");
+        assert_eq!(si.format(3.3e-6), "3.30 µ");
+        assert_eq!(si.with_trim_zeros(true).format(3.3e-6), "3.3 µ");
+        assert_eq!(si.format(-1.21e9), "-1.21 G");
+        assert_eq!(si.format(42.0), "42.0");
+        assert_eq!(si.format(999_999.0), "1.00 M");
+        assert_eq!(si.format(1.21e30), "1.21e30");
+        assert_eq!(si.format(2e-28), "200e-30");
+        assert_eq!(si.with_locale(Locale::German).format(12400.0), "12,4 k");
+    }
+
+    #[test]
+    fn test_fraction() {
+        let fraction = NumberFormat::new(Notation::Fraction);
+        assert_eq!(fraction.format(0.75), "3/4");
+        assert_eq!(fraction.format(-2.5), "-5/2");
+        assert_eq!(fraction.format(3.0), "3");
+        assert_eq!(fraction.format(core::f64::consts::PI), "≈ 355/113");
+        assert_eq!(fraction.with_max_denominator(10).format(core::f64::consts::PI), "≈ 22/7");
+        assert_eq!(fraction.format(1.0 / 3.0), "1/3");
+    }
+
+    #[test]
+    fn test_as_fraction() {
+        assert_eq!(as_fraction(0.75, 1000), Some((3, 4)));
+        assert_eq!(as_fraction(-0.125, 1000), Some((-1, 8)));
+        assert_eq!(as_fraction(core::f64::consts::PI, 100), Some((311, 99)));
+        assert_eq!(as_fraction(2e15, 1000), Some((2_000_000_000_000_000, 1)));
+        assert_eq!(as_fraction(f64::NAN, 1000), None);
+        assert_eq!(as_fraction(1e300, 1000), None);
+
+        let auto = NumberFormat::default().with_show_fraction(true);
+        assert_eq!(auto.format_result(0.75), "0.75 = 3/4");
+        assert_eq!(auto.format_result(core::f64::consts::PI), "3.1415926536 ≈ 355/113");
+        assert_eq!(auto.format_result(4.0), "4");
+        assert_eq!(auto.format_result(f64::INFINITY), "inf");
+        assert_eq!(auto.format(0.75), "0.75");
+        assert_eq!(NumberFormat::default().format_result(0.75), "0.75");
+    }
+
+    #[test]
+    fn test_to_base() {
+        assert_eq!(to_base(255.0, 16).as_deref(), Some("ff"));
+        assert_eq!(to_base(-5.0, 2).as_deref(), Some("-101"));
+        assert_eq!(to_base(0.0, 36).as_deref(), Some("0"));
+        assert_eq!(to_base(35.0, 36).as_deref(), Some("z"));
+        assert_eq!(to_base(1.5, 2), None);
+        assert_eq!(to_base(10.0, 37), None);
+    }
+
+    #[test]
+    fn test_exact() {
+        let exact = NumberFormat::new(Notation::Exact);
+        assert_eq!(exact.format(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(exact.format(90.0), "90");
+        assert_eq!(exact.format(-1.5e-7), "-1.5e-7");
+        assert_eq!(exact.format(6.02e23), "6.02e23");
+    }
+
+    #[test]
+    fn test_options() {
+        let fixed = NumberFormat::new(Notation::Fixed).with_digits(4);
+        assert_eq!(fixed.with_trim_zeros(true).format(2.5), "2.5");
+        assert_eq!(fixed.with_trim_zeros(true).format(3.0), "3");
+        assert_eq!(fixed.with_exact_integers(true).format(3.0), "3");
+        assert_eq!(fixed.with_exact_integers(true).format(3.5), "3.5000");
+
+        let scientific = NumberFormat::new(Notation::Scientific).with_digits(4).with_trim_zeros(true);
+        assert_eq!(scientific.format(2000.0), "2e3");
+        assert_eq!(scientific.format(2500.0), "2.5e3");
+        assert_eq!(scientific.with_exact_integers(true).format(2000.0), "2000");
+    }
+
+    #[test]
+    fn test_locale() {
+        let fixed = NumberFormat::new(Notation::Fixed).with_digits(2);
+        assert_eq!(fixed.with_locale(Locale::English).format(-1234567.891), "-1,234,567.89");
+        assert_eq!(fixed.with_locale(Locale::German).format(1234567.891), "1.234.567,89");
+        assert_eq!(fixed.with_locale(Locale::German).format(123.0), "123,00");
+        assert_eq!(fixed.format(1234.5), "1234.50");
+        let scientific = NumberFormat::new(Notation::Scientific).with_digits(3).with_locale(Locale::German);
+        assert_eq!(scientific.format(12345.0), "1,23e4");
+        let fraction = NumberFormat::new(Notation::Fraction).with_locale(Locale::English);
+        assert_eq!(fraction.format(1234.5), "2,469/2");
+        assert_eq!(
+            NumberFormat::default().with_locale(Locale::German).format_list(&[0.5, 1000.0]),
+            "[0,5, 1.000]"
+        );
+    }
+
+    #[test]
+    fn test_format_array() {
+        let value = Value::Array(vec![0.5, 0.25]);
+        assert_eq!(NumberFormat::new(Notation::Fraction).format_value(&value), "[1/2, 1/4]");
+    }
+}
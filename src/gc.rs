@@ -6,23 +6,122 @@
 //!
 //! For this calculator VM, roots are:
 //!   - Values on the VM stack
-//!   - Constants in the bytecode chunk
-
-use crate::memory::MemoryManager;
-use std::ptr::NonNull;
+//!   - Stack snapshots kept for `step_back`
+//!   - Registers of a running register-machine program
+//!
+//! Roots registered with `add_root` are always scanned. Callers that own
+//! values referencing the heap pass them to `collect_with`/`start_cycle_with`
+//! instead, which trace them through `Traceable` when the cycle starts.
+//!
+//! `collect` runs a whole cycle at once. `step(budget)` instead advances
+//! the current cycle by at most `budget` units of work (one root marked or
+//! one heap slot swept), so a collection can be spread across GUI frames.
+//! Objects allocated while a cycle is in progress are allocated marked and
+//! survive it.
+//!
+//! With `std`, every call that does collection work is timed as a pause:
+//! a whole cycle for `collect`, one increment for `step`. `GcStats` keeps
+//! the shortest, longest and total pause and the last `RECENT_PAUSES`.
+
+use crate::prelude::*;
+use crate::allocator::Allocator;
+use crate::memory::{MemoryManager, ObjectInfo, SweepCursor};
+use alloc::collections::VecDeque;
+use core::ptr::NonNull;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use web_time::Instant;
+
+/// Number of pauses `GcStats::recent_pauses` keeps
+pub const RECENT_PAUSES: usize = 32;
 
 /// Trait for objects that can be traced by the GC
+///
+/// Handles (`GcValue`, `GcArray`) mark the object they point to and then
+/// trace its contents; anything else traces its fields. Implementations
+/// for plain structs can be generated with `impl_traceable!`.
 pub trait Traceable {
     /// Visit all references held by this object
     fn trace(&self, gc: &mut GarbageCollector);
 }
 
+/// Implement `Traceable` for a struct by tracing each listed field
+///
+/// Every field named must itself be `Traceable`; fields holding no heap
+/// references can simply be left out. Tuple structs use field indices.
+///
+/// ```
+/// use calculator::gc::{GcArray, GcValue};
+/// use calculator::impl_traceable;
+///
+/// struct Pair {
+///     values: GcArray,
+///     cached: Option<GcValue<f64>>,
+///     label: String,
+/// }
+/// impl_traceable!(Pair { values, cached });
+/// ```
+#[macro_export]
+macro_rules! impl_traceable {
+    ($ty:ty { $($field:tt),* $(,)? }) => {
+        impl $crate::gc::Traceable for $ty {
+            fn trace(&self, gc: &mut $crate::gc::GarbageCollector) {
+                $( $crate::gc::Traceable::trace(&self.$field, gc); )*
+            }
+        }
+    };
+}
+
 /// GC statistics
 #[derive(Debug, Clone, Default)]
 pub struct GcStats {
     pub collections: usize,
     pub total_objects_freed: usize,
     pub total_bytes_freed: usize,
+    /// Calls to `step` that did collection work
+    pub increments: usize,
+    /// Pauses timed so far, and their shortest, longest and total duration
+    pub pauses: usize,
+    pub min_pause: Duration,
+    pub max_pause: Duration,
+    pub total_pause: Duration,
+    /// The last `RECENT_PAUSES` pauses, oldest first
+    pub recent_pauses: VecDeque<Duration>,
+}
+
+impl GcStats {
+    /// Mean pause, if any were timed
+    pub fn average_pause(&self) -> Option<Duration> {
+        let pauses = u32::try_from(self.pauses).ok().filter(|&pauses| pauses > 0)?;
+        Some(self.total_pause / pauses)
+    }
+
+    #[cfg(feature = "std")]
+    fn record_pause(&mut self, pause: Duration) {
+        self.min_pause = if self.pauses == 0 { pause } else { self.min_pause.min(pause) };
+        self.max_pause = self.max_pause.max(pause);
+        self.total_pause += pause;
+        self.pauses += 1;
+        if self.recent_pauses.len() == RECENT_PAUSES {
+            self.recent_pauses.pop_front();
+        }
+        self.recent_pauses.push_back(pause);
+    }
+}
+
+/// Where the collector is within a cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcPhase {
+    Idle,
+    Marking,
+    Sweeping,
+}
+
+/// Progress of the current collection cycle
+enum Cycle {
+    Idle,
+    Marking { gray: Vec<NonNull<u8>> },
+    Sweeping { cursor: SweepCursor },
 }
 
 /// Mark-and-sweep garbage collector
@@ -33,6 +132,12 @@ pub struct GarbageCollector {
     roots: Vec<NonNull<u8>>,
     /// Whether GC is currently running (prevents recursive collection)
     collecting: bool,
+    /// Current incremental cycle
+    cycle: Cycle,
+    /// Usage when the current cycle began
+    cycle_bytes_before: usize,
+    /// Objects freed so far in the current cycle
+    cycle_objects_freed: usize,
 }
 
 impl GarbageCollector {
@@ -42,6 +147,9 @@ impl GarbageCollector {
             stats: GcStats::default(),
             roots: Vec::new(),
             collecting: false,
+            cycle: Cycle::Idle,
+            cycle_bytes_before: 0,
+            cycle_objects_freed: 0,
         }
     }
 
@@ -52,24 +160,92 @@ impl GarbageCollector {
             stats: GcStats::default(),
             roots: Vec::new(),
             collecting: false,
+            cycle: Cycle::Idle,
+            cycle_bytes_before: 0,
+            cycle_objects_freed: 0,
         }
     }
 
+    /// Create GC whose heap takes its blocks from `allocator`
+    pub fn with_allocator(allocator: impl Allocator + 'static) -> Self {
+        let mut gc = Self::new();
+        gc.memory = MemoryManager::with_allocator(allocator);
+        gc
+    }
+
+    /// Create GC whose heap is capped at `limit` bytes
+    pub fn with_limit(limit: usize) -> Self {
+        let mut gc = Self::new();
+        gc.memory = MemoryManager::with_limit(limit);
+        gc
+    }
+
     /// Allocate memory, potentially triggering GC
+    ///
+    /// Returns `None` if the allocation doesn't fit under the memory cap
+    /// even after a forced collection.
+    #[track_caller]
     pub fn allocate(&mut self, size: usize) -> Option<NonNull<u8>> {
+        self.allocate_aligned(size, 1)
+    }
+
+    /// `allocate`, aligned to `align` (see `MemoryManager::allocate_aligned`)
+    #[track_caller]
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
         // Check if we should collect before allocating
-        if self.should_collect() {
-            self.collect();
+        if self.should_collect() || !self.memory.fits(size) {
+            self.force_collect();
         }
 
+        self.memory.allocate_aligned(size, align)
+    }
+
+    /// Whether an allocation of `size` bytes stays within the memory cap
+    pub fn fits(&self, size: usize) -> bool {
+        self.memory.fits(size)
+    }
+
+    /// Allocate memory without collecting
+    ///
+    /// For callers whose roots are only known to them; they collect at
+    /// their own safe points through `collect_with`/`start_cycle_with`.
+    #[track_caller]
+    pub fn allocate_uncollected(&mut self, size: usize) -> Option<NonNull<u8>> {
         self.memory.allocate(size)
     }
 
+    /// Run `finalizer` with the object's pointer when it is swept
+    ///
+    /// Use this for objects holding resources outside the GC heap.
+    pub fn set_finalizer(&mut self, ptr: NonNull<u8>, finalizer: impl FnOnce(NonNull<u8>) + 'static) {
+        self.memory.set_finalizer(ptr, finalizer);
+    }
+
+    /// Mark an object as reachable (used by `Traceable` implementations)
+    pub fn mark(&mut self, ptr: NonNull<u8>) {
+        self.memory.mark(ptr);
+    }
+
+    /// Mark an object, returning `false` if it was already marked
+    ///
+    /// Lets handles skip tracing contents twice and terminates cycles.
+    pub fn mark_new(&mut self, ptr: NonNull<u8>) -> bool {
+        if self.memory.is_marked(ptr) {
+            return false;
+        }
+        self.memory.mark(ptr);
+        true
+    }
+
     /// Add a root reference
     pub fn add_root(&mut self, ptr: NonNull<u8>) {
         if !self.roots.contains(&ptr) {
             self.roots.push(ptr);
         }
+        // A cycle in progress may already be past its root scan
+        if !matches!(self.cycle, Cycle::Idle) {
+            self.memory.mark(ptr);
+        }
     }
 
     /// Remove a root reference
@@ -94,51 +270,150 @@ impl GarbageCollector {
     }
 
     /// Run garbage collection
+    ///
+    /// Finishes the current incremental cycle if one is running, otherwise
+    /// performs a complete new cycle. Returns the number of objects freed.
     pub fn collect(&mut self) -> usize {
         if self.collecting {
             return 0;
         }
+        if matches!(self.cycle, Cycle::Idle) {
+            self.begin_cycle();
+        }
+        self.advance(usize::MAX);
+        self.cycle_objects_freed
+    }
 
-        self.collecting = true;
-        let bytes_before = self.memory.current_usage();
+    /// Run a full collection with everything `roots` traces treated as reachable
+    pub fn collect_with<T: Traceable + ?Sized>(&mut self, roots: &T) -> usize {
+        if self.collecting {
+            return 0;
+        }
+        if !matches!(self.cycle, Cycle::Idle) {
+            // Finish the running cycle; its roots were scanned when it began
+            self.collect();
+        }
+        self.start_cycle_with(roots);
+        self.collect()
+    }
 
-        // Mark phase
-        self.mark_phase();
+    /// Start an incremental cycle, tracing `roots` now; continue with `step`
+    pub fn start_cycle_with<T: Traceable + ?Sized>(&mut self, roots: &T) {
+        if !matches!(self.cycle, Cycle::Idle) {
+            return;
+        }
+        self.begin_cycle();
+        roots.trace(self);
+    }
 
-        // Sweep phase
-        let objects_freed = self.sweep_phase();
+    /// Do at most `budget` units of collection work
+    ///
+    /// Starts a cycle when idle and the memory threshold has been reached.
+    /// Returns the phase the collector is in afterwards; `GcPhase::Idle`
+    /// means there is nothing left to do.
+    pub fn step(&mut self, budget: usize) -> GcPhase {
+        if self.collecting {
+            return self.phase();
+        }
+        if matches!(self.cycle, Cycle::Idle) {
+            if !self.memory.should_collect() {
+                return GcPhase::Idle;
+            }
+            self.begin_cycle();
+        }
+        self.stats.increments += 1;
+        self.advance(budget.max(1));
+        self.phase()
+    }
 
-        let bytes_after = self.memory.current_usage();
-        let bytes_freed = bytes_before.saturating_sub(bytes_after);
+    /// Current phase of the collector
+    pub fn phase(&self) -> GcPhase {
+        match self.cycle {
+            Cycle::Idle => GcPhase::Idle,
+            Cycle::Marking { .. } => GcPhase::Marking,
+            Cycle::Sweeping { .. } => GcPhase::Sweeping,
+        }
+    }
 
-        self.stats.collections += 1;
-        self.stats.total_objects_freed += objects_freed;
-        self.stats.total_bytes_freed += bytes_freed;
+    /// Start a cycle: clear marks and queue the roots for marking
+    fn begin_cycle(&mut self) {
+        self.memory.unmark_all();
+        self.memory.set_allocate_marked(true);
+        self.cycle_bytes_before = self.memory.current_usage();
+        self.cycle_objects_freed = 0;
+        self.cycle = Cycle::Marking { gray: self.roots.clone() };
+    }
+
+    /// Advance the current cycle by up to `budget` units of work
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "gc", level = "trace", skip(self)))]
+    fn advance(&mut self, budget: usize) {
+        #[cfg(feature = "std")]
+        let started = Instant::now();
+        self.collecting = true;
+        let mut remaining = budget;
+
+        // Mark phase: mark reachable objects starting from roots
+        if let Cycle::Marking { gray } = &mut self.cycle {
+            while remaining > 0 {
+                let Some(ptr) = gray.pop() else { break };
+                self.memory.mark(ptr);
+                remaining -= 1;
+            }
+            if gray.is_empty() {
+                self.cycle = Cycle::Sweeping { cursor: SweepCursor::default() };
+            }
+        }
+
+        // Sweep phase: free all unmarked objects
+        if let Cycle::Sweeping { cursor } = &mut self.cycle {
+            let (freed, done) = self.memory.sweep_step(cursor, remaining);
+            self.cycle_objects_freed += freed;
+            if done {
+                self.finish_cycle();
+            }
+        }
 
         self.collecting = false;
-        objects_freed
+        #[cfg(feature = "std")]
+        self.stats.record_pause(started.elapsed());
     }
 
-    /// Mark phase: mark all reachable objects starting from roots
-    fn mark_phase(&mut self) {
-        // Clear all marks
-        self.memory.unmark_all();
+    fn finish_cycle(&mut self) {
+        self.memory.finish_sweep();
+        self.memory.set_allocate_marked(false);
+        self.cycle = Cycle::Idle;
 
-        // Mark from roots
-        for &root in &self.roots {
-            self.memory.mark(root);
-        }
+        let bytes_after = self.memory.current_usage();
+        debug_event!(
+            objects_freed = self.cycle_objects_freed,
+            bytes_freed = self.cycle_bytes_before.saturating_sub(bytes_after),
+            bytes_live = bytes_after,
+            "collection finished"
+        );
+        self.stats.collections += 1;
+        self.stats.total_objects_freed += self.cycle_objects_freed;
+        self.stats.total_bytes_freed += self.cycle_bytes_before.saturating_sub(bytes_after);
     }
 
-    /// Sweep phase: free all unmarked objects
-    fn sweep_phase(&mut self) -> usize {
-        self.memory.sweep()
+    /// Free the whole heap at once, abandoning any cycle in progress
+    ///
+    /// For when nothing on the heap is needed any more, e.g. between
+    /// independent evaluations; see `MemoryManager::reset`.
+    pub fn reset(&mut self) {
+        self.memory.reset();
+        self.roots.clear();
+        self.cycle = Cycle::Idle;
+        self.cycle_objects_freed = 0;
     }
 
     /// Force a full garbage collection
     pub fn force_collect(&mut self) -> usize {
         let was_collecting = self.collecting;
         self.collecting = false;
+        if !matches!(self.cycle, Cycle::Idle) {
+            // Finish the interrupted cycle before starting a fresh one
+            self.collect();
+        }
         let result = self.collect();
         self.collecting = was_collecting;
         result
@@ -154,10 +429,40 @@ impl GarbageCollector {
         self.memory.stats()
     }
 
+    /// Record allocation sites so live objects can be reported
+    pub fn enable_leak_detection(&mut self) {
+        self.memory.enable_leak_detection();
+    }
+
+    /// Count allocations by site (see `MemoryManager::enable_site_tracking`)
+    pub fn enable_site_tracking(&mut self) {
+        self.memory.enable_site_tracking();
+    }
+
+    /// Tag allocations from now on as made by `site`
+    pub fn set_allocation_site(&mut self, site: &'static str) {
+        self.memory.set_site(site);
+    }
+
+    /// Hold the last `capacity` freed slots back from reuse (see `MemoryManager::enable_quarantine`)
+    pub fn enable_quarantine(&mut self, capacity: usize) {
+        self.memory.enable_quarantine(capacity);
+    }
+
+    /// Live allocations grouped by call site (`None` unless leak detection is on)
+    pub fn leak_report(&self) -> Option<String> {
+        self.memory.leak_report()
+    }
+
     /// Get current memory usage
     pub fn current_usage(&self) -> usize {
         self.memory.current_usage()
     }
+
+    /// Every object on the heap, including unswept garbage
+    pub fn objects(&self) -> impl Iterator<Item = ObjectInfo> + '_ {
+        self.memory.objects()
+    }
 }
 
 impl Default for GarbageCollector {
@@ -167,36 +472,88 @@ impl Default for GarbageCollector {
 }
 
 /// A GC-managed value wrapper
+///
+/// A `GcValue` is a handle: copies and clones alias the same heap object.
+/// Use `deep_clone` for an independent copy. In debug builds every access
+/// asserts that the object has not been swept since the handle was made.
 #[derive(Debug)]
 pub struct GcValue<T> {
     ptr: NonNull<T>,
+    /// Slot generation at allocation, to catch use after sweep
+    #[cfg(debug_assertions)]
+    generation: u32,
 }
 
 impl<T> GcValue<T> {
     /// Create a new GC-managed value
+    #[track_caller]
     pub fn new(gc: &mut GarbageCollector, value: T) -> Option<Self> {
-        let size = std::mem::size_of::<T>();
-        let ptr = gc.allocate(size)?;
+        let size = core::mem::size_of::<T>();
+        let ptr = gc.allocate_aligned(size, core::mem::align_of::<T>())?;
 
         unsafe {
             let typed_ptr = ptr.as_ptr() as *mut T;
-            std::ptr::write(typed_ptr, value);
+            core::ptr::write(typed_ptr, value);
             Some(GcValue {
                 ptr: NonNull::new_unchecked(typed_ptr),
+                #[cfg(debug_assertions)]
+                generation: MemoryManager::generation(ptr).unwrap_or_default(),
             })
         }
     }
 
+    /// Allocate an independent copy of the value
+    ///
+    /// Like `new`, the copy is never dropped; finalizers are not carried over.
+    #[track_caller]
+    pub fn deep_clone(&self, gc: &mut GarbageCollector) -> Option<Self>
+    where
+        T: Clone,
+    {
+        Self::new(gc, self.get().clone())
+    }
+
+    /// Create a GC-managed value whose destructor runs when it is swept
+    ///
+    /// `new` never drops the value; use this for types owning resources.
+    #[track_caller]
+    pub fn with_drop(gc: &mut GarbageCollector, value: T) -> Option<Self>
+    where
+        T: 'static,
+    {
+        let value = Self::new(gc, value)?;
+        gc.set_finalizer(value.as_ptr(), |ptr| unsafe {
+            core::ptr::drop_in_place(ptr.as_ptr() as *mut T);
+        });
+        Some(value)
+    }
+
     /// Get a reference to the value
     pub fn get(&self) -> &T {
+        self.debug_assert_live();
         unsafe { self.ptr.as_ref() }
     }
 
     /// Get a mutable reference to the value
     pub fn get_mut(&mut self) -> &mut T {
+        self.debug_assert_live();
         unsafe { self.ptr.as_mut() }
     }
 
+    /// Panic in debug builds if the object was swept after this handle was made
+    #[inline]
+    fn debug_assert_live(&self) {
+        #[cfg(debug_assertions)]
+        if MemoryManager::fits_in_block(core::mem::size_of::<T>(), core::mem::align_of::<T>()) {
+            // SAFETY: arena slots stay mapped until the collector is dropped
+            let generation = unsafe { MemoryManager::generation(self.ptr.cast()) };
+            assert!(
+                generation == Some(self.generation),
+                "GcValue used after its object was swept"
+            );
+        }
+    }
+
     /// Get the raw pointer for rooting
     pub fn as_ptr(&self) -> NonNull<u8> {
         self.ptr.cast()
@@ -204,15 +561,130 @@ impl<T> GcValue<T> {
 }
 
 impl<T: Clone> Clone for GcValue<T> {
+    /// Copy the handle; both handles refer to the same object (see `deep_clone`)
     fn clone(&self) -> Self {
-        // Note: This creates a shallow clone of the pointer, not the value
-        // For deep clones, use GcValue::new with the cloned value
-        GcValue { ptr: self.ptr }
+        GcValue {
+            ptr: self.ptr,
+            #[cfg(debug_assertions)]
+            generation: self.generation,
+        }
     }
 }
 
 impl<T: Copy> Copy for GcValue<T> {}
 
+impl<T: Traceable> Traceable for GcValue<T> {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        if gc.mark_new(self.as_ptr()) {
+            self.get().trace(gc);
+        }
+    }
+}
+
+/// A GC-managed array of numbers
+///
+/// Stored as a length followed by the elements in one heap object. The
+/// handle is only valid while the object is reachable from the roots of
+/// the collector that allocated it.
+#[derive(Clone, Copy)]
+pub struct GcArray {
+    ptr: NonNull<u8>,
+}
+
+impl GcArray {
+    /// Allocate an array holding a copy of `values` (does not trigger collection)
+    #[track_caller]
+    pub fn new(gc: &mut GarbageCollector, values: &[f64]) -> Option<Self> {
+        let size = Self::size_for(values.len());
+        let ptr = gc.allocate_uncollected(size)?;
+
+        unsafe {
+            (ptr.as_ptr() as *mut usize).write(values.len());
+            let elements = ptr.as_ptr().add(core::mem::size_of::<usize>()) as *mut f64;
+            core::ptr::copy_nonoverlapping(values.as_ptr(), elements, values.len());
+        }
+        Some(GcArray { ptr })
+    }
+
+    /// Heap bytes needed for an array of `len` elements
+    pub fn size_for(len: usize) -> usize {
+        core::mem::size_of::<usize>() + len * core::mem::size_of::<f64>()
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { *(self.ptr.as_ptr() as *const usize) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The elements of the array
+    pub fn as_slice(&self) -> &[f64] {
+        unsafe {
+            let elements = self.ptr.as_ptr().add(core::mem::size_of::<usize>()) as *const f64;
+            core::slice::from_raw_parts(elements, self.len())
+        }
+    }
+
+    /// Get the raw pointer for rooting
+    pub fn as_ptr(&self) -> NonNull<u8> {
+        self.ptr
+    }
+}
+
+impl core::fmt::Debug for GcArray {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+impl Traceable for GcArray {
+    /// Numbers hold no references, so only the array itself is marked
+    fn trace(&self, gc: &mut GarbageCollector) {
+        gc.mark(self.ptr);
+    }
+}
+
+impl<T: Traceable> Traceable for [T] {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        for item in self {
+            item.trace(gc);
+        }
+    }
+}
+
+impl<T: Traceable> Traceable for Vec<T> {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        self.as_slice().trace(gc);
+    }
+}
+
+impl<T: Traceable> Traceable for Option<T> {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        if let Some(value) = self {
+            value.trace(gc);
+        }
+    }
+}
+
+impl<T: Traceable + ?Sized> Traceable for Box<T> {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        (**self).trace(gc);
+    }
+}
+
+/// Types that can never reference the heap
+macro_rules! impl_traceable_leaf {
+    ($($ty:ty),*) => {
+        $(impl Traceable for $ty {
+            fn trace(&self, _gc: &mut GarbageCollector) {}
+        })*
+    };
+}
+
+impl_traceable_leaf!(f64, f32, i64, i32, u64, u32, usize, isize, bool, char, String);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -246,4 +718,183 @@ mod tests {
 
         assert_eq!(*value.get(), 42.0);
     }
+
+    #[test]
+    fn test_gc_value_with_drop() {
+        use alloc::rc::Rc;
+
+        let resource = Rc::new(());
+        let mut gc = GarbageCollector::new();
+        let kept = GcValue::with_drop(&mut gc, Rc::clone(&resource)).expect("Allocation failed");
+        GcValue::with_drop(&mut gc, Rc::clone(&resource)).expect("Allocation failed");
+        gc.add_root(kept.as_ptr());
+        assert_eq!(Rc::strong_count(&resource), 3);
+
+        gc.force_collect();
+        assert_eq!(Rc::strong_count(&resource), 2);
+
+        drop(gc);
+        assert_eq!(Rc::strong_count(&resource), 1);
+    }
+
+    #[test]
+    fn test_gc_array() {
+        let mut gc = GarbageCollector::new();
+        let array = GcArray::new(&mut gc, &[1.0, 2.0, 3.0]).expect("Allocation failed");
+        assert_eq!(array.len(), 3);
+        assert_eq!(array.as_slice(), &[1.0, 2.0, 3.0]);
+        assert!(GcArray::new(&mut gc, &[]).expect("Allocation failed").is_empty());
+    }
+
+    #[test]
+    fn test_deep_clone() {
+        let mut gc = GarbageCollector::new();
+        let original = GcValue::new(&mut gc, vec![1.0, 2.0]).expect("Allocation failed");
+        let mut alias = original.clone();
+        let mut copy = original.deep_clone(&mut gc).expect("Allocation failed");
+
+        alias.get_mut().push(3.0);
+        copy.get_mut().clear();
+        assert_eq!(original.get(), &[1.0, 2.0, 3.0]);
+        assert_ne!(copy.as_ptr(), original.as_ptr());
+    }
+
+    #[test]
+    fn test_impl_traceable() {
+        struct Node {
+            values: GcArray,
+            next: Option<GcValue<Node>>,
+            label: String,
+        }
+        impl_traceable!(Node { values, next });
+
+        let mut gc = GarbageCollector::new();
+        let values = GcArray::new(&mut gc, &[1.0]).expect("Allocation failed");
+        let tail = Node { values, next: None, label: "tail".into() };
+        let tail = GcValue::new(&mut gc, tail).expect("Allocation failed");
+        let values = GcArray::new(&mut gc, &[2.0]).expect("Allocation failed");
+        let head = Node { values, next: Some(tail), label: "head".into() };
+        GcArray::new(&mut gc, &[3.0]).expect("Allocation failed");
+
+        // Only the unreferenced array is garbage; the chain is reached through `next`
+        assert_eq!(gc.collect_with(&head), 1);
+        let tail = head.next.as_ref().unwrap().get();
+        assert_eq!((tail.label.as_str(), tail.values.as_slice()), ("tail", &[1.0][..]));
+        assert_eq!(head.label, "head");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "used after its object was swept")]
+    fn test_use_after_sweep_asserts() {
+        let mut gc = GarbageCollector::new();
+        let value = GcValue::new(&mut gc, 42.0).expect("Allocation failed");
+        gc.force_collect();
+        value.get();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "used after its object was swept")]
+    fn test_use_after_reset_asserts() {
+        let mut gc = GarbageCollector::new();
+        let value = GcValue::new(&mut gc, 42.0).expect("Allocation failed");
+        gc.reset();
+        // Carved from the same spot, but a new generation
+        let other = GcValue::new(&mut gc, 7.0).expect("Allocation failed");
+        assert_eq!(other.as_ptr(), value.as_ptr());
+        value.get();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_quarantine_keeps_freed_slots_poisoned() {
+        let mut gc = GarbageCollector::new();
+        gc.enable_quarantine(16);
+        let value = GcValue::new(&mut gc, 42.0).expect("Allocation failed");
+        gc.force_collect();
+        for _ in 0..8 {
+            assert_ne!(GcValue::new(&mut gc, 1.0).expect("Allocation failed").as_ptr(), value.as_ptr());
+        }
+        assert_eq!(unsafe { *value.as_ptr().as_ptr() }, crate::memory::POISON);
+    }
+
+    #[test]
+    fn test_collect_with_traced_roots() {
+        struct Holder(GcArray);
+        impl Traceable for Holder {
+            fn trace(&self, gc: &mut GarbageCollector) {
+                gc.mark(self.0.as_ptr());
+                self.0.trace(gc);
+            }
+        }
+
+        let mut gc = GarbageCollector::new();
+        let kept = Holder(GcArray::new(&mut gc, &[1.0]).expect("Allocation failed"));
+        GcArray::new(&mut gc, &[2.0, 3.0]).expect("Allocation failed");
+
+        assert_eq!(gc.collect_with(core::slice::from_ref(&kept)), 1);
+        assert_eq!(kept.0.as_slice(), &[1.0]);
+    }
+
+    #[test]
+    fn test_incremental_step() {
+        let mut gc = GarbageCollector::with_threshold(1);
+        // Allocate through the memory manager so nothing is collected yet
+        let root = gc.memory.allocate(64).expect("Allocation failed");
+        gc.add_root(root);
+        for _ in 0..9 {
+            gc.memory.allocate(64).expect("Allocation failed");
+        }
+
+        // One root to mark, then ten slots to sweep, three per step
+        assert_eq!(gc.step(1), GcPhase::Sweeping);
+        let mut steps = 1;
+        while gc.step(3) != GcPhase::Idle {
+            steps += 1;
+        }
+        assert_eq!(steps, 4);
+        assert_eq!(gc.stats().collections, 1);
+        assert_eq!(gc.stats().total_objects_freed, 9);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_pause_times() {
+        let mut gc = GarbageCollector::with_threshold(1);
+        assert_eq!(gc.stats().average_pause(), None);
+        for _ in 0..3 {
+            gc.memory.allocate(64).expect("Allocation failed");
+        }
+        // One pause per increment, then one for the whole of each full collection
+        while gc.step(1) != GcPhase::Idle {}
+        let increments = gc.stats().increments;
+        for _ in 0..RECENT_PAUSES {
+            gc.force_collect();
+        }
+
+        let stats = gc.stats();
+        assert_eq!(stats.pauses, increments + RECENT_PAUSES);
+        assert_eq!(stats.recent_pauses.len(), RECENT_PAUSES);
+        let average = stats.average_pause().unwrap();
+        assert!(stats.min_pause <= average && average <= stats.max_pause);
+        assert!(stats.recent_pauses.iter().all(|&pause| pause <= stats.max_pause));
+    }
+
+    #[test]
+    fn test_allocation_during_cycle_survives() {
+        let mut gc = GarbageCollector::with_threshold(1);
+        for _ in 0..3 {
+            gc.memory.allocate(64).expect("Allocation failed");
+        }
+        assert_eq!(gc.step(1), GcPhase::Sweeping);
+
+        // Allocated ahead of the sweep cursor, so it must survive this cycle
+        gc.memory.allocate(64).expect("Allocation failed");
+        while gc.step(1) != GcPhase::Idle {}
+        assert_eq!(gc.stats().total_objects_freed, 3);
+
+        // Unrooted, so the next full cycle frees it
+        assert_eq!(gc.force_collect(), 1);
+    }
 }
@@ -1,86 +1,734 @@
 //! Calculator GUI Application
 //!
 //! egui-based graphical interface showing:
-//!   - Input expression
+//!   - Input expression with autocompletion, or a multi-line script editor with syntax highlighting
+//!     (`.calc` files dropped onto the window open in it)
 //!   - Tokenized output
 //!   - AST visualization
-//!   - Bytecode disassembly
+//!   - Bytecode disassembly, with a hex viewer linked to the decoded instructions
 //!   - VM execution result
 //!   - Memory/GC statistics
-//!   - Time-travel debugging with stack visualization
+//!   - Live debugger stepping a real VM, with breakpoints, stack and globals
+//!   - Stack vs register backend comparison
+//!   - Variables, user functions and the memory register, kept between evaluations
+//!
+//! The token, AST and bytecode views follow the input while typing; running
+//! it still waits for Enter. Detail sections can be reordered, hidden and
+//! resized, and the arrangement is saved with the session.
+//!
+//! History, settings and definitions are saved through eframe's storage
+//! (plus a JSON file on native targets) and restored on the next launch.
+//! The history can be searched, pinned, reloaded into the input with a click,
+//! and exported as CSV, JSON or Markdown. Ticking two entries opens a window
+//! diffing their tokens, AST and bytecode.
+//!
+//! Everything can be driven from the keyboard; see `SHORTCUTS`. Colors and
+//! fonts follow the theme chosen in the settings dialog, and all text comes
+//! from the translation bundle of the chosen language (see `i18n`).
 
 use eframe::egui;
 use crate::ast::Expr;
-use crate::bytecode::Chunk;
-use crate::codegen::CodeGenerator;
-use crate::disassembler::Disassembler;
+use crate::builtins::{self, BuiltinKind};
+use crate::bytecode::{Chunk, Function};
+use crate::codegen::{CodeGenerator, CompileError, Diagnostic};
+use crate::disassembler::{DisassembledInstruction, Disassembler};
+use crate::export::{ExportFormat, Exporter, HistoryEntry};
+use crate::format::{Locale, Notation, NumberFormat};
 use crate::gc::GcStats;
+use crate::i18n::Language;
 use crate::memory::MemoryStats;
+use crate::optimizer::Optimizer;
 use crate::parser::{ParseError, Parser};
-use crate::tokenizer::{Token, Tokenizer, TokenizerError};
-use crate::vm::{ExecutionStep, VirtualMachine, VmError};
+use crate::register::RegisterChunk;
+use crate::share::SharedLink;
+use crate::symbolic;
+use crate::theme::{MonospaceFont, Palette, ThemePreset, ThemeSettings};
+use crate::tokenizer::{ENotation, Edit, Token, Tokenizer, TokenizerError};
+use crate::vm::{ExecutionStep, StackValue, Value, VirtualMachine, VmError};
+use crate::PhaseTimings;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::sync::Arc;
+use web_time::{Duration, Instant};
+
+/// Wall-clock budget for a single evaluation so a runaway program can't freeze the UI thread
+const EXECUTION_TIMEOUT: Duration = Duration::from_secs(2);
+/// Heap cap for evaluations, so a huge array fails cleanly instead of aborting (notably on WASM)
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Quiet time after an edit before the pipeline views are recompiled
+const PREVIEW_DELAY: Duration = Duration::from_millis(300);
+
+/// Smallest height a detail section can be dragged to
+const MIN_SECTION_HEIGHT: f32 = 40.0;
+
+/// Instructions a running debugger executes per frame
+const DEBUGGER_STEPS_PER_FRAME: usize = 64;
+
+/// Keyboard shortcuts and the message naming their action, as listed in the shortcuts menu
+const SHORTCUTS: &[(&str, &str)] = &[
+    ("Enter", "shortcut-evaluate"),
+    ("Ctrl+Enter", "shortcut-evaluate-script"),
+    ("Esc", "shortcut-clear"),
+    ("↑ / ↓", "shortcut-recall"),
+    ("Tab", "shortcut-complete"),
+    ("Ctrl+D", "shortcut-details"),
+];
+
+/// Most entries shown in the autocompletion popup
+const MAX_COMPLETIONS: usize = 8;
+
+/// egui id of the expression input, shared by the single-line field and the script editor
+const INPUT_ID: &str = "expression_input";
+
+/// Marks where a custom button's snippet puts the selection and the cursor
+const SNIPPET_CURSOR: &str = "$0";
+
+/// Extension of script files that can be dropped onto the window
+const SCRIPT_EXTENSION: &str = "calc";
+
+/// Application name, also the id of eframe's native storage directory
+pub const APP_NAME: &str = "Bytecode Calculator";
+/// Session file written next to eframe's own storage on native targets
+#[cfg(not(target_arch = "wasm32"))]
+const SESSION_FILE: &str = "session.json";
 
 /// Compilation pipeline result
 #[allow(dead_code)]
+#[derive(Default)]
 struct CompilationResult {
     input: String,
     tokens: Option<Result<Vec<Token>, TokenizerError>>,
+    /// Chars of the input each token was read from
+    token_spans: Vec<Range<usize>>,
     ast: Option<Result<Expr, ParseError>>,
+    /// The parsed input written out again, when `expand` or `factor` rewrote it
+    rewritten: Option<String>,
     chunk: Option<Chunk>,
+    /// Compiler warnings about operations bound to fail
+    diagnostics: Vec<Diagnostic>,
+    /// Why a parsed input couldn't be compiled
+    compile_error: Option<CompileError>,
     disassembly: String,
-    result: Option<Result<f64, VmError>>,
+    /// Decoded instructions of `chunk`, for the hex viewer
+    instructions: Vec<DisassembledInstruction>,
+    result: Option<Result<Value, VmError>>,
     execution_trace: Vec<ExecutionStep>,
+    /// Time spent tokenizing, parsing, compiling and running the input
+    timings: PhaseTimings,
     /// Memory statistics captured from VM after execution
     memory_stats: Option<MemoryStats>,
     /// GC statistics captured from VM after execution
     gc_stats: Option<GcStats>,
+    /// Same expression run on the stack and register backends
+    backends: Option<BackendComparison>,
+    /// Chunk before and after the optimizer passes
+    optimization: Option<OptimizerComparison>,
+    /// VM paused at the start of the chunk, for the live debugger
+    debugger: Option<Debugger>,
+}
+
+/// Live debugger: a VM loaded with the current chunk, driven one step at a time
+struct Debugger {
+    vm: VirtualMachine,
+    chunk: Chunk,
+    /// Byte offsets of instructions to pause before
+    breakpoints: BTreeSet<usize>,
+    /// Whether `advance` keeps executing on the following frames
+    running: bool,
+    /// Run was just resumed, so don't stop at the breakpoint we're paused on
+    resumed: bool,
+    /// Error raised by the last step; the VM stays before the failing instruction
+    error: Option<VmError>,
+}
+
+impl Debugger {
+    /// Load `chunk` into a VM that sees the definitions in `session`
+    fn new(chunk: &Chunk, session: &Session) -> Self {
+        let mut vm = VirtualMachine::with_memory_limit(MEMORY_LIMIT);
+        let error = session.load_into(&mut vm).err();
+        vm.load(chunk);
+        Debugger {
+            vm,
+            chunk: chunk.clone(),
+            breakpoints: BTreeSet::new(),
+            running: false,
+            resumed: false,
+            error,
+        }
+    }
+
+    /// Whether the program has run to completion
+    fn finished(&self) -> bool {
+        self.vm.is_halted() || self.vm.ip() >= self.chunk.len()
+    }
+
+    /// Go back to the first instruction, keeping breakpoints
+    ///
+    /// Definitions made by the program so far are undone with the rest of its state.
+    fn restart(&mut self) {
+        self.running = false;
+        self.error = self.vm.seek(0).err();
+    }
+
+    fn step(&mut self) {
+        self.running = false;
+        self.error = self.vm.step().err();
+    }
+
+    fn step_back(&mut self) {
+        self.running = false;
+        self.error = self.vm.step_back().err();
+    }
+
+    /// Jump to the state after `step` instructions
+    fn seek(&mut self, step: usize) {
+        self.running = false;
+        self.error = self.vm.seek(step).err();
+    }
+
+    fn toggle_breakpoint(&mut self, offset: usize) {
+        if !self.breakpoints.remove(&offset) {
+            self.breakpoints.insert(offset);
+        }
+    }
+
+    fn run(&mut self) {
+        self.running = !self.finished();
+        self.resumed = true;
+        self.error = None;
+    }
+
+    fn pause(&mut self) {
+        self.running = false;
+    }
+
+    /// Execute up to `budget` instructions of a running program
+    ///
+    /// Stops before a breakpoint, at the end of the program, or on error.
+    fn advance(&mut self, budget: usize) {
+        for _ in 0..budget {
+            if !self.running {
+                return;
+            }
+            let resumed = std::mem::take(&mut self.resumed);
+            if !resumed && self.breakpoints.contains(&self.vm.ip()) {
+                self.running = false;
+                return;
+            }
+            match self.vm.step() {
+                Ok(true) => {}
+                Ok(false) => self.running = false,
+                Err(e) => {
+                    self.error = Some(e);
+                    self.running = false;
+                }
+            }
+        }
+    }
+}
+
+/// A collapsible section of the details panel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+enum DetailSection {
+    Tokens,
+    Ast,
+    Bytecode,
+    HexViewer,
+    Optimizer,
+    RegisterMachine,
+    Trace,
+    Debugger,
+    Memory,
+}
+
+impl DetailSection {
+    const ALL: [DetailSection; 9] = [
+        DetailSection::Tokens,
+        DetailSection::Ast,
+        DetailSection::Bytecode,
+        DetailSection::HexViewer,
+        DetailSection::Optimizer,
+        DetailSection::RegisterMachine,
+        DetailSection::Trace,
+        DetailSection::Debugger,
+        DetailSection::Memory,
+    ];
+
+    /// Message key of the section title
+    fn key(self) -> &'static str {
+        match self {
+            DetailSection::Tokens => "section-tokens",
+            DetailSection::Ast => "section-ast",
+            DetailSection::Bytecode => "section-bytecode",
+            DetailSection::HexViewer => "section-hex-viewer",
+            DetailSection::Optimizer => "section-optimizer",
+            DetailSection::RegisterMachine => "section-register-machine",
+            DetailSection::Trace => "section-trace",
+            DetailSection::Debugger => "section-debugger",
+            DetailSection::Memory => "section-memory",
+        }
+    }
+}
+
+/// A user-defined calculator button
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CustomButton {
+    label: String,
+    /// Text inserted at the cursor, e.g. "sqrt($0)" to wrap the selection
+    snippet: String,
+}
+
+/// Order, visibility and heights of the detail sections
+///
+/// Trace and Debugger visibility stay with `show_trace` and `debugger_active`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct PanelLayout {
+    order: Vec<DetailSection>,
+    hidden: BTreeSet<DetailSection>,
+    /// Heights set by dragging; sections without one fit their content
+    heights: BTreeMap<DetailSection, f32>,
 }
 
-impl Default for CompilationResult {
+impl Default for PanelLayout {
     fn default() -> Self {
-        Self {
-            input: String::new(),
-            tokens: None,
-            ast: None,
-            chunk: None,
-            disassembly: String::new(),
-            result: None,
-            execution_trace: Vec::new(),
-            memory_stats: None,
-            gc_stats: None,
+        PanelLayout {
+            order: DetailSection::ALL.to_vec(),
+            hidden: BTreeSet::new(),
+            heights: BTreeMap::new(),
+        }
+    }
+}
+
+impl PanelLayout {
+    /// Swap `section` with its neighbour `offset` places away, if there is one
+    fn move_section(&mut self, section: DetailSection, offset: isize) {
+        let Some(index) = self.order.iter().position(|&s| s == section) else {
+            return;
+        };
+        if let Some(other) = index.checked_add_signed(offset).filter(|&other| other < self.order.len()) {
+            self.order.swap(index, other);
+        }
+    }
+
+    /// Make sure every section appears exactly once, e.g. after loading an older layout
+    fn repair(&mut self) {
+        let mut seen = BTreeSet::new();
+        self.order.retain(|&section| seen.insert(section));
+        for section in DetailSection::ALL {
+            if seen.insert(section) {
+                self.order.push(section);
+            }
+        }
+    }
+}
+
+/// Definitions carried from one evaluation to the next
+#[derive(Default)]
+struct Session {
+    variables: BTreeMap<String, Value>,
+    functions: BTreeMap<String, Arc<Function>>,
+    /// Memory register driven by the MC/MR/M+/M− buttons
+    memory: f64,
+    /// How `e` after a number is read, chosen in the settings dialog
+    e_notation: ENotation,
+}
+
+/// A named entry of the session, as listed in the definitions panel
+#[derive(Debug, Clone, PartialEq)]
+enum Binding {
+    Variable(String),
+    Function(String),
+}
+
+impl Session {
+    /// Bind the session's variables and functions in `vm`
+    fn load_into(&self, vm: &mut VirtualMachine) -> Result<(), VmError> {
+        for (name, value) in &self.variables {
+            match value {
+                // Rebuilt from its source; whatever it captured is looked up as globals instead
+                Value::Function(source) => {
+                    let source = format!("{} = {}", name, source);
+                    let ast = Tokenizer::new(&source)
+                        .tokenize()
+                        .map_err(|e| VmError::InvalidOperation(e.to_string()))
+                        .and_then(|tokens| Parser::new(tokens).parse().map_err(|e| VmError::InvalidOperation(e.to_string())))?;
+                    let (chunk, _) =
+                        CodeGenerator::new().compile(&ast).map_err(|e| VmError::InvalidOperation(e.to_string()))?;
+                    vm.execute(&chunk)?;
+                }
+                _ => vm.set_global(name, value.clone())?,
+            }
+        }
+        for function in self.functions.values() {
+            vm.define_function(function.clone());
+        }
+        Ok(())
+    }
+
+    /// Compile and bind a function from its source, e.g. "f(x) = x ^ 2"
+    fn define(&mut self, source: &str) -> Result<(), String> {
+        let tokens = Tokenizer::new(source).tokenize().map_err(|e| e.to_string())?;
+        let ast = Parser::new(tokens).parse().map_err(|e| e.to_string())?;
+        if !matches!(ast, Expr::FunctionDef { .. }) {
+            return Err(format!("Not a function definition: {}", source));
+        }
+        let mut vm = VirtualMachine::new();
+        let (chunk, _) = CodeGenerator::new().compile(&ast).map_err(|e| e.to_string())?;
+        vm.execute(&chunk).map_err(|e| e.to_string())?;
+        self.functions.extend(vm.functions().map(|f| (f.name.clone(), f.clone())));
+        Ok(())
+    }
+
+    /// Take over everything bound in `vm`
+    fn store_from(&mut self, vm: &VirtualMachine) {
+        self.variables = vm.globals().map(|(name, value)| (name.to_string(), value.to_value())).collect();
+        self.functions = vm.functions().map(|f| (f.name.clone(), f.clone())).collect();
+    }
+
+    fn remove(&mut self, binding: &Binding) {
+        match binding {
+            Binding::Variable(name) => {
+                self.variables.remove(name);
+            }
+            Binding::Function(name) => {
+                self.functions.remove(name);
+            }
+        }
+    }
+
+    /// Rebind under `new_name`; fails if the name is not a free identifier
+    ///
+    /// Renaming a function doesn't rewrite calls to the old name, including
+    /// recursive calls in its own body.
+    fn rename(&mut self, binding: &Binding, new_name: &str) -> Result<(), String> {
+        if !is_identifier(new_name) {
+            return Err(format!("'{}' is not a valid name", new_name));
+        }
+        match binding {
+            Binding::Variable(name) => {
+                if name != new_name && self.variables.contains_key(new_name) {
+                    return Err(format!("{} is already defined", new_name));
+                }
+                if let Some(value) = self.variables.remove(name) {
+                    self.variables.insert(new_name.to_string(), value);
+                }
+            }
+            Binding::Function(name) => {
+                if name != new_name && self.functions.contains_key(new_name) {
+                    return Err(format!("{} is already defined", new_name));
+                }
+                if let Some(function) = self.functions.remove(name) {
+                    let function = Function {
+                        name: new_name.to_string(),
+                        ..(*function).clone()
+                    };
+                    self.functions.insert(new_name.to_string(), Arc::new(function));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything restored on the next launch
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedState {
+    history: Vec<HistoryEntry>,
+    show_details: bool,
+    show_trace: bool,
+    script_editor: bool,
+    debugger_active: bool,
+    variables: BTreeMap<String, Value>,
+    /// Function definitions as source, e.g. "f(x) = (x ^ 2)"
+    functions: Vec<String>,
+    memory: f64,
+    theme: ThemeSettings,
+    number_format: NumberFormat,
+    layout: PanelLayout,
+    language: Language,
+    custom_buttons: Vec<CustomButton>,
+    e_notation: ENotation,
+}
+
+impl SavedState {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn path() -> Option<std::path::PathBuf> {
+        eframe::storage_dir(APP_NAME).map(|dir| dir.join(SESSION_FILE))
+    }
+
+    /// Read the native session file, if there is a valid one
+    #[cfg(not(target_arch = "wasm32"))]
+    fn read_file() -> Option<Self> {
+        let json = std::fs::read_to_string(Self::path()?).ok()?;
+        serde_json::from_str(&json)
+            .map_err(|e| log::warn!("Ignoring unreadable {}: {}", SESSION_FILE, e))
+            .ok()
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn read_file() -> Option<Self> {
+        None
+    }
+
+    /// Write the native session file
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write_file(&self) {
+        let Some(path) = Self::path() else { return };
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| {
+                let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+                std::fs::write(&path, json)
+            });
+        if let Err(e) = written {
+            log::warn!("Failed to save session to {}: {}", path.display(), e);
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn write_file(&self) {}
+}
+
+/// Whether `name` can be used as a variable or function name
+fn is_identifier(name: &str) -> bool {
+    matches!(Tokenizer::new(name).tokenize().as_deref(), Ok([Token::Identifier(word)]) if word == name)
+}
+
+/// An entry of the autocompletion popup
+#[derive(Debug, Clone, PartialEq)]
+struct Completion {
+    /// Replaces the identifier being typed
+    insert: String,
+    /// e.g. "nCr(n, k)"
+    signature: String,
+    description: String,
+}
+
+/// Side-by-side run of the stack and register machines
+struct BackendComparison {
+    registers: RegisterChunk,
+    listing: String,
+    stack_steps: usize,
+    stack_time: Duration,
+    register_steps: usize,
+    register_time: Duration,
+    register_result: Result<f64, VmError>,
+}
+
+impl BackendComparison {
+    fn run(registers: RegisterChunk, chunk: &Chunk, session: &Session) -> Self {
+        let mut vm = VirtualMachine::with_memory_limit(MEMORY_LIMIT);
+
+        // Untraced runs so the timings reflect the backends, not the tracer
+        let _ = session.load_into(&mut vm);
+        let start = Instant::now();
+        let _ = vm.execute_with_timeout(chunk, EXECUTION_TIMEOUT);
+        let stack_time = start.elapsed();
+        let stack_steps = vm.step_count();
+
+        // Start the register run from the same definitions
+        vm.clear_definitions();
+        let _ = session.load_into(&mut vm);
+        let start = Instant::now();
+        let register_result = vm.execute_registers(&registers);
+        let register_time = start.elapsed();
+
+        BackendComparison {
+            listing: registers.to_string(),
+            registers,
+            stack_steps,
+            stack_time,
+            register_steps: vm.step_count(),
+            register_time,
+            register_result,
+        }
+    }
+}
+
+/// One line of a line diff
+#[derive(Debug, Clone, PartialEq)]
+enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Tokens, AST outline and bytecode of two history entries, diffed
+struct HistoryComparison {
+    expressions: [String; 2],
+    results: [String; 2],
+    tokens: Vec<DiffLine>,
+    ast: Vec<DiffLine>,
+    /// Instructions without their offsets, so an insertion doesn't mark everything after it
+    bytecode: Vec<DiffLine>,
+}
+
+impl HistoryComparison {
+    fn new(left: &HistoryEntry, right: &HistoryEntry, e_notation: ENotation) -> Self {
+        let [left_views, right_views] = [left, right].map(|entry| {
+            let compilation = CompilationResult::preview(&entry.expression, e_notation);
+            let tokens = match &compilation.tokens {
+                Some(Ok(tokens)) => tokens.iter().map(|token| token.to_string()).collect(),
+                Some(Err(e)) => vec![e.to_string()],
+                None => Vec::new(),
+            };
+            let ast = match &compilation.ast {
+                Some(Ok(ast)) => ast_outline(ast),
+                Some(Err(e)) => vec![e.to_string()],
+                None => Vec::new(),
+            };
+            let bytecode = compilation
+                .instructions
+                .iter()
+                .map(|instruction| {
+                    let text = &instruction.text;
+                    text.split_once(": ").map_or(text.as_str(), |(_, rest)| rest).to_string()
+                })
+                .collect();
+            [tokens, ast, bytecode]
+        });
+        let [left_tokens, left_ast, left_bytecode] = left_views;
+        let [right_tokens, right_ast, right_bytecode] = right_views;
+        HistoryComparison {
+            expressions: [left.expression.clone(), right.expression.clone()],
+            results: [left.result.clone(), right.result.clone()],
+            tokens: diff_lines(&left_tokens, &right_tokens),
+            ast: diff_lines(&left_ast, &right_ast),
+            bytecode: diff_lines(&left_bytecode, &right_bytecode),
+        }
+    }
+}
+
+/// The chunk as generated and as rewritten by the optimizer, both executed
+struct OptimizerComparison {
+    original_listing: String,
+    optimized_listing: String,
+    original_instructions: usize,
+    optimized_instructions: usize,
+    original_bytes: usize,
+    optimized_bytes: usize,
+    original_steps: usize,
+    optimized_steps: usize,
+    /// Result of the optimized chunk, which should match the original's
+    optimized_result: Result<Value, VmError>,
+}
+
+impl OptimizerComparison {
+    fn run(chunk: &Chunk, session: &Session) -> Self {
+        let optimized = Optimizer::optimize(chunk);
+        let steps = |chunk: &Chunk| {
+            let mut vm = VirtualMachine::with_memory_limit(MEMORY_LIMIT);
+            let result = session.load_into(&mut vm).and_then(|()| vm.execute_value_with_timeout(chunk, EXECUTION_TIMEOUT));
+            (vm.step_count(), result)
+        };
+        let (original_steps, _) = steps(chunk);
+        let (optimized_steps, optimized_result) = steps(&optimized);
+
+        OptimizerComparison {
+            original_listing: Disassembler::format(chunk),
+            optimized_listing: Disassembler::format(&optimized),
+            original_instructions: chunk.instructions().len(),
+            optimized_instructions: optimized.instructions().len(),
+            original_bytes: chunk.len(),
+            optimized_bytes: optimized.len(),
+            original_steps,
+            optimized_steps,
+            optimized_result,
         }
     }
 }
 
 impl CompilationResult {
-    fn compile(input: &str) -> Self {
+    /// Tokenize, parse and compile `input` without running it
+    fn preview(input: &str, e_notation: ENotation) -> Self {
+        let started = Instant::now();
+        let spanned = Tokenizer::new(input).with_e_notation(e_notation).tokenize_spanned();
+        let tokenize = started.elapsed();
+        let mut result = Self::preview_tokens(input, spanned);
+        result.timings.tokenize = tokenize;
+        result
+    }
+
+    /// Parse and compile `input`, already tokenized as `spanned`
+    fn preview_tokens(input: &str, spanned: Result<Vec<(Token, Range<usize>)>, TokenizerError>) -> Self {
         let mut result = CompilationResult {
             input: input.to_string(),
             ..Default::default()
         };
 
         // Tokenize
-        let mut tokenizer = Tokenizer::new(input);
-        result.tokens = Some(tokenizer.tokenize());
+        result.token_spans = spanned.iter().flatten().map(|(_, span)| span.clone()).collect();
+        result.tokens = Some(spanned.map(|tokens| tokens.into_iter().map(|(token, _)| token).collect()));
 
         // Parse
+        let mut spans = Vec::new();
         if let Some(Ok(ref tokens)) = result.tokens {
             let mut parser = Parser::new(tokens.clone());
-            result.ast = Some(parser.parse());
+            let started = Instant::now();
+            result.ast = Some(parser.parse_spanned().map(|(ast, node_spans)| {
+                spans = node_spans;
+                ast
+            }));
+            result.timings.parse = started.elapsed();
+            if let Some(Ok(ref ast)) = result.ast {
+                if tokens.iter().any(|token| matches!(token, Token::Expand | Token::Factor)) {
+                    result.rewritten = Some(symbolic::to_infix(ast));
+                }
+            }
         }
 
         // Compile
         if let Some(Ok(ref ast)) = result.ast {
-            let chunk = CodeGenerator::new().compile(ast);
-            result.disassembly = Disassembler::format_with_hex(&chunk);
-            result.chunk = Some(chunk);
+            let started = Instant::now();
+            let compiled = CodeGenerator::new().with_source(input).with_spans(spans).compile(ast);
+            result.timings.compile = started.elapsed();
+            match compiled {
+                Ok((chunk, diagnostics)) => {
+                    result.diagnostics = diagnostics;
+                    result.disassembly = Disassembler::format_with_hex(&chunk);
+                    result.instructions = Disassembler::disassemble(&chunk);
+                    result.chunk = Some(chunk);
+                }
+                Err(error) => result.compile_error = Some(error),
+            }
+        }
+
+        result
+    }
+
+    /// Run `input` against the definitions in `session`, updating it on success
+    fn compile(input: &str, session: &mut Session) -> Self {
+        let mut result = Self::preview(input, session.e_notation);
+
+        // Comparison runs first so it sees the definitions from before this input
+        if let (Some(Ok(ast)), Some(chunk)) = (&result.ast, &result.chunk) {
+            result.backends = CodeGenerator::new()
+                .compile_registers(ast)
+                .ok()
+                .map(|registers| BackendComparison::run(registers, chunk, session));
+            result.optimization = Some(OptimizerComparison::run(chunk, session));
         }
 
         // Execute
         if let Some(ref chunk) = result.chunk {
-            let mut vm = VirtualMachine::new();
+            result.debugger = Some(Debugger::new(chunk, session));
+            let mut vm = VirtualMachine::with_memory_limit(MEMORY_LIMIT);
             vm.enable_tracing();
-            result.result = Some(vm.execute(chunk));
+            vm.enable_site_tracking();
+            let started = Instant::now();
+            let executed = session
+                .load_into(&mut vm)
+                .and_then(|()| vm.execute_value_with_timeout(chunk, EXECUTION_TIMEOUT));
+            result.timings.execute = started.elapsed();
+            if executed.is_ok() {
+                session.store_from(&vm);
+            }
+            result.result = Some(executed);
             result.execution_trace = vm.trace().to_vec();
             // Capture stats from the VM before it drops
             result.memory_stats = Some(vm.memory_stats().clone());
@@ -89,6 +737,39 @@ impl CompilationResult {
 
         result
     }
+
+    /// Chars of the input a tokenizer or parse error points at, with the error
+    fn error_span(&self) -> Option<(Range<usize>, String)> {
+        match (&self.tokens, &self.ast) {
+            (Some(Err(error)), _) => {
+                let chars: Vec<char> = self.input.chars().collect();
+                let mut end = error.position + 1;
+                while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_' || chars[end] == '.') {
+                    end += 1;
+                }
+                Some((error.position..end.min(chars.len()), error.to_string()))
+            }
+            (_, Some(Err(error))) => {
+                // Parse errors index tokens; an error at the end points at the last one
+                let span = self.token_spans.get(error.position).or(self.token_spans.last())?;
+                Some((span.clone(), error.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    /// "defined f(x)" if the input ends with a function definition
+    fn definition_summary(&self) -> Option<String> {
+        let last = match &self.ast {
+            Some(Ok(Expr::Sequence(statements))) => statements.last()?,
+            Some(Ok(ast)) => ast,
+            _ => return None,
+        };
+        match last {
+            Expr::FunctionDef { name, params, .. } => Some(format!("defined {}({})", name, params.join(", "))),
+            _ => None,
+        }
+    }
 }
 
 /// Calculator application state
@@ -96,19 +777,83 @@ pub struct CalculatorApp {
     /// Current input expression
     input: String,
     /// History of calculations
-    history: Vec<(String, String)>,
+    history: Vec<HistoryEntry>,
     /// Current compilation result
     compilation: CompilationResult,
     /// Show detailed view
     show_details: bool,
     /// Show execution trace
     show_trace: bool,
-    /// Time-travel debugging: current step index
-    debug_step: usize,
-    /// Whether time-travel debugger is active
+    /// Edit the input in a multi-line, syntax-highlighted editor
+    script_editor: bool,
+    /// Whether the live debugger is shown
     debugger_active: bool,
     /// Mobile view mode: 0 = calculator, 1 = details, 2 = history
     mobile_view: usize,
+    /// Variables, functions and memory register
+    session: Session,
+    /// Binding being renamed in the definitions panel, with the edited name
+    renaming: Option<(Binding, String)>,
+    /// Why the last rename was rejected
+    rename_error: Option<String>,
+    /// Format used by the history export
+    export_format: ExportFormat,
+    /// Append the current disassembly to exports
+    export_disassembly: bool,
+    /// Outcome of the last export
+    export_status: Option<String>,
+    /// History entry recalled into the input with Up/Down, if any
+    history_cursor: Option<usize>,
+    /// Input being typed before history recall started
+    draft: String,
+    /// Selected chars of the input as last seen in the text field; `None` is the end
+    caret: Option<Range<usize>>,
+    /// Move the text field's cursor to `caret` on the next frame
+    move_caret: bool,
+    /// Input as of the last frame and when it last changed, for the live preview
+    edited: Option<(String, Instant)>,
+    /// Tokenizer of the last live preview and the input it read, retokenized by the next
+    live_tokenizer: Option<(String, Tokenizer)>,
+    /// Whether the autocompletion popup was shown last frame
+    completion_open: bool,
+    /// Highlighted entry of the autocompletion popup
+    completion_index: usize,
+    /// Popup closed with Esc or by accepting; reopens on the next edit
+    completion_dismissed: bool,
+    /// Filter typed into the history search box
+    history_search: String,
+    /// Disassembly of history entries expanded with "show bytecode", by index
+    history_bytecode: BTreeMap<usize, String>,
+    /// History entries ticked for comparison, by index, at most two
+    compare_selection: Vec<usize>,
+    /// Diff of the two ticked entries, shown in the comparison window
+    comparison: Option<HistoryComparison>,
+    /// Look of the app, edited in the settings dialog
+    theme: ThemeSettings,
+    /// Theme last applied to the egui context
+    applied_theme: Option<ThemeSettings>,
+    /// How results are displayed
+    number_format: NumberFormat,
+    /// Language of the interface
+    language: Language,
+    /// Buttons added in the settings dialog, shown below the function buttons
+    custom_buttons: Vec<CustomButton>,
+    /// Arrangement of the details panel
+    layout: PanelLayout,
+    /// UI scale being dragged in the settings dialog, not yet applied
+    ui_scale_edit: Option<f32>,
+    /// Whether the settings dialog is open
+    show_settings: bool,
+    /// Instruction selected in the hex viewer, by index
+    hex_selection: Option<usize>,
+    /// Address of the page hosting the web build, for share links
+    page_url: Option<String>,
+    /// Whether share links carry the result format
+    share_format: bool,
+    /// Last link copied from the share menu
+    copied_link: Option<String>,
+    /// Why the last dropped file couldn't be loaded
+    file_error: Option<String>,
 }
 
 impl Default for CalculatorApp {
@@ -119,106 +864,891 @@ impl Default for CalculatorApp {
             compilation: CompilationResult::default(),
             show_details: true,
             show_trace: false,
-            debug_step: 0,
+            script_editor: false,
             debugger_active: false,
             mobile_view: 0,
+            session: Session::default(),
+            renaming: None,
+            rename_error: None,
+            export_format: ExportFormat::Csv,
+            export_disassembly: false,
+            export_status: None,
+            history_cursor: None,
+            draft: String::new(),
+            caret: None,
+            move_caret: false,
+            edited: None,
+            live_tokenizer: None,
+            completion_open: false,
+            completion_index: 0,
+            completion_dismissed: false,
+            history_search: String::new(),
+            history_bytecode: BTreeMap::new(),
+            compare_selection: Vec::new(),
+            comparison: None,
+            theme: ThemeSettings::default(),
+            applied_theme: None,
+            number_format: NumberFormat::default(),
+            language: Language::default(),
+            custom_buttons: Vec::new(),
+            layout: PanelLayout::default(),
+            ui_scale_edit: None,
+            show_settings: false,
+            hex_selection: None,
+            page_url: None,
+            share_format: false,
+            copied_link: None,
+            file_error: None,
         }
     }
 }
 
 impl CalculatorApp {
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        Self::default()
+    /// Create the app, restoring the previous session if one was saved
+    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+        let mut app = Self::default();
+        let saved = cc
+            .storage
+            .and_then(|storage| eframe::get_value::<SavedState>(storage, eframe::APP_KEY))
+            .or_else(SavedState::read_file);
+        if let Some(saved) = saved {
+            app.restore(saved);
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let location = &cc.integration_info.web_info.location;
+            app.page_url = Some(location.url.clone());
+            if let Some(link) = SharedLink::from_fragment(&location.hash) {
+                app.open_shared(link);
+            }
+        }
+        app
+    }
+
+    /// Capture the state worth keeping across launches
+    fn snapshot(&self) -> SavedState {
+        SavedState {
+            history: self.history.clone(),
+            show_details: self.show_details,
+            show_trace: self.show_trace,
+            script_editor: self.script_editor,
+            debugger_active: self.debugger_active,
+            variables: self.session.variables.clone(),
+            functions: self.session.functions.values().map(|f| f.to_string()).collect(),
+            memory: self.session.memory,
+            theme: self.theme.clone(),
+            number_format: self.number_format,
+            layout: self.layout.clone(),
+            language: self.language,
+            custom_buttons: self.custom_buttons.clone(),
+            e_notation: self.session.e_notation,
+        }
+    }
+
+    fn restore(&mut self, saved: SavedState) {
+        self.history = saved.history;
+        self.history_bytecode.clear();
+        self.close_comparison();
+        self.show_details = saved.show_details;
+        self.show_trace = saved.show_trace;
+        self.script_editor = saved.script_editor;
+        self.debugger_active = saved.debugger_active;
+        self.theme = saved.theme;
+        self.number_format = saved.number_format;
+        self.language = saved.language;
+        self.custom_buttons = saved.custom_buttons;
+        self.layout = saved.layout;
+        self.layout.repair();
+        self.session = Session {
+            variables: saved.variables,
+            memory: saved.memory,
+            e_notation: saved.e_notation,
+            ..Session::default()
+        };
+        for source in &saved.functions {
+            if let Err(e) = self.session.define(source) {
+                log::warn!("Dropping saved function '{}': {}", source, e);
+            }
+        }
     }
 
     fn calculate(&mut self) {
         if self.input.trim().is_empty() {
             return;
         }
+        self.history_cursor = None;
+        self.file_error = None;
 
-        self.compilation = CompilationResult::compile(&self.input);
-        // Reset debugger to start
-        self.debug_step = 0;
+        self.compilation = CompilationResult::compile(&self.input, &mut self.session);
+        self.hex_selection = None;
 
         // Add to history
         let result_str = match &self.compilation.result {
-            Some(Ok(value)) => format!("{}", value),
+            Some(Ok(_)) if self.compilation.definition_summary().is_some() => {
+                self.compilation.definition_summary().unwrap_or_default()
+            }
+            Some(Ok(value)) => self.number_format.format_result_value(value),
             Some(Err(e)) => format!("Error: {}", e),
             None => String::from("No result"),
         };
-        self.history.push((self.input.clone(), result_str));
+        self.history.push(HistoryEntry::new(self.input.clone(), result_str));
+    }
+
+    /// Recompile (without executing) once the input has settled after an edit
+    ///
+    /// Keeps the token, AST and bytecode views following the input while
+    /// typing; results and history still wait for Enter.
+    fn update_preview(&mut self, now: Instant) {
+        match &self.edited {
+            Some((input, _)) if *input != self.input => self.edited = Some((self.input.clone(), now)),
+            Some((_, at)) if now.duration_since(*at) >= PREVIEW_DELAY => {
+                self.edited = None;
+                if self.compilation.input != self.input {
+                    self.compilation = self.live_preview();
+                    self.hex_selection = None;
+                }
+            }
+            Some(_) => {}
+            None if self.compilation.input != self.input => self.edited = Some((self.input.clone(), now)),
+            None => {}
+        }
+    }
+
+    /// `CompilationResult::preview` of the input, retokenizing only what
+    /// changed since the last live preview, so long scripts stay cheap to type
+    fn live_preview(&mut self) -> CompilationResult {
+        let e_notation = self.session.e_notation;
+        if !self.live_tokenizer.as_ref().is_some_and(|(_, tokenizer)| tokenizer.e_notation() == e_notation) {
+            self.live_tokenizer = Some((String::new(), Tokenizer::new("").with_e_notation(e_notation)));
+        }
+        let (read, tokenizer) = self.live_tokenizer.as_mut().expect("set above");
+        let spanned = tokenizer.retokenize(&Edit::between(read, &self.input)).map(<[_]>::to_vec);
+        *read = self.input.clone();
+        CompilationResult::preview_tokens(&self.input, spanned)
+    }
+
+    /// Selection in the input, clamped to its length
+    fn caret_range(&self) -> Range<usize> {
+        let len = self.input.chars().count();
+        match &self.caret {
+            Some(range) => range.start.min(len)..range.end.min(len),
+            None => len..len,
+        }
+    }
+
+    /// Replace the chars in `range` and put the cursor after the new text
+    fn replace_chars(&mut self, range: Range<usize>, text: &str) {
+        let byte = |index: usize| self.input.char_indices().nth(index).map_or(self.input.len(), |(i, _)| i);
+        let bytes = byte(range.start)..byte(range.end);
+        self.input.replace_range(bytes, text);
+        let caret = range.start + text.chars().count();
+        self.caret = Some(caret..caret);
+        self.move_caret = true;
     }
 
+    /// Insert at the cursor, replacing any selection
     fn insert_text(&mut self, text: &str) {
-        self.input.push_str(text);
+        self.replace_chars(self.caret_range(), text);
+    }
+
+    /// Insert a custom button's snippet, putting any selection and the cursor at its `$0`
+    fn insert_snippet(&mut self, snippet: &str) {
+        let Some((before, after)) = snippet.split_once(SNIPPET_CURSOR) else {
+            return self.insert_text(snippet);
+        };
+        let range = self.caret_range();
+        let selected: String = self.input.chars().skip(range.start).take(range.len()).collect();
+        self.replace_chars(range.clone(), &format!("{}{}{}", before, selected, after));
+        let caret = range.start + before.chars().count() + selected.chars().count();
+        self.caret = Some(caret..caret);
     }
 
     fn clear_input(&mut self) {
         self.input.clear();
+        self.caret = None;
         self.compilation = CompilationResult::default();
+        self.history_cursor = None;
     }
 
-    fn backspace(&mut self) {
-        self.input.pop();
+    /// Replace the input with the previous history entry (Up)
+    fn recall_previous(&mut self) {
+        let index = match self.history_cursor {
+            _ if self.history.is_empty() => return,
+            None => {
+                self.draft = self.input.clone();
+                self.history.len() - 1
+            }
+            Some(index) => index.saturating_sub(1),
+        };
+        self.history_cursor = Some(index);
+        self.input = self.history[index].expression.clone();
+        self.caret = None;
+        self.move_caret = true;
     }
-}
 
-impl eframe::App for CalculatorApp {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Get available screen size to determine layout
-        // Use available_rect for better cross-platform support
-        let available_rect = ctx.available_rect();
-        let screen_width = available_rect.width();
-        let is_mobile = screen_width < 600.0;
+    /// Move forward through the history (Down), ending at the draft input
+    fn recall_next(&mut self) {
+        match self.history_cursor {
+            None => return,
+            Some(index) if index + 1 < self.history.len() => {
+                self.history_cursor = Some(index + 1);
+                self.input = self.history[index + 1].expression.clone();
+            }
+            Some(_) => {
+                self.history_cursor = None;
+                self.input = std::mem::take(&mut self.draft);
+            }
+        }
+        self.caret = None;
+        self.move_caret = true;
+    }
 
-        // Request continuous repaint for responsive updates
-        ctx.request_repaint();
+    /// Indices of the history entries matching the search, in display order
+    ///
+    /// Pinned entries come first; within each group the newest is first.
+    fn history_matches(&self) -> Vec<usize> {
+        let search = self.history_search.trim().to_lowercase();
+        let matches = |entry: &HistoryEntry| {
+            search.is_empty()
+                || entry.expression.to_lowercase().contains(&search)
+                || entry.result.to_lowercase().contains(&search)
+        };
+        let mut indices: Vec<usize> = (0..self.history.len())
+            .rev()
+            .filter(|&index| matches(&self.history[index]))
+            .collect();
+        indices.sort_by_key(|&index| !self.history[index].pinned);
+        indices
+    }
 
-        // Top panel with title
-        egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
-            ui.horizontal_wrapped(|ui| {
-                ui.heading("Bytecode Calculator");
-                if !is_mobile {
-                    ui.separator();
-                    ui.checkbox(&mut self.show_details, "Show Details");
-                    ui.checkbox(&mut self.show_trace, "Show Trace");
-                    ui.checkbox(&mut self.debugger_active, "Debugger");
-                }
-            });
-        });
+    /// Put a history entry's expression back into the input
+    fn load_history_entry(&mut self, index: usize) {
+        self.input = self.history[index].expression.clone();
+        self.history_cursor = None;
+        self.caret = None;
+        self.move_caret = true;
+        self.mobile_view = 0;
+    }
 
-        if is_mobile {
-            // Mobile: Bottom navigation tabs
-            egui::TopBottomPanel::bottom("mobile_nav").show(ctx, |ui| {
-                ui.horizontal(|ui| {
-                    let tab_width = ui.available_width() / 3.0;
-                    let tab_size = egui::vec2(tab_width - 8.0, 40.0);
-                    
-                    if ui.add_sized(tab_size, egui::SelectableLabel::new(self.mobile_view == 0, "🔢 Calc")).clicked() {
-                        self.mobile_view = 0;
-                    }
-                    if ui.add_sized(tab_size, egui::SelectableLabel::new(self.mobile_view == 1, "📋 Details")).clicked() {
-                        self.mobile_view = 1;
+    /// Enter and evaluate the expression of a shared link
+    #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+    fn open_shared(&mut self, link: SharedLink) {
+        if let Some(format) = link.format {
+            self.number_format = format;
+        }
+        self.script_editor |= link.expression.contains('\n');
+        self.input = link.expression;
+        self.caret = None;
+        self.move_caret = true;
+        self.calculate();
+    }
+
+    /// Link to the current input, if there is one to share
+    fn share_link(&self) -> Option<SharedLink> {
+        let expression = self.input.trim();
+        if expression.is_empty() {
+            return None;
+        }
+        let link = SharedLink::new(expression);
+        Some(if self.share_format { link.with_format(self.number_format) } else { link })
+    }
+
+    /// Put a script into the editor and evaluate it
+    fn load_script(&mut self, script: &str) {
+        self.script_editor = true;
+        self.input = script.trim_end().to_string();
+        self.caret = None;
+        self.move_caret = true;
+        self.mobile_view = 0;
+        self.calculate();
+    }
+
+    /// Load the first `.calc` file dropped onto the window this frame
+    fn handle_dropped_files(&mut self, ctx: &egui::Context) {
+        let dropped = ctx.input(|i| i.raw.dropped_files.clone());
+        let Some(file) = dropped.first() else { return };
+        match read_script(file) {
+            Ok(script) => self.load_script(&script),
+            Err(error) => {
+                let name = file.path.as_ref().map_or(file.name.clone(), |path| path.display().to_string());
+                self.file_error = Some(self.language.format("file-error", &[("file", &name), ("error", &self.language.tr(error))]));
+            }
+        }
+    }
+
+    /// Remove all but the pinned history entries
+    fn clear_history(&mut self) {
+        self.history.retain(|entry| entry.pinned);
+        self.history_bytecode.clear();
+        self.close_comparison();
+        self.history_cursor = None;
+    }
+
+    /// Show or hide the bytecode of a history entry
+    fn toggle_history_bytecode(&mut self, index: usize) {
+        if self.history_bytecode.remove(&index).is_none() {
+            let listing = crate::disassemble(&self.history[index].expression).unwrap_or_else(|e| e);
+            self.history_bytecode.insert(index, listing);
+        }
+    }
+
+    /// Tick or untick a history entry for comparison; ticking a third drops the oldest
+    fn toggle_compare(&mut self, index: usize) {
+        if let Some(position) = self.compare_selection.iter().position(|&selected| selected == index) {
+            self.compare_selection.remove(position);
+        } else {
+            self.compare_selection.push(index);
+            if self.compare_selection.len() > 2 {
+                self.compare_selection.remove(0);
+            }
+        }
+        self.comparison = match self.compare_selection[..] {
+            [left, right] => Some(HistoryComparison::new(&self.history[left], &self.history[right], self.session.e_notation)),
+            _ => None,
+        };
+    }
+
+    fn close_comparison(&mut self) {
+        self.compare_selection.clear();
+        self.comparison = None;
+    }
+
+    /// Window diffing the two history entries ticked for comparison
+    fn render_comparison(&mut self, ctx: &egui::Context) {
+        let Some(comparison) = &self.comparison else { return };
+        let palette = self.theme.palette();
+        let lang = self.language;
+        let mut open = true;
+        egui::Window::new(lang.tr("compare-title"))
+            .open(&mut open)
+            .default_width(420.0)
+            .show(ctx, |ui| {
+                egui::Grid::new("comparison_grid").num_columns(3).spacing([12.0, 4.0]).show(ui, |ui| {
+                    ui.label("");
+                    ui.label(egui::RichText::new("−").color(palette.error).strong());
+                    ui.label(egui::RichText::new("+").color(palette.accent).strong());
+                    ui.end_row();
+                    ui.label(lang.tr("expression-label"));
+                    for expression in &comparison.expressions {
+                        ui.monospace(expression);
                     }
-                    if ui.add_sized(tab_size, egui::SelectableLabel::new(self.mobile_view == 2, "📜 History")).clicked() {
-                        self.mobile_view = 2;
+                    ui.end_row();
+                    ui.label(lang.tr("result-label"));
+                    let changed = comparison.results[0] != comparison.results[1];
+                    for result in &comparison.results {
+                        let text = egui::RichText::new(result).monospace().strong();
+                        ui.label(if changed { text.color(palette.current) } else { text });
                     }
+                    ui.end_row();
                 });
-            });
+                ui.separator();
 
-            // Mobile: Content based on selected tab
-            egui::CentralPanel::default().show(ctx, |ui| {
-                match self.mobile_view {
-                    0 => {
-                        egui::ScrollArea::vertical().show(ui, |ui| {
-                            self.render_calculator_responsive(ui, screen_width);
+                egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                    for (key, lines) in [
+                        ("section-tokens", &comparison.tokens),
+                        ("compare-ast", &comparison.ast),
+                        ("section-bytecode", &comparison.bytecode),
+                    ] {
+                        let changes = lines.iter().filter(|line| !matches!(line, DiffLine::Same(_))).count();
+                        let title = lang.format("compare-changes", &[("section", &lang.tr(key)), ("count", &changes)]);
+                        egui::CollapsingHeader::new(title).id_salt(key).default_open(changes > 0).show(ui, |ui| {
+                            for line in lines {
+                                let (marker, text, color) = match line {
+                                    DiffLine::Same(text) => (" ", text, palette.muted),
+                                    DiffLine::Removed(text) => ("−", text, palette.error),
+                                    DiffLine::Added(text) => ("+", text, palette.accent),
+                                };
+                                ui.label(egui::RichText::new(format!("{} {}", marker, text)).monospace().color(color));
+                            }
                         });
                     }
-                    1 => {
-                        // Enable trace and debugger toggles on mobile details view
-                        ui.horizontal(|ui| {
-                            ui.checkbox(&mut self.show_trace, "Trace");
-                            ui.checkbox(&mut self.debugger_active, "Debug");
+                });
+            });
+        if !open {
+            self.close_comparison();
+        }
+    }
+
+    /// Settings dialog for the theme and result format
+    fn render_settings(&mut self, ctx: &egui::Context) {
+        let lang = self.language;
+        let mut open = self.show_settings;
+        egui::Window::new(lang.tr("settings"))
+            .open(&mut open)
+            .resizable(false)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("settings_grid").num_columns(2).spacing([12.0, 8.0]).show(ui, |ui| {
+                    ui.label(lang.tr("settings-theme"));
+                    ui.horizontal(|ui| {
+                        for preset in ThemePreset::ALL {
+                            if ui.radio(self.theme.preset == preset, lang.tr(preset_key(preset))).clicked() {
+                                self.theme.preset = preset;
+                                self.theme.accent = preset.default_accent();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-accent"));
+                    ui.color_edit_button_srgb(&mut self.theme.accent);
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-font"));
+                    egui::ComboBox::from_id_salt("monospace_font")
+                        .selected_text(self.theme.monospace_font.name())
+                        .show_ui(ui, |ui| {
+                            for font in MonospaceFont::ALL {
+                                ui.selectable_value(&mut self.theme.monospace_font, font, font.name());
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-font-size"));
+                    ui.add(egui::Slider::new(&mut self.theme.monospace_size, 10.0..=24.0).suffix(" pt"));
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-ui-scale"));
+                    // Applied on release so the slider doesn't move under the pointer
+                    let mut scale = self.ui_scale_edit.unwrap_or(self.theme.ui_scale);
+                    let slider = ui.add(egui::Slider::new(&mut scale, 0.75..=2.0).step_by(0.05).suffix("×"));
+                    if slider.dragged() {
+                        self.ui_scale_edit = Some(scale);
+                    } else {
+                        self.ui_scale_edit = None;
+                        self.theme.ui_scale = scale;
+                    }
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-touch"));
+                    ui.checkbox(&mut self.theme.large_buttons, lang.tr("settings-large-buttons"));
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-language"));
+                    egui::ComboBox::from_id_salt("language")
+                        .selected_text(self.language.native_name())
+                        .show_ui(ui, |ui| {
+                            for language in Language::ALL {
+                                ui.selectable_value(&mut self.language, language, language.native_name());
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-e-notation"));
+                    egui::ComboBox::from_id_salt("e_notation")
+                        .selected_text(lang.tr(e_notation_key(self.session.e_notation)))
+                        .show_ui(ui, |ui| {
+                            for e_notation in [ENotation::Scientific, ENotation::Euler] {
+                                ui.selectable_value(&mut self.session.e_notation, e_notation, lang.tr(e_notation_key(e_notation)));
+                            }
+                        });
+                    ui.end_row();
+                });
+
+                ui.separator();
+                let preview = self.last_result().unwrap_or(std::f64::consts::PI);
+                egui::Grid::new("format_grid").num_columns(2).spacing([12.0, 8.0]).show(ui, |ui| {
+                    let format = &mut self.number_format;
+                    ui.label(lang.tr("settings-result-format"));
+                    egui::ComboBox::from_id_salt("notation")
+                        .selected_text(lang.tr(notation_key(format.notation)))
+                        .show_ui(ui, |ui| {
+                            for notation in Notation::ALL {
+                                ui.selectable_value(&mut format.notation, notation, lang.tr(notation_key(notation)));
+                            }
+                        });
+                    ui.end_row();
+
+                    match format.notation {
+                        Notation::Auto | Notation::Exact => {}
+                        Notation::Fixed => {
+                            ui.label(lang.tr("settings-decimals"));
+                            ui.add(egui::Slider::new(&mut format.digits, 0..=15));
+                            ui.end_row();
+                        }
+                        Notation::Scientific | Notation::Engineering | Notation::SiPrefix => {
+                            ui.label(lang.tr("settings-significant"));
+                            ui.add(egui::Slider::new(&mut format.digits, 1..=17));
+                            ui.end_row();
+                        }
+                        Notation::Fraction => {}
+                    }
+                    if format.notation == Notation::Fraction || format.show_fraction {
+                        ui.label(lang.tr("settings-max-denominator"));
+                        ui.add(egui::Slider::new(&mut format.max_denominator, 2..=1_000_000).logarithmic(true));
+                        ui.end_row();
+                    }
+
+                    ui.label(lang.tr("settings-locale"));
+                    egui::ComboBox::from_id_salt("locale")
+                        .selected_text(lang.tr(locale_key(format.locale)))
+                        .show_ui(ui, |ui| {
+                            for locale in Locale::ALL {
+                                ui.selectable_value(&mut format.locale, locale, lang.tr(locale_key(locale)));
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label("");
+                    ui.vertical(|ui| {
+                        ui.checkbox(&mut format.trim_zeros, lang.tr("settings-trim-zeros"));
+                        ui.checkbox(&mut format.exact_integers, lang.tr("settings-exact-integers"));
+                        ui.checkbox(&mut format.show_fraction, lang.tr("settings-show-fraction"));
+                    });
+                    ui.end_row();
+
+                    ui.label(lang.tr("settings-preview"));
+                    ui.monospace(format.format_result(preview));
+                    ui.end_row();
+                });
+
+                ui.separator();
+                self.render_custom_button_editor(ui);
+
+                ui.separator();
+                if ui.button(lang.tr("settings-reset")).clicked() {
+                    self.theme = ThemeSettings::default();
+                    self.number_format = NumberFormat::default();
+                    self.session.e_notation = ENotation::default();
+                }
+            });
+        self.show_settings = open;
+    }
+
+    /// Settings rows to add, edit, reorder and remove custom buttons
+    fn render_custom_button_editor(&mut self, ui: &mut egui::Ui) {
+        let lang = self.language;
+        ui.label(lang.tr("custom-buttons"));
+        let mut moved = None;
+        let mut removed = None;
+        let count = self.custom_buttons.len();
+        egui::Grid::new("custom_buttons").num_columns(4).show(ui, |ui| {
+            for (index, button) in self.custom_buttons.iter_mut().enumerate() {
+                ui.add(egui::TextEdit::singleline(&mut button.label).desired_width(60.0).hint_text(lang.tr("custom-button-label")));
+                ui.add(
+                    egui::TextEdit::singleline(&mut button.snippet)
+                        .desired_width(160.0)
+                        .font(egui::TextStyle::Monospace)
+                        .hint_text(lang.tr("custom-button-snippet")),
+                );
+                if ui.add_enabled(index > 0, egui::Button::new("⬆").small()).clicked() {
+                    moved = Some(index);
+                }
+                if accessible(ui.small_button("🗑"), lang.tr("definitions-delete")).on_hover_text(lang.tr("definitions-delete")).clicked() {
+                    removed = Some(index);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(index) = moved {
+            self.custom_buttons.swap(index - 1, index);
+        }
+        if let Some(index) = removed {
+            self.custom_buttons.remove(index);
+        }
+        if ui.button(lang.tr("custom-button-add")).on_hover_text(lang.tr("custom-button-hint")).clicked() {
+            self.custom_buttons.push(CustomButton {
+                label: format!("F{}", count + 1),
+                snippet: String::new(),
+            });
+        }
+    }
+
+    /// Shortcuts that work regardless of focus
+    fn handle_shortcuts(&mut self, ctx: &egui::Context) {
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::D)) {
+            self.show_details = !self.show_details;
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Escape)) {
+            if self.completion_open {
+                self.completion_dismissed = true;
+            } else {
+                self.clear_input();
+            }
+        }
+    }
+
+    /// Delete the selection, or the char before the cursor
+    fn backspace(&mut self) {
+        let range = self.caret_range();
+        if range.is_empty() && range.start > 0 {
+            self.replace_chars(range.start - 1..range.start, "");
+        } else {
+            self.replace_chars(range, "");
+        }
+    }
+
+    /// Chars of the identifier that ends at the cursor, if one is being typed
+    fn completion_prefix(&self) -> Option<Range<usize>> {
+        let caret = self.caret_range();
+        let chars: Vec<char> = self.input.chars().collect();
+        let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+        if !caret.is_empty() || chars.get(caret.end).is_some_and(is_word) {
+            return None;
+        }
+        let mut start = caret.end;
+        while start > 0 && is_word(&chars[start - 1]) {
+            start -= 1;
+        }
+        // "2x" is a number followed by the identifier "x"
+        while start < caret.end && chars[start].is_ascii_digit() {
+            start += 1;
+        }
+        (start < caret.end && chars[start].is_alphabetic()).then_some(start..caret.end)
+    }
+
+    /// Session definitions and built-ins completing the identifier at the cursor
+    fn completions(&self) -> Vec<Completion> {
+        let Some(range) = self.completion_prefix() else {
+            return Vec::new();
+        };
+        let prefix: String = self.input.chars().skip(range.start).take(range.len()).collect();
+        let variables = self.session.variables.iter().filter(|(name, _)| name.starts_with(&prefix)).map(|(name, value)| {
+            Completion {
+                insert: name.clone(),
+                signature: name.clone(),
+                description: format!("= {}", self.number_format.format_value(value)),
+            }
+        });
+        let functions = self.session.functions.values().filter(|f| f.name.starts_with(&prefix)).map(|f| Completion {
+            insert: format!("{}(", f.name),
+            signature: format!("{}({})", f.name, f.params.join(", ")),
+            description: format!("= {}", f.body),
+        });
+        let builtins = builtins::completions(&prefix).map(|builtin| Completion {
+            insert: match builtin.kind {
+                BuiltinKind::Function => format!("{}(", builtin.name),
+                BuiltinKind::Constant => builtin.name.to_string(),
+                BuiltinKind::Keyword => format!("{} ", builtin.name),
+            },
+            signature: builtin.signature.to_string(),
+            description: builtin.description.to_string(),
+        });
+        variables
+            .chain(functions)
+            .chain(builtins)
+            // Nothing left to complete
+            .filter(|completion| completion.insert != prefix)
+            .take(MAX_COMPLETIONS)
+            .collect()
+    }
+
+    /// Replace the identifier being typed with `completion`
+    fn apply_completion(&mut self, completion: &Completion) {
+        if let Some(range) = self.completion_prefix() {
+            self.replace_chars(range, &completion.insert);
+        }
+        self.completion_dismissed = true;
+    }
+
+    /// Keep `caret` in sync with the text field, or move the field's cursor to it
+    fn sync_caret(&mut self, ctx: &egui::Context, id: egui::Id) {
+        let Some(mut state) = egui::TextEdit::load_state(ctx, id) else {
+            return;
+        };
+        if std::mem::take(&mut self.move_caret) {
+            let range = self.caret_range();
+            state.cursor.set_char_range(Some(egui::text::CCursorRange::two(
+                egui::text::CCursor::new(range.start),
+                egui::text::CCursor::new(range.end),
+            )));
+            state.store(ctx, id);
+        } else if let Some(range) = state.cursor.char_range() {
+            let (a, b) = (range.primary.index, range.secondary.index);
+            self.caret = Some(a.min(b)..a.max(b));
+        }
+    }
+
+    fn section_visible(&self, section: DetailSection) -> bool {
+        match section {
+            DetailSection::Trace => self.show_trace,
+            DetailSection::Debugger => self.debugger_active,
+            _ => !self.layout.hidden.contains(&section),
+        }
+    }
+
+    fn set_section_visible(&mut self, section: DetailSection, visible: bool) {
+        match section {
+            DetailSection::Trace => self.show_trace = visible,
+            DetailSection::Debugger => self.debugger_active = visible,
+            _ if visible => {
+                self.layout.hidden.remove(&section);
+            }
+            _ => {
+                self.layout.hidden.insert(section);
+            }
+        }
+    }
+
+    /// Menu to copy a link that opens the web build with the current input
+    fn render_share_menu(&mut self, ui: &mut egui::Ui) {
+        let lang = self.language;
+        ui.checkbox(&mut self.share_format, lang.tr("share-include-format"));
+        let url = self.share_link().zip(self.page_url.as_ref()).map(|(link, page)| link.to_url(page));
+        if ui.add_enabled(url.is_some(), egui::Button::new(lang.tr("share-copy"))).clicked() {
+            ui.ctx().copy_text(url.clone().unwrap_or_default());
+            self.copied_link = url.clone();
+        }
+        if url.is_some() && self.copied_link == url {
+            ui.label(lang.tr("share-copied"));
+        }
+    }
+
+    /// Menu to show, hide and reorder the detail sections
+    fn render_layout_menu(&mut self, ui: &mut egui::Ui) {
+        let lang = self.language;
+        egui::Grid::new("layout_menu").num_columns(3).show(ui, |ui| {
+            let order = self.layout.order.clone();
+            for (index, section) in order.into_iter().enumerate() {
+                let mut visible = self.section_visible(section);
+                if ui.checkbox(&mut visible, lang.tr(section.key())).changed() {
+                    self.set_section_visible(section, visible);
+                }
+                if ui.add_enabled(index > 0, egui::Button::new("⬆").small()).clicked() {
+                    self.layout.move_section(section, -1);
+                }
+                if ui.add_enabled(index + 1 < self.layout.order.len(), egui::Button::new("⬇").small()).clicked() {
+                    self.layout.move_section(section, 1);
+                }
+                ui.end_row();
+            }
+        });
+        ui.separator();
+        if ui.button(lang.tr("layout-reset")).clicked() {
+            self.layout = PanelLayout::default();
+        }
+    }
+
+    /// Error span of the last evaluation, while the input still matches it
+    fn input_error(&self) -> Option<(Range<usize>, String)> {
+        if self.compilation.input != self.input {
+            return None;
+        }
+        self.compilation.error_span()
+    }
+
+    /// Dim the window while a file is dragged over it
+    fn render_drop_overlay(ctx: &egui::Context, lang: Language) {
+        let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Foreground, egui::Id::new("drop_overlay")));
+        let rect = ctx.screen_rect();
+        painter.rect_filled(rect, 0.0, egui::Color32::from_black_alpha(180));
+        painter.text(
+            rect.center(),
+            egui::Align2::CENTER_CENTER,
+            lang.tr("drop-hint"),
+            egui::TextStyle::Heading.resolve(&ctx.style()),
+            egui::Color32::WHITE,
+        );
+    }
+
+    /// Value of the last successful calculation
+    fn last_result(&self) -> Option<f64> {
+        match &self.compilation.result {
+            Some(Ok(Value::Scalar(value))) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl eframe::App for CalculatorApp {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let state = self.snapshot();
+        eframe::set_value(storage, eframe::APP_KEY, &state);
+        state.write_file();
+    }
+
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let lang = self.language;
+        // Get available screen size to determine layout
+        // Use available_rect for better cross-platform support
+        let available_rect = ctx.available_rect();
+        let screen_width = available_rect.width();
+        let is_mobile = screen_width < 600.0;
+
+        // Request continuous repaint for responsive updates
+        ctx.request_repaint();
+
+        self.handle_shortcuts(ctx);
+        self.handle_dropped_files(ctx);
+        self.update_preview(Instant::now());
+        if self.debugger_active {
+            if let Some(debugger) = &mut self.compilation.debugger {
+                debugger.advance(DEBUGGER_STEPS_PER_FRAME);
+            }
+        }
+        if self.applied_theme.as_ref() != Some(&self.theme) {
+            self.theme.apply(ctx);
+            self.applied_theme = Some(self.theme.clone());
+        }
+
+        // Top panel with title
+        egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
+            ui.horizontal_wrapped(|ui| {
+                ui.heading(lang.tr("app-title"));
+                if !is_mobile {
+                    ui.separator();
+                    ui.checkbox(&mut self.show_details, lang.tr("show-details"));
+                    ui.checkbox(&mut self.show_trace, lang.tr("show-trace"));
+                    ui.checkbox(&mut self.script_editor, lang.tr("script-editor"));
+                    ui.checkbox(&mut self.debugger_active, lang.tr("debugger"));
+                    let shortcuts = ui.menu_button("⌨", |ui| {
+                        egui::Grid::new("shortcuts").num_columns(2).show(ui, |ui| {
+                            for (keys, action) in SHORTCUTS {
+                                ui.label(egui::RichText::new(*keys).monospace().strong());
+                                ui.label(lang.tr(action));
+                                ui.end_row();
+                            }
+                        });
+                    });
+                    accessible(shortcuts.response, lang.tr("keyboard-shortcuts")).on_hover_text(lang.tr("keyboard-shortcuts"));
+                }
+                if self.page_url.is_some() {
+                    let share = ui.menu_button("🔗", |ui| self.render_share_menu(ui));
+                    accessible(share.response, lang.tr("share-link")).on_hover_text(lang.tr("share-link"));
+                }
+                let layout = ui.menu_button("🗔", |ui| self.render_layout_menu(ui));
+                accessible(layout.response, lang.tr("details-layout")).on_hover_text(lang.tr("details-layout"));
+                if accessible(ui.button("⚙"), lang.tr("settings")).on_hover_text(lang.tr("settings")).clicked() {
+                    self.show_settings = !self.show_settings;
+                }
+            });
+        });
+
+        self.render_settings(ctx);
+        self.render_comparison(ctx);
+
+        if is_mobile {
+            // Mobile: Bottom navigation tabs
+            egui::TopBottomPanel::bottom("mobile_nav").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let tab_width = ui.available_width() / 3.0;
+                    let tab_size = egui::vec2(tab_width - 8.0, 40.0);
+                    
+                    if ui.add_sized(tab_size, egui::SelectableLabel::new(self.mobile_view == 0, lang.tr("mobile-calc"))).clicked() {
+                        self.mobile_view = 0;
+                    }
+                    if ui.add_sized(tab_size, egui::SelectableLabel::new(self.mobile_view == 1, lang.tr("mobile-details"))).clicked() {
+                        self.mobile_view = 1;
+                    }
+                    if ui.add_sized(tab_size, egui::SelectableLabel::new(self.mobile_view == 2, lang.tr("mobile-history"))).clicked() {
+                        self.mobile_view = 2;
+                    }
+                });
+            });
+
+            // Mobile: Content based on selected tab
+            egui::CentralPanel::default().show(ctx, |ui| {
+                match self.mobile_view {
+                    0 => {
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            self.render_calculator_responsive(ui, screen_width);
+                        });
+                    }
+                    1 => {
+                        // Enable trace and debugger toggles on mobile details view
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut self.show_trace, lang.tr("mobile-trace"));
+                            ui.checkbox(&mut self.debugger_active, lang.tr("mobile-debug"));
                         });
                         ui.separator();
                         self.render_details(ui);
@@ -249,109 +1779,481 @@ impl eframe::App for CalculatorApp {
                 }
             });
         }
+
+        if ctx.input(|i| !i.raw.hovered_files.is_empty()) {
+            Self::render_drop_overlay(ctx, lang);
+        }
     }
 }
 impl CalculatorApp {
     fn render_calculator_responsive(&mut self, ui: &mut egui::Ui, available_width: f32) {
+        let palette = self.theme.palette();
+        let lang = self.language;
         let padding = 16.0;
         let usable_width = (available_width - padding).max(200.0);
         
         ui.vertical(|ui| {
+            if let Some(error) = &self.file_error {
+                ui.colored_label(palette.error, error);
+            }
+
             // Input field - full width
             ui.group(|ui| {
-                ui.label("Expression:");
-                let response = ui.add(
+                if self.script_editor {
+                    ui.label(lang.tr("script-label"));
+                    // Take the shortcut before the editor turns it into a newline
+                    let run = ui.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::Enter));
+                    let error = self.input_error();
+                    let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+                        let font = egui::TextStyle::Monospace.resolve(ui.style());
+                        let mut job = highlight_job(text, ui.visuals().text_color(), &palette, font);
+                        if let Some((span, _)) = &error {
+                            underline(&mut job, text, span, palette.error);
+                        }
+                        job.wrap.max_width = wrap_width;
+                        ui.fonts(|fonts| fonts.layout_job(job))
+                    };
+                    let response = ui.add(
+                        egui::TextEdit::multiline(&mut self.input)
+                            .id(egui::Id::new(INPUT_ID))
+                            .desired_width(usable_width)
+                            .desired_rows(6)
+                            .code_editor()
+                            .layouter(&mut layouter),
+                    );
+                    if let Some((_, message)) = &error {
+                        response.on_hover_text(message);
+                    }
+                    self.sync_caret(ui.ctx(), egui::Id::new(INPUT_ID));
+                    if run {
+                        self.calculate();
+                    }
+                    return;
+                }
+
+                let label = ui.label(lang.tr("expression-label"));
+                let input_id = egui::Id::new(INPUT_ID);
+                let focused = ui.ctx().memory(|m| m.has_focus(input_id));
+                let completions = if focused && !self.completion_dismissed { self.completions() } else { Vec::new() };
+                self.completion_open = !completions.is_empty();
+
+                // Popup and history keys; taken before the text field sees them
+                if self.completion_open {
+                    let count = completions.len();
+                    if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown)) {
+                        self.completion_index = (self.completion_index + 1) % count;
+                    }
+                    if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp)) {
+                        self.completion_index = (self.completion_index + count - 1) % count;
+                    }
+                    let accept = ui.input_mut(|i| {
+                        i.consume_key(egui::Modifiers::NONE, egui::Key::Tab)
+                            || i.consume_key(egui::Modifiers::NONE, egui::Key::Enter)
+                    });
+                    if accept {
+                        self.apply_completion(&completions[self.completion_index.min(count - 1)]);
+                    }
+                } else {
+                    if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp)) {
+                        self.recall_previous();
+                    }
+                    if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown)) {
+                        self.recall_next();
+                    }
+                }
+
+                let error = self.input_error();
+                let mut layouter = |ui: &egui::Ui, text: &str, _wrap_width: f32| {
+                    let font = egui::TextStyle::Monospace.resolve(ui.style());
+                    let mut job = egui::text::LayoutJob::simple_singleline(text.to_string(), font, ui.visuals().text_color());
+                    if let Some((span, _)) = &error {
+                        underline(&mut job, text, span, palette.error);
+                    }
+                    ui.fonts(|fonts| fonts.layout_job(job))
+                };
+                let mut response = ui.add(
                     egui::TextEdit::singleline(&mut self.input)
+                        .id(input_id)
                         .desired_width(usable_width)
-                        .font(egui::TextStyle::Monospace),
+                        .font(egui::TextStyle::Monospace)
+                        .layouter(&mut layouter)
+                        // Keep Tab for the popup instead of moving focus
+                        .lock_focus(self.completion_open),
                 );
+                response = response.labelled_by(label.id);
+                if let Some((_, message)) = &error {
+                    response = response.on_hover_text(message);
+                }
+                self.sync_caret(ui.ctx(), input_id);
+                if response.changed() {
+                    self.completion_dismissed = false;
+                    self.completion_index = 0;
+                }
+                if response.has_focus() && !self.completion_dismissed {
+                    self.render_completions(ui, &palette, response.rect.left_bottom());
+                }
 
-                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                let enter = ui.input(|i| i.key_pressed(egui::Key::Enter));
+                let nothing_focused = ui.ctx().memory(|m| m.focused().is_none());
+                if enter && (response.lost_focus() || nothing_focused) {
                     self.calculate();
                 }
+                // Keep typing going to the expression unless another widget took focus
+                if nothing_focused || (enter && response.lost_focus()) {
+                    response.request_focus();
+                }
             });
 
             // Result display - full width
+            let mut use_rewritten = None;
             ui.group(|ui| {
-                ui.label("Result:");
+                let label = ui.label(lang.tr("result-label"));
                 let result_text = match &self.compilation.result {
-                    Some(Ok(value)) => {
-                        if value.fract() == 0.0 && value.abs() < 1e15 {
-                            format!("{}", *value as i64)
-                        } else {
-                            format!("{:.10}", value)
-                                .trim_end_matches('0')
-                                .trim_end_matches('.')
-                                .to_string()
-                        }
-                    }
-                    Some(Err(e)) => format!("{}", e),
-                    None => String::new(),
+                    Some(Ok(value)) => self.number_format.format_result_value(value),
+                    Some(Err(e)) => lang.format("error-prefix", &[("error", e)]),
+                    None => match &self.compilation.compile_error {
+                        Some(e) => lang.format("error-prefix", &[("error", e)]),
+                        None => String::new(),
+                    },
                 };
                 ui.add(
                     egui::TextEdit::singleline(&mut result_text.as_str())
                         .desired_width(usable_width)
                         .font(egui::TextStyle::Monospace),
-                );
+                )
+                .labelled_by(label.id);
+                for diagnostic in &self.compilation.diagnostics {
+                    ui.colored_label(palette.error, lang.format("warning-prefix", &[("warning", diagnostic)]));
+                }
+
+                if let Some(rewritten) = &self.compilation.rewritten {
+                    let label = ui.label(lang.tr("rewritten-label"));
+                    ui.horizontal(|ui| {
+                        let button = ui.button(lang.tr("rewritten-use"));
+                        ui.add(
+                            egui::TextEdit::singleline(&mut rewritten.as_str())
+                                .desired_width(ui.available_width())
+                                .font(egui::TextStyle::Monospace),
+                        )
+                        .labelled_by(label.id);
+                        if button.clicked() {
+                            use_rewritten = Some(rewritten.clone());
+                        }
+                    });
+                }
             });
+            // Editing the rewritten form is how to carry on from it
+            if let Some(rewritten) = use_rewritten {
+                self.replace_chars(0..self.input.chars().count(), &rewritten);
+            }
 
             ui.add_space(10.0);
 
             // Responsive buttons
             self.render_buttons_responsive(ui, usable_width);
+
+            ui.add_space(10.0);
+            self.render_definitions(ui);
         });
     }
 
-    fn render_buttons_responsive(&mut self, ui: &mut egui::Ui, available_width: f32) {
-        // Calculate button sizes based on available width
-        let num_cols = 4.0;
-        let spacing = 4.0;
-        let button_width = ((available_width - (num_cols - 1.0) * spacing) / num_cols).max(40.0);
-        let button_size = egui::vec2(button_width, 40.0);
-        
-        let func_cols = 5.0;
-        let small_width = ((available_width - (func_cols - 1.0) * spacing) / func_cols).max(35.0);
-        let small_button = egui::vec2(small_width, 32.0);
+    /// Stack depth after each step, marking `current`; returns a step clicked or dragged to
+    fn render_depth_chart(ui: &mut egui::Ui, lang: Language, palette: &Palette, depths: &[usize], current: usize) -> Option<usize> {
+        let max_depth = depths.iter().copied().max().unwrap_or(0).max(1);
+        ui.label(lang.format("debugger-depth", &[("steps", &(depths.len() - 1)), ("max", &max_depth)]));
+
+        let size = egui::vec2(ui.available_width(), 60.0);
+        let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click_and_drag());
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 2.0, palette.code_background);
+
+        let last = (depths.len() - 1) as f32;
+        let x = |step: usize| rect.left() + rect.width() * step as f32 / last;
+        let y = |depth: usize| rect.bottom() - 4.0 - (rect.height() - 8.0) * depth as f32 / max_depth as f32;
+        let points: Vec<egui::Pos2> = depths.iter().enumerate().map(|(step, &depth)| egui::pos2(x(step), y(depth))).collect();
+        painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, palette.accent)));
+        if current < depths.len() {
+            painter.vline(x(current), rect.y_range(), egui::Stroke::new(1.0, palette.current));
+        }
 
-        ui.style_mut().spacing.item_spacing = egui::vec2(spacing, spacing);
+        let pointer = response.interact_pointer_pos()?;
+        let fraction = ((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+        let step = (fraction * last).round() as usize;
+        (step != current).then_some(step)
+    }
 
-        // Function buttons - Trig
-        ui.horizontal_wrapped(|ui| {
-            if ui.add_sized(small_button, egui::Button::new("sin")).clicked() {
-                self.insert_text("sin(");
-            }
-            if ui.add_sized(small_button, egui::Button::new("cos")).clicked() {
-                self.insert_text("cos(");
-            }
-            if ui.add_sized(small_button, egui::Button::new("tan")).clicked() {
-                self.insert_text("tan(");
-            }
-            if ui.add_sized(small_button, egui::Button::new("sqrt")).clicked() {
-                self.insert_text("sqrt(");
-            }
-            if ui.add_sized(small_button, egui::Button::new("log")).clicked() {
-                self.insert_text("log(");
+    /// Bytecode before and after the optimizer, with instruction and step counts
+    fn render_optimizer(&self, ui: &mut egui::Ui) {
+        let palette = self.theme.palette();
+        let lang = self.language;
+        let Some(optimization) = &self.compilation.optimization else {
+            ui.label(lang.tr("optimizer-empty"));
+            return;
+        };
+
+        ui.columns(2, |columns| {
+            let listings = [
+                (lang.tr("optimizer-generated"), &optimization.original_listing),
+                (lang.tr("optimizer-optimized"), &optimization.optimized_listing),
+            ];
+            for (ui, (title, listing)) in columns.iter_mut().zip(listings) {
+                ui.label(egui::RichText::new(title).strong());
+                ui.add(
+                    egui::TextEdit::multiline(&mut listing.as_str())
+                        .font(egui::TextStyle::Monospace)
+                        .desired_width(f32::INFINITY),
+                );
             }
         });
 
-        ui.horizontal_wrapped(|ui| {
-            if ui.add_sized(small_button, egui::Button::new("ln")).clicked() {
-                self.insert_text("ln(");
+        egui::Grid::new("optimizer_grid").num_columns(3).striped(true).show(ui, |ui| {
+            ui.label("");
+            ui.label(egui::RichText::new(lang.tr("optimizer-generated")).strong());
+            ui.label(egui::RichText::new(lang.tr("optimizer-optimized")).strong());
+            ui.end_row();
+
+            let saved = |before: usize, after: usize| {
+                if after < before {
+                    egui::RichText::new(format!("{} (−{})", after, before - after)).color(palette.accent)
+                } else {
+                    egui::RichText::new(after.to_string())
+                }
+            };
+            for (label, before, after) in [
+                (lang.tr("stat-instructions"), optimization.original_instructions, optimization.optimized_instructions),
+                (lang.tr("stat-bytes"), optimization.original_bytes, optimization.optimized_bytes),
+                (lang.tr("stat-executed"), optimization.original_steps, optimization.optimized_steps),
+            ] {
+                ui.label(label);
+                ui.label(before.to_string());
+                ui.label(saved(before, after));
+                ui.end_row();
             }
-            if ui.add_sized(small_button, egui::Button::new("exp")).clicked() {
-                self.insert_text("exp(");
+
+            ui.label(lang.tr("result-label"));
+            match &self.compilation.result {
+                Some(Ok(value)) => ui.label(self.number_format.format_value(value)),
+                Some(Err(e)) => ui.colored_label(palette.error, e.to_string()),
+                None => ui.label("-"),
+            };
+            match &optimization.optimized_result {
+                Ok(value) => ui.label(self.number_format.format_value(value)),
+                Err(e) => ui.colored_label(palette.error, e.to_string()),
+            };
+            ui.end_row();
+        });
+    }
+
+    /// Autocompletion popup anchored below the expression input
+    fn render_completions(&mut self, ui: &egui::Ui, palette: &Palette, anchor: egui::Pos2) {
+        let completions = self.completions();
+        if completions.is_empty() {
+            return;
+        }
+        self.completion_index = self.completion_index.min(completions.len() - 1);
+        let mut clicked = None;
+        egui::Area::new(egui::Id::new("completions"))
+            .order(egui::Order::Foreground)
+            .fixed_pos(anchor)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    for (index, completion) in completions.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            let signature = egui::RichText::new(&completion.signature).monospace();
+                            if ui.selectable_label(index == self.completion_index, signature).clicked() {
+                                clicked = Some(index);
+                            }
+                            ui.label(egui::RichText::new(&completion.description).small().color(palette.muted));
+                        });
+                    }
+                });
+            });
+        if let Some(index) = clicked {
+            self.apply_completion(&completions[index]);
+        }
+    }
+
+    /// Table of variables, functions and the memory register
+    ///
+    /// Clicking a name inserts it into the input; each entry can be renamed
+    /// or deleted in place.
+    fn render_definitions(&mut self, ui: &mut egui::Ui) {
+        let palette = self.theme.palette();
+        let lang = self.language;
+        ui.collapsing(lang.tr("definitions"), |ui| {
+            let mut insert = None;
+            let mut delete = None;
+            let mut commit_rename = false;
+            let mut cancel_rename = false;
+
+            let entries: Vec<(Binding, String, String)> = self
+                .session
+                .variables
+                .iter()
+                .map(|(name, value)| {
+                    (Binding::Variable(name.clone()), name.clone(), self.number_format.format_value(value))
+                })
+                .chain(self.session.functions.values().map(|f| {
+                    let head = format!("{}({})", f.name, f.params.join(", "));
+                    (Binding::Function(f.name.clone()), head, f.body.clone())
+                }))
+                .collect();
+
+            egui::Grid::new("definitions").num_columns(4).striped(true).show(ui, |ui| {
+                for (binding, label, value) in &entries {
+                    match &mut self.renaming {
+                        Some((renamed, new_name)) if renamed == binding => {
+                            let response = ui.add(egui::TextEdit::singleline(new_name).desired_width(80.0));
+                            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                                commit_rename = true;
+                            }
+                            ui.label(egui::RichText::new(value).monospace());
+                            if accessible(ui.small_button("✔"), lang.tr("definitions-rename")).on_hover_text(lang.tr("definitions-rename")).clicked() {
+                                commit_rename = true;
+                            }
+                            if accessible(ui.small_button("✖"), lang.tr("definitions-cancel")).on_hover_text(lang.tr("definitions-cancel")).clicked() {
+                                cancel_rename = true;
+                            }
+                        }
+                        _ => {
+                            let name_button = egui::Button::new(egui::RichText::new(label).monospace()).frame(false);
+                            if ui.add(name_button).on_hover_text(lang.tr("definitions-insert")).clicked() {
+                                insert = Some(match binding {
+                                    Binding::Variable(name) => name.clone(),
+                                    Binding::Function(name) => format!("{}(", name),
+                                });
+                            }
+                            ui.label(egui::RichText::new(value).monospace());
+                            if ui.small_button("✏").on_hover_text(lang.tr("definitions-rename")).clicked() {
+                                let name = match binding {
+                                    Binding::Variable(name) | Binding::Function(name) => name.clone(),
+                                };
+                                self.renaming = Some((binding.clone(), name));
+                                self.rename_error = None;
+                            }
+                            if ui.small_button("🗑").on_hover_text(lang.tr("definitions-delete")).clicked() {
+                                delete = Some(binding.clone());
+                            }
+                        }
+                    }
+                    ui.end_row();
+                }
+
+                let memory = self.session.memory;
+                if ui.add(egui::Button::new(egui::RichText::new("M").monospace()).frame(false))
+                    .on_hover_text(lang.tr("definitions-insert-memory"))
+                    .clicked()
+                {
+                    insert = Some(format!("{}", memory));
+                }
+                ui.label(egui::RichText::new(format!("{}", memory)).monospace());
+                ui.label("");
+                if ui.small_button("🗑").on_hover_text(lang.tr("definitions-clear-memory")).clicked() {
+                    self.session.memory = 0.0;
+                }
+                ui.end_row();
+            });
+
+            if entries.is_empty() {
+                ui.label(lang.tr("definitions-empty"));
+            }
+            if let Some(error) = &self.rename_error {
+                ui.colored_label(palette.error, error);
+            }
+
+            if let Some(text) = insert {
+                self.insert_text(&text);
+            }
+            if let Some(binding) = delete {
+                self.session.remove(&binding);
+            }
+            if cancel_rename {
+                self.renaming = None;
+                self.rename_error = None;
+            }
+            if commit_rename {
+                if let Some((binding, new_name)) = self.renaming.take() {
+                    if let Err(error) = self.session.rename(&binding, new_name.trim()) {
+                        self.rename_error = Some(error);
+                        self.renaming = Some((binding, new_name));
+                    } else {
+                        self.rename_error = None;
+                    }
+                }
+            }
+        });
+    }
+
+    fn render_buttons_responsive(&mut self, ui: &mut egui::Ui, available_width: f32) {
+        let lang = self.language;
+        // Calculate button sizes based on available width
+        let num_cols = 4.0;
+        let spacing = 4.0;
+        let button_width = ((available_width - (num_cols - 1.0) * spacing) / num_cols).max(40.0);
+        // Touch mode makes every button taller
+        let height_scale = if self.theme.large_buttons { 1.5 } else { 1.0 };
+        let button_size = egui::vec2(button_width, 40.0 * height_scale);
+        
+        let func_cols = 5.0;
+        let small_width = ((available_width - (func_cols - 1.0) * spacing) / func_cols).max(35.0);
+        let small_button = egui::vec2(small_width, 32.0 * height_scale);
+
+        ui.style_mut().spacing.item_spacing = egui::vec2(spacing, spacing);
+
+        // Function buttons - Trig
+        ui.horizontal_wrapped(|ui| {
+            if ui.add_sized(small_button, egui::Button::new("sin")).clicked() {
+                self.insert_text("sin(");
+            }
+            if ui.add_sized(small_button, egui::Button::new("cos")).clicked() {
+                self.insert_text("cos(");
+            }
+            if ui.add_sized(small_button, egui::Button::new("tan")).clicked() {
+                self.insert_text("tan(");
+            }
+            if ui.add_sized(small_button, egui::Button::new("sqrt")).clicked() {
+                self.insert_text("sqrt(");
+            }
+            if ui.add_sized(small_button, egui::Button::new("log")).clicked() {
+                self.insert_text("log(");
+            }
+        });
+
+        ui.horizontal_wrapped(|ui| {
+            if ui.add_sized(small_button, egui::Button::new("ln")).clicked() {
+                self.insert_text("ln(");
+            }
+            if ui.add_sized(small_button, egui::Button::new("exp")).clicked() {
+                self.insert_text("exp(");
             }
             if ui.add_sized(small_button, egui::Button::new("abs")).clicked() {
                 self.insert_text("abs(");
             }
-            if ui.add_sized(small_button, egui::Button::new("n!")).clicked() {
+            if accessible(ui.add_sized(small_button, egui::Button::new("n!")), lang.tr("button-factorial")).clicked() {
                 self.insert_text("!");
             }
-            if ui.add_sized(small_button, egui::Button::new("^")).clicked() {
+            if accessible(ui.add_sized(small_button, egui::Button::new("^")), lang.tr("button-power")).clicked() {
                 self.insert_text("^");
             }
         });
 
+        // User-defined buttons
+        if !self.custom_buttons.is_empty() {
+            let mut clicked = None;
+            ui.horizontal_wrapped(|ui| {
+                for button in &self.custom_buttons {
+                    let label = if button.label.is_empty() { &button.snippet } else { &button.label };
+                    let response = ui.add_sized(small_button, egui::Button::new(label));
+                    if accessible(response, label).on_hover_text(egui::RichText::new(&button.snippet).monospace()).clicked() {
+                        clicked = Some(button.snippet.clone());
+                    }
+                }
+            });
+            if let Some(snippet) = clicked {
+                self.insert_snippet(&snippet);
+            }
+        }
+
         ui.add_space(8.0);
 
         // Number pad - 4 columns
@@ -365,7 +2267,7 @@ impl CalculatorApp {
             if ui.add_sized(button_size, egui::Button::new("9")).clicked() {
                 self.insert_text("9");
             }
-            if ui.add_sized(button_size, egui::Button::new("/")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("/")), lang.tr("button-divide")).clicked() {
                 self.insert_text("/");
             }
         });
@@ -380,7 +2282,7 @@ impl CalculatorApp {
             if ui.add_sized(button_size, egui::Button::new("6")).clicked() {
                 self.insert_text("6");
             }
-            if ui.add_sized(button_size, egui::Button::new("*")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("*")), lang.tr("button-multiply")).clicked() {
                 self.insert_text("*");
             }
         });
@@ -395,7 +2297,7 @@ impl CalculatorApp {
             if ui.add_sized(button_size, egui::Button::new("3")).clicked() {
                 self.insert_text("3");
             }
-            if ui.add_sized(button_size, egui::Button::new("-")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("-")), lang.tr("button-minus")).clicked() {
                 self.insert_text("-");
             }
         });
@@ -404,55 +2306,113 @@ impl CalculatorApp {
             if ui.add_sized(button_size, egui::Button::new("0")).clicked() {
                 self.insert_text("0");
             }
-            if ui.add_sized(button_size, egui::Button::new(".")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new(".")), lang.tr("button-decimal-point")).clicked() {
                 self.insert_text(".");
             }
-            if ui.add_sized(button_size, egui::Button::new("(")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("(")), lang.tr("button-open-paren")).clicked() {
                 self.insert_text("(");
             }
-            if ui.add_sized(button_size, egui::Button::new("+")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("+")), lang.tr("button-plus")).clicked() {
                 self.insert_text("+");
             }
         });
 
         ui.horizontal(|ui| {
-            if ui.add_sized(button_size, egui::Button::new(")")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new(")")), lang.tr("button-close-paren")).clicked() {
                 self.insert_text(")");
             }
-            if ui.add_sized(button_size, egui::Button::new("pi")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("pi")), lang.tr("button-pi")).clicked() {
                 self.insert_text("pi");
             }
-            if ui.add_sized(button_size, egui::Button::new("e")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("e")), lang.tr("button-e")).clicked() {
                 self.insert_text("e");
             }
-            if ui.add_sized(button_size, egui::Button::new("%")).clicked() {
+            if accessible(ui.add_sized(button_size, egui::Button::new("%")), lang.tr("button-modulo")).clicked() {
                 self.insert_text("%");
             }
         });
 
+        // Memory register
+        ui.horizontal(|ui| {
+            if accessible(ui.add_sized(button_size, egui::Button::new("MC")), lang.tr("button-memory-clear")).clicked() {
+                self.session.memory = 0.0;
+            }
+            if accessible(ui.add_sized(button_size, egui::Button::new("MR")), lang.tr("button-memory-recall")).clicked() {
+                let memory = format!("{}", self.session.memory);
+                self.insert_text(&memory);
+            }
+            if accessible(ui.add_sized(button_size, egui::Button::new("M+")), lang.tr("button-memory-add")).clicked() {
+                self.session.memory += self.last_result().unwrap_or(0.0);
+            }
+            if accessible(ui.add_sized(button_size, egui::Button::new("M−")), lang.tr("button-memory-subtract")).clicked() {
+                self.session.memory -= self.last_result().unwrap_or(0.0);
+            }
+        });
+
         ui.add_space(8.0);
 
         // Control buttons
         ui.horizontal(|ui| {
             let ctrl_width = (available_width - 2.0 * spacing) / 3.0;
-            let ctrl_size = egui::vec2(ctrl_width, 45.0);
+            let ctrl_size = egui::vec2(ctrl_width, 45.0 * height_scale);
             
-            if ui.add_sized(ctrl_size, egui::Button::new("⌫")).clicked() {
+            if accessible(ui.add_sized(ctrl_size, egui::Button::new("⌫")), lang.tr("button-backspace")).clicked() {
                 self.backspace();
             }
-            if ui.add_sized(ctrl_size, egui::Button::new("C")).clicked() {
+            if accessible(ui.add_sized(ctrl_size, egui::Button::new("C")), lang.tr("button-clear")).clicked() {
                 self.clear_input();
             }
-            if ui.add_sized(ctrl_size, egui::Button::new("=")).clicked() {
+            if accessible(ui.add_sized(ctrl_size, egui::Button::new("=")), lang.tr("button-evaluate")).clicked() {
                 self.calculate();
             }
         });
     }
 
+    /// Detail sections in the user's order, each collapsible and resizable
     fn render_details(&mut self, ui: &mut egui::Ui) {
+        let lang = self.language;
         egui::ScrollArea::vertical().show(ui, |ui| {
-            // Tokens
-            ui.collapsing("Tokens", |ui| {
+            for section in self.layout.order.clone() {
+                if !self.section_visible(section) {
+                    continue;
+                }
+                egui::CollapsingHeader::new(lang.tr(section.key())).id_salt(section).show(ui, |ui| {
+                    let max_height = self.layout.heights.get(&section).copied().unwrap_or(f32::INFINITY);
+                    let content = egui::ScrollArea::vertical()
+                        .id_salt(("section_scroll", section))
+                        .max_height(max_height)
+                        .show(ui, |ui| self.render_section(ui, section));
+
+                    // Drag handle; double-click to fit the content again
+                    let (handle, response) = ui.allocate_exact_size(
+                        egui::vec2(ui.available_width(), 6.0),
+                        egui::Sense::click_and_drag(),
+                    );
+                    let response = response.on_hover_cursor(egui::CursorIcon::ResizeVertical);
+                    let stroke = if response.hovered() || response.dragged() {
+                        ui.visuals().widgets.hovered.fg_stroke
+                    } else {
+                        ui.visuals().widgets.noninteractive.bg_stroke
+                    };
+                    ui.painter().hline(handle.x_range(), handle.center().y, stroke);
+                    if response.double_clicked() {
+                        self.layout.heights.remove(&section);
+                    } else if response.dragged() {
+                        let height = content.inner_rect.height() + response.drag_delta().y;
+                        self.layout.heights.insert(section, height.max(MIN_SECTION_HEIGHT));
+                    }
+                });
+                ui.add_space(5.0);
+            }
+        });
+    }
+
+    /// Contents of one detail section
+    fn render_section(&mut self, ui: &mut egui::Ui, section: DetailSection) {
+        let palette = self.theme.palette();
+        let lang = self.language;
+        match section {
+            DetailSection::Tokens => {
                 match &self.compilation.tokens {
                     Some(Ok(tokens)) => {
                         ui.horizontal_wrapped(|ui| {
@@ -460,41 +2420,33 @@ impl CalculatorApp {
                                 ui.label(
                                     egui::RichText::new(format!("{}", token))
                                         .monospace()
-                                        .background_color(egui::Color32::from_gray(40)),
+                                        .background_color(palette.code_background),
                                 );
                             }
                         });
                     }
                     Some(Err(e)) => {
-                        ui.colored_label(egui::Color32::RED, format!("{}", e));
+                        ui.colored_label(palette.error, format!("{}", e));
                     }
                     None => {
-                        ui.label("No tokens");
+                        ui.label(lang.tr("no-tokens"));
                     }
                 }
-            });
-
-            ui.add_space(5.0);
-
-            // AST
-            ui.collapsing("Abstract Syntax Tree", |ui| {
+            }
+            DetailSection::Ast => {
                 match &self.compilation.ast {
                     Some(Ok(ast)) => {
                         ui.label(egui::RichText::new(format!("{}", ast)).monospace());
                     }
                     Some(Err(e)) => {
-                        ui.colored_label(egui::Color32::RED, format!("{}", e));
+                        ui.colored_label(palette.error, format!("{}", e));
                     }
                     None => {
-                        ui.label("No AST");
+                        ui.label(lang.tr("no-ast"));
                     }
                 }
-            });
-
-            ui.add_space(5.0);
-
-            // Bytecode
-            ui.collapsing("Bytecode Disassembly", |ui| {
+            }
+            DetailSection::Bytecode => {
                 if !self.compilation.disassembly.is_empty() {
                     ui.add(
                         egui::TextEdit::multiline(&mut self.compilation.disassembly.as_str())
@@ -502,164 +2454,422 @@ impl CalculatorApp {
                             .desired_width(f32::INFINITY),
                     );
                 } else {
-                    ui.label("No bytecode generated");
+                    ui.label(lang.tr("no-bytecode"));
                 }
-            });
-
-            ui.add_space(5.0);
-
-            // Execution trace
-            if self.show_trace {
-                ui.collapsing("Execution Trace", |ui| {
-                    if self.compilation.execution_trace.is_empty() {
-                        ui.label("No trace available");
-                    } else {
-                        egui::Grid::new("trace_grid")
-                            .num_columns(4)
-                            .striped(true)
-                            .show(ui, |ui| {
-                                ui.label(egui::RichText::new("IP").strong());
-                                ui.label(egui::RichText::new("Opcode").strong());
-                                ui.label(egui::RichText::new("Stack Before").strong());
-                                ui.label(egui::RichText::new("Stack After").strong());
-                                ui.end_row();
-
-                                for step in &self.compilation.execution_trace {
-                                    ui.label(format!("0x{:02X}", step.ip));
-                                    let op_text = match step.operand {
-                                        Some(v) => format!("{} {}", step.opcode, v),
-                                        None => format!("{}", step.opcode),
-                                    };
-                                    ui.label(op_text);
-                                    ui.label(format!("{:?}", step.stack_before));
-                                    ui.label(format!("{:?}", step.stack_after));
-                                    ui.end_row();
-                                }
-                            });
-                    }
-                });
             }
+            DetailSection::HexViewer => self.render_hex_viewer(ui),
+            DetailSection::Optimizer => self.render_optimizer(ui),
+            DetailSection::RegisterMachine => {
+                if let Some(backends) = &self.compilation.backends {
+                    ui.add(
+                        egui::TextEdit::multiline(&mut backends.listing.as_str())
+                            .font(egui::TextStyle::Monospace)
+                            .desired_width(f32::INFINITY),
+                    );
 
-            ui.add_space(5.0);
+                    egui::Grid::new("backend_grid")
+                        .num_columns(3)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.label("");
+                            ui.label(egui::RichText::new(lang.tr("backend-stack")).strong());
+                            ui.label(egui::RichText::new(lang.tr("backend-register")).strong());
+                            ui.end_row();
 
-            // Time-travel debugger
-            if self.debugger_active && !self.compilation.execution_trace.is_empty() {
-                ui.collapsing("Time-Travel Debugger", |ui| {
-                    let trace_len = self.compilation.execution_trace.len();
-                    
-                    ui.horizontal(|ui| {
-                        ui.label("Step:");
-                        ui.add(
-                            egui::Slider::new(&mut self.debug_step, 0..=(trace_len.saturating_sub(1)))
-                                .show_value(true)
-                                .text(format!("/ {}", trace_len.saturating_sub(1))),
-                        );
-                    });
+                            ui.label(lang.tr("stat-instructions"));
+                            ui.label(format!("{}", self.compilation.chunk.as_ref().map_or(0, |c| c.instructions().len())));
+                            ui.label(format!("{}", backends.registers.len()));
+                            ui.end_row();
 
-                    ui.horizontal(|ui| {
-                        if ui.button("|<").clicked() {
-                            self.debug_step = 0;
-                        }
-                        if ui.button("<").clicked() && self.debug_step > 0 {
-                            self.debug_step -= 1;
-                        }
-                        if ui.button(">").clicked() && self.debug_step < trace_len.saturating_sub(1) {
-                            self.debug_step += 1;
-                        }
-                        if ui.button(">|").clicked() {
-                            self.debug_step = trace_len.saturating_sub(1);
-                        }
-                    });
+                            ui.label(lang.tr("stat-executed"));
+                            ui.label(format!("{}", backends.stack_steps));
+                            ui.label(format!("{}", backends.register_steps));
+                            ui.end_row();
 
-                    ui.separator();
+                            ui.label(lang.tr("stat-storage"));
+                            ui.label(lang.format("storage-bytes", &[("count", &self.compilation.chunk.as_ref().map_or(0, |c| c.len()))]));
+                            ui.label(lang.format("storage-registers", &[("count", &backends.registers.register_count())]));
+                            ui.end_row();
 
-                    if let Some(step) = self.compilation.execution_trace.get(self.debug_step) {
-                        // Current instruction
-                        ui.horizontal(|ui| {
-                            ui.label(egui::RichText::new("Instruction:").strong());
-                            let op_text = match step.operand {
-                                Some(v) => format!("{} {}", step.opcode, v),
-                                None => format!("{}", step.opcode),
+                            ui.label(lang.tr("stat-time"));
+                            ui.label(format!("{:?}", backends.stack_time));
+                            ui.label(format!("{:?}", backends.register_time));
+                            ui.end_row();
+
+                            ui.label(lang.tr("result-label"));
+                            match &self.compilation.result {
+                                Some(Ok(value)) => ui.label(self.number_format.format_value(value)),
+                                Some(Err(e)) => ui.colored_label(palette.error, format!("{}", e)),
+                                None => ui.label("-"),
                             };
-                            ui.label(
-                                egui::RichText::new(format!("0x{:02X}: {}", step.ip, op_text))
-                                    .monospace()
-                                    .color(egui::Color32::YELLOW),
-                            );
+                            match &backends.register_result {
+                                Ok(value) => ui.label(self.number_format.format(*value)),
+                                Err(e) => ui.colored_label(palette.error, format!("{}", e)),
+                            };
+                            ui.end_row();
                         });
+                } else {
+                    ui.label(lang.tr("no-register-code"));
+                }
+            }
+            DetailSection::Trace => {
+                if self.compilation.execution_trace.is_empty() {
+                    ui.label(lang.tr("no-trace"));
+                } else {
+                    egui::Grid::new("trace_grid")
+                        .num_columns(4)
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.label(egui::RichText::new(lang.tr("trace-ip")).strong());
+                            ui.label(egui::RichText::new(lang.tr("trace-opcode")).strong());
+                            ui.label(egui::RichText::new(lang.tr("trace-stack-before")).strong());
+                            ui.label(egui::RichText::new(lang.tr("trace-stack-after")).strong());
+                            ui.end_row();
 
-                        ui.add_space(5.0);
-
-                        // Stack visualization
-                        ui.label(egui::RichText::new("Stack State:").strong());
-                        
-                        ui.horizontal(|ui| {
-                            // Stack before
-                            ui.vertical(|ui| {
-                                ui.label("Before:");
-                                self.render_stack_visual(ui, &step.stack_before);
-                            });
-
-                            ui.separator();
-
-                            // Stack after
-                            ui.vertical(|ui| {
-                                ui.label("After:");
-                                self.render_stack_visual(ui, &step.stack_after);
-                            });
+                            let exact = NumberFormat::new(Notation::Exact);
+                            for step in &self.compilation.execution_trace {
+                                ui.label(format!("0x{:02X}", step.ip));
+                                let op_text = match step.operand {
+                                    Some(v) => format!("{} {}", step.opcode, exact.format(v)),
+                                    None => format!("{}", step.opcode),
+                                };
+                                ui.label(op_text);
+                                ui.label(self.number_format.format_list(&step.stack_before));
+                                ui.label(self.number_format.format_list(&step.stack_after));
+                                ui.end_row();
+                            }
                         });
-                    }
-                });
+                }
             }
-
-            ui.add_space(5.0);
-
-            // Memory stats
-            ui.collapsing("Memory Statistics", |ui| {
+            DetailSection::Debugger => {
+                if self.compilation.debugger.is_some() {
+                    self.render_debugger(ui);
+                } else {
+                    ui.label(lang.tr("debugger-empty"));
+                }
+            }
+            DetailSection::Memory => {
                 if let (Some(mem_stats), Some(gc_stats)) = 
                     (&self.compilation.memory_stats, &self.compilation.gc_stats) 
                 {
                     egui::Grid::new("mem_stats_grid")
                         .num_columns(2)
                         .show(ui, |ui| {
-                            ui.label("Total Allocated:");
-                            ui.label(format!("{} bytes", mem_stats.total_allocated));
+                            ui.label(lang.tr("memory-total"));
+                            ui.label(lang.format("storage-bytes", &[("count", &mem_stats.total_allocated)]));
+                            ui.end_row();
+
+                            ui.label(lang.tr("memory-current"));
+                            ui.label(lang.format("storage-bytes", &[("count", &mem_stats.current_usage)]));
                             ui.end_row();
 
-                            ui.label("Current Usage:");
-                            ui.label(format!("{} bytes", mem_stats.current_usage));
+                            ui.label(lang.tr("memory-peak"));
+                            ui.label(lang.format("storage-bytes", &[("count", &mem_stats.peak_usage)]));
                             ui.end_row();
 
-                            ui.label("Peak Usage:");
-                            ui.label(format!("{} bytes", mem_stats.peak_usage));
+                            ui.label(lang.tr("memory-reserved"));
+                            ui.label(lang.format("storage-bytes", &[("count", &mem_stats.reserved)]));
                             ui.end_row();
 
-                            ui.label("Allocations:");
+                            ui.label(lang.tr("memory-allocations"));
                             ui.label(format!("{}", mem_stats.allocation_count));
                             ui.end_row();
 
-                            ui.label("GC Collections:");
+                            ui.label(lang.tr("memory-collections"));
                             ui.label(format!("{}", gc_stats.collections));
                             ui.end_row();
 
-                            ui.label("Objects Freed:");
+                            ui.label(lang.tr("memory-freed"));
                             ui.label(format!("{}", gc_stats.total_objects_freed));
                             ui.end_row();
+
+                            ui.label(lang.tr("memory-pauses"));
+                            ui.label(format!("{}", gc_stats.pauses));
+                            ui.end_row();
+
+                            if let Some(average) = gc_stats.average_pause() {
+                                ui.label(lang.tr("memory-pause-times"));
+                                ui.label(format!("{:?} / {:?} / {:?}", gc_stats.min_pause, average, gc_stats.max_pause));
+                                ui.end_row();
+                            }
+
+                            let timings = &self.compilation.timings;
+                            ui.label(lang.tr("memory-performance"));
+                            ui.label(lang.format(
+                                "memory-phase-times",
+                                &[
+                                    ("tokenize", &timings.tokenize.as_micros()),
+                                    ("parse", &timings.parse.as_micros()),
+                                    ("compile", &timings.compile.as_micros()),
+                                    ("execute", &timings.execute.as_micros()),
+                                ],
+                            ));
+                            ui.end_row();
                         });
+
+                    if !gc_stats.recent_pauses.is_empty() {
+                        ui.add_space(5.0);
+                        ui.label(lang.tr("memory-recent-pauses"));
+                        self.render_pause_times(ui, gc_stats);
+                    }
+
+                    ui.add_space(5.0);
+                    ui.label(lang.tr("memory-by-size"));
+                    self.render_size_histogram(ui, mem_stats);
+
+                    if !mem_stats.sites.is_empty() {
+                        ui.add_space(5.0);
+                        ui.label(lang.tr("memory-by-site"));
+                        self.render_sites(ui, mem_stats);
+                    }
                 } else {
-                    ui.label("No statistics available - run a calculation first");
+                    ui.label(lang.tr("no-statistics"));
+                }
+            }
+        }
+    }
+
+    /// Controls, breakpoint gutter, stack and globals of the live debugger
+    fn render_debugger(&mut self, ui: &mut egui::Ui) {
+        let palette = self.theme.palette();
+        let lang = self.language;
+        let Some(debugger) = &mut self.compilation.debugger else {
+            return;
+        };
+
+        let depths = stack_depths(&self.compilation.execution_trace);
+        if depths.len() > 1 {
+            if let Some(step) = Self::render_depth_chart(ui, lang, &palette, &depths, debugger.vm.step_count()) {
+                debugger.seek(step);
+            }
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button(lang.tr("debugger-restart")).clicked() {
+                debugger.restart();
+            }
+            if ui.add_enabled(debugger.vm.step_count() > 0, egui::Button::new(lang.tr("debugger-back"))).clicked() {
+                debugger.step_back();
+            }
+            let finished = debugger.finished();
+            if ui.add_enabled(!finished, egui::Button::new(lang.tr("debugger-step"))).clicked() {
+                debugger.step();
+            }
+            if debugger.running {
+                if ui.button(lang.tr("debugger-pause")).clicked() {
+                    debugger.pause();
+                }
+            } else if ui.add_enabled(!finished, egui::Button::new(lang.tr("debugger-run"))).clicked() {
+                debugger.run();
+            }
+        });
+
+        let status = if let Some(error) = &debugger.error {
+            egui::RichText::new(lang.format("error-prefix", &[("error", error)])).color(palette.error)
+        } else if debugger.finished() {
+            egui::RichText::new(lang.format("debugger-finished", &[("steps", &debugger.vm.step_count())]))
+        } else if debugger.running {
+            egui::RichText::new(lang.tr("debugger-running"))
+        } else {
+            egui::RichText::new(lang.format(
+                "debugger-paused",
+                &[("ip", &format!("0x{:04X}", debugger.vm.ip())), ("step", &debugger.vm.step_count())],
+            ))
+        };
+        ui.label(status);
+        ui.separator();
+
+        ui.horizontal_top(|ui| {
+            // Disassembly with a breakpoint gutter
+            ui.vertical(|ui| {
+                ui.label(egui::RichText::new(lang.tr("debugger-code")).strong());
+                let current = (!debugger.finished()).then(|| debugger.vm.ip());
+                let mut toggled = None;
+                egui::Grid::new("debugger_code").num_columns(3).spacing([6.0, 2.0]).show(ui, |ui| {
+                    for instruction in &self.compilation.instructions {
+                        let has_breakpoint = debugger.breakpoints.contains(&instruction.offset);
+                        let dot = egui::RichText::new("●").color(if has_breakpoint { palette.error } else { palette.muted });
+                        let label = lang.format("debugger-toggle-breakpoint", &[("offset", &format!("0x{:04X}", instruction.offset))]);
+                        if accessible(ui.add(egui::Button::new(dot).frame(false)), &label).clicked() {
+                            toggled = Some(instruction.offset);
+                        }
+                        let is_current = current == Some(instruction.offset);
+                        ui.label(if is_current { "▶" } else { "" });
+                        let text = egui::RichText::new(&instruction.text).monospace();
+                        ui.label(if is_current { text.color(palette.current).strong() } else { text });
+                        ui.end_row();
+                    }
+                });
+                if let Some(offset) = toggled {
+                    debugger.toggle_breakpoint(offset);
                 }
             });
+
+            ui.separator();
+
+            ui.vertical(|ui| {
+                ui.label(egui::RichText::new(lang.tr("debugger-stack")).strong());
+                Self::render_stack_visual(ui, lang, &palette, &self.number_format, debugger.vm.stack());
+
+                ui.add_space(5.0);
+                ui.label(egui::RichText::new(lang.tr("debugger-globals")).strong());
+                let globals: Vec<_> = debugger.vm.globals().collect();
+                if globals.is_empty() {
+                    ui.label(egui::RichText::new(lang.tr("globals-empty")).monospace().color(palette.muted));
+                }
+                egui::Grid::new("debugger_globals").num_columns(2).show(ui, |ui| {
+                    for (name, value) in globals {
+                        ui.label(egui::RichText::new(name).monospace());
+                        ui.label(egui::RichText::new(self.number_format.format_value(&value.to_value())).monospace().color(palette.secondary));
+                        ui.end_row();
+                    }
+                });
+            });
         });
     }
 
+    /// Hex dump and instruction list side by side, under the input's tokens;
+    /// selecting any of them highlights the others through the source map
+    fn render_hex_viewer(&mut self, ui: &mut egui::Ui) {
+        let lang = self.language;
+        const BYTES_PER_ROW: usize = 8;
+        let palette = self.theme.palette();
+        let Some(chunk) = &self.compilation.chunk else {
+            ui.label(lang.tr("no-bytecode"));
+            return;
+        };
+        let instructions = &self.compilation.instructions;
+        let selected = self.hex_selection.and_then(|index| instructions.get(index)).map(|i| i.byte_range());
+        let selected_tokens = selected.as_ref().and_then(|range| chunk.span(range.start));
+        let mut clicked = None;
+
+        let chars: Vec<char> = self.compilation.input.chars().collect();
+        ui.horizontal_wrapped(|ui| {
+            ui.spacing_mut().item_spacing.x = 2.0;
+            for (index, span) in self.compilation.token_spans.iter().enumerate() {
+                // Implied tokens, like the multiplication in `2e`, have no text to click
+                if span.is_empty() {
+                    continue;
+                }
+                let is_selected = selected_tokens.as_ref().is_some_and(|tokens| tokens.contains(&index));
+                let text: String = chars.get(span.clone()).unwrap_or_default().iter().collect();
+                if ui.selectable_label(is_selected, egui::RichText::new(text).monospace()).clicked() {
+                    let offset = chunk.source_map().offset_of(index);
+                    clicked = Some(offset.and_then(|offset| Disassembler::instruction_containing(instructions, offset)));
+                }
+            }
+        });
+        ui.separator();
+
+        ui.horizontal_top(|ui| {
+            egui::ScrollArea::vertical().id_salt("hex_bytes").max_height(300.0).show(ui, |ui| {
+                egui::Grid::new("hex_grid").spacing([4.0, 2.0]).show(ui, |ui| {
+                    for (row, bytes) in chunk.code().chunks(BYTES_PER_ROW).enumerate() {
+                        let row_offset = row * BYTES_PER_ROW;
+                        ui.label(egui::RichText::new(format!("0x{:04X}", row_offset)).monospace().color(palette.muted));
+                        for (column, byte) in bytes.iter().enumerate() {
+                            let offset = row_offset + column;
+                            let is_selected = selected.as_ref().is_some_and(|range| range.contains(&offset));
+                            let text = egui::RichText::new(format!("{:02X}", byte)).monospace();
+                            if ui.selectable_label(is_selected, text).clicked() {
+                                clicked = Some(Disassembler::instruction_containing(instructions, offset));
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+
+            ui.separator();
+
+            egui::ScrollArea::vertical().id_salt("hex_instructions").max_height(300.0).show(ui, |ui| {
+                for (index, instruction) in instructions.iter().enumerate() {
+                    let is_selected = self.hex_selection == Some(index);
+                    let text = egui::RichText::new(&instruction.text).monospace();
+                    if ui.selectable_label(is_selected, text).clicked() {
+                        clicked = Some(Some(index));
+                    }
+                }
+            });
+        });
+
+        if let Some(selection) = clicked {
+            // Clicking the selected instruction again clears the selection
+            self.hex_selection = selection.filter(|&index| self.hex_selection != Some(index));
+        }
+    }
+
+    /// Render the allocation size-class histogram as a bar per non-empty bucket
+    fn render_size_histogram(&self, ui: &mut egui::Ui, stats: &MemoryStats) {
+        let palette = self.theme.palette();
+        let lang = self.language;
+        let largest = stats.size_classes.iter().copied().max().unwrap_or(0);
+        if largest == 0 {
+            ui.label(egui::RichText::new(lang.tr("memory-no-allocations")).monospace().color(palette.muted));
+            return;
+        }
+
+        egui::Grid::new("size_histogram_grid")
+            .num_columns(2)
+            .show(ui, |ui| {
+                for (index, &count) in stats.size_classes.iter().enumerate() {
+                    if count == 0 {
+                        continue;
+                    }
+                    ui.label(egui::RichText::new(MemoryStats::size_class_label(index)).monospace());
+                    ui.add(
+                        egui::ProgressBar::new(count as f32 / largest as f32)
+                            .desired_width(120.0)
+                            .text(format!("{}", count)),
+                    );
+                    ui.end_row();
+                }
+            });
+    }
+
+    /// Live bytes and objects per allocation site, largest first
+    fn render_sites(&self, ui: &mut egui::Ui, stats: &MemoryStats) {
+        let lang = self.language;
+        let sites = stats.sites_by_bytes();
+        let largest = sites.first().map_or(0, |(_, site)| site.bytes);
+        egui::Grid::new("site_grid")
+            .num_columns(3)
+            .show(ui, |ui| {
+                for (name, site) in sites {
+                    let fraction = if largest > 0 { site.bytes as f32 / largest as f32 } else { 0.0 };
+                    ui.label(egui::RichText::new(name).monospace());
+                    ui.add(
+                        egui::ProgressBar::new(fraction)
+                            .desired_width(120.0)
+                            .text(lang.format("storage-bytes", &[("count", &site.bytes)])),
+                    );
+                    ui.label(lang.format("memory-site-objects", &[("live", &site.objects), ("total", &site.allocations)]));
+                    ui.end_row();
+                }
+            });
+    }
+
+    /// Bars of the last pauses, newest at the bottom, scaled to the longest
+    fn render_pause_times(&self, ui: &mut egui::Ui, stats: &GcStats) {
+        let longest = stats.recent_pauses.iter().max().copied().unwrap_or_default().as_secs_f32();
+        egui::Grid::new("pause_times_grid")
+            .num_columns(1)
+            .show(ui, |ui| {
+                for pause in &stats.recent_pauses {
+                    let fraction = if longest > 0.0 { pause.as_secs_f32() / longest } else { 0.0 };
+                    ui.add(egui::ProgressBar::new(fraction).desired_width(160.0).text(format!("{:?}", pause)));
+                    ui.end_row();
+                }
+            });
+    }
+
     /// Render a visual stack representation
-    fn render_stack_visual(&self, ui: &mut egui::Ui, stack: &[f64]) {
+    fn render_stack_visual(ui: &mut egui::Ui, lang: Language, palette: &Palette, format: &NumberFormat, stack: &[StackValue]) {
         if stack.is_empty() {
             ui.label(
-                egui::RichText::new("[empty]")
+                egui::RichText::new(lang.tr("stack-empty"))
                     .monospace()
-                    .color(egui::Color32::GRAY),
+                    .color(palette.muted),
             );
             return;
         }
@@ -668,19 +2878,19 @@ impl CalculatorApp {
             // Show stack top to bottom (reversed)
             for (i, value) in stack.iter().rev().enumerate() {
                 let is_top = i == 0;
-                let formatted = if value.fract() == 0.0 && value.abs() < 1e10 {
-                    format!("{}", *value as i64)
-                } else {
-                    format!("{:.6}", value)
+                let formatted = match value {
+                    StackValue::Scalar(value) => format.format(*value),
+                    StackValue::Array(_) => format.format_list(value.as_slice()),
+                    StackValue::Closure(_) | StackValue::Text(_) => format.format_value(&value.to_value()),
                 };
-                
+
                 let text = egui::RichText::new(format!("[{}]", formatted))
                     .monospace();
                 
                 let text = if is_top {
-                    text.color(egui::Color32::LIGHT_GREEN).strong()
+                    text.color(palette.accent).strong()
                 } else {
-                    text.color(egui::Color32::LIGHT_GRAY)
+                    text.color(palette.secondary)
                 };
                 
                 ui.label(text);
@@ -688,23 +2898,893 @@ impl CalculatorApp {
         });
     }
 
+    /// History list with search, pinning and per-entry bytecode
+    ///
+    /// Clicking an expression loads it back into the input.
     fn render_history(&mut self, ui: &mut egui::Ui) {
-        ui.heading("Calculation History");
+        let palette = self.theme.palette();
+        let lang = self.language;
+        ui.horizontal(|ui| {
+            ui.heading(lang.tr("history-title"));
+            if !self.history.is_empty() && ui.button(lang.tr("history-clear")).on_hover_text(lang.tr("history-clear-hint")).clicked() {
+                self.clear_history();
+            }
+        });
+        if !self.history.is_empty() {
+            self.render_export(ui);
+            ui.horizontal(|ui| {
+                ui.label("🔍");
+                ui.add(egui::TextEdit::singleline(&mut self.history_search).hint_text(lang.tr("history-search")));
+                if !self.history_search.is_empty() && accessible(ui.small_button("✖"), lang.tr("history-clear-search")).on_hover_text(lang.tr("history-clear-search")).clicked() {
+                    self.history_search.clear();
+                }
+            });
+        }
         ui.separator();
 
+        let matches = self.history_matches();
+        let mut load = None;
+        let mut toggle_pin = None;
+        let mut toggle_bytecode = None;
+        let mut toggle_compare = None;
         egui::ScrollArea::vertical().show(ui, |ui| {
-            for (expr, result) in self.history.iter().rev() {
+            for &index in &matches {
+                let entry = &self.history[index];
                 ui.horizontal(|ui| {
-                    ui.label(egui::RichText::new(expr).monospace());
+                    let pin = if entry.pinned { "📌" } else { "📍" };
+                    let pin_hint = if entry.pinned { lang.tr("history-unpin") } else { lang.tr("history-pin") };
+                    if accessible(ui.small_button(pin), pin_hint).on_hover_text(pin_hint).clicked() {
+                        toggle_pin = Some(index);
+                    }
+                    let expression = egui::Button::new(egui::RichText::new(&entry.expression).monospace()).frame(false);
+                    if ui.add(expression).on_hover_text(lang.tr("history-load")).clicked() {
+                        load = Some(index);
+                    }
                     ui.label("=");
-                    ui.label(egui::RichText::new(result).monospace().strong());
+                    ui.label(egui::RichText::new(&entry.result).monospace().strong());
+                    let selected = self.compare_selection.contains(&index);
+                    let compare = accessible(ui.add(egui::SelectableLabel::new(selected, "⇄")), lang.tr("compare-toggle"));
+                    if compare.on_hover_text(lang.tr("compare-hint")).clicked() {
+                        toggle_compare = Some(index);
+                    }
                 });
+                let expanded = self.history_bytecode.get(&index);
+                let label = if expanded.is_some() { lang.tr("history-bytecode-hide") } else { lang.tr("history-bytecode-show") };
+                if ui.small_button(label).clicked() {
+                    toggle_bytecode = Some(index);
+                }
+                if let Some(listing) = expanded {
+                    egui::Frame::none()
+                        .fill(palette.code_background)
+                        .inner_margin(4.0)
+                        .show(ui, |ui| {
+                            ui.label(egui::RichText::new(listing.trim_end()).monospace().color(palette.secondary));
+                        });
+                }
                 ui.separator();
             }
         });
 
+        if let Some(index) = load {
+            self.load_history_entry(index);
+        }
+        if let Some(index) = toggle_pin {
+            self.history[index].pinned = !self.history[index].pinned;
+        }
+        if let Some(index) = toggle_bytecode {
+            self.toggle_history_bytecode(index);
+        }
+        if let Some(index) = toggle_compare {
+            self.toggle_compare(index);
+        }
+
         if self.history.is_empty() {
-            ui.label("No calculations yet");
+            ui.label(lang.tr("history-empty"));
+        } else if matches.is_empty() {
+            ui.label(lang.tr("history-no-matches"));
+        }
+    }
+
+    /// Export controls: format, whether to include the disassembly, and the export button
+    fn render_export(&mut self, ui: &mut egui::Ui) {
+        let lang = self.language;
+        ui.horizontal_wrapped(|ui| {
+            egui::ComboBox::from_id_salt("export_format")
+                .selected_text(self.export_format.name())
+                .show_ui(ui, |ui| {
+                    for format in ExportFormat::ALL {
+                        ui.selectable_value(&mut self.export_format, format, format.name());
+                    }
+                });
+            ui.checkbox(&mut self.export_disassembly, lang.tr("export-include-disassembly"));
+            if ui.button(lang.tr("export-button")).clicked() {
+                let disassembly = Some(self.compilation.disassembly.as_str())
+                    .filter(|listing| self.export_disassembly && !listing.is_empty());
+                let text = Exporter::export(&self.history, disassembly, self.export_format);
+                self.export_status = self.save_export(ui.ctx(), text);
+            }
+            if let Some(status) = &self.export_status {
+                ui.label(status);
+            }
+        });
+    }
+
+    /// Write an export to a file picked by the user
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_export(&self, _ctx: &egui::Context, text: String) -> Option<String> {
+        let format = self.export_format;
+        let path = rfd::FileDialog::new()
+            .set_file_name(format!("history.{}", format.extension()))
+            .add_filter(format.name(), &[format.extension()])
+            .save_file()?;
+        Some(match std::fs::write(&path, text) {
+            Ok(()) => self.language.format("export-saved", &[("path", &path.display())]),
+            Err(e) => self.language.format("export-failed", &[("error", &e)]),
+        })
+    }
+
+    /// Browsers can't write files directly, so exports go to the clipboard
+    #[cfg(target_arch = "wasm32")]
+    fn save_export(&self, ctx: &egui::Context, text: String) -> Option<String> {
+        ctx.copy_text(text);
+        Some(self.language.format("export-copied", &[("format", &self.export_format.name())]))
+    }
+}
+
+/// Text of a dropped script, or the message key of why it can't be read
+///
+/// Native drops carry a path; web drops carry the bytes.
+fn read_script(file: &egui::DroppedFile) -> Result<String, &'static str> {
+    let name = file
+        .path
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map_or(file.name.clone(), |name| name.to_string_lossy().into_owned());
+    let is_script = name
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case(SCRIPT_EXTENSION));
+    if !is_script {
+        return Err("file-not-script");
+    }
+    let bytes = match (&file.bytes, &file.path) {
+        (Some(bytes), _) => bytes.to_vec(),
+        #[cfg(not(target_arch = "wasm32"))]
+        (None, Some(path)) => std::fs::read(path).map_err(|_| "file-unreadable")?,
+        _ => return Err("file-unreadable"),
+    };
+    String::from_utf8(bytes).map_err(|_| "file-not-text")
+}
+
+/// Message key of a theme preset's name
+fn preset_key(preset: ThemePreset) -> &'static str {
+    match preset {
+        ThemePreset::Dark => "preset-dark",
+        ThemePreset::Light => "preset-light",
+        ThemePreset::HighContrast => "preset-high-contrast",
+    }
+}
+
+/// Message key of a notation's name
+fn notation_key(notation: Notation) -> &'static str {
+    match notation {
+        Notation::Auto => "notation-auto",
+        Notation::Fixed => "notation-fixed",
+        Notation::Scientific => "notation-scientific",
+        Notation::Engineering => "notation-engineering",
+        Notation::SiPrefix => "notation-si-prefix",
+        Notation::Fraction => "notation-fraction",
+        Notation::Exact => "notation-exact",
+    }
+}
+
+fn e_notation_key(e_notation: ENotation) -> &'static str {
+    match e_notation {
+        ENotation::Scientific => "e-notation-scientific",
+        ENotation::Euler => "e-notation-euler",
+    }
+}
+
+fn locale_key(locale: Locale) -> &'static str {
+    match locale {
+        Locale::Plain => "locale-plain",
+        Locale::English => "locale-english",
+        Locale::German => "locale-german",
+    }
+}
+
+/// Name an icon-only button for screen readers
+fn accessible(response: egui::Response, name: &str) -> egui::Response {
+    response.widget_info(|| egui::WidgetInfo::labeled(egui::WidgetType::Button, response.enabled(), name));
+    response
+}
+
+/// Indented outline of an AST, one node per line
+fn ast_outline(expr: &Expr) -> Vec<String> {
+    fn walk(expr: &Expr, depth: usize, lines: &mut Vec<String>) {
+        let (node, children): (String, Vec<&Expr>) = match expr {
+            Expr::Number(_) => (format!("Number {}", expr), Vec::new()),
+            Expr::Array(elements) => ("Array".into(), elements.iter().collect()),
+            Expr::UnaryOp { op, operand } => (format!("Unary {}", op), vec![operand.as_ref()]),
+            Expr::PostfixOp { op, operand } => (format!("Postfix {}", op), vec![operand.as_ref()]),
+            Expr::BinaryOp { op, left, right } => (format!("Binary {}", op), vec![left.as_ref(), right.as_ref()]),
+            Expr::Variable(name) => (format!("Variable {}", name), Vec::new()),
+            Expr::Assign { name, value } => (format!("Assign {}", name), vec![value.as_ref()]),
+            Expr::FunctionDef { name, params, body } => {
+                (format!("Define {}({})", name, params.join(", ")), vec![body.as_ref()])
+            }
+            Expr::Nary { op, args } => (format!("Builtin {}", op), args.iter().collect()),
+            Expr::Call { name, args } => (format!("Call {}", name), args.iter().collect()),
+            Expr::Lambda { params, body } => (format!("Lambda ({})", params.join(", ")), vec![body.as_ref()]),
+            Expr::Let { name, value, body } => (format!("Let {}", name), vec![value.as_ref(), body.as_ref()]),
+            Expr::Sequence(statements) => ("Sequence".into(), statements.iter().collect()),
+        };
+        lines.push(format!("{}{}", "  ".repeat(depth), node));
+        for child in children {
+            walk(child, depth + 1, lines);
         }
     }
+    let mut lines = Vec::new();
+    walk(expr, 0, &mut lines);
+    lines
+}
+
+/// Line diff of `old` against `new` through their longest common subsequence
+fn diff_lines(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    // common[i][j]: length of the LCS of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            // Removals first on a tie, as diff tools list them
+            lines.push(DiffLine::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Stack depth before the first step and after every step of `trace`
+fn stack_depths(trace: &[ExecutionStep]) -> Vec<usize> {
+    let start = trace.first().map_or(0, |step| step.stack_before.len());
+    std::iter::once(start).chain(trace.iter().map(|step| step.stack_after.len())).collect()
+}
+
+/// Syntax class of a span in the script editor
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Highlight {
+    Number,
+    Function,
+    Constant,
+    /// User variable or function name
+    Variable,
+    Operator,
+    Bracket,
+    /// Input the tokenizer rejects
+    Error,
+}
+
+impl Highlight {
+    fn of(token: &Token) -> Self {
+        match token {
+            Token::Number(_) => Highlight::Number,
+            Token::Plus | Token::Minus | Token::Multiply | Token::Divide | Token::Power
+            | Token::Modulo | Token::Factorial | Token::Comma | Token::Assign
+            | Token::Semicolon | Token::Arrow | Token::Let | Token::In => Highlight::Operator,
+            Token::LParen | Token::RParen | Token::LBracket | Token::RBracket | Token::Pipe => Highlight::Bracket,
+            Token::Pi | Token::E | Token::Tau | Token::Phi => Highlight::Constant,
+            Token::Identifier(_) => Highlight::Variable,
+            _ => Highlight::Function,
+        }
+    }
+
+    fn color(self, palette: &Palette) -> egui::Color32 {
+        match self {
+            Highlight::Number => palette.number,
+            Highlight::Function => palette.function,
+            Highlight::Constant => palette.constant,
+            Highlight::Variable => palette.variable,
+            Highlight::Operator => palette.operator,
+            Highlight::Bracket => palette.muted,
+            Highlight::Error => palette.error,
+        }
+    }
+}
+
+/// Classify the characters of `text` for highlighting
+///
+/// The tokenizer stops at the first bad character, so after an error the
+/// offending word is marked and tokenizing resumes behind it. Ranges are
+/// in characters and sorted; anything not covered is whitespace.
+fn highlight_spans(text: &str) -> Vec<(Range<usize>, Highlight)> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans = Vec::new();
+    let mut offset = 0;
+
+    while offset < chars.len() {
+        let rest: String = chars[offset..].iter().collect();
+        let error = match Tokenizer::new(&rest).tokenize_spanned() {
+            Ok(tokens) => {
+                spans.extend(tokens.into_iter().map(|(token, span)| {
+                    (span.start + offset..span.end + offset, Highlight::of(&token))
+                }));
+                break;
+            }
+            Err(error) => error.position,
+        };
+
+        // Everything before the error tokenizes on its own
+        let prefix: String = chars[offset..offset + error].iter().collect();
+        if let Ok(tokens) = Tokenizer::new(&prefix).tokenize_spanned() {
+            spans.extend(tokens.into_iter().map(|(token, span)| {
+                (span.start + offset..span.end + offset, Highlight::of(&token))
+            }));
+        }
+
+        let start = offset + error;
+        let mut end = start + 1;
+        while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_' || chars[end] == '.') {
+            end += 1;
+        }
+        spans.push((start..end, Highlight::Error));
+        offset = end;
+    }
+
+    spans
+}
+
+/// Lay out `text` with syntax colors, using `plain` for whitespace
+fn highlight_job(text: &str, plain: egui::Color32, palette: &Palette, font: egui::FontId) -> egui::text::LayoutJob {
+    // Byte offset of every character, plus the end of the text
+    let bytes: Vec<usize> = text.char_indices().map(|(i, _)| i).chain([text.len()]).collect();
+    let mut job = egui::text::LayoutJob::default();
+    let mut cursor = 0;
+
+    for (span, highlight) in highlight_spans(text) {
+        if span.start > cursor {
+            job.append(&text[bytes[cursor]..bytes[span.start]], 0.0, egui::TextFormat::simple(font.clone(), plain));
+        }
+        let color = highlight.color(palette);
+        let mut format = egui::TextFormat::simple(font.clone(), color);
+        if highlight == Highlight::Error {
+            format.underline = egui::Stroke::new(1.0, color);
+        }
+        job.append(&text[bytes[span.start]..bytes[span.end]], 0.0, format);
+        cursor = span.end;
+    }
+    if cursor < bytes.len() - 1 {
+        job.append(&text[bytes[cursor]..], 0.0, egui::TextFormat::simple(font, plain));
+    }
+
+    job
+}
+
+/// Underline the chars in `span` of the text laid out by `job`
+///
+/// Sections are split at the span's edges so only the error is marked.
+fn underline(job: &mut egui::text::LayoutJob, text: &str, span: &Range<usize>, color: egui::Color32) {
+    let byte = |index: usize| text.char_indices().nth(index).map_or(text.len(), |(i, _)| i);
+    let (start, end) = (byte(span.start), byte(span.end));
+    let mut sections = Vec::with_capacity(job.sections.len() + 2);
+    for section in job.sections.drain(..) {
+        let range = section.byte_range.clone();
+        let cuts = [range.start, start.clamp(range.start, range.end), end.clamp(range.start, range.end), range.end];
+        for piece in cuts.windows(2).filter(|piece| piece[0] < piece[1]) {
+            let mut part = section.clone();
+            part.byte_range = piece[0]..piece[1];
+            if piece[0] != range.start {
+                part.leading_space = 0.0;
+            }
+            if start <= piece[0] && piece[1] <= end {
+                part.format.underline = egui::Stroke::new(1.5, color);
+                part.format.color = color;
+            }
+            sections.push(part);
+        }
+    }
+    job.sections = sections;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_spans() {
+        let spans = highlight_spans("sqrt(x) +\n  2 @ 1");
+        let classes: Vec<_> = spans.iter().map(|(span, class)| (span.clone(), *class)).collect();
+        assert_eq!(classes, vec![
+            (0..4, Highlight::Function),
+            (4..5, Highlight::Bracket),
+            (5..6, Highlight::Variable),
+            (6..7, Highlight::Bracket),
+            (8..9, Highlight::Operator),
+            (12..13, Highlight::Number),
+            (14..15, Highlight::Error),
+            (16..17, Highlight::Number),
+        ]);
+    }
+
+    #[test]
+    fn test_phase_timings() {
+        let mut session = Session::default();
+        let result = CompilationResult::compile("1 +", &mut session);
+        assert!(matches!(result.ast, Some(Err(_))));
+        assert_eq!((result.timings.compile, result.timings.execute), (Duration::ZERO, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_error_span() {
+        let mut session = Session::default();
+        let span = |input: &str, session: &mut Session| CompilationResult::compile(input, session).error_span();
+
+        let (range, message) = span("1 + 2 @ 3", &mut session).unwrap();
+        assert_eq!(range, 6..7);
+        assert!(message.contains("Unexpected character"));
+        // Parse errors map token positions back to characters
+        assert_eq!(span("sin(1 2)", &mut session).unwrap().0, 6..7);
+        assert_eq!(span("2 × (3 +", &mut session).unwrap().0, 7..8);
+        // Runtime errors have no span
+        assert!(span("1 / 0", &mut session).is_none());
+
+        let mut job = egui::text::LayoutJob::simple_singleline("π @ 2".into(), egui::FontId::monospace(14.0), egui::Color32::WHITE);
+        underline(&mut job, "π @ 2", &(2..3), egui::Color32::RED);
+        let underlined: Vec<_> = job.sections.iter().map(|s| (s.byte_range.clone(), s.format.underline.width > 0.0)).collect();
+        assert_eq!(underlined, [(0..3, false), (3..4, true), (4..6, false)]);
+    }
+
+    #[test]
+    fn test_e_notation_setting() {
+        let mut session = Session::default();
+        assert!(matches!(CompilationResult::compile("2e3", &mut session).result, Some(Ok(Value::Scalar(value))) if value == 2000.0));
+        session.e_notation = ENotation::Euler;
+        let result = CompilationResult::compile("2e3", &mut session).result;
+        assert!(matches!(result, Some(Ok(Value::Scalar(value))) if value == 6.0 * std::f64::consts::E));
+    }
+
+    #[test]
+    fn test_array_result() {
+        let mut app = CalculatorApp {
+            input: "linspace(0, 1, 5)".into(),
+            ..Default::default()
+        };
+        app.calculate();
+        assert!(matches!(&app.compilation.result, Some(Ok(Value::Array(elements))) if elements.len() == 5));
+        assert_eq!(app.history[0].result, "[0, 0.25, 0.5, 0.75, 1]");
+        assert_eq!(app.last_result(), None);
+    }
+
+    #[test]
+    fn test_optimizer_comparison() {
+        let mut session = Session::default();
+        let result = CompilationResult::compile("x = 3; x * x + 1", &mut session);
+        let optimization = result.optimization.unwrap();
+        assert!(optimization.optimized_listing.contains("PUSH_ADD"));
+        assert!(optimization.optimized_instructions < optimization.original_instructions);
+        assert!(optimization.optimized_steps < optimization.original_steps);
+        assert!(matches!(optimization.optimized_result, Ok(Value::Scalar(value)) if value == 10.0));
+    }
+
+    #[test]
+    fn test_panel_layout() {
+        let mut app = CalculatorApp::default();
+        app.layout.move_section(DetailSection::Memory, -1);
+        app.layout.move_section(DetailSection::Tokens, -1);
+        assert_eq!(app.layout.order[0], DetailSection::Tokens);
+        assert_eq!(app.layout.order[7..], [DetailSection::Memory, DetailSection::Debugger]);
+
+        app.set_section_visible(DetailSection::Ast, false);
+        app.set_section_visible(DetailSection::Trace, true);
+        assert!(!app.section_visible(DetailSection::Ast));
+        assert!(app.show_trace);
+        app.layout.heights.insert(DetailSection::Bytecode, 120.0);
+
+        let json = serde_json::to_string(&app.snapshot()).unwrap();
+        let mut restored = CalculatorApp::default();
+        restored.restore(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.layout, app.layout);
+
+        // A saved layout missing sections gets them back at the end
+        let mut layout: PanelLayout = serde_json::from_str(r#"{"order": ["Memory", "Memory", "Tokens"]}"#).unwrap();
+        layout.repair();
+        assert_eq!(layout.order.len(), DetailSection::ALL.len());
+        assert_eq!(layout.order[..3], [DetailSection::Memory, DetailSection::Tokens, DetailSection::Ast]);
+    }
+
+    #[test]
+    fn test_open_shared() {
+        let app = CalculatorApp {
+            input: "1 + 1".into(),
+            share_format: true,
+            number_format: NumberFormat::new(Notation::Fraction),
+            ..Default::default()
+        };
+        let link = app.share_link().unwrap();
+        assert_eq!(link.format, Some(app.number_format));
+
+        let mut opened = CalculatorApp::default();
+        opened.open_shared(link);
+        assert_eq!(opened.input, "1 + 1");
+        assert_eq!(opened.number_format.notation, Notation::Fraction);
+        assert_eq!(opened.history.len(), 1);
+        assert!(CalculatorApp::default().share_link().is_none());
+    }
+
+    #[test]
+    fn test_dropped_script() {
+        let dropped = |name: &str, bytes: &[u8]| egui::DroppedFile {
+            name: name.into(),
+            bytes: Some(bytes.into()),
+            ..Default::default()
+        };
+        assert_eq!(read_script(&dropped("area.CALC", b"r = 2;\npi * r^2\n")), Ok("r = 2;\npi * r^2\n".into()));
+        assert_eq!(read_script(&dropped("notes.txt", b"1 + 1")), Err("file-not-script"));
+        assert_eq!(read_script(&dropped("bad.calc", &[0xff, 0xfe])), Err("file-not-text"));
+        assert_eq!(read_script(&egui::DroppedFile { name: "empty.calc".into(), ..Default::default() }), Err("file-unreadable"));
+
+        let mut app = CalculatorApp::default();
+        app.load_script("r = 2;\npi * r^2\n");
+        assert!(app.script_editor);
+        assert_eq!(app.input, "r = 2;\npi * r^2");
+        assert!(matches!(app.compilation.result, Some(Ok(Value::Scalar(value))) if (value - 4.0 * std::f64::consts::PI).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_custom_button_snippets() {
+        let mut app = CalculatorApp {
+            input: "1 + 2".into(),
+            caret: Some(4..5),
+            ..Default::default()
+        };
+        app.insert_snippet("sqrt($0) * 2");
+        assert_eq!(app.input, "1 + sqrt(2) * 2");
+        assert_eq!(app.caret, Some(10..10));
+
+        app.caret = None;
+        app.insert_snippet(" + g");
+        assert_eq!(app.input, "1 + sqrt(2) * 2 + g");
+
+        app.custom_buttons.push(CustomButton {
+            label: "½mv²".into(),
+            snippet: "0.5 * m * $0^2".into(),
+        });
+        let saved: SavedState = serde_json::from_str(&serde_json::to_string(&app.snapshot()).unwrap()).unwrap();
+        assert_eq!(saved.custom_buttons, app.custom_buttons);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let lines = |text: &str| text.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            diff_lines(&lines("a b c d"), &lines("a x c d e")),
+            [
+                DiffLine::Same("a".into()),
+                DiffLine::Removed("b".into()),
+                DiffLine::Added("x".into()),
+                DiffLine::Same("c".into()),
+                DiffLine::Same("d".into()),
+                DiffLine::Added("e".into()),
+            ]
+        );
+        assert!(diff_lines(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_history_comparison() {
+        let mut app = CalculatorApp::default();
+        for input in ["1 + 2 * 3", "(1 + 2) * 3", "4"] {
+            app.input = input.into();
+            app.calculate();
+        }
+        app.toggle_compare(0);
+        assert!(app.comparison.is_none());
+        app.toggle_compare(1);
+        let comparison = app.comparison.as_ref().unwrap();
+        assert_eq!(comparison.results, ["7".to_string(), "9".to_string()]);
+        assert!(comparison.tokens.contains(&DiffLine::Added("(".into())));
+        assert_eq!(comparison.ast[0], DiffLine::Removed("Binary +".into()));
+        // Same instructions in a different order; offsets don't count as changes
+        assert!(comparison.bytecode.contains(&DiffLine::Same("HALT".into())));
+
+        // A third pick replaces the oldest
+        app.toggle_compare(2);
+        assert_eq!(app.compare_selection, [1, 2]);
+        app.clear_history();
+        assert!(app.comparison.is_none() && app.compare_selection.is_empty());
+    }
+
+    #[test]
+    fn test_live_preview() {
+        let mut app = CalculatorApp::default();
+        let start = Instant::now();
+        app.input = "2 +".into();
+        app.update_preview(start);
+        app.input = "2 + 3".into();
+        app.update_preview(start + Duration::from_millis(200));
+        assert!(app.compilation.chunk.is_none());
+
+        // Still typing: the delay restarts from the last edit
+        app.update_preview(start + Duration::from_millis(400));
+        assert!(app.compilation.chunk.is_none());
+        app.update_preview(start + Duration::from_millis(500));
+        assert_eq!(app.compilation.input, "2 + 3");
+        assert!(app.compilation.chunk.is_some());
+        assert!(app.compilation.result.is_none());
+        assert!(app.history.is_empty());
+
+        // Later previews retokenize only the edit
+        app.input = "2 + 3 * x".into();
+        app.update_preview(start + Duration::from_millis(600));
+        app.update_preview(start + Duration::from_secs(1));
+        let fresh = CompilationResult::preview("2 + 3 * x", ENotation::default());
+        assert_eq!(app.compilation.tokens.as_ref().unwrap().as_ref().unwrap(), fresh.tokens.unwrap().unwrap().as_slice());
+        assert_eq!(app.compilation.token_spans, fresh.token_spans);
+
+        // Evaluating doesn't get replaced by a preview
+        app.input = "x = 4".into();
+        app.calculate();
+        app.update_preview(start + Duration::from_secs(2));
+        assert!(app.compilation.result.is_some());
+        assert!(app.edited.is_none());
+    }
+
+    #[test]
+    fn test_session_keeps_definitions() {
+        let mut session = Session::default();
+        let result = CompilationResult::compile("f(x) = x * k", &mut session);
+        assert_eq!(result.definition_summary().as_deref(), Some("defined f(x)"));
+        CompilationResult::compile("k = 3", &mut session);
+
+        let result = CompilationResult::compile("f(2) + 1", &mut session);
+        assert!(matches!(result.result, Some(Ok(Value::Scalar(value))) if value == 7.0));
+        assert!(result.definition_summary().is_none());
+
+        // A failed evaluation leaves the session alone
+        CompilationResult::compile("k = 1; nope", &mut session);
+        assert_eq!(session.variables.get("k"), Some(&Value::Scalar(3.0)));
+    }
+
+    #[test]
+    fn test_rewritten_input() {
+        let mut session = Session::default();
+        let result = CompilationResult::compile("x = 3; factor(x^2 - 1)", &mut session);
+        assert_eq!(result.rewritten.as_deref(), Some("x = 3; (x - 1)*(x + 1)"));
+        assert!(matches!(result.result, Some(Ok(Value::Scalar(value))) if value == 8.0));
+        assert!(CompilationResult::preview("(x + 1)^2", ENotation::default()).rewritten.is_none());
+    }
+
+    #[test]
+    fn test_saved_state_round_trip() {
+        let mut app = CalculatorApp {
+            script_editor: true,
+            input: "sq(x) = x * x; y = [1, 2]; len(y)".into(),
+            ..Default::default()
+        };
+        app.calculate();
+        app.session.memory = 4.0;
+        app.theme.preset = ThemePreset::HighContrast;
+
+        let json = serde_json::to_string(&app.snapshot()).unwrap();
+        let mut restored = CalculatorApp::default();
+        restored.restore(serde_json::from_str(&json).unwrap());
+
+        assert_eq!(restored.history, app.history);
+        assert!(restored.script_editor);
+        assert_eq!(restored.session.memory, 4.0);
+        assert_eq!(restored.theme.preset, ThemePreset::HighContrast);
+        assert_eq!(restored.session.variables.get("y"), Some(&Value::Array(vec![1.0, 2.0])));
+        restored.input = "sq(sum(y))".into();
+        restored.calculate();
+        assert!(matches!(restored.compilation.result, Some(Ok(Value::Scalar(value))) if value == 9.0));
+    }
+
+    #[test]
+    fn test_debugger_breakpoints() {
+        let mut session = Session::default();
+        let result = CompilationResult::compile("x = 1 + 2 * 3; x", &mut session);
+        let mul = result.instructions.iter().find(|i| i.opcode == crate::bytecode::OpCode::Mul).unwrap().offset;
+        let mut debugger = result.debugger.unwrap();
+
+        debugger.toggle_breakpoint(mul);
+        debugger.run();
+        debugger.advance(100);
+        assert!(!debugger.running);
+        assert_eq!(debugger.vm.ip(), mul);
+        assert_eq!(debugger.vm.stack().len(), 3);
+
+        // Resuming runs past the breakpoint we're paused on
+        debugger.run();
+        debugger.advance(100);
+        assert!(debugger.finished());
+        assert_eq!(debugger.vm.global("x"), Some(Value::Scalar(7.0)));
+
+        debugger.step_back();
+        assert!(!debugger.finished());
+        debugger.restart();
+        assert_eq!(debugger.vm.step_count(), 0);
+        assert_eq!(debugger.vm.global("x"), None);
+    }
+
+    #[test]
+    fn test_stack_depths() {
+        let mut session = Session::default();
+        let result = CompilationResult::compile("1 + 2 * 3", &mut session);
+        // PUSH 1, PUSH 2, PUSH 3, MUL, ADD, HALT
+        assert_eq!(stack_depths(&result.execution_trace), [0, 1, 2, 3, 2, 1, 1]);
+
+        let mut debugger = result.debugger.unwrap();
+        debugger.seek(3);
+        assert_eq!(debugger.vm.stack().len(), 3);
+    }
+
+    #[test]
+    fn test_debugger_stops_on_error() {
+        let mut session = Session::default();
+        let mut debugger = CompilationResult::compile("1 / 0", &mut session).debugger.unwrap();
+        debugger.run();
+        debugger.advance(100);
+        assert!(matches!(debugger.error, Some(VmError::DivisionByZero)));
+        // Stopped before the failing DIV with both operands still on the stack
+        assert_eq!(debugger.vm.stack().len(), 2);
+    }
+
+    #[test]
+    fn test_history_recall() {
+        let mut app = CalculatorApp::default();
+        for input in ["1 + 1", "2 * 3"] {
+            app.input = input.into();
+            app.calculate();
+        }
+        app.input = "draft".into();
+
+        app.recall_previous();
+        assert_eq!(app.input, "2 * 3");
+        app.recall_previous();
+        app.recall_previous();
+        assert_eq!(app.input, "1 + 1");
+        app.recall_next();
+        assert_eq!(app.input, "2 * 3");
+        app.recall_next();
+        assert_eq!(app.input, "draft");
+        app.recall_next();
+        assert_eq!(app.input, "draft");
+
+        // Recalling and evaluating starts over from the newest entry
+        app.recall_previous();
+        app.calculate();
+        app.recall_previous();
+        assert_eq!(app.input, "2 * 3");
+        assert_eq!(app.history.len(), 3);
+    }
+
+    #[test]
+    fn test_history_search_and_pins() {
+        let mut app = CalculatorApp::default();
+        for input in ["sin(0)", "2 + 2", "sqrt(16)"] {
+            app.input = input.into();
+            app.calculate();
+        }
+        assert_eq!(app.history_matches(), [2, 1, 0]);
+
+        app.history[0].pinned = true;
+        assert_eq!(app.history_matches(), [0, 2, 1]);
+
+        // Matches expressions and results, ignoring case
+        app.history_search = "SQRT".into();
+        assert_eq!(app.history_matches(), [2]);
+        app.history_search = "4".into();
+        assert_eq!(app.history_matches(), [2, 1]);
+
+        app.load_history_entry(1);
+        assert_eq!(app.input, "2 + 2");
+
+        app.toggle_history_bytecode(1);
+        assert!(app.history_bytecode[&1].contains("HALT"));
+        app.clear_history();
+        assert_eq!(app.history.len(), 1);
+        assert_eq!(app.history[0].expression, "sin(0)");
+        assert!(app.history_bytecode.is_empty());
+    }
+
+    #[test]
+    fn test_insert_at_caret() {
+        let mut app = CalculatorApp {
+            input: "2 × 3".into(),
+            ..Default::default()
+        };
+        app.insert_text("1");
+        assert_eq!(app.input, "2 × 31");
+
+        app.caret = Some(1..1);
+        app.insert_text("0");
+        assert_eq!(app.input, "20 × 31");
+        assert_eq!(app.caret, Some(2..2));
+        app.backspace();
+        app.backspace();
+        assert_eq!(app.input, " × 31");
+
+        // A selection is replaced, or deleted as a whole
+        app.caret = Some(1..2);
+        app.insert_text("+");
+        assert_eq!(app.input, " + 31");
+        app.caret = Some(3..5);
+        app.backspace();
+        assert_eq!(app.input, " + ");
+        app.caret = Some(0..0);
+        app.backspace();
+        assert_eq!(app.input, " + ");
+    }
+
+    #[test]
+    fn test_completions() {
+        let mut app = CalculatorApp {
+            input: "2 * sq".into(),
+            ..Default::default()
+        };
+        let signatures = |app: &CalculatorApp| app.completions().into_iter().map(|c| c.signature).collect::<Vec<_>>();
+        assert_eq!(signatures(&app), ["sqrt(x)"]);
+
+        CompilationResult::compile("sq(n) = n * n; sqx = 3", &mut app.session);
+        assert_eq!(signatures(&app), ["sqx", "sq(n)", "sqrt(x)"]);
+
+        // Accepting replaces just the identifier before the cursor
+        app.input = "2 * sq + 1".into();
+        app.caret = Some(6..6);
+        app.apply_completion(&app.completions()[2]);
+        assert_eq!(app.input, "2 * sqrt( + 1");
+        assert_eq!(app.caret, Some(9..9));
+
+        // Nothing to complete inside a number, mid-word or after an exact name
+        app.input = "12".into();
+        app.caret = None;
+        assert!(app.completions().is_empty());
+        app.input = "sqrt".into();
+        app.caret = Some(2..2);
+        assert!(app.completions().is_empty());
+        app.input = "2pi".into();
+        app.caret = None;
+        assert!(app.completions().is_empty());
+        app.input = "2ph".into();
+        assert_eq!(signatures(&app), ["phi"]);
+    }
+
+    #[test]
+    fn test_session_rename_and_delete() {
+        let mut session = Session::default();
+        CompilationResult::compile("a = 1; b = 2; sq(x) = x^2", &mut session);
+
+        assert!(session.rename(&Binding::Variable("a".into()), "b").is_err());
+        assert!(session.rename(&Binding::Variable("a".into()), "sin").is_err());
+        session.rename(&Binding::Variable("a".into()), "c").unwrap();
+        session.rename(&Binding::Function("sq".into()), "square").unwrap();
+        session.remove(&Binding::Variable("b".into()));
+
+        let result = CompilationResult::compile("square(c + 2)", &mut session);
+        assert!(matches!(result.result, Some(Ok(Value::Scalar(value))) if value == 9.0));
+        assert_eq!(session.variables.keys().collect::<Vec<_>>(), ["c"]);
+    }
+
+    #[test]
+    fn test_highlight_job_covers_text() {
+        let text = "π + foo(1)\n× 2";
+        let font = egui::FontId::monospace(14.0);
+        let job = highlight_job(text, egui::Color32::WHITE, &ThemeSettings::default().palette(), font);
+        assert_eq!(job.text, text);
+    }
 }
@@ -0,0 +1,112 @@
+//! GUI translations
+//!
+//! Every string the GUI shows comes from a Fluent-style bundle in `locales/`:
+//! one `key = value` message per line, `#` comments, and `{ $name }`
+//! placeables filled in by `Language::format`. Bundles are compiled into the
+//! binary and parsed on first use. A message missing from a bundle falls back
+//! to English, and a key missing from English is shown as is.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Language {
+    #[default]
+    English,
+    German,
+}
+
+type Bundle = HashMap<&'static str, &'static str>;
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::English, Language::German];
+
+    /// Name of the language in itself, as listed in the language picker
+    pub fn native_name(self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::German => "Deutsch",
+        }
+    }
+
+    fn bundle(self) -> &'static Bundle {
+        static ENGLISH: OnceLock<Bundle> = OnceLock::new();
+        static GERMAN: OnceLock<Bundle> = OnceLock::new();
+        match self {
+            Language::English => ENGLISH.get_or_init(|| parse(include_str!("../locales/en.ftl"))),
+            Language::German => GERMAN.get_or_init(|| parse(include_str!("../locales/de.ftl"))),
+        }
+    }
+
+    /// The message `key` in this language
+    pub fn tr(self, key: &'static str) -> &'static str {
+        self.bundle()
+            .get(key)
+            .or_else(|| Language::English.bundle().get(key))
+            .copied()
+            .unwrap_or(key)
+    }
+
+    /// The message `key` with each `{ $name }` replaced by its argument
+    pub fn format(self, key: &'static str, args: &[(&str, &dyn Display)]) -> String {
+        let mut message = self.tr(key).to_string();
+        for (name, value) in args {
+            message = message.replace(&format!("{{ ${} }}", name), &value.to_string());
+        }
+        message
+    }
+}
+
+/// Read `key = value` lines, skipping blanks and comments
+fn parse(source: &'static str) -> Bundle {
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn keys(language: Language) -> BTreeSet<&'static str> {
+        language.bundle().keys().copied().collect()
+    }
+
+    #[test]
+    fn test_bundles_have_the_same_messages() {
+        let english = keys(Language::English);
+        for language in Language::ALL {
+            assert_eq!(keys(language), english, "{:?}", language);
+        }
+    }
+
+    #[test]
+    fn test_gui_keys_exist() {
+        let english = keys(Language::English);
+        let source = include_str!("gui.rs");
+        for call in ["tr(\"", "format(\""] {
+            for (start, _) in source.match_indices(call) {
+                let rest = &source[start + call.len()..];
+                let key = &rest[..rest.find('"').unwrap()];
+                assert!(english.contains(key), "missing message {}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookup_and_placeables() {
+        assert_eq!(Language::German.tr("settings"), "Einstellungen");
+        assert_eq!(Language::German.tr("no-such-key"), "no-such-key");
+        assert_eq!(
+            Language::English.format("debugger-paused", &[("ip", &"0x03"), ("step", &2)]),
+            "Paused at 0x03, step 2"
+        );
+    }
+}
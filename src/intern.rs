@@ -0,0 +1,235 @@
+//! Interned names
+//!
+//! Every identifier is interned once, when the code generator or the VM
+//! first needs it, into one table the whole process shares. From then on the
+//! name travels as a `Symbol`: chunk name tables, register instructions and
+//! the VM's globals and functions all hold symbols, so looking a name up at
+//! run time compares pointers instead of hashing or comparing strings, and
+//! storing a variable allocates nothing.
+//!
+//! A symbol holds its name by reference count. Once every symbol for a name
+//! has been dropped the table is its last owner, and the name is freed the
+//! next time the table sweeps, which it does whenever it has doubled since
+//! the last sweep. A server evaluating formulas full of fresh names holds at
+//! most twice the names still in use, not every name it has ever seen.
+
+use crate::prelude::*;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::cmp::Ordering as CmpOrdering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Names the table holds before it first sweeps
+const MIN_SWEEP: usize = 64;
+
+/// An interned name
+///
+/// Two symbols are equal when they are the same name; symbols are ordered by
+/// address, which is stable while they live but says nothing about the text.
+#[derive(Clone)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol {
+    /// The symbol for `name`, adding it to the table if it is new
+    pub fn intern(name: &str) -> Symbol {
+        TABLE.with(|table| {
+            if let Some(name) = table.names.get(name) {
+                return Symbol(name.clone());
+            }
+            if table.names.len() >= table.next_sweep {
+                table.sweep();
+            }
+            let name: Arc<str> = name.into();
+            table.names.insert(name.clone());
+            Symbol(name)
+        })
+    }
+
+    /// The symbol for `name` if it has been interned, without adding it
+    pub fn lookup(name: &str) -> Option<Symbol> {
+        TABLE.with(|table| table.names.get(name).cloned().map(Symbol))
+    }
+
+    /// The interned text
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Number of names in the table, including any no longer used that the
+    /// next sweep will free
+    pub fn interned() -> usize {
+        TABLE.with(|table| table.names.len())
+    }
+
+    fn address(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const u8 as usize
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        // The table holds one allocation per name
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.address().cmp(&other.address())
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::intern(&name)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(symbol: Symbol) -> Self {
+        symbol.as_str().to_string()
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+struct Table {
+    names: BTreeSet<Arc<str>>,
+    /// Size at which `intern` sweeps before adding another name
+    next_sweep: usize,
+}
+
+impl Table {
+    /// Free the names no symbol refers to any more
+    ///
+    /// Runs under the lock, and a symbol can only be made under the lock or
+    /// cloned from another, so a name the table alone holds stays unused.
+    fn sweep(&mut self) {
+        self.names.retain(|name| Arc::strong_count(name) > 1);
+        self.next_sweep = (self.names.len() * 2).max(MIN_SWEEP);
+    }
+}
+
+/// The table behind a spin lock, which works without `std`; names are
+/// interned while compiling, never in the VM's inner loop, so it is rarely
+/// contended
+struct Interner {
+    locked: AtomicBool,
+    table: UnsafeCell<Table>,
+}
+
+// SAFETY: the table is only reached through `with`, which holds the lock
+unsafe impl Sync for Interner {}
+
+impl Interner {
+    fn with<R>(&self, f: impl FnOnce(&mut Table) -> R) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        // Unlock even if `f` panics, so one failure doesn't wedge every thread
+        struct Unlock<'a>(&'a AtomicBool);
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+        let _unlock = Unlock(&self.locked);
+        // SAFETY: the lock is held until `_unlock` drops
+        f(unsafe { &mut *self.table.get() })
+    }
+}
+
+static TABLE: Interner = Interner {
+    locked: AtomicBool::new(false),
+    table: UnsafeCell::new(Table {
+        names: BTreeSet::new(),
+        next_sweep: MIN_SWEEP,
+    }),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Symbol::intern("intern_test_a");
+        assert_eq!(Symbol::intern("intern_test_a"), a);
+        assert_ne!(Symbol::intern("intern_test_b"), a);
+        assert_eq!(a.as_str(), "intern_test_a");
+        assert_eq!(a, "intern_test_a");
+        assert_eq!(a.to_string(), "intern_test_a");
+        assert_eq!(format!("{:?}", a), "\"intern_test_a\"");
+
+        assert_eq!(Symbol::lookup("intern_test_a"), Some(a.clone()));
+        assert_eq!(Symbol::lookup("intern_test_never"), None);
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let symbols: Vec<Symbol> = (0..4)
+            .map(|_| std::thread::spawn(|| Symbol::intern("intern_test_shared")))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert!(symbols.iter().all(|symbol| *symbol == symbols[0]));
+    }
+
+    #[test]
+    fn test_unused_names_are_freed() {
+        let kept = Symbol::intern("intern_test_kept");
+        for round in 0..20 {
+            let names: Vec<Symbol> = (0..100).map(|n| Symbol::intern(&format!("intern_test_{}_{}", round, n))).collect();
+            assert!(names.iter().all(|name| name.as_str().starts_with("intern_test_")));
+        }
+        // Other tests intern concurrently, but nowhere near 2000 names
+        assert!(Symbol::interned() < 1000, "{} names interned", Symbol::interned());
+        assert_eq!(Symbol::lookup("intern_test_kept"), Some(kept));
+    }
+}
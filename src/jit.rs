@@ -0,0 +1,302 @@
+//! JIT - Compiles bytecode chunks to native code with Cranelift
+//!
+//! Only straight-line scalar arithmetic is supported:
+//!   PUSH, POP, DUP, ADD, SUB, MUL, DIV, NEG, SQRT, ABS, FLOOR, CEIL,
+//!   PUSH_ADD, PUSH_MUL, DUP_MUL, HALT
+//!
+//! The operand stack is resolved at compile time, so every stack slot
+//! becomes an SSA value and the generated function is a single block of
+//! floating-point instructions. Operations that can fail in the
+//! interpreter (division by zero, sqrt of a negative number) are checked
+//! at runtime and bail out instead of raising an error; the caller then
+//! re-runs the chunk on the interpreter, which reports the error exactly
+//! as it would without the JIT.
+//!
+//! Compiled with: cargo build --features jit
+
+use crate::bytecode::{Chunk, OpCode, Operand};
+use cranelift_codegen::ir::condcodes::FloatCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use std::fmt;
+
+/// Status returned by compiled code when it computed a result
+const STATUS_OK: i64 = 0;
+/// Status returned by compiled code when the interpreter must take over
+const STATUS_BAIL: i64 = 1;
+
+/// Signature of generated code: writes the result through `out`, returns a status
+type NativeFn = unsafe extern "C" fn(out: *mut f64) -> i32;
+
+#[derive(Debug, Clone)]
+pub enum JitError {
+    /// Chunk uses an opcode the JIT does not compile
+    UnsupportedOpcode(OpCode),
+    /// Chunk would fail in the interpreter (underflow, overflow, bad encoding)
+    InvalidProgram(String),
+    /// Cranelift rejected the generated function
+    Codegen(String),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::UnsupportedOpcode(op) => write!(f, "JIT does not support {}", op),
+            JitError::InvalidProgram(msg) => write!(f, "Invalid program: {}", msg),
+            JitError::Codegen(msg) => write!(f, "Code generation failed: {}", msg),
+        }
+    }
+}
+
+/// Native code for one chunk
+///
+/// Owns the executable memory it lives in; dropping it frees the code.
+pub struct JitFunction {
+    module: Option<JITModule>,
+    code: NativeFn,
+}
+
+// The module is only touched again in `drop`; calling `code` needs no shared state.
+unsafe impl Send for JitFunction {}
+unsafe impl Sync for JitFunction {}
+
+impl JitFunction {
+    /// Run the native code, returning `None` if it bailed out to the interpreter
+    pub fn call(&self) -> Option<f64> {
+        let mut result = 0.0;
+        // SAFETY: `code` was produced by `compile` with the `NativeFn` signature
+        // and its memory is kept alive by `self.module`.
+        let status = unsafe { (self.code)(&mut result) };
+        (status as i64 == STATUS_OK).then_some(result)
+    }
+}
+
+impl Drop for JitFunction {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `code` is the only pointer into the module and dies with `self`.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+impl fmt::Debug for JitFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JitFunction").field("code", &(self.code as *const u8)).finish()
+    }
+}
+
+/// Compile a chunk to native code
+pub fn compile(chunk: &Chunk) -> Result<JitFunction, JitError> {
+    let codegen = |e: &dyn fmt::Display| JitError::Codegen(e.to_string());
+
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(|e| codegen(&e))?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| codegen(&e))?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| codegen(&e))?;
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+    let mut ctx = module.make_context();
+    let pointer = module.target_config().pointer_type();
+    ctx.func.signature.params.push(AbiParam::new(pointer));
+    ctx.func.signature.returns.push(AbiParam::new(types::I32));
+
+    let mut builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    let entry = builder.create_block();
+    let bail = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    builder.seal_block(entry);
+    let out = builder.block_params(entry)[0];
+
+    let result = translate(chunk, &mut builder, bail)?;
+    let status = builder.ins().iconst(types::I32, STATUS_OK);
+    builder.ins().store(MemFlags::trusted(), result, out, 0);
+    builder.ins().return_(&[status]);
+
+    builder.switch_to_block(bail);
+    builder.seal_block(bail);
+    let status = builder.ins().iconst(types::I32, STATUS_BAIL);
+    builder.ins().return_(&[status]);
+    builder.finalize();
+
+    let id = module
+        .declare_anonymous_function(&ctx.func.signature)
+        .map_err(|e| codegen(&e))?;
+    module.define_function(id, &mut ctx).map_err(|e| codegen(&e))?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().map_err(|e| codegen(&e))?;
+
+    // SAFETY: the function was declared with the `NativeFn` signature above.
+    let code = unsafe { std::mem::transmute::<*const u8, NativeFn>(module.get_finalized_function(id)) };
+    Ok(JitFunction { module: Some(module), code })
+}
+
+/// Emit IR for every instruction and return the value left on top of the stack
+fn translate(chunk: &Chunk, b: &mut FunctionBuilder, bail: Block) -> Result<Value, JitError> {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut end = 0;
+
+    for instr in chunk.instructions() {
+        end = instr.next_offset();
+        match instr.opcode {
+            OpCode::Push => {
+                let value = b.ins().f64const(constant(instr.operand)?);
+                push(&mut stack, value)?;
+            }
+            OpCode::Pop => {
+                pop(&mut stack)?;
+            }
+            OpCode::Dup => {
+                let top = *stack.last().ok_or_else(underflow)?;
+                push(&mut stack, top)?;
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div => {
+                let rhs = pop(&mut stack)?;
+                let lhs = pop(&mut stack)?;
+                let value = match instr.opcode {
+                    OpCode::Add => b.ins().fadd(lhs, rhs),
+                    OpCode::Sub => b.ins().fsub(lhs, rhs),
+                    OpCode::Mul => b.ins().fmul(lhs, rhs),
+                    _ => {
+                        let zero = b.ins().f64const(0.0);
+                        let is_zero = b.ins().fcmp(FloatCC::Equal, rhs, zero);
+                        bail_if(b, is_zero, bail);
+                        b.ins().fdiv(lhs, rhs)
+                    }
+                };
+                push(&mut stack, value)?;
+            }
+            OpCode::Neg | OpCode::Sqrt | OpCode::Abs | OpCode::Floor | OpCode::Ceil => {
+                let a = pop(&mut stack)?;
+                let value = match instr.opcode {
+                    OpCode::Neg => b.ins().fneg(a),
+                    OpCode::Abs => b.ins().fabs(a),
+                    OpCode::Floor => b.ins().floor(a),
+                    OpCode::Ceil => b.ins().ceil(a),
+                    _ => {
+                        let zero = b.ins().f64const(0.0);
+                        let negative = b.ins().fcmp(FloatCC::LessThan, a, zero);
+                        bail_if(b, negative, bail);
+                        b.ins().sqrt(a)
+                    }
+                };
+                push(&mut stack, value)?;
+            }
+            OpCode::PushAdd | OpCode::PushMul => {
+                let a = pop(&mut stack)?;
+                let c = b.ins().f64const(constant(instr.operand)?);
+                let value = match instr.opcode {
+                    OpCode::PushAdd => b.ins().fadd(a, c),
+                    _ => b.ins().fmul(a, c),
+                };
+                push(&mut stack, value)?;
+            }
+            OpCode::DupMul => {
+                let a = pop(&mut stack)?;
+                let value = b.ins().fmul(a, a);
+                push(&mut stack, value)?;
+            }
+            OpCode::Halt => return Ok(top_or_zero(b, &stack)),
+            op => return Err(JitError::UnsupportedOpcode(op)),
+        }
+    }
+
+    // Running off the end is a normal finish, unless decoding stopped early
+    if end < chunk.len() {
+        return Err(JitError::InvalidProgram(format!("Undecodable byte at 0x{:04X}", end)));
+    }
+    Ok(top_or_zero(b, &stack))
+}
+
+/// Branch to `bail` when `condition` holds, continuing in a fresh block otherwise
+fn bail_if(b: &mut FunctionBuilder, condition: Value, bail: Block) {
+    let next = b.create_block();
+    b.ins().brif(condition, bail, &[], next, &[]);
+    b.switch_to_block(next);
+    b.seal_block(next);
+}
+
+fn top_or_zero(b: &mut FunctionBuilder, stack: &[Value]) -> Value {
+    match stack.last() {
+        Some(&value) => value,
+        None => b.ins().f64const(0.0),
+    }
+}
+
+fn constant(operand: Operand) -> Result<f64, JitError> {
+    match operand {
+        Operand::Number(value) => Ok(value),
+        _ => Err(JitError::InvalidProgram("Instruction without constant".into())),
+    }
+}
+
+fn push(stack: &mut Vec<Value>, value: Value) -> Result<(), JitError> {
+    if stack.len() >= crate::vm::STACK_MAX {
+        return Err(JitError::InvalidProgram("Stack overflow".into()));
+    }
+    stack.push(value);
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, JitError> {
+    stack.pop().ok_or_else(underflow)
+}
+
+fn underflow() -> JitError {
+    JitError::InvalidProgram("Stack underflow".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::CodeGenerator;
+    use crate::optimizer::Optimizer;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+
+    fn compile_source(input: &str) -> Chunk {
+        let tokens = Tokenizer::new(input).tokenize().unwrap();
+        let ast = Parser::new(tokens).parse().unwrap();
+        CodeGenerator::new().compile(&ast).unwrap().0
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let chunk = compile_source("(1 + 2) * 3 - 4 / 8");
+        assert_eq!(compile(&chunk).unwrap().call(), Some(8.5));
+    }
+
+    #[test]
+    fn test_fused_and_unary() {
+        let chunk = Optimizer::fuse_superinstructions(&compile_source("sqrt(abs(-16)) * 2 + floor(2.5) + ceil(0.5)"));
+        assert_eq!(compile(&chunk).unwrap().call(), Some(11.0));
+    }
+
+    #[test]
+    fn test_runtime_errors_bail() {
+        assert_eq!(compile(&compile_source("1 / (2 - 2)")).unwrap().call(), None);
+        assert_eq!(compile(&compile_source("sqrt(0 - 1)")).unwrap().call(), None);
+    }
+
+    #[test]
+    fn test_unsupported_opcode() {
+        assert!(matches!(
+            compile(&compile_source("sin(90)")),
+            Err(JitError::UnsupportedOpcode(OpCode::Sin))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_program() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::Add);
+        assert!(matches!(compile(&chunk), Err(JitError::InvalidProgram(_))));
+    }
+}
@@ -0,0 +1,197 @@
+//! JavaScript API
+//!
+//! On wasm32 these are exported with wasm-bindgen, so a page can use the
+//! engine without the egui canvas:
+//!
+//!   cargo rustc --lib --release --target wasm32-unknown-unknown \
+//!       --no-default-features --features std --crate-type cdylib
+//!   wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/calculator.wasm
+//!
+//! ```js
+//! import init, { evaluate, Evaluator } from "./pkg/calculator.js";
+//! await init();
+//! evaluate("2^10");                 // 1024
+//! const calc = new Evaluator();
+//! calc.evaluate("f(x) = x^2");
+//! calc.evaluate("f(3)");            // 9
+//! ```
+//!
+//! Long computations can run in slices so the page stays responsive:
+//!
+//! ```js
+//! calc.start("sum(linspace(0, 1, 100000))");
+//! let result;
+//! while ((result = calc.resume(10000)) === undefined) {
+//!     await new Promise((resolve) => setTimeout(resolve));
+//! }
+//! ```
+//!
+//! Errors are thrown as strings. Elsewhere the module is plain Rust.
+
+use crate::tokenizer::{Token, Tokenizer};
+use crate::vm::Value;
+use crate::{CodeGenerator, Optimizer, Parser, VirtualMachine};
+use serde::Serialize;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Evaluate an expression
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn evaluate(input: &str) -> Result<f64, String> {
+    crate::evaluate(input)
+}
+
+/// Compile an expression and disassemble its bytecode
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn disassemble(input: &str) -> Result<String, String> {
+    crate::disassemble(input)
+}
+
+/// A token as `tokenize_json` reports it
+#[derive(Debug, Serialize)]
+struct JsonToken {
+    kind: &'static str,
+    text: String,
+    /// Character offsets, end exclusive
+    start: usize,
+    end: usize,
+}
+
+fn kind(token: &Token) -> &'static str {
+    match token {
+        Token::Number(_) => "number",
+        Token::Plus | Token::Minus | Token::Multiply | Token::Divide | Token::Power
+        | Token::Modulo | Token::Factorial | Token::Comma | Token::Assign
+        | Token::Semicolon | Token::Arrow => "operator",
+        Token::Let | Token::In => "keyword",
+        Token::LParen | Token::RParen | Token::LBracket | Token::RBracket | Token::Pipe => "bracket",
+        Token::Pi | Token::E | Token::Tau | Token::Phi => "constant",
+        Token::Identifier(_) => "identifier",
+        Token::Text(_) => "string",
+        _ => "function",
+    }
+}
+
+/// Tokenize an expression into a JSON array of `{kind, text, start, end}`
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn tokenize_json(input: &str) -> Result<String, String> {
+    let tokens = Tokenizer::new(input).tokenize_spanned().map_err(|e| e.to_string())?;
+    let tokens: Vec<JsonToken> = tokens
+        .into_iter()
+        .map(|(token, span)| JsonToken {
+            kind: kind(&token),
+            text: token.to_string(),
+            start: span.start,
+            end: span.end,
+        })
+        .collect();
+    serde_json::to_string(&tokens).map_err(|e| e.to_string())
+}
+
+/// An evaluator whose variables and functions carry over between calls
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub struct Evaluator {
+    vm: VirtualMachine,
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+impl Evaluator {
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(constructor))]
+    pub fn new() -> Self {
+        Evaluator {
+            vm: VirtualMachine::new(),
+        }
+    }
+
+    /// Evaluate one or more `;`-separated statements
+    pub fn evaluate(&mut self, input: &str) -> Result<f64, String> {
+        let tokens = Tokenizer::new(input).tokenize().map_err(|e| e.to_string())?;
+        let ast = Parser::new(tokens).parse().map_err(|e| e.to_string())?;
+        let chunk = Optimizer::fuse_superinstructions(&CodeGenerator::new().compile(&ast).map_err(|e| e.to_string())?.0);
+        self.vm.execute(&chunk).map_err(|e| e.to_string())
+    }
+
+    /// Compile statements and load them to be run by `resume`
+    pub fn start(&mut self, input: &str) -> Result<(), String> {
+        let tokens = Tokenizer::new(input).tokenize().map_err(|e| e.to_string())?;
+        let ast = Parser::new(tokens).parse().map_err(|e| e.to_string())?;
+        let chunk = Optimizer::fuse_superinstructions(&CodeGenerator::new().compile(&ast).map_err(|e| e.to_string())?.0);
+        self.vm.load(&chunk);
+        Ok(())
+    }
+
+    /// Run up to `steps` more instructions of the started statements,
+    /// returning the result once they finish and `undefined` until then
+    pub fn resume(&mut self, steps: usize) -> Result<Option<f64>, String> {
+        self.vm.run_for(steps).map_err(|e| e.to_string())
+    }
+
+    /// A scalar variable, or `undefined` if it is unset, an array, a function or a string
+    pub fn variable(&self, name: &str) -> Option<f64> {
+        match self.vm.global(name)? {
+            Value::Scalar(value) => Some(value),
+            Value::Array(_) | Value::Function(_) | Value::Text(_) => None,
+        }
+    }
+
+    /// Set a scalar variable
+    pub fn set_variable(&mut self, name: &str, value: f64) -> Result<(), String> {
+        self.vm.set_global(name, Value::Scalar(value)).map_err(|e| e.to_string())
+    }
+
+    /// Forget all variables and functions, and free the heap they used
+    pub fn clear(&mut self) {
+        self.vm.reset_heap();
+    }
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_json() {
+        assert_eq!(
+            tokenize_json("sin(x) + 2").unwrap(),
+            concat!(
+                r#"[{"kind":"function","text":"sin","start":0,"end":3},"#,
+                r#"{"kind":"bracket","text":"(","start":3,"end":4},"#,
+                r#"{"kind":"identifier","text":"x","start":4,"end":5},"#,
+                r#"{"kind":"bracket","text":")","start":5,"end":6},"#,
+                r#"{"kind":"operator","text":"+","start":7,"end":8},"#,
+                r#"{"kind":"number","text":"2","start":9,"end":10}]"#
+            )
+        );
+        assert!(tokenize_json("2 $ 3").is_err());
+    }
+
+    #[test]
+    fn test_evaluator() {
+        let mut evaluator = Evaluator::new();
+        evaluator.evaluate("f(x) = x^2; y = 3").unwrap();
+        assert_eq!(evaluator.evaluate("f(y)"), Ok(9.0));
+        evaluator.set_variable("y", 4.0).unwrap();
+        assert_eq!(evaluator.evaluate("f(y)"), Ok(16.0));
+        assert_eq!(evaluator.variable("y"), Some(4.0));
+        evaluator.clear();
+        assert_eq!(evaluator.variable("y"), None);
+        assert!(evaluator.evaluate("f(1)").is_err());
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut evaluator = Evaluator::new();
+        assert!(evaluator.resume(10).is_err());
+        evaluator.start("y = 2; y ^ 10").unwrap();
+        assert_eq!(evaluator.resume(3), Ok(None));
+        assert_eq!(evaluator.resume(100), Ok(Some(1024.0)));
+        assert_eq!(evaluator.variable("y"), Some(2.0));
+        assert!(evaluator.start("1 +").is_err());
+    }
+}
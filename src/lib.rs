@@ -20,28 +20,118 @@
 //!     0x1D: ADD
 //!     0x1E: HALT
 //!   Result: 9.0
+//!
+//! The GUI (`gui`, `theme` and the `calculator` binary) is behind the default
+//! `gui` feature; `default-features = false` leaves just the evaluator.
+//!
+//! The expression engine (tokenizer, parser, code generator, bytecode,
+//! optimizer, VM and its garbage-collected heap) needs only `alloc`: without
+//! the default `std` feature the crate is `no_std`, and the modules that need
+//! an operating system (export, sharing, translations, the JavaScript API)
+//! are left out along with VM timeouts and leak backtraces.
+//!
+//! With the `tracing` feature each stage runs in a `tracing` span (tokenize,
+//! parse, compile, execute, and every GC increment) and reports what it did
+//! in events, so slow or failing evaluations show up in whatever subscriber
+//! the embedder has installed.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate alloc;
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+/// The `alloc` names `std`'s prelude would provide
+mod prelude {
+    pub(crate) use alloc::boxed::Box;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec::Vec;
+    /// Float methods from libm, where `f64` has none (tests link `std`)
+    #[cfg(not(any(feature = "std", test)))]
+    pub(crate) use num_traits::Float;
+}
+
+/// A `tracing::debug!` event, compiled out without the `tracing` feature
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
 
+use prelude::*;
+
+pub mod allocator;
 pub mod ast;
+pub mod builtins;
 pub mod bytecode;
+pub mod bytefile;
 pub mod codegen;
 pub mod disassembler;
+#[cfg(feature = "std")]
+pub mod export;
+pub mod format;
 pub mod gc;
+#[cfg(feature = "gui")]
 pub mod gui;
+#[cfg(feature = "std")]
+pub mod i18n;
+pub mod intern;
+#[cfg(feature = "std")]
+pub mod js;
+#[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+pub mod jit;
+pub mod linalg;
+pub mod linker;
 pub mod memory;
+pub mod optimizer;
 pub mod parser;
+#[cfg(feature = "std")]
+pub mod pool;
+pub mod register;
+pub mod sandbox;
+#[cfg(feature = "std")]
+pub mod share;
+pub mod stats;
+pub mod symbolic;
+#[cfg(feature = "gui")]
+pub mod theme;
 pub mod tokenizer;
 pub mod vm;
 
 pub use ast::{BinaryOp, Expr, UnaryOp};
-pub use bytecode::{Chunk, OpCode};
-pub use codegen::CodeGenerator;
+pub use bytecode::{Chunk, ChunkMetadata, Function, OpCode, PoolStats, SourceMap};
+pub use bytefile::LoadError;
+pub use codegen::{Backend, CodeGenerator, CompileError, Diagnostic, Program};
 pub use disassembler::Disassembler;
-pub use gc::GarbageCollector;
+#[cfg(feature = "std")]
+pub use export::{ExportFormat, Exporter, HistoryEntry};
+pub use format::{as_fraction, to_base, Notation, NumberFormat};
+pub use gc::{GarbageCollector, GcPhase};
+#[cfg(feature = "gui")]
 pub use gui::CalculatorApp;
+#[cfg(feature = "std")]
+pub use i18n::Language;
+pub use intern::Symbol;
+pub use linker::{LinkError, LinkedProgram, Linker};
 pub use memory::MemoryManager;
+pub use optimizer::Optimizer;
 pub use parser::Parser;
-pub use tokenizer::Tokenizer;
-pub use vm::VirtualMachine;
+#[cfg(feature = "std")]
+pub use pool::EvaluatorPool;
+pub use register::RegisterChunk;
+pub use sandbox::{evaluate_sandboxed, Limits};
+#[cfg(feature = "std")]
+pub use share::SharedLink;
+pub use tokenizer::{ENotation, Tokenizer};
+pub use vm::{
+    CancellationToken, Closure, Divergence, HeapKind, HeapObject, HookEvent, HookPhase, HostContext, Steps, Value, ValueRef,
+    VirtualMachine,
+};
 
 /// Evaluate an expression string and return the result
 pub fn evaluate(input: &str) -> Result<f64, String> {
@@ -54,13 +144,62 @@ pub fn evaluate(input: &str) -> Result<f64, String> {
     let ast = parser.parse().map_err(|e| e.to_string())?;
 
     // Compile
-    let chunk = CodeGenerator::new().compile(&ast);
+    let (chunk, _) = CodeGenerator::new().compile(&ast).map_err(|e| e.to_string())?;
+    let chunk = Optimizer::fuse_superinstructions(&chunk);
 
     // Execute
     let mut vm = VirtualMachine::new();
     vm.execute(&chunk).map_err(|e| e.to_string())
 }
 
+/// Time an evaluation spent in each stage; stages it didn't reach are zero
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    pub tokenize: core::time::Duration,
+    pub parse: core::time::Duration,
+    /// Code generation and superinstruction fusion
+    pub compile: core::time::Duration,
+    pub execute: core::time::Duration,
+}
+
+#[cfg(feature = "std")]
+impl PhaseTimings {
+    pub fn total(&self) -> core::time::Duration {
+        self.tokenize + self.parse + self.compile + self.execute
+    }
+}
+
+/// `evaluate`, also reporting how long each stage took
+#[cfg(feature = "std")]
+pub fn evaluate_timed(input: &str) -> (Result<f64, String>, PhaseTimings) {
+    /// Run `stage`, adding the time it took to `phase`
+    fn timed<T>(phase: &mut core::time::Duration, stage: impl FnOnce() -> T) -> T {
+        let started = web_time::Instant::now();
+        let result = stage();
+        *phase += started.elapsed();
+        result
+    }
+
+    let mut timings = PhaseTimings::default();
+    let result = (|| {
+        let tokens = timed(&mut timings.tokenize, || Tokenizer::new(input).tokenize()).map_err(|e| e.to_string())?;
+        let ast = timed(&mut timings.parse, || Parser::new(tokens).parse()).map_err(|e| e.to_string())?;
+        let chunk = timed(&mut timings.compile, || {
+            let (chunk, _) = CodeGenerator::new().compile(&ast)?;
+            Ok(Optimizer::fuse_superinstructions(&chunk))
+        })
+        .map_err(|e: CompileError| e.to_string())?;
+        timed(&mut timings.execute, || VirtualMachine::new().execute(&chunk)).map_err(|e| e.to_string())
+    })();
+    (result, timings)
+}
+
+/// Evaluate an expression string and format the result with `format`
+pub fn evaluate_formatted(input: &str, format: &NumberFormat) -> Result<String, String> {
+    evaluate(input).map(|value| format.format_result(value))
+}
+
 /// Compile and disassemble an expression
 pub fn disassemble(input: &str) -> Result<String, String> {
     // Tokenize
@@ -72,8 +211,26 @@ pub fn disassemble(input: &str) -> Result<String, String> {
     let ast = parser.parse().map_err(|e| e.to_string())?;
 
     // Compile
-    let chunk = CodeGenerator::new().compile(&ast);
+    let (chunk, _) = CodeGenerator::new().with_source(input).compile(&ast).map_err(|e| e.to_string())?;
 
     // Disassemble
     Ok(Disassembler::format_with_hex(&chunk))
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::time::Duration;
+
+    #[test]
+    fn test_evaluate_timed() {
+        let (result, timings) = evaluate_timed("sum([1, 2, 3]) * 2");
+        assert_eq!(result, Ok(12.0));
+        assert_eq!(timings.total(), timings.tokenize + timings.parse + timings.compile + timings.execute);
+
+        // Stages after a failing one take no time
+        let (result, timings) = evaluate_timed("1 +");
+        assert!(result.is_err());
+        assert_eq!((timings.compile, timings.execute), (Duration::ZERO, Duration::ZERO));
+    }
+}
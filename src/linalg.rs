@@ -0,0 +1,240 @@
+//! Dense linear algebra for the matrix builtins
+//!
+//! Arrays have no shape, so a matrix is an array of its rows laid end to
+//! end: the nested literal `[[2, 1], [1, 3]]` is the array `[2, 1, 1, 3]`,
+//! and the size of a square matrix follows from its length.
+
+use crate::prelude::*;
+use crate::vm::VmError;
+
+/// Pivots smaller than this, relative to the largest entry, count as zero
+const SINGULAR_TOLERANCE: f64 = 1e-12;
+
+/// Solve `a x = b` for x by Gaussian elimination with partial pivoting
+///
+/// `a` is the n×n matrix for the n values of `b`.
+pub fn solve(a: &[f64], b: &[f64]) -> Result<Vec<f64>, VmError> {
+    let n = b.len();
+    if n == 0 || a.len() != n * n {
+        return Err(VmError::MathError(format!(
+            "solve needs a {0}x{0} matrix for {0} values, got {1} elements",
+            n,
+            a.len()
+        )));
+    }
+    let mut m = a.to_vec();
+    let mut x = b.to_vec();
+    let scale = m.iter().fold(0.0f64, |max, v| max.max(v.abs()));
+
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| m[i * n + col].abs().total_cmp(&m[j * n + col].abs()))
+            .unwrap_or(col);
+        let largest = m[pivot * n + col].abs();
+        if largest.is_nan() || largest <= scale * SINGULAR_TOLERANCE {
+            return Err(VmError::SingularMatrix);
+        }
+        if pivot != col {
+            for k in 0..n {
+                m.swap(pivot * n + k, col * n + k);
+            }
+            x.swap(pivot, col);
+        }
+        for row in col + 1..n {
+            let factor = m[row * n + col] / m[col * n + col];
+            for k in col..n {
+                m[row * n + k] -= factor * m[col * n + k];
+            }
+            x[row] -= factor * x[col];
+        }
+    }
+
+    // Back substitution
+    for row in (0..n).rev() {
+        let known: f64 = (row + 1..n).map(|k| m[row * n + k] * x[k]).sum();
+        x[row] = (x[row] - known) / m[row * n + row];
+    }
+    Ok(x)
+}
+
+/// Give up on QR iteration after this many steps per eigenvalue
+const MAX_QR_STEPS: usize = 100;
+
+/// Eigenvalues of the square matrix `m`, ascending
+///
+/// The matrix is reduced to Hessenberg form and then to triangular form by
+/// shifted QR steps; a 2x2 block that splits off is solved directly.
+/// Complex eigenvalues are an error, since arrays hold reals only.
+pub fn eigvals(m: &[f64]) -> Result<Vec<f64>, VmError> {
+    let n = (m.len() as f64).sqrt() as usize;
+    if n == 0 || n * n != m.len() {
+        return Err(VmError::MathError(format!("eigvals needs a square matrix, got {} elements", m.len())));
+    }
+    if m.iter().any(|v| !v.is_finite()) {
+        return Err(VmError::MathError("eigvals needs finite entries".into()));
+    }
+    let mut h = m.to_vec();
+    hessenberg(&mut h, n);
+
+    let mut values = Vec::with_capacity(n);
+    let mut size = n;
+    let mut steps = 0;
+    while size > 0 {
+        let at = |row: usize, col: usize| h[row * n + col];
+        let negligible = |row: usize| {
+            at(row, row - 1).abs() <= f64::EPSILON * (at(row, row).abs() + at(row - 1, row - 1).abs())
+        };
+        if size == 1 || negligible(size - 1) {
+            values.push(at(size - 1, size - 1));
+            size -= 1;
+            steps = 0;
+            continue;
+        }
+        if size == 2 || negligible(size - 2) {
+            let (low, high) = eigvals_2x2(
+                at(size - 2, size - 2),
+                at(size - 2, size - 1),
+                at(size - 1, size - 2),
+                at(size - 1, size - 1),
+            )
+            .ok_or_else(|| VmError::MathError("eigvals found complex eigenvalues".into()))?;
+            values.extend([low, high]);
+            size -= 2;
+            steps = 0;
+            continue;
+        }
+        steps += 1;
+        if steps > MAX_QR_STEPS {
+            return Err(VmError::MathError("eigvals did not converge".into()));
+        }
+        // Wilkinson shift, with an occasional kick if the iteration stalls
+        let last = at(size - 1, size - 1);
+        let shift = if steps % 10 == 0 {
+            last + at(size - 1, size - 2).abs()
+        } else {
+            match eigvals_2x2(at(size - 2, size - 2), at(size - 2, size - 1), at(size - 1, size - 2), last) {
+                Some((low, high)) if (low - last).abs() < (high - last).abs() => low,
+                Some((_, high)) => high,
+                None => last,
+            }
+        };
+        qr_step(&mut h, n, size, shift);
+    }
+    values.sort_by(f64::total_cmp);
+    Ok(values)
+}
+
+/// Real eigenvalues of [[a, b], [c, d]], smaller first
+fn eigvals_2x2(a: f64, b: f64, c: f64, d: f64) -> Option<(f64, f64)> {
+    let mid = (a + d) / 2.0;
+    let discriminant = (a - d) * (a - d) / 4.0 + b * c;
+    // Rounding can push a double eigenvalue's discriminant just below zero
+    let tolerance = f64::EPSILON * (a.abs() + b.abs() + c.abs() + d.abs()).powi(2);
+    if discriminant < -tolerance {
+        return None;
+    }
+    let root = discriminant.max(0.0).sqrt();
+    Some((mid - root, mid + root))
+}
+
+/// Reduce the n×n matrix `h` to upper Hessenberg form by Householder
+/// reflections, which keeps its eigenvalues
+fn hessenberg(h: &mut [f64], n: usize) {
+    for k in 0..n.saturating_sub(2) {
+        let mut v: Vec<f64> = (k + 1..n).map(|row| h[row * n + k]).collect();
+        let norm = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm == 0.0 {
+            continue;
+        }
+        v[0] += if v[0] < 0.0 { -norm } else { norm };
+        let length = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        v.iter_mut().for_each(|x| *x /= length);
+
+        // h = (I - 2vv^T) h (I - 2vv^T), acting on rows and columns k+1..
+        for col in 0..n {
+            let dot: f64 = v.iter().enumerate().map(|(i, x)| x * h[(k + 1 + i) * n + col]).sum();
+            for (i, x) in v.iter().enumerate() {
+                h[(k + 1 + i) * n + col] -= 2.0 * x * dot;
+            }
+        }
+        for row in 0..n {
+            let dot: f64 = v.iter().enumerate().map(|(i, x)| x * h[row * n + k + 1 + i]).sum();
+            for (i, x) in v.iter().enumerate() {
+                h[row * n + k + 1 + i] -= 2.0 * x * dot;
+            }
+        }
+    }
+}
+
+/// One shifted QR step on the leading `size`×`size` block of the Hessenberg
+/// matrix `h`: factor h - shift·I = QR by Givens rotations, then form RQ + shift·I
+fn qr_step(h: &mut [f64], n: usize, size: usize, shift: f64) {
+    for i in 0..size {
+        h[i * n + i] -= shift;
+    }
+    let mut rotations = Vec::with_capacity(size - 1);
+    for i in 0..size - 1 {
+        let (a, b) = (h[i * n + i], h[(i + 1) * n + i]);
+        let r = a.hypot(b);
+        let (c, s) = if r == 0.0 { (1.0, 0.0) } else { (a / r, b / r) };
+        for col in i..size {
+            let (top, bottom) = (h[i * n + col], h[(i + 1) * n + col]);
+            h[i * n + col] = c * top + s * bottom;
+            h[(i + 1) * n + col] = c * bottom - s * top;
+        }
+        rotations.push((c, s));
+    }
+    for (i, (c, s)) in rotations.into_iter().enumerate() {
+        for row in 0..(i + 2).min(size) {
+            let (left, right) = (h[row * n + i], h[row * n + i + 1]);
+            h[row * n + i] = c * left + s * right;
+            h[row * n + i + 1] = c * right - s * left;
+        }
+    }
+    for i in 0..size {
+        h[i * n + i] += shift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve() {
+        let x = solve(&[2.0, 1.0, 1.0, 3.0], &[3.0, 5.0]).unwrap();
+        assert!((x[0] - 0.8).abs() < 1e-12 && (x[1] - 1.4).abs() < 1e-12, "{:?}", x);
+
+        // Needs a row swap: the first pivot is zero
+        let x = solve(&[0.0, 1.0, 1.0, 0.0], &[4.0, 7.0]).unwrap();
+        assert_eq!(x, [7.0, 4.0]);
+
+        assert!(matches!(solve(&[1.0, 2.0, 2.0, 4.0], &[1.0, 2.0]), Err(VmError::SingularMatrix)));
+        assert!(matches!(solve(&[0.0; 4], &[1.0, 2.0]), Err(VmError::SingularMatrix)));
+        assert!(matches!(solve(&[1.0, 2.0, 3.0], &[1.0, 2.0]), Err(VmError::MathError(_))));
+    }
+
+    #[test]
+    fn test_eigvals() {
+        let close = |actual: Vec<f64>, expected: &[f64]| {
+            actual.len() == expected.len() && actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-9)
+        };
+        assert!(close(eigvals(&[2.0, 1.0, 1.0, 2.0]).unwrap(), &[1.0, 3.0]));
+        assert!(close(eigvals(&[5.0]).unwrap(), &[5.0]));
+        // Triangular, non-symmetric, and with a repeated eigenvalue
+        assert!(close(eigvals(&[1.0, 7.0, 0.0, 1.0]).unwrap(), &[1.0, 1.0]));
+        let m = [2.0, -1.0, 0.0, -1.0, 2.0, -1.0, 0.0, -1.0, 2.0];
+        let r = 2f64.sqrt();
+        assert!(close(eigvals(&m).unwrap(), &[2.0 - r, 2.0, 2.0 + r]));
+        let m = [2.0, 0.0, 0.0, 1.0, 3.0, 0.0, 4.0, 5.0, 6.0];
+        assert!(close(eigvals(&m).unwrap(), &[2.0, 3.0, 6.0]));
+        let m = [4.0, 1.0, 2.0, 0.0, 1.0, 5.0, 1.0, 2.0, 2.0, 1.0, 3.0, 1.0, 0.0, 2.0, 1.0, 6.0];
+        let values = eigvals(&m).unwrap();
+        // The eigenvalues sum to the trace
+        assert!((values.iter().sum::<f64>() - 18.0).abs() < 1e-9, "{:?}", values);
+
+        // A rotation has eigenvalues ±i
+        assert!(eigvals(&[0.0, -1.0, 1.0, 0.0]).is_err());
+        assert!(eigvals(&[1.0, 2.0, 3.0]).is_err());
+    }
+}
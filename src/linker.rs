@@ -0,0 +1,222 @@
+//! Linker - Combines separately compiled chunks into one program
+//!
+//! A chunk only knows the functions it defines itself, bound by DEF_FUNC as
+//! it runs, and looks every call up by name at run time. The linker takes a
+//! main chunk and library chunks of definitions, gathers every function
+//! they define into a symbol table, and checks each CALL against it before
+//! anything runs, so a misspelt name or a wrong argument count is reported
+//! up front. `VirtualMachine::execute_linked` binds the whole table and then
+//! runs the main chunk.
+//!
+//! Only a library's function table is used; its own code never runs.
+
+use crate::prelude::*;
+use crate::bytecode::{Chunk, Function, Operand};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use core::fmt;
+
+/// Why chunks couldn't be linked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// Two chunks define a function of the same name
+    DuplicateSymbol(String),
+    /// A call of a function that nothing defines
+    UndefinedSymbol { name: String, caller: String },
+    /// A call with the wrong number of arguments
+    ArityMismatch { name: String, caller: String, expected: usize, found: usize },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol(name) => write!(f, "Function {} is defined by more than one chunk", name),
+            LinkError::UndefinedSymbol { name, caller } => {
+                write!(f, "Undefined function {} called from {}", name, caller)
+            }
+            LinkError::ArityMismatch { name, caller, expected, found } => write!(
+                f,
+                "{} expects {} arguments, got {} in {}",
+                name, expected, found, caller
+            ),
+        }
+    }
+}
+
+/// A main chunk with every function it can call
+#[derive(Debug, Clone)]
+pub struct LinkedProgram {
+    main: Chunk,
+    /// Functions by name
+    symbols: BTreeMap<String, Arc<Function>>,
+}
+
+impl LinkedProgram {
+    /// Program of `main` and `functions` as they are, without checking calls
+    pub(crate) fn from_parts(main: Chunk, functions: Vec<Arc<Function>>) -> Self {
+        let symbols = functions.into_iter().map(|function| (function.name.clone(), function)).collect();
+        LinkedProgram { main, symbols }
+    }
+
+    pub fn main(&self) -> &Chunk {
+        &self.main
+    }
+
+    /// The function `name`
+    pub fn symbol(&self, name: &str) -> Option<&Arc<Function>> {
+        self.symbols.get(name)
+    }
+
+    /// All functions, sorted by name
+    pub fn symbols(&self) -> impl Iterator<Item = &Arc<Function>> + '_ {
+        self.symbols.values()
+    }
+}
+
+/// Builds a `LinkedProgram` from a main chunk and libraries
+#[derive(Debug, Clone)]
+pub struct Linker {
+    main: Chunk,
+    libraries: Vec<Chunk>,
+    /// Names left for a `HostContext` to provide
+    externals: BTreeSet<String>,
+}
+
+impl Linker {
+    pub fn new(main: Chunk) -> Self {
+        Linker {
+            main,
+            libraries: Vec::new(),
+            externals: BTreeSet::new(),
+        }
+    }
+
+    /// Make the functions `library` defines callable from the program
+    pub fn with_library(mut self, library: Chunk) -> Self {
+        self.libraries.push(library);
+        self
+    }
+
+    /// Allow calls of `name`, which the host will provide at run time
+    pub fn with_external(mut self, name: impl Into<String>) -> Self {
+        self.externals.insert(name.into());
+        self
+    }
+
+    /// Gather the symbol table and resolve every call against it
+    pub fn link(self) -> Result<LinkedProgram, LinkError> {
+        let mut symbols: BTreeMap<String, Arc<Function>> = BTreeMap::new();
+        for chunk in core::iter::once(&self.main).chain(&self.libraries) {
+            // A chunk may redefine its own functions, the last one winning as it would at run time
+            let mut own: BTreeMap<String, Arc<Function>> = BTreeMap::new();
+            collect_functions(chunk, &mut own);
+            for (name, function) in own {
+                if symbols.contains_key(&name) {
+                    return Err(LinkError::DuplicateSymbol(name));
+                }
+                symbols.insert(name, function);
+            }
+        }
+
+        self.check_calls(&self.main, "main", &symbols)?;
+        for function in symbols.values() {
+            self.check_calls(&function.chunk, &function.name, &symbols)?;
+        }
+        Ok(LinkedProgram {
+            main: self.main,
+            symbols,
+        })
+    }
+
+    /// Check the calls `chunk` makes, reporting them as made from `caller`
+    fn check_calls(
+        &self,
+        chunk: &Chunk,
+        caller: &str,
+        symbols: &BTreeMap<String, Arc<Function>>,
+    ) -> Result<(), LinkError> {
+        for instruction in chunk.instructions() {
+            let Operand::Call { name, argc } = instruction.operand else {
+                continue;
+            };
+            let name = chunk.name(name).unwrap_or_default();
+            match symbols.get(name) {
+                Some(function) if function.arity() != argc as usize => {
+                    return Err(LinkError::ArityMismatch {
+                        name: name.to_string(),
+                        caller: caller.to_string(),
+                        expected: function.arity(),
+                        found: argc as usize,
+                    });
+                }
+                Some(_) => {}
+                None if self.externals.contains(name) => {}
+                None => {
+                    return Err(LinkError::UndefinedSymbol {
+                        name: name.to_string(),
+                        caller: caller.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Functions `chunk` defines, including those defined inside their bodies
+fn collect_functions(chunk: &Chunk, functions: &mut BTreeMap<String, Arc<Function>>) {
+    for function in chunk.functions() {
+        // Lambdas are values, never called by name
+        if !function.is_lambda() {
+            functions.insert(function.name.clone(), function.clone());
+        }
+        collect_functions(&function.chunk, functions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CodeGenerator, Parser, Tokenizer, VirtualMachine};
+
+    fn compile(input: &str) -> Chunk {
+        let ast = Parser::new(Tokenizer::new(input).tokenize().unwrap()).parse().unwrap();
+        CodeGenerator::new().with_source(input).compile(&ast).unwrap().0
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let library = compile("square(x) = x * x; hyp(a, b) = sqrt(square(a) + square(b))");
+        let program = Linker::new(compile("twice(x) = 2 * x; hyp(3, 4) + twice(1)"))
+            .with_library(library)
+            .link()
+            .unwrap();
+        let names: Vec<_> = program.symbols().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["hyp", "square", "twice"]);
+        assert_eq!(program.symbol("hyp").unwrap().arity(), 2);
+
+        // The library's functions are bound before main runs
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(program.main()).map_err(|e| e.to_string()), Err("Undefined function: hyp".to_string()));
+        assert_eq!(vm.execute_linked(&program).unwrap(), 7.0);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let error = Linker::new(compile("f(1)")).link().unwrap_err();
+        assert_eq!(error, LinkError::UndefinedSymbol { name: "f".into(), caller: "main".into() });
+        assert_eq!(error.to_string(), "Undefined function f called from main");
+
+        let error = Linker::new(compile("g(1, 2)")).with_library(compile("g(x) = x")).link().unwrap_err();
+        assert_eq!(error.to_string(), "g expects 1 arguments, got 2 in main");
+
+        let error = Linker::new(compile("1")).with_library(compile("h(x) = missing(x)")).link().unwrap_err();
+        assert_eq!(error, LinkError::UndefinedSymbol { name: "missing".into(), caller: "h".into() });
+
+        let error = Linker::new(compile("f(x) = x; f(1)")).with_library(compile("f(y) = y")).link().unwrap_err();
+        assert_eq!(error, LinkError::DuplicateSymbol("f".into()));
+
+        // Redefining within one chunk is fine, and externals are left to the host
+        assert!(Linker::new(compile("f(x) = x; f(x) = 2 * x; f(host(1))")).with_external("host").link().is_ok());
+    }
+}
@@ -5,6 +5,7 @@
 
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use calculator::gui::APP_NAME;
 use calculator::CalculatorApp;
 
 // Native entry point
@@ -21,7 +22,7 @@ fn main() -> eframe::Result<()> {
     };
 
     eframe::run_native(
-        "Bytecode Calculator",
+        APP_NAME,
         native_options,
         Box::new(|cc| Ok(Box::new(CalculatorApp::new(cc)))),
     )
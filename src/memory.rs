@@ -4,17 +4,287 @@
 //!   - Arena-based allocation for efficient memory management
 //!   - Object tracking for garbage collection
 //!   - Memory statistics and monitoring
+//!
+//! Objects are bump-allocated out of fixed-size blocks. Every object starts
+//! with a `BlockHeader`, so a block can be walked object by object during
+//! marking and sweeping. Swept objects go onto a free list for their size
+//! class, linked through their payloads, and are handed out again before
+//! the bump pointer advances.
+//!
+//! A header is the payload size with the mark and other flags packed into
+//! its low bits, and a generation: 16 bytes, or 8 with the
+//! `compact-headers` feature, which limits objects to 256 MiB. Payloads
+//! are 8-byte aligned unless `allocate_aligned` asks for more; the padding
+//! that takes is zeroed, and as a header's first word never is, walking a
+//! block steps over it.
+//! Objects too big for a block get a dedicated block that is returned to
+//! the system as soon as the object dies.
+//!
+//! Blocks come from an `Allocator`, the global allocator unless the manager
+//! is built `with_allocator`; see the `allocator` module.
+//!
+//! An allocation may carry a finalizer, run with the object's data pointer
+//! when the object is swept or when the manager itself is dropped.
+//!
+//! When nothing on the heap is needed any more, `reset` frees it all at once,
+//! block by block, instead of sweeping it object by object.
+//!
+//! To catch use after free during development, debug builds fill freed
+//! payloads with `POISON`, and `enable_quarantine` holds freed slots back
+//! from reuse for a while, so a stale `GcValue` finds its slot still free
+//! and poisoned rather than handed to another object.
+//!
+//! With site tracking on, every allocation is tagged with the site the
+//! caller set last (an opcode or a subsystem, see `set_site`), and
+//! `MemoryStats::sites` counts objects and bytes by site.
+//!
+//! In leak-detection mode every live allocation remembers its call site
+//! (and, with the `std` feature, a backtrace when `RUST_BACKTRACE` is set); `leak_report` lists what
+//! is still alive, and the report is logged if anything is left at drop.
+
+use crate::prelude::*;
+use crate::allocator::{Allocator, Global};
+use alloc::alloc::Layout;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::rc::Rc;
+use core::cell::Cell;
+use core::fmt::Write;
+use core::panic::Location;
+use core::ptr::NonNull;
+#[cfg(feature = "std")]
+use std::backtrace::{Backtrace, BacktraceStatus};
+
+/// Cleanup callback run with an object's data pointer before its memory is reused
+pub type Finalizer = Box<dyn FnOnce(NonNull<u8>)>;
 
-use std::alloc::{alloc, dealloc, Layout};
-use std::cell::Cell;
-use std::ptr::NonNull;
+/// Size of a regular arena block
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Alignment of every object header and payload, unless more is asked for
+const MIN_ALIGN: usize = 8;
+
+/// First word of a header: the requested payload size above the flag bits
+#[cfg(feature = "compact-headers")]
+type Word = u32;
+#[cfg(not(feature = "compact-headers"))]
+type Word = usize;
+
+/// Object is reachable in the current cycle
+const MARKED: Word = 1;
+/// Slot is on a free list rather than holding a live object
+const FREE: Word = 1 << 1;
+/// Object has an entry in the finalizer table
+const FINALIZER: Word = 1 << 2;
+/// Always set, so a header's first word is never zero like padding is
+const PRESENT: Word = 1 << 3;
+const FLAG_BITS: u32 = 4;
+
+/// Byte freed payloads are filled with in debug builds
+pub const POISON: u8 = 0xDB;
+
+/// Largest payload a header can record
+#[allow(clippy::unnecessary_cast)] // `Word` is only `usize` without compact headers
+const MAX_SIZE: usize = (Word::MAX >> FLAG_BITS) as usize;
 
 /// Memory block header for tracking allocations
-#[repr(C)]
+#[repr(C, align(8))]
 struct BlockHeader {
+    /// Requested payload size, shifted past the flags
+    bits: Cell<Word>,
+    /// Bumped every time the slot is freed, so stale handles can be detected
+    generation: Cell<u32>,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<BlockHeader>();
+
+impl BlockHeader {
+    fn new(size: usize, flags: Word, generation: u32) -> Self {
+        BlockHeader {
+            bits: Cell::new((size as Word) << FLAG_BITS | flags | PRESENT),
+            generation: Cell::new(generation),
+        }
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn size(&self) -> usize {
+        (self.bits.get() >> FLAG_BITS) as usize
+    }
+
+    /// Payload bytes available in this slot, fixed for the slot's lifetime
+    /// as free lists only hand a slot out again for the same capacity
+    fn capacity(&self) -> usize {
+        payload_size(self.size())
+    }
+
+    fn is(&self, flag: Word) -> bool {
+        self.bits.get() & flag != 0
+    }
+
+    fn set(&self, flag: Word, on: bool) {
+        let bits = self.bits.get();
+        self.bits.set(if on { bits | flag } else { bits & !flag });
+    }
+
+    /// The payload following `header`
+    fn data(header: NonNull<BlockHeader>) -> NonNull<u8> {
+        // SAFETY: every header is followed by its payload within the block
+        unsafe { NonNull::new_unchecked((header.as_ptr() as *mut u8).add(HEADER_SIZE)) }
+    }
+
+    /// Header of the payload at `data`
+    ///
+    /// # Safety
+    /// `data` must have been returned by `MemoryManager::allocate`.
+    unsafe fn of<'a>(data: NonNull<u8>) -> &'a BlockHeader {
+        &*(data.as_ptr().sub(HEADER_SIZE) as *const BlockHeader)
+    }
+
+    /// Next slot of a free list, kept in a free slot's payload
+    ///
+    /// # Safety
+    /// `header` must be on a free list.
+    unsafe fn next_free(header: NonNull<BlockHeader>) -> Option<NonNull<BlockHeader>> {
+        (Self::data(header).as_ptr() as *const Option<NonNull<BlockHeader>>).read()
+    }
+}
+
+/// Payload bytes a slot needs for `size` requested, at least room for a
+/// free-list link
+const fn payload_size(size: usize) -> usize {
+    let size = if size == 0 { 1 } else { size };
+    (size + MIN_ALIGN - 1) & !(MIN_ALIGN - 1)
+}
+
+/// A region of memory objects are carved out of
+struct Block {
+    start: NonNull<u8>,
     size: usize,
-    marked: Cell<bool>,
-    next: Option<NonNull<BlockHeader>>,
+    /// Bump offset: bytes already handed out
+    used: usize,
+    /// Where the block came from and goes back to
+    allocator: Rc<dyn Allocator>,
+}
+
+impl Block {
+    fn new(size: usize, allocator: &Rc<dyn Allocator>) -> Option<Self> {
+        let layout = Layout::from_size_align(size, MIN_ALIGN).ok()?;
+        let start = allocator.allocate(layout)?;
+        Some(Block {
+            start,
+            size,
+            used: 0,
+            allocator: allocator.clone(),
+        })
+    }
+
+    /// Carve a slot of `slot_size` bytes off the unused tail, zeroing the
+    /// padding before it that aligns its payload to `align`
+    fn bump(&mut self, slot_size: usize, align: usize) -> Option<NonNull<BlockHeader>> {
+        let payload = self.start.as_ptr() as usize + self.used + HEADER_SIZE;
+        let padding = payload.next_multiple_of(align) - payload;
+        if self.size - self.used < padding + slot_size {
+            return None;
+        }
+        // SAFETY: padding and slot stay within the block
+        unsafe {
+            let ptr = self.start.as_ptr().add(self.used);
+            ptr.write_bytes(0, padding);
+            self.used += padding + slot_size;
+            NonNull::new(ptr.add(padding) as *mut BlockHeader)
+        }
+    }
+
+    /// The first header at or after `offset`, skipping padding, and the
+    /// offset just past its slot
+    fn header_from(&self, mut offset: usize) -> Option<(NonNull<BlockHeader>, usize)> {
+        while offset < self.used {
+            // SAFETY: below `used`, every aligned word is a header's first
+            // word, part of a payload skipped over, or zeroed padding
+            let ptr = unsafe { self.start.as_ptr().add(offset) };
+            if unsafe { (ptr as *const Word).read() } != 0 {
+                let header = unsafe { NonNull::new_unchecked(ptr as *mut BlockHeader) };
+                return Some((header, offset + HEADER_SIZE + unsafe { header.as_ref() }.capacity()));
+            }
+            offset += MIN_ALIGN;
+        }
+        None
+    }
+
+    /// Headers of all slots handed out so far, in address order
+    fn headers(&self) -> impl Iterator<Item = NonNull<BlockHeader>> + '_ {
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let (header, next) = self.header_from(offset)?;
+            offset = next;
+            Some(header)
+        })
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `Block::new` with the same layout
+        unsafe { self.allocator.deallocate(self.start, Layout::from_size_align_unchecked(self.size, MIN_ALIGN)) };
+    }
+}
+
+/// Origin of a live allocation, recorded in leak-detection mode
+#[derive(Debug)]
+pub struct AllocationRecord {
+    /// Requested payload size
+    pub size: usize,
+    /// Source location that requested the allocation
+    pub site: &'static Location<'static>,
+    /// Captured only when backtraces are enabled through `RUST_BACKTRACE`
+    #[cfg(feature = "std")]
+    pub backtrace: Backtrace,
+}
+
+impl AllocationRecord {
+    /// Append the backtrace, if one was captured
+    #[cfg(feature = "std")]
+    fn write_backtrace(&self, out: &mut String) {
+        if self.backtrace.status() == BacktraceStatus::Captured {
+            let _ = writeln!(out, "{}", self.backtrace);
+        }
+    }
+
+    /// Backtraces need `std`
+    #[cfg(not(feature = "std"))]
+    fn write_backtrace(&self, _out: &mut String) {}
+}
+
+/// Site of allocations made before any `set_site`
+pub const DEFAULT_SITE: &str = "other";
+
+/// Allocations made from one site, as `MemoryStats::sites` counts them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SiteStats {
+    /// Allocations ever made from the site
+    pub allocations: usize,
+    /// Objects from the site still alive
+    pub objects: usize,
+    /// Bytes those objects take, headers included
+    pub bytes: usize,
+}
+
+/// Number of buckets in the allocation size histogram
+pub const SIZE_CLASS_COUNT: usize = 12;
+
+/// Upper bound (inclusive) of the smallest histogram bucket; each next bucket doubles it
+const SMALLEST_SIZE_CLASS: usize = 16;
+
+/// A live object, as `MemoryManager::objects` lists it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// The object's data, as `allocate` returned it
+    pub ptr: NonNull<u8>,
+    /// Requested payload size
+    pub size: usize,
+    /// Bytes its slot takes, header and padding included
+    pub footprint: usize,
+    /// A finalizer will run when it is freed
+    pub finalizer: bool,
 }
 
 /// Statistics about memory usage
@@ -26,9 +296,44 @@ pub struct MemoryStats {
     pub peak_usage: usize,
     pub allocation_count: usize,
     pub deallocation_count: usize,
+    /// Bytes reserved from the system for arena blocks
+    pub reserved: usize,
+    /// Allocations served from a free list instead of fresh block space
+    pub reused_count: usize,
+    /// Finalizers that have run
+    pub finalized_count: usize,
+    /// Allocation counts by requested size, in power-of-two buckets
+    ///
+    /// Bucket `i` holds sizes up to `16 << i` bytes; the last one also
+    /// collects everything larger. See `size_class_label`.
+    pub size_classes: [usize; SIZE_CLASS_COUNT],
+    /// Allocations by site, while site tracking is on
+    pub sites: BTreeMap<&'static str, SiteStats>,
 }
 
 impl MemoryStats {
+    /// Histogram bucket for an allocation of `size` bytes
+    pub fn size_class(size: usize) -> usize {
+        let buckets = size.div_ceil(SMALLEST_SIZE_CLASS).max(1).next_power_of_two().trailing_zeros();
+        (buckets as usize).min(SIZE_CLASS_COUNT - 1)
+    }
+
+    /// Human-readable range of a histogram bucket, e.g. "≤64 B" or ">16 KiB"
+    pub fn size_class_label(index: usize) -> String {
+        let format = |bytes: usize| {
+            if bytes >= 1024 {
+                format!("{} KiB", bytes / 1024)
+            } else {
+                format!("{} B", bytes)
+            }
+        };
+        if index + 1 >= SIZE_CLASS_COUNT {
+            format!(">{}", format(SMALLEST_SIZE_CLASS << (SIZE_CLASS_COUNT - 2)))
+        } else {
+            format!("≤{}", format(SMALLEST_SIZE_CLASS << index))
+        }
+    }
+
     fn record_allocation(&mut self, size: usize) {
         self.total_allocated += size;
         self.current_usage += size;
@@ -43,18 +348,61 @@ impl MemoryStats {
         self.current_usage = self.current_usage.saturating_sub(size);
         self.deallocation_count += 1;
     }
+
+    /// Sites by live bytes, largest first
+    pub fn sites_by_bytes(&self) -> Vec<(&'static str, SiteStats)> {
+        let mut sites: Vec<_> = self.sites.iter().map(|(site, stats)| (*site, *stats)).collect();
+        sites.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(b.0)));
+        sites
+    }
+}
+
+/// Position of an incremental sweep
+///
+/// Slots are visited block by block; anything allocated behind the cursor
+/// is not looked at again until the next cycle.
+#[derive(Debug, Clone, Default)]
+pub struct SweepCursor {
+    block: usize,
+    offset: usize,
+    large: usize,
 }
 
 /// Memory manager with arena-based allocation
 pub struct MemoryManager {
-    /// Head of the allocation list
-    head: Option<NonNull<BlockHeader>>,
+    /// Regular blocks; the last one is the current bump target
+    blocks: Vec<Block>,
+    /// Dedicated blocks each holding one oversized object
+    large_blocks: Vec<Block>,
+    /// Free-list heads indexed by slot size / MIN_ALIGN
+    free_lists: Vec<Option<NonNull<BlockHeader>>>,
     /// Memory statistics
     stats: MemoryStats,
     /// Threshold for triggering GC (in bytes)
     gc_threshold: usize,
     /// Growth factor for GC threshold
     gc_growth_factor: f64,
+    /// New objects start out marked (set while an incremental cycle runs)
+    allocate_marked: bool,
+    /// Finalizers keyed by data pointer
+    finalizers: BTreeMap<NonNull<u8>, Finalizer>,
+    /// Live allocations by data pointer, when leak detection is enabled
+    live_records: Option<BTreeMap<NonNull<u8>, AllocationRecord>>,
+    /// Site new allocations are tagged with
+    site: &'static str,
+    /// Site of every live allocation, when site tracking is enabled
+    live_sites: Option<BTreeMap<NonNull<u8>, &'static str>>,
+    /// Hard cap on `current_usage`; allocations that would exceed it fail
+    limit: Option<usize>,
+    /// Source of blocks
+    allocator: Rc<dyn Allocator>,
+    /// Freed slots kept off the free lists, oldest first
+    quarantine: VecDeque<NonNull<BlockHeader>>,
+    /// Slots the quarantine holds before releasing the oldest; 0 disables it
+    quarantine_capacity: usize,
+    /// Last generation given out; fresh and freed slots each take the next,
+    /// so no two lives of a slot share one, even across `reset`
+    generation: u32,
 }
 
 impl MemoryManager {
@@ -66,53 +414,283 @@ impl MemoryManager {
     /// Create a memory manager with custom GC threshold
     pub fn with_threshold(threshold: usize) -> Self {
         MemoryManager {
-            head: None,
+            blocks: Vec::new(),
+            large_blocks: Vec::new(),
+            free_lists: Vec::new(),
             stats: MemoryStats::default(),
             gc_threshold: threshold,
             gc_growth_factor: 2.0,
+            allocate_marked: false,
+            finalizers: BTreeMap::new(),
+            live_records: None,
+            site: DEFAULT_SITE,
+            live_sites: None,
+            limit: None,
+            allocator: Rc::new(Global),
+            quarantine: VecDeque::new(),
+            quarantine_capacity: 0,
+            generation: 0,
         }
     }
 
-    /// Allocate memory of given size
+    /// Create a memory manager that takes its blocks from `allocator`
+    pub fn with_allocator(allocator: impl Allocator + 'static) -> Self {
+        let mut manager = Self::new();
+        manager.allocator = Rc::new(allocator);
+        manager
+    }
+
+    /// Create a memory manager that refuses to hold more than `limit` bytes
+    ///
+    /// The cap applies to `current_usage`, headers included. Allocations
+    /// that would exceed it return `None` so the caller can collect and retry.
+    pub fn with_limit(limit: usize) -> Self {
+        let mut manager = Self::new();
+        manager.limit = Some(limit);
+        manager
+    }
+
+    /// The hard memory cap, if any
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Whether an allocation of `size` bytes stays within the memory cap
+    pub fn fits(&self, size: usize) -> bool {
+        match self.limit {
+            Some(limit) => self.stats.current_usage + HEADER_SIZE + payload_size(size) <= limit,
+            None => true,
+        }
+    }
+
+    /// Record the call site of every allocation from now on
+    pub fn enable_leak_detection(&mut self) {
+        self.live_records.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Count allocations by site in `MemoryStats::sites` from now on
+    pub fn enable_site_tracking(&mut self) {
+        self.live_sites.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Tag allocations from now on as made by `site`, e.g. an opcode name
+    pub fn set_site(&mut self, site: &'static str) {
+        self.site = site;
+    }
+
+    /// Keep the last `capacity` freed slots from being reused
+    ///
+    /// Stale handles to them are then caught as freed instead of silently
+    /// reading whatever took their place. Costs up to `capacity` slots of
+    /// memory that can't be handed out; 0 turns the quarantine off.
+    pub fn enable_quarantine(&mut self, capacity: usize) {
+        self.quarantine_capacity = capacity;
+        while self.quarantine.len() > capacity {
+            self.release_quarantined();
+        }
+    }
+
+    /// Freed slots currently held back from reuse
+    pub fn quarantined(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    /// Allocate memory of given size, 8-byte aligned
+    #[track_caller]
     pub fn allocate(&mut self, size: usize) -> Option<NonNull<u8>> {
-        let header_size = std::mem::size_of::<BlockHeader>();
-        let total_size = header_size + size;
-        let align = std::mem::align_of::<BlockHeader>();
+        self.allocate_aligned(size, MIN_ALIGN)
+    }
+
+    /// Allocate memory of given size aligned to `align`, a power of two
+    ///
+    /// Alignment beyond 8 bytes is paid for in padding before the object,
+    /// and is always carved from fresh block space rather than a free list.
+    #[track_caller]
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        if !align.is_power_of_two() || size > MAX_SIZE || !self.fits(size) {
+            return None;
+        }
+        let align = align.max(MIN_ALIGN);
+        let slot_size = HEADER_SIZE.checked_add(payload_size(size))?;
+        // Enough for the slot however much padding it takes
+        let padded = slot_size.checked_add(align - MIN_ALIGN)?;
 
-        let layout = Layout::from_size_align(total_size, align).ok()?;
+        let mut reused_generation = None;
+        let header = if padded > BLOCK_SIZE {
+            let mut block = Block::new(padded, &self.allocator)?;
+            let header = block.bump(slot_size, align)?;
+            self.stats.reserved += block.size;
+            self.large_blocks.push(block);
+            header
+        } else if let Some(header) = (align == MIN_ALIGN).then(|| self.pop_free(slot_size)).flatten() {
+            self.stats.reused_count += 1;
+            // SAFETY: free-list entries are valid headers inside live blocks
+            reused_generation = Some(unsafe { header.as_ref() }.generation.get());
+            header
+        } else {
+            let bumped = self.blocks.last_mut().and_then(|block| block.bump(slot_size, align));
+            match bumped {
+                Some(header) => header,
+                None => {
+                    let mut block = Block::new(BLOCK_SIZE, &self.allocator)?;
+                    let header = block.bump(slot_size, align)?;
+                    self.stats.reserved += block.size;
+                    self.blocks.push(block);
+                    header
+                }
+            }
+        };
+        let generation = reused_generation.unwrap_or_else(|| self.next_generation());
 
         unsafe {
-            let ptr = alloc(layout);
-            if ptr.is_null() {
-                return None;
+            // Initialize header (capacity of a reused slot is unchanged)
+            let flags = if self.allocate_marked { MARKED } else { 0 };
+            header.as_ptr().write(BlockHeader::new(size, flags, generation));
+            self.stats.record_allocation(slot_size);
+            self.stats.size_classes[MemoryStats::size_class(size)] += 1;
+
+            // Return pointer to data area (after header)
+            let data = BlockHeader::data(header);
+            if let Some(sites) = &mut self.live_sites {
+                sites.insert(data, self.site);
+                let stats = self.stats.sites.entry(self.site).or_default();
+                stats.allocations += 1;
+                stats.objects += 1;
+                stats.bytes += slot_size;
             }
+            if let Some(records) = &mut self.live_records {
+                let site = Location::caller();
+                records.insert(
+                    data,
+                    AllocationRecord {
+                        size,
+                        site,
+                        #[cfg(feature = "std")]
+                        backtrace: Backtrace::capture(),
+                    },
+                );
+            }
+            Some(data)
+        }
+    }
 
-            // Initialize header
-            let header = ptr as *mut BlockHeader;
-            (*header).size = size;
-            (*header).marked = Cell::new(false);
-            (*header).next = self.head;
+    fn next_generation(&mut self) -> u32 {
+        self.generation = self.generation.wrapping_add(1);
+        self.generation
+    }
 
-            // Add to allocation list
-            self.head = Some(NonNull::new_unchecked(header));
-            self.stats.record_allocation(total_size);
+    fn free_list_index(slot_size: usize) -> usize {
+        slot_size / MIN_ALIGN
+    }
 
-            // Return pointer to data area (after header)
-            let data_ptr = ptr.add(header_size);
-            Some(NonNull::new_unchecked(data_ptr))
+    fn pop_free(&mut self, slot_size: usize) -> Option<NonNull<BlockHeader>> {
+        let head = self.free_lists.get_mut(Self::free_list_index(slot_size))?;
+        let header = (*head)?;
+        // SAFETY: free-list entries are valid headers inside live blocks
+        *head = unsafe { BlockHeader::next_free(header) };
+        Some(header)
+    }
+
+    /// Put a dead object's slot on its free list, or in quarantine
+    unsafe fn free_slot(&mut self, header: NonNull<BlockHeader>) {
+        let slot = header.as_ref();
+        let slot_size = HEADER_SIZE + slot.capacity();
+        slot.set(FREE, true);
+        slot.generation.set(self.next_generation());
+        let data = BlockHeader::data(header);
+        self.forget_record(data, slot_size);
+        #[cfg(debug_assertions)]
+        data.as_ptr().write_bytes(POISON, slot.capacity());
+        self.stats.record_deallocation(slot_size);
+
+        if self.quarantine_capacity == 0 {
+            self.push_free(header);
+            return;
+        }
+        self.quarantine.push_back(header);
+        if self.quarantine.len() > self.quarantine_capacity {
+            self.release_quarantined();
+        }
+    }
+
+    /// Let the oldest quarantined slot be reused
+    fn release_quarantined(&mut self) {
+        if let Some(header) = self.quarantine.pop_front() {
+            // SAFETY: quarantined slots are freed headers inside live blocks
+            unsafe { self.push_free(header) };
+        }
+    }
+
+    /// Link a freed slot into the free list for its size
+    unsafe fn push_free(&mut self, header: NonNull<BlockHeader>) {
+        let index = Self::free_list_index(HEADER_SIZE + header.as_ref().capacity());
+        if self.free_lists.len() <= index {
+            self.free_lists.resize(index + 1, None);
+        }
+        (BlockHeader::data(header).as_ptr() as *mut Option<NonNull<BlockHeader>>).write(self.free_lists[index]);
+        self.free_lists[index] = Some(header);
+    }
+
+    /// Run `finalizer` when the object at `ptr` is swept or the manager is dropped
+    ///
+    /// Replaces any finalizer already registered for the object.
+    pub fn set_finalizer(&mut self, ptr: NonNull<u8>, finalizer: impl FnOnce(NonNull<u8>) + 'static) {
+        unsafe { BlockHeader::of(ptr) }.set(FINALIZER, true);
+        self.finalizers.insert(ptr, Box::new(finalizer));
+    }
+
+    /// Run the finalizer of a dying object, if it has one
+    unsafe fn finalize(&mut self, header: NonNull<BlockHeader>) {
+        if !header.as_ref().is(FINALIZER) {
+            return;
+        }
+        header.as_ref().set(FINALIZER, false);
+        let data = BlockHeader::data(header);
+        if let Some(finalizer) = self.finalizers.remove(&data) {
+            finalizer(data);
+            self.stats.finalized_count += 1;
         }
     }
 
-    /// Deallocate a specific block
-    unsafe fn deallocate_block(&mut self, header: NonNull<BlockHeader>) {
-        let header_size = std::mem::size_of::<BlockHeader>();
-        let total_size = header_size + (*header.as_ptr()).size;
-        let align = std::mem::align_of::<BlockHeader>();
+    fn forget_record(&mut self, data: NonNull<u8>, footprint: usize) {
+        if let Some(records) = &mut self.live_records {
+            records.remove(&data);
+        }
+        if let Some(site) = self.live_sites.as_mut().and_then(|sites| sites.remove(&data)) {
+            let stats = self.stats.sites.entry(site).or_default();
+            stats.objects -= 1;
+            stats.bytes -= footprint;
+        }
+    }
 
-        let layout = Layout::from_size_align_unchecked(total_size, align);
-        dealloc(header.as_ptr() as *mut u8, layout);
+    /// Records of allocations that are still alive (empty unless leak detection is on)
+    pub fn live_allocations(&self) -> impl Iterator<Item = (NonNull<u8>, &AllocationRecord)> {
+        self.live_records.iter().flatten().map(|(ptr, record)| (*ptr, record))
+    }
+
+    /// Summary of live allocations grouped by call site
+    ///
+    /// Returns `None` unless leak detection is enabled.
+    pub fn leak_report(&self) -> Option<String> {
+        let records = self.live_records.as_ref()?;
+
+        // site -> (blocks, bytes, first record)
+        let mut sites: BTreeMap<String, (usize, usize, &AllocationRecord)> = BTreeMap::new();
+        for record in records.values() {
+            let entry = sites.entry(record.site.to_string()).or_insert((0, 0, record));
+            entry.0 += 1;
+            entry.1 += record.size;
+        }
+        let mut sites: Vec<_> = sites.into_iter().collect();
+        sites.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then_with(|| a.0.cmp(&b.0)));
 
-        self.stats.record_deallocation(total_size);
+        let total: usize = records.values().map(|r| r.size).sum();
+        let mut report = format!("{} live blocks ({} bytes)\n", records.len(), total);
+        for (site, (blocks, bytes, first)) in sites {
+            let _ = writeln!(report, "  {} blocks ({} bytes) allocated at {}", blocks, bytes, site);
+            first.write_backtrace(&mut report);
+        }
+        Some(report)
     }
 
     /// Check if GC should be triggered
@@ -122,61 +700,181 @@ impl MemoryManager {
 
     /// Mark a block as reachable
     pub fn mark(&self, ptr: NonNull<u8>) {
-        unsafe {
-            let header_size = std::mem::size_of::<BlockHeader>();
-            let header_ptr = (ptr.as_ptr() as *mut u8).sub(header_size) as *mut BlockHeader;
-            (*header_ptr).marked.set(true);
-        }
+        unsafe { BlockHeader::of(ptr) }.set(MARKED, true);
+    }
+
+    /// Generation of the live object at `ptr`, or `None` if its slot has been freed
+    ///
+    /// Only meaningful for objects that fit in a regular block (see
+    /// `fits_in_block`): their header outlives them, while a large object's
+    /// memory is returned to the system when it is swept.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` on a manager that is still alive.
+    pub(crate) unsafe fn generation(ptr: NonNull<u8>) -> Option<u32> {
+        let header = BlockHeader::of(ptr);
+        (!header.is(FREE)).then_some(header.generation.get())
+    }
+
+    /// Whether an object of `size` bytes aligned to `align` lives in a shared arena block
+    pub(crate) const fn fits_in_block(size: usize, align: usize) -> bool {
+        HEADER_SIZE + payload_size(size) + align.saturating_sub(MIN_ALIGN) <= BLOCK_SIZE
+    }
+
+    /// Whether the object at `ptr` is marked in the current cycle
+    pub fn is_marked(&self, ptr: NonNull<u8>) -> bool {
+        unsafe { BlockHeader::of(ptr) }.is(MARKED)
+    }
+
+    /// Headers of every slot in every block, live or free
+    fn headers(&self) -> impl Iterator<Item = NonNull<BlockHeader>> + '_ {
+        self.blocks.iter().chain(&self.large_blocks).flat_map(Block::headers)
+    }
+
+    /// Every object not yet freed, in address order within each block
+    ///
+    /// Includes garbage that hasn't been swept yet.
+    pub fn objects(&self) -> impl Iterator<Item = ObjectInfo> + '_ {
+        self.headers().filter_map(|header| {
+            // SAFETY: every slot handed out starts with an initialized header
+            let slot = unsafe { header.as_ref() };
+            (!slot.is(FREE)).then(|| ObjectInfo {
+                ptr: BlockHeader::data(header),
+                size: slot.size(),
+                footprint: HEADER_SIZE + slot.capacity(),
+                finalizer: slot.is(FINALIZER),
+            })
+        })
     }
 
     /// Clear all marks (prepare for marking phase)
     pub fn unmark_all(&mut self) {
-        let mut current = self.head;
-        while let Some(header) = current {
-            unsafe {
-                (*header.as_ptr()).marked.set(false);
-                current = (*header.as_ptr()).next;
-            }
+        for header in self.headers() {
+            unsafe { header.as_ref() }.set(MARKED, false);
         }
     }
 
+    /// Make new objects start out marked so an in-progress collection keeps them
+    pub fn set_allocate_marked(&mut self, marked: bool) {
+        self.allocate_marked = marked;
+    }
+
     /// Sweep unmarked objects (deallocation phase)
     pub fn sweep(&mut self) -> usize {
+        let mut cursor = SweepCursor::default();
+        let (freed_count, _) = self.sweep_step(&mut cursor, usize::MAX);
+        self.finish_sweep();
+        freed_count
+    }
+
+    /// Sweep up to `budget` slots starting at `cursor`
+    ///
+    /// Returns the number of objects freed and whether the sweep reached
+    /// the end of the heap.
+    pub fn sweep_step(&mut self, cursor: &mut SweepCursor, budget: usize) -> (usize, bool) {
         let mut freed_count = 0;
-        let mut prev: Option<NonNull<BlockHeader>> = None;
-        let mut current = self.head;
+        let mut work = 0;
 
-        while let Some(header) = current {
+        while cursor.block < self.blocks.len() {
+            let Some((header, next)) = self.blocks[cursor.block].header_from(cursor.offset) else {
+                cursor.block += 1;
+                cursor.offset = 0;
+                continue;
+            };
+            if work >= budget {
+                return (freed_count, false);
+            }
+            work += 1;
+            cursor.offset = next;
             unsafe {
-                let next = (*header.as_ptr()).next;
-
-                if !(*header.as_ptr()).marked.get() {
-                    // Remove from list
-                    match prev {
-                        Some(p) => (*p.as_ptr()).next = next,
-                        None => self.head = next,
-                    }
-
-                    // Deallocate
-                    self.deallocate_block(header);
-                    freed_count += 1;
-                } else {
+                let slot = header.as_ref();
+                if slot.is(FREE) {
+                    continue;
+                }
+                if slot.is(MARKED) {
                     // Clear mark for next cycle
-                    (*header.as_ptr()).marked.set(false);
-                    prev = Some(header);
+                    slot.set(MARKED, false);
+                } else {
+                    self.finalize(header);
+                    self.free_slot(header);
+                    freed_count += 1;
                 }
+            }
+        }
 
-                current = next;
+        // Oversized objects give their whole block back
+        while cursor.large < self.large_blocks.len() {
+            if work >= budget {
+                return (freed_count, false);
+            }
+            work += 1;
+            let (header, _) = self.large_blocks[cursor.large].header_from(0).expect("A large block holds its object");
+            unsafe {
+                if header.as_ref().is(MARKED) {
+                    header.as_ref().set(MARKED, false);
+                    cursor.large += 1;
+                    continue;
+                }
+                self.finalize(header);
             }
+            // The block swapped into this index hasn't been visited yet
+            let block = self.large_blocks.swap_remove(cursor.large);
+            let footprint = HEADER_SIZE + unsafe { header.as_ref() }.capacity();
+            self.forget_record(BlockHeader::data(header), footprint);
+            self.stats.record_deallocation(footprint);
+            self.stats.reserved -= block.size;
+            freed_count += 1;
         }
 
-        // Adjust threshold after collection
+        (freed_count, true)
+    }
+
+    /// Adjust the GC threshold once a sweep has covered the whole heap
+    pub fn finish_sweep(&mut self) {
         if self.stats.current_usage > 0 {
             self.gc_threshold =
                 ((self.stats.current_usage as f64) * self.gc_growth_factor) as usize;
         }
+    }
 
-        freed_count
+    /// Free every object at once, keeping one regular block for what comes next
+    ///
+    /// Takes time in the number of blocks and finalizers, not objects.
+    /// Finalizers run as they would at drop, and every pointer handed out
+    /// before is dangling afterwards.
+    pub fn reset(&mut self) {
+        for (ptr, finalizer) in core::mem::take(&mut self.finalizers) {
+            finalizer(ptr);
+            self.stats.finalized_count += 1;
+        }
+        if let Some(records) = &mut self.live_records {
+            records.clear();
+        }
+        if let Some(sites) = &mut self.live_sites {
+            sites.clear();
+            for stats in self.stats.sites.values_mut() {
+                stats.objects = 0;
+                stats.bytes = 0;
+            }
+        }
+        self.blocks.truncate(1);
+        if let Some(block) = self.blocks.first_mut() {
+            // SAFETY: the first `used` bytes are within the block
+            #[cfg(debug_assertions)]
+            unsafe {
+                block.start.as_ptr().write_bytes(POISON, block.used)
+            };
+            block.used = 0;
+        }
+        self.large_blocks.clear();
+        self.free_lists.clear();
+        self.quarantine.clear();
+        self.allocate_marked = false;
+
+        self.stats.reserved = self.blocks.iter().map(|block| block.size).sum();
+        self.stats.total_freed += self.stats.current_usage;
+        self.stats.current_usage = 0;
+        self.stats.deallocation_count = self.stats.allocation_count;
     }
 
     /// Get memory statistics
@@ -203,15 +901,17 @@ impl Default for MemoryManager {
 
 impl Drop for MemoryManager {
     fn drop(&mut self) {
-        // Clean up all remaining allocations
-        let mut current = self.head;
-        while let Some(header) = current {
-            unsafe {
-                let next = (*header.as_ptr()).next;
-                self.deallocate_block(header);
-                current = next;
+        if self.live_records.as_ref().is_some_and(|records| !records.is_empty()) {
+            if let Some(report) = self.leak_report() {
+                log::warn!("MemoryManager dropped with live allocations: {}", report);
             }
         }
+
+        // Objects still alive at teardown get their cleanup too
+        for (ptr, finalizer) in core::mem::take(&mut self.finalizers) {
+            finalizer(ptr);
+            self.stats.finalized_count += 1;
+        }
     }
 }
 
@@ -245,4 +945,315 @@ mod tests {
         assert_eq!(freed, 1);
         assert_eq!(mm.stats().deallocation_count, 1);
     }
+
+    #[test]
+    fn test_objects() {
+        let mut mm = MemoryManager::new();
+        let small = mm.allocate(40).expect("Allocation failed");
+        let big = mm.allocate(BLOCK_SIZE * 2).expect("Allocation failed");
+        let objects: Vec<_> = mm.objects().collect();
+        assert_eq!(objects.len(), 2);
+        assert_eq!((objects[0].ptr, objects[0].size, objects[0].footprint), (small, 40, HEADER_SIZE + 40));
+        assert_eq!((objects[1].ptr, objects[1].size), (big, BLOCK_SIZE * 2));
+
+        mm.unmark_all();
+        mm.mark(big);
+        mm.sweep();
+        assert_eq!(mm.objects().map(|object| object.ptr).collect::<Vec<_>>(), [big]);
+    }
+
+    #[test]
+    fn test_swept_slot_is_reused() {
+        let mut mm = MemoryManager::new();
+        let keep = mm.allocate(40).expect("Allocation failed");
+        let dead = mm.allocate(40).expect("Allocation failed");
+
+        mm.unmark_all();
+        mm.mark(keep);
+        assert_eq!(mm.sweep(), 1);
+
+        // Same size class comes back from the free list
+        let reused = mm.allocate(33).expect("Allocation failed");
+        assert_eq!(reused, dead);
+        assert_eq!(mm.stats().reused_count, 1);
+        assert_eq!(mm.stats().reserved, BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_allocations_span_blocks() {
+        let mut mm = MemoryManager::new();
+        let ptrs: Vec<_> = (0..2 * BLOCK_SIZE / 64)
+            .map(|_| mm.allocate(48).expect("Allocation failed"))
+            .collect();
+        assert!(mm.stats().reserved > BLOCK_SIZE);
+        assert!(ptrs.iter().all(|p| (p.as_ptr() as usize).is_multiple_of(MIN_ALIGN)));
+
+        // Nothing rooted: everything is freed
+        mm.unmark_all();
+        assert_eq!(mm.sweep(), ptrs.len());
+        assert_eq!(mm.current_usage(), 0);
+    }
+
+    #[test]
+    fn test_large_object_gets_own_block() {
+        let mut mm = MemoryManager::new();
+        let big = mm.allocate(BLOCK_SIZE * 2).expect("Allocation failed");
+        unsafe { core::ptr::write_bytes(big.as_ptr(), 0xAB, BLOCK_SIZE * 2) };
+        assert!(mm.stats().reserved > BLOCK_SIZE * 2);
+
+        mm.unmark_all();
+        assert_eq!(mm.sweep(), 1);
+        assert_eq!(mm.stats().reserved, 0);
+        assert_eq!(mm.current_usage(), 0);
+    }
+
+    #[test]
+    fn test_finalizers_run_on_sweep_and_drop() {
+        use alloc::rc::Rc;
+
+        let log = Rc::new(Cell::new(0));
+        let mut mm = MemoryManager::new();
+        let live = mm.allocate(8).expect("Allocation failed");
+        let dead = mm.allocate(BLOCK_SIZE).expect("Allocation failed");
+        for (ptr, bit) in [(live, 1), (dead, 2)] {
+            let log = Rc::clone(&log);
+            mm.set_finalizer(ptr, move |_| log.set(log.get() | bit));
+        }
+
+        mm.unmark_all();
+        mm.mark(live);
+        mm.sweep();
+        assert_eq!(log.get(), 2);
+        assert_eq!(mm.stats().finalized_count, 1);
+
+        // Each finalizer runs at most once
+        mm.unmark_all();
+        mm.mark(live);
+        mm.sweep();
+        assert_eq!(mm.stats().finalized_count, 1);
+
+        drop(mm);
+        assert_eq!(log.get(), 3);
+    }
+
+    #[test]
+    fn test_size_class_histogram() {
+        assert_eq!(MemoryStats::size_class(0), 0);
+        assert_eq!(MemoryStats::size_class(16), 0);
+        assert_eq!(MemoryStats::size_class(17), 1);
+        assert_eq!(MemoryStats::size_class(64), 2);
+        assert_eq!(MemoryStats::size_class(1 << 20), SIZE_CLASS_COUNT - 1);
+        assert_eq!(MemoryStats::size_class_label(2), "≤64 B");
+        assert_eq!(MemoryStats::size_class_label(SIZE_CLASS_COUNT - 1), ">16 KiB");
+
+        let mut mm = MemoryManager::new();
+        for size in [8, 16, 40, 40, 100_000] {
+            mm.allocate(size).expect("Allocation failed");
+        }
+        let classes = mm.stats().size_classes;
+        assert_eq!(classes[0], 2);
+        assert_eq!(classes[2], 2);
+        assert_eq!(classes[SIZE_CLASS_COUNT - 1], 1);
+        assert_eq!(classes.iter().sum::<usize>(), mm.allocation_count());
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut mm = MemoryManager::with_limit(4 * (HEADER_SIZE + 64));
+        let ptrs: Vec<_> = (0..4).map(|_| mm.allocate(64).expect("Allocation failed")).collect();
+        assert!(!mm.fits(1));
+        assert!(mm.allocate(1).is_none());
+
+        // Freeing makes room again
+        mm.unmark_all();
+        mm.mark(ptrs[0]);
+        mm.sweep();
+        assert!(mm.allocate(64).is_some());
+        assert_eq!(mm.limit(), Some(4 * (HEADER_SIZE + 64)));
+    }
+
+    #[test]
+    fn test_allocator() {
+        use crate::allocator::FixedBuffer;
+        let buffer: &'static mut [u8] = Box::leak(vec![0u8; 3 * BLOCK_SIZE].into_boxed_slice());
+        let region = buffer.as_ptr_range();
+        let mut mm = MemoryManager::with_allocator(FixedBuffer::new(buffer));
+
+        let small = mm.allocate(64).expect("Allocation failed");
+        let big = mm.allocate(BLOCK_SIZE + 1).expect("Allocation failed");
+        assert!(region.contains(&small.as_ptr().cast_const()) && region.contains(&big.as_ptr().cast_const()));
+        // Both blocks are taken, leaving too little for another large one
+        assert!(mm.allocate(BLOCK_SIZE + 1).is_none());
+
+        // A dead large object gives its block back to the buffer
+        mm.unmark_all();
+        mm.mark(small);
+        mm.sweep();
+        assert!(mm.allocate(BLOCK_SIZE + 1).is_some());
+    }
+
+    #[test]
+    fn test_leak_report() {
+        let mut mm = MemoryManager::new();
+        assert!(mm.leak_report().is_none());
+        mm.enable_leak_detection();
+
+        let kept = mm.allocate(24).expect("Allocation failed");
+        for _ in 0..3 {
+            mm.allocate(8).expect("Allocation failed");
+        }
+        mm.unmark_all();
+        mm.mark(kept);
+        mm.sweep();
+
+        let (ptr, record) = mm.live_allocations().next().expect("one live block");
+        assert_eq!(mm.live_allocations().count(), 1);
+        assert_eq!((ptr, record.size), (kept, 24));
+        assert!(record.site.file().ends_with("memory.rs"));
+
+        let report = mm.leak_report().unwrap();
+        assert!(report.starts_with("1 live blocks (24 bytes)"), "{}", report);
+        assert!(report.contains("allocated at src/memory.rs:"), "{}", report);
+    }
+
+    #[test]
+    fn test_compact_headers() {
+        // A boxed f64 takes a header and its own 8 bytes, nothing more
+        #[cfg(not(feature = "compact-headers"))]
+        assert_eq!(HEADER_SIZE, 16);
+        #[cfg(feature = "compact-headers")]
+        assert_eq!(HEADER_SIZE, 8);
+        let mut mm = MemoryManager::new();
+        let boxed = mm.allocate(8).expect("Allocation failed");
+        assert_eq!(mm.current_usage(), HEADER_SIZE + 8);
+
+        // Flags share the size's word
+        mm.set_finalizer(boxed, |_| {});
+        mm.mark(boxed);
+        assert!(mm.is_marked(boxed));
+        let info = mm.objects().next().unwrap();
+        assert_eq!((info.size, info.finalizer), (8, true));
+        assert!(mm.allocate(MAX_SIZE + 1).is_none());
+    }
+
+    #[test]
+    fn test_aligned_allocation() {
+        let mut mm = MemoryManager::new();
+        let small = mm.allocate(1).expect("Allocation failed");
+        let aligned = mm.allocate_aligned(24, 64).expect("Allocation failed");
+        let big = mm.allocate_aligned(BLOCK_SIZE, 4096).expect("Allocation failed");
+        let after = mm.allocate(8).expect("Allocation failed");
+        assert!((aligned.as_ptr() as usize).is_multiple_of(64));
+        assert!((big.as_ptr() as usize).is_multiple_of(4096));
+        assert!(mm.allocate_aligned(8, 24).is_none());
+
+        // Walking the blocks steps over the padding
+        let sizes: Vec<_> = mm.objects().map(|object| (object.ptr, object.size)).collect();
+        assert_eq!(sizes, [(small, 1), (aligned, 24), (after, 8), (big, BLOCK_SIZE)]);
+        mm.unmark_all();
+        mm.mark(after);
+        assert_eq!(mm.sweep(), 3);
+        assert_eq!(mm.current_usage(), HEADER_SIZE + 8);
+        assert_eq!(mm.objects().map(|object| object.ptr).collect::<Vec<_>>(), [after]);
+    }
+
+    #[test]
+    fn test_reset() {
+        use alloc::rc::Rc;
+
+        let finalized = Rc::new(Cell::new(false));
+        let mut mm = MemoryManager::new();
+        mm.enable_leak_detection();
+        let first = mm.allocate(48).expect("Allocation failed");
+        for _ in 0..2 * BLOCK_SIZE / 64 {
+            mm.allocate(48).expect("Allocation failed");
+        }
+        let big = mm.allocate(BLOCK_SIZE * 2).expect("Allocation failed");
+        let flag = Rc::clone(&finalized);
+        mm.set_finalizer(big, move |_| flag.set(true));
+
+        mm.reset();
+        assert!(finalized.get());
+        assert_eq!(mm.objects().count(), 0);
+        assert_eq!(mm.live_allocations().count(), 0);
+        let stats = mm.stats();
+        assert_eq!((stats.current_usage, stats.reserved), (0, BLOCK_SIZE));
+        assert_eq!((stats.deallocation_count, stats.total_freed), (stats.allocation_count, stats.total_allocated));
+
+        // The kept block is bumped from its start again
+        assert_eq!(mm.allocate(16).expect("Allocation failed"), first);
+        assert_eq!(mm.stats().reused_count, 0);
+    }
+
+    #[test]
+    fn test_site_tracking() {
+        let mut mm = MemoryManager::new();
+        mm.allocate(8).expect("Allocation failed");
+        mm.enable_site_tracking();
+        let kept = mm.allocate(16).expect("Allocation failed");
+        mm.set_site("arrays");
+        mm.allocate(64).expect("Allocation failed");
+        mm.allocate(BLOCK_SIZE).expect("Allocation failed");
+
+        // Allocations from before tracking started are not counted
+        let stats = mm.stats().sites.clone();
+        assert_eq!(stats[DEFAULT_SITE], SiteStats { allocations: 1, objects: 1, bytes: HEADER_SIZE + 16 });
+        assert_eq!((stats["arrays"].allocations, stats["arrays"].objects), (2, 2));
+        assert_eq!(mm.stats().sites_by_bytes()[0].0, "arrays");
+
+        mm.unmark_all();
+        mm.mark(kept);
+        mm.sweep();
+        assert_eq!(mm.stats().sites["arrays"], SiteStats { allocations: 2, objects: 0, bytes: 0 });
+        assert_eq!(mm.stats().sites[DEFAULT_SITE].objects, 1);
+        mm.reset();
+        assert_eq!(mm.stats().sites[DEFAULT_SITE].bytes, 0);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let mut mm = MemoryManager::new();
+        mm.enable_quarantine(2);
+        let ptrs: Vec<_> = (0..3).map(|_| mm.allocate(32).expect("Allocation failed")).collect();
+        mm.unmark_all();
+        mm.sweep();
+        assert_eq!(mm.quarantined(), 2);
+        #[cfg(debug_assertions)]
+        unsafe {
+            assert!(core::slice::from_raw_parts(ptrs[2].as_ptr(), 32).iter().all(|&byte| byte == POISON));
+        }
+
+        // Only the slot pushed out of quarantine is reused
+        assert_eq!(mm.allocate(32).expect("Allocation failed"), ptrs[0]);
+        assert!(!ptrs.contains(&mm.allocate(32).expect("Allocation failed")));
+        assert!(unsafe { MemoryManager::generation(ptrs[1]) }.is_none());
+
+        mm.enable_quarantine(0);
+        assert_eq!(mm.quarantined(), 0);
+        assert_eq!(mm.allocate(32).expect("Allocation failed"), ptrs[2]);
+    }
+
+    #[test]
+    fn test_incremental_sweep_keeps_objects_allocated_mid_cycle() {
+        let mut mm = MemoryManager::new();
+        for _ in 0..10 {
+            mm.allocate(16).expect("Allocation failed");
+        }
+        mm.unmark_all();
+        mm.set_allocate_marked(true);
+
+        let mut cursor = SweepCursor::default();
+        let (freed, done) = mm.sweep_step(&mut cursor, 4);
+        assert_eq!((freed, done), (4, false));
+
+        // Lands ahead of the cursor but must survive this cycle
+        let fresh = mm.allocate(2000).expect("Allocation failed");
+        let (freed, done) = mm.sweep_step(&mut cursor, usize::MAX);
+        assert_eq!((freed, done), (6, true));
+        mm.set_allocate_marked(false);
+
+        mm.unmark_all();
+        mm.mark(fresh);
+        assert_eq!(mm.sweep(), 0);
+    }
 }
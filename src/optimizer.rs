@@ -0,0 +1,235 @@
+//! Optimizer - Bytecode-to-bytecode passes
+//!
+//! Passes take a finished chunk and return a rewritten one that computes
+//! the same result with less work in the VM.
+//!
+//! Superinstruction fusion replaces common instruction pairs with a single
+//! fused opcode, saving one dispatch per pair:
+//!   PUSH c; ADD   ->  PUSH_ADD c
+//!   PUSH c; MUL   ->  PUSH_MUL c
+//!   DUP; MUL      ->  DUP_MUL
+//!
+//! Dead code elimination drops what can never affect the result: anything
+//! after HALT, which is never reached, and constants a statement sequence
+//! pushes only to POP them again:
+//!   PUSH 1; PUSH 2; PUSH_ARRAY 2; POP  ->  (nothing)
+//!
+//! The bytecode has no jumps, so instructions can be rewritten freely
+//! without patching offsets. Every instruction keeps its token span in the
+//! source map; a fused one takes the span of the operation it absorbed.
+
+use crate::prelude::*;
+use crate::bytecode::{Chunk, ChunkMetadata, Instruction, OpCode, Operand};
+
+/// Bytecode optimization passes
+pub struct Optimizer;
+
+impl Optimizer {
+    /// Every pass, in the order the GUI's comparison view shows them applied
+    pub fn optimize(chunk: &Chunk) -> Chunk {
+        Self::fuse_superinstructions(&Self::eliminate_dead_code(chunk))
+    }
+
+    /// Remove unreachable instructions and constants popped unused
+    pub fn eliminate_dead_code(chunk: &Chunk) -> Chunk {
+        let instructions = chunk.instructions();
+        let mut live: Vec<&Instruction> = Vec::with_capacity(instructions.len());
+        let mut halted = false;
+
+        for instruction in instructions {
+            match instruction.opcode {
+                OpCode::Pop => match Self::constant_length(&live) {
+                    Some(length) => live.truncate(live.len() - length),
+                    None => live.push(instruction),
+                },
+                OpCode::Halt => {
+                    live.push(instruction);
+                    halted = true;
+                    break;
+                }
+                _ => live.push(instruction),
+            }
+        }
+
+        let mut output = Chunk::with_tables_of(chunk);
+        for instruction in live {
+            Self::copy_instruction(chunk, instruction, &mut output);
+        }
+        // Without a HALT, keep any undecodable tail so the VM still reports it
+        if !halted {
+            output.set_span(None);
+            let decoded_end = instructions.last().map_or(0, |last| last.next_offset());
+            for offset in decoded_end..chunk.len() {
+                output.write_byte(chunk.code()[offset]);
+            }
+        }
+        if let Some(metadata) = chunk.metadata() {
+            output.set_metadata(metadata.clone());
+        }
+
+        output
+    }
+
+    /// Number of trailing instructions that only push one constant
+    fn constant_length(instructions: &[&Instruction]) -> Option<usize> {
+        let last = instructions.last()?;
+        match (last.opcode, last.operand) {
+            (OpCode::Push, _) => Some(1),
+            (OpCode::PushArray, Operand::Count(count)) => {
+                let count = usize::try_from(count).ok()?;
+                let elements = instructions.len().checked_sub(count + 1)?..instructions.len() - 1;
+                instructions[elements]
+                    .iter()
+                    .all(|element| element.opcode == OpCode::Push)
+                    .then_some(count + 1)
+            }
+            _ => None,
+        }
+    }
+
+    /// Fuse common instruction pairs into superinstructions
+    pub fn fuse_superinstructions(chunk: &Chunk) -> Chunk {
+        let instructions = chunk.instructions();
+        let mut output = Chunk::with_tables_of(chunk);
+        let mut i = 0;
+
+        while i < instructions.len() {
+            let current = &instructions[i];
+
+            if let Some(next) = instructions.get(i + 1) {
+                if let Some((fused, operand)) = Self::fuse_pair(current, next) {
+                    output.set_span(chunk.span(next.offset));
+                    match operand {
+                        Some(value) => output.write_op_f64(fused, value),
+                        None => output.write_op(fused),
+                    }
+                    i += 2;
+                    continue;
+                }
+            }
+
+            Self::copy_instruction(chunk, current, &mut output);
+            i += 1;
+        }
+
+        // Keep any undecodable tail so the VM still reports it
+        output.set_span(None);
+        let decoded_end = instructions.last().map_or(0, |last| last.next_offset());
+        for offset in decoded_end..chunk.len() {
+            output.write_byte(chunk.code()[offset]);
+        }
+
+        if let Some(metadata) = chunk.metadata() {
+            output.set_metadata(ChunkMetadata {
+                optimized: true,
+                ..metadata.clone()
+            });
+        }
+
+        output
+    }
+
+    /// Superinstruction replacing `first; second`, with its constant operand
+    fn fuse_pair(first: &Instruction, second: &Instruction) -> Option<(OpCode, Option<f64>)> {
+        match (first.opcode, first.operand, second.opcode) {
+            (OpCode::Push, Operand::Number(value), OpCode::Add) => Some((OpCode::PushAdd, Some(value))),
+            (OpCode::Push, Operand::Number(value), OpCode::Mul) => Some((OpCode::PushMul, Some(value))),
+            (OpCode::Dup, _, OpCode::Mul) => Some((OpCode::DupMul, None)),
+            _ => None,
+        }
+    }
+
+    /// Copy an instruction's bytes and span unchanged
+    fn copy_instruction(chunk: &Chunk, instruction: &Instruction, output: &mut Chunk) {
+        output.set_span(chunk.span(instruction.offset));
+        for offset in instruction.offset..instruction.next_offset() {
+            output.write_byte(chunk.code()[offset]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Expr;
+    use crate::codegen::CodeGenerator;
+    use crate::vm::VirtualMachine;
+
+    fn opcodes(chunk: &Chunk) -> Vec<OpCode> {
+        chunk.instructions().iter().map(|i| i.opcode).collect()
+    }
+
+    #[test]
+    fn test_fuse_push_add_and_mul() {
+        // (2 * 3) + 4
+        let expr = Expr::add(
+            Expr::multiply(Expr::number(2.0), Expr::number(3.0)),
+            Expr::number(4.0),
+        );
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
+        let fused = Optimizer::fuse_superinstructions(&chunk);
+
+        assert_eq!(
+            opcodes(&fused),
+            vec![OpCode::Push, OpCode::PushMul, OpCode::PushAdd, OpCode::Halt]
+        );
+        assert_eq!(fused.instructions()[1].operand, Operand::Number(3.0));
+
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(&chunk).unwrap(), vm.execute(&fused).unwrap());
+    }
+
+    #[test]
+    fn test_fuse_dup_mul() {
+        let mut chunk = Chunk::new();
+        chunk.write_push(7.0);
+        chunk.write_op(OpCode::Dup);
+        chunk.write_op(OpCode::Mul);
+        chunk.write_op(OpCode::Halt);
+        let fused = Optimizer::fuse_superinstructions(&chunk);
+
+        assert_eq!(opcodes(&fused), vec![OpCode::Push, OpCode::DupMul, OpCode::Halt]);
+        let mut vm = VirtualMachine::new();
+        assert!((vm.execute(&fused).unwrap() - 49.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_eliminate_dead_code() {
+        use crate::{Parser, Tokenizer};
+        let ast = Parser::new(Tokenizer::new("1 + 2; [3, 4]; x = 5; x * 3").tokenize().unwrap()).parse().unwrap();
+        let (mut chunk, _) = CodeGenerator::new().compile(&ast).unwrap();
+        // Unreachable, and not even decodable
+        chunk.write_push(6.0);
+        chunk.write_byte(0xFF);
+        let optimized = Optimizer::eliminate_dead_code(&chunk);
+
+        // `1 + 2` computes before its POP, so only the array and the trailer go
+        assert_eq!(
+            opcodes(&optimized),
+            vec![
+                OpCode::Push,
+                OpCode::Push,
+                OpCode::Add,
+                OpCode::Pop,
+                OpCode::Push,
+                OpCode::StoreGlobal,
+                OpCode::Pop,
+                OpCode::LoadGlobal,
+                OpCode::Push,
+                OpCode::Mul,
+                OpCode::Halt
+            ]
+        );
+        assert!(optimized.len() < chunk.len());
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(&optimized).unwrap(), 15.0);
+    }
+
+    #[test]
+    fn test_unfusable_chunk_unchanged() {
+        let expr = Expr::subtract(Expr::number(5.0), Expr::number(3.0));
+        let (chunk, _) = CodeGenerator::new().compile(&expr).unwrap();
+        let fused = Optimizer::fuse_superinstructions(&chunk);
+        assert_eq!(fused.code(), chunk.code());
+    }
+}
@@ -1,24 +1,77 @@
 //! Parser - Converts tokens into AST using recursive descent with Pratt parsing
 //!
 //! Grammar (Extended):
-//!   expression  -> term (('+' | '-') term)*
+//!   program     -> statement (';' statement)* ';'?
+//!   statement   -> IDENT '(' params? ')' '=' expression    // function definition
+//!                | IDENT '=' expression                     // assignment
+//!                | expression
+//!   params      -> IDENT (',' IDENT)*
+//!   expression  -> lambda | let | term (('+' | '-') term)*
+//!   lambda      -> (IDENT | '(' params? ')') '->' expression
+//!   let         -> 'let' IDENT '=' expression 'in' expression
 //!   term        -> factor (('*' | '/' | '%') factor)*
 //!   factor      -> base ('^' factor)?          // right associative
 //!   base        -> unary | primary
 //!   unary       -> ('-' unary) | postfix
-//!   postfix     -> function_call ('!')*
-//!   function    -> FUNC '(' expression ')' | FUNC '(' expression ',' expression ')'
-//!   primary     -> NUMBER | '(' expression ')' | CONSTANT | array
+//!   postfix     -> function_call ('!' | 'deg')*   // 'deg' only after a number
+//!   function    -> FUNC '(' (expression (',' expression)*)? ')'
+//!                | ('expand' | 'factor') '(' expression ')'  // rewritten in place
+//!   primary     -> NUMBER | '(' expression ')' | '|' expression '|' | CONSTANT | array | call | IDENT
+//!   call        -> IDENT '(' (expression (',' expression)*)? ')'
 //!   array       -> '[' (expression (',' expression)*)? ']'
+//!
+//! `|x|` is read as `abs(x)`. A bar where an operand is expected opens one
+//! and the next bar where an operator could follow closes it, so bars nest
+//! as in `| |x| - 1 |`.
+//!
+//! Every builtin takes its arguments as a list, counted against the
+//! function's arity once the list is closed, so a wrong count is reported
+//! as such at the function's name rather than as a missing token.
+//!
+//! `parse_spanned` also returns the tokens each node was parsed from. Nodes
+//! are built children first, so spans are recorded in that order as they are
+//! built and put in pre-order once the whole tree is known. A bracketed
+//! expression spans its contents, and every node of a symbolic rewrite spans
+//! the whole `expand(...)` call.
 
-use crate::ast::{BinaryOp, Expr, UnaryOp};
+use crate::prelude::*;
+use crate::ast::{BinaryOp, Expr, NaryOp, UnaryOp};
+use crate::builtins;
+use crate::symbolic;
 use crate::tokenizer::Token;
-use std::fmt;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::ops::{Range, RangeInclusive};
+
+/// How deep an expression may nest unless `Parser::with_max_depth` says
+/// otherwise, well within what the parser, the code generator and dropping
+/// the tree can recurse through on a small thread stack
+pub const DEFAULT_MAX_DEPTH: usize = 128;
 
 #[derive(Debug, Clone)]
 pub struct ParseError {
     pub message: String,
     pub position: usize,
+    pub kind: ParseErrorKind,
+}
+
+/// What kind of input a `ParseError` rejects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// Tokens that don't fit the grammar
+    Syntax,
+    /// An expression nested deeper than the parser's depth limit
+    TooDeep,
+}
+
+impl ParseError {
+    fn too_deep(max_depth: usize, position: usize) -> Self {
+        ParseError {
+            message: format!("Expression nested more than {} levels deep", max_depth),
+            position,
+            kind: ParseErrorKind::TooDeep,
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
@@ -30,11 +83,30 @@ impl fmt::Display for ParseError {
 pub struct Parser {
     tokens: Vec<Token>,
     position: usize,
+    /// Current nesting of `unary` and exponents, which every recursive rule goes through
+    depth: usize,
+    max_depth: usize,
+    /// Token span of every node built so far, children before parents
+    spans: Vec<Range<usize>>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, position: 0 }
+        Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            spans: Vec::new(),
+        }
+    }
+
+    /// Reject input nested more than `max_depth` levels deep (brackets,
+    /// arguments, exponents, negations, factorials) instead of `DEFAULT_MAX_DEPTH`;
+    /// operator chains like 1+1+1 loop rather than nest, and aren't limited
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
     }
 
     fn peek(&self) -> Option<&Token> {
@@ -49,6 +121,12 @@ impl Parser {
         token
     }
 
+    /// Record `expr` as built from the tokens since `start`
+    fn node(&mut self, start: usize, expr: Expr) -> Expr {
+        self.spans.push(start..self.position);
+        expr
+    }
+
     fn is_at_end(&self) -> bool {
         self.position >= self.tokens.len()
     }
@@ -62,27 +140,170 @@ impl Parser {
             Some(token) => Err(ParseError {
                 message: format!("Expected {:?}, found {:?}", expected, token),
                 position: self.position,
+                kind: ParseErrorKind::Syntax,
             }),
             None => Err(ParseError {
                 message: format!("Expected {:?}, found end of input", expected),
                 position: self.position,
+                kind: ParseErrorKind::Syntax,
             }),
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err, fields(tokens = self.tokens.len())))]
     pub fn parse(&mut self) -> Result<Expr, ParseError> {
-        let expr = self.expression()?;
+        let mut statements = vec![self.statement()?];
+        while let Some(Token::Semicolon) = self.peek() {
+            self.advance();
+            // A trailing ';' is allowed
+            if self.is_at_end() {
+                break;
+            }
+            statements.push(self.statement()?);
+        }
         if !self.is_at_end() {
             return Err(ParseError {
                 message: format!("Unexpected token: {:?}", self.peek()),
                 position: self.position,
+                kind: ParseErrorKind::Syntax,
             });
         }
-        Ok(expr)
+        if statements.len() == 1 {
+            return Ok(statements.remove(0));
+        }
+        Ok(self.node(0, Expr::Sequence(statements)))
+    }
+
+    /// Parse, along with the token span of every node of the tree in
+    /// pre-order, as `CodeGenerator::with_spans` takes them
+    pub fn parse_spanned(&mut self) -> Result<(Expr, Vec<Range<usize>>), ParseError> {
+        self.spans.clear();
+        let expr = self.parse()?;
+        let spans = preorder(&expr, &self.spans);
+        Ok((expr, spans))
     }
 
-    // expression -> term (('+' | '-') term)*
+    // statement -> IDENT '(' params? ')' '=' expression | IDENT '=' expression | expression
+    fn statement(&mut self) -> Result<Expr, ParseError> {
+        if let Some(Token::Identifier(name)) = self.peek().cloned() {
+            let start = self.position;
+            if let Some(Token::Assign) = self.tokens.get(self.position + 1) {
+                self.position += 2;
+                let value = self.expression()?;
+                return Ok(self.node(start, Expr::assign(name, value)));
+            }
+
+            if let Some(params) = self.definition_head() {
+                if let Some(param) = duplicate(&params) {
+                    return Err(ParseError {
+                        message: format!("Duplicate parameter {} in definition of {}", param, name),
+                        position: start,
+                        kind: ParseErrorKind::Syntax,
+                    });
+                }
+                let body = self.expression()?;
+                return Ok(self.node(start, Expr::function_def(name, params, body)));
+            }
+            self.position = start;
+        }
+
+        self.expression()
+    }
+
+    /// Consume `IDENT '(' params? ')' '='` and return the parameter names
+    ///
+    /// Returns `None` if the tokens are not a definition head (e.g. a call);
+    /// the caller then rewinds.
+    fn definition_head(&mut self) -> Option<Vec<String>> {
+        self.advance();
+        if self.advance() != Some(&Token::LParen) {
+            return None;
+        }
+        let params = self.params()?;
+        (self.advance() == Some(&Token::Assign)).then_some(params)
+    }
+
+    /// Consume `params? ')'` after an opening parenthesis
+    fn params(&mut self) -> Option<Vec<String>> {
+        let mut params = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.advance();
+            return Some(params);
+        }
+        loop {
+            match self.advance() {
+                Some(Token::Identifier(param)) => params.push(param.clone()),
+                _ => return None,
+            }
+            match self.advance() {
+                Some(Token::Comma) => continue,
+                Some(Token::RParen) => return Some(params),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Consume `IDENT '->'` or `'(' params? ')' '->'` and return the parameter names
+    ///
+    /// Leaves the position where it was if the tokens don't start a lambda.
+    fn lambda_head(&mut self) -> Option<Vec<String>> {
+        let start = self.position;
+        let params = match self.advance() {
+            Some(Token::Identifier(param)) => Some(vec![param.clone()]),
+            Some(Token::LParen) => self.params(),
+            _ => None,
+        };
+        match params {
+            Some(params) if self.peek() == Some(&Token::Arrow) => {
+                self.advance();
+                Some(params)
+            }
+            _ => {
+                self.position = start;
+                None
+            }
+        }
+    }
+
+    // let -> 'let' IDENT '=' expression 'in' expression
+    fn let_binding(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position;
+        self.expect(&Token::Let)?;
+        let name = match self.advance().cloned() {
+            Some(Token::Identifier(name)) => name,
+            token => {
+                return Err(ParseError {
+                    message: format!("Expected a name after let, found {:?}", token),
+                    position: self.position.saturating_sub(1),
+                    kind: ParseErrorKind::Syntax,
+                })
+            }
+        };
+        self.expect(&Token::Assign)?;
+        let value = self.nested(|parser| parser.expression())?;
+        self.expect(&Token::In)?;
+        let body = self.nested(|parser| parser.expression())?;
+        Ok(self.node(start, Expr::let_in(name, value, body)))
+    }
+
+    // expression -> lambda | let | term (('+' | '-') term)*
     fn expression(&mut self) -> Result<Expr, ParseError> {
+        if let Some(Token::Let) = self.peek() {
+            return self.let_binding();
+        }
+        let start = self.position;
+        if let Some(params) = self.lambda_head() {
+            if let Some(param) = duplicate(&params) {
+                return Err(ParseError {
+                    message: format!("Duplicate parameter {} in lambda", param),
+                    position: start,
+                    kind: ParseErrorKind::Syntax,
+                });
+            }
+            let body = self.nested(|parser| parser.expression())?;
+            return Ok(self.node(start, Expr::lambda(params, body)));
+        }
+
         let mut left = self.term()?;
 
         while let Some(token) = self.peek().cloned() {
@@ -90,12 +311,12 @@ impl Parser {
                 Token::Plus => {
                     self.advance();
                     let right = self.term()?;
-                    left = Expr::add(left, right);
+                    left = self.node(start, Expr::add(left, right));
                 }
                 Token::Minus => {
                     self.advance();
                     let right = self.term()?;
-                    left = Expr::subtract(left, right);
+                    left = self.node(start, Expr::subtract(left, right));
                 }
                 _ => break,
             }
@@ -106,6 +327,7 @@ impl Parser {
 
     // term -> factor (('*' | '/' | '%') factor)*
     fn term(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position;
         let mut left = self.factor()?;
 
         while let Some(token) = self.peek().cloned() {
@@ -113,17 +335,17 @@ impl Parser {
                 Token::Multiply => {
                     self.advance();
                     let right = self.factor()?;
-                    left = Expr::multiply(left, right);
+                    left = self.node(start, Expr::multiply(left, right));
                 }
                 Token::Divide => {
                     self.advance();
                     let right = self.factor()?;
-                    left = Expr::divide(left, right);
+                    left = self.node(start, Expr::divide(left, right));
                 }
                 Token::Modulo => {
                     self.advance();
                     let right = self.factor()?;
-                    left = Expr::modulo(left, right);
+                    left = self.node(start, Expr::modulo(left, right));
                 }
                 _ => break,
             }
@@ -134,12 +356,13 @@ impl Parser {
 
     // factor -> base ('^' factor)?  (right associative)
     fn factor(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position;
         let base = self.unary()?;
 
         if let Some(Token::Power) = self.peek() {
             self.advance();
-            let exponent = self.factor()?;
-            return Ok(Expr::power(base, exponent));
+            let exponent = self.nested(Self::factor)?;
+            return Ok(self.node(start, Expr::power(base, exponent)));
         }
 
         Ok(base)
@@ -147,23 +370,55 @@ impl Parser {
 
     // unary -> ('-' unary) | postfix
     fn unary(&mut self) -> Result<Expr, ParseError> {
-        if let Some(Token::Minus) = self.peek() {
-            self.advance();
-            let operand = self.unary()?;
-            return Ok(Expr::negate(operand));
-        }
+        self.nested(|parser| {
+            if let Some(Token::Minus) = parser.peek() {
+                let start = parser.position;
+                parser.advance();
+                let operand = parser.unary()?;
+                return Ok(parser.node(start, Expr::negate(operand)));
+            }
+
+            parser.postfix()
+        })
+    }
 
-        self.postfix()
+    /// Apply `rule` one nesting level deeper, failing past `max_depth`
+    fn nested(&mut self, rule: impl FnOnce(&mut Self) -> Result<Expr, ParseError>) -> Result<Expr, ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(ParseError::too_deep(self.max_depth, self.position));
+        }
+        self.depth += 1;
+        let expr = rule(self);
+        self.depth -= 1;
+        expr
     }
 
     // postfix -> function_call ('!')*
     fn postfix(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position;
         let mut expr = self.function_call()?;
+        // Each `!` wraps the operand one level deeper, like a prefix operator
+        let mut depth = self.depth;
 
-        // Handle postfix factorial
-        while let Some(Token::Factorial) = self.peek() {
-            self.advance();
-            expr = Expr::factorial(expr);
+        loop {
+            match self.peek() {
+                Some(Token::Factorial) => {
+                    if depth >= self.max_depth {
+                        return Err(ParseError::too_deep(self.max_depth, self.position));
+                    }
+                    depth += 1;
+                    self.advance();
+                    expr = self.node(start, Expr::factorial(expr));
+                }
+                // `45 deg` marks a number of degrees, which angles already are;
+                // `deg(x)` is the conversion from radians
+                Some(Token::ToDeg)
+                    if matches!(expr, Expr::Number(_)) && self.tokens.get(self.position + 1) != Some(&Token::LParen) =>
+                {
+                    self.advance();
+                }
+                _ => break,
+            }
         }
 
         Ok(expr)
@@ -171,12 +426,14 @@ impl Parser {
 
     // function_call -> FUNC '(' args ')' | primary
     fn function_call(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position;
         let token = match self.peek().cloned() {
             Some(t) => t,
             None => {
                 return Err(ParseError {
                     message: "Unexpected end of input".to_string(),
                     position: self.position,
+                    kind: ParseErrorKind::Syntax,
                 })
             }
         };
@@ -189,9 +446,15 @@ impl Parser {
             Token::Asin => Some(UnaryOp::Asin),
             Token::Acos => Some(UnaryOp::Acos),
             Token::Atan => Some(UnaryOp::Atan),
+            Token::Sec => Some(UnaryOp::Sec),
+            Token::Csc => Some(UnaryOp::Csc),
+            Token::Cot => Some(UnaryOp::Cot),
             Token::Sinh => Some(UnaryOp::Sinh),
             Token::Cosh => Some(UnaryOp::Cosh),
             Token::Tanh => Some(UnaryOp::Tanh),
+            Token::Asinh => Some(UnaryOp::Asinh),
+            Token::Acosh => Some(UnaryOp::Acosh),
+            Token::Atanh => Some(UnaryOp::Atanh),
             Token::Sqrt => Some(UnaryOp::Sqrt),
             Token::Cbrt => Some(UnaryOp::Cbrt),
             Token::Log => Some(UnaryOp::Log),
@@ -202,6 +465,8 @@ impl Parser {
             Token::Floor => Some(UnaryOp::Floor),
             Token::Ceil => Some(UnaryOp::Ceil),
             Token::Round => Some(UnaryOp::Round),
+            Token::Trunc => Some(UnaryOp::Trunc),
+            Token::Frac => Some(UnaryOp::Frac),
             Token::Sign => Some(UnaryOp::Sign),
             Token::ToRad => Some(UnaryOp::ToRad),
             Token::ToDeg => Some(UnaryOp::ToDeg),
@@ -210,40 +475,104 @@ impl Parser {
             Token::Min => Some(UnaryOp::Min),
             Token::Max => Some(UnaryOp::Max),
             Token::Len => Some(UnaryOp::Len),
+            Token::Multinomial => Some(UnaryOp::Multinomial),
+            Token::Derange => Some(UnaryOp::Derange),
+            Token::Totient => Some(UnaryOp::Totient),
+            Token::PrimePi => Some(UnaryOp::PrimePi),
             _ => None,
         };
 
         if let Some(op) = unary_op {
             self.advance();
-            self.expect(&Token::LParen)?;
-            let arg = self.expression()?;
-            self.expect(&Token::RParen)?;
-            return Ok(Expr::unary(op, arg));
+            // round(x, digits) is the one function with an optional argument
+            let arity = if op == UnaryOp::Round { 1..=2 } else { 1..=1 };
+            let mut args = self.builtin_arguments(start, &token, arity, Self::expression)?.into_iter();
+            let (arg, digits) = (args.next().expect("arity checked"), args.next());
+            let expr = match digits {
+                Some(digits) => Expr::binary(BinaryOp::RoundTo, arg, digits),
+                None => Expr::unary(op, arg),
+            };
+            return Ok(self.node(start, expr));
         }
 
-        // Binary functions (gcd, lcm, nPr, nCr)
+        // Binary functions (gcd, lcm, nPr, nCr, stirling2)
         let binary_op = match &token {
             Token::Gcd => Some(BinaryOp::Gcd),
             Token::Lcm => Some(BinaryOp::Lcm),
             Token::Npr => Some(BinaryOp::Npr),
             Token::Ncr => Some(BinaryOp::Ncr),
+            Token::Stirling2 => Some(BinaryOp::Stirling2),
             _ => None,
         };
 
         if let Some(op) = binary_op {
             self.advance();
-            self.expect(&Token::LParen)?;
-            let arg1 = self.expression()?;
-            self.expect(&Token::Comma)?;
-            let arg2 = self.expression()?;
-            self.expect(&Token::RParen)?;
-            return Ok(Expr::binary(op, arg1, arg2));
+            let mut args = self.builtin_arguments(start, &token, 2..=2, Self::expression)?.into_iter();
+            let (arg1, arg2) = (args.next().expect("arity checked"), args.next().expect("arity checked"));
+            return Ok(self.node(start, Expr::binary(op, arg1, arg2)));
+        }
+
+        // Symbolic rewrites replace the call with the rewritten argument
+        if let Token::Expand | Token::Factor = token {
+            self.advance();
+            let position = self.position + 1;
+            let recorded = self.spans.len();
+            let arg = self.builtin_arguments(start, &token, 1..=1, Self::expression)?.remove(0);
+            let rewritten = if token == Token::Expand { symbolic::expand(&arg) } else { symbolic::factor(&arg) };
+            let rewritten = rewritten.map_err(|message| ParseError { message, position, kind: ParseErrorKind::Syntax })?;
+            // The argument's nodes are gone; the whole call produced the new ones
+            self.spans.truncate(recorded);
+            self.spans.extend(core::iter::repeat_n(start..self.position, rewritten.size()));
+            return Ok(rewritten);
+        }
+
+        // Builtins with more than two arguments or array arguments
+        let nary_op = match &token {
+            Token::Linspace => Some(NaryOp::Linspace),
+            Token::Zeros => Some(NaryOp::Zeros),
+            Token::Ones => Some(NaryOp::Ones),
+            Token::Repeat => Some(NaryOp::Repeat),
+            Token::Hist => Some(NaryOp::Hist),
+            Token::HistEdges => Some(NaryOp::HistEdges),
+            Token::Solve => Some(NaryOp::Solve),
+            Token::Eigvals => Some(NaryOp::Eigvals),
+            Token::Linfit => Some(NaryOp::Linfit),
+            Token::NormPdf => Some(NaryOp::NormPdf),
+            Token::NormCdf => Some(NaryOp::NormCdf),
+            Token::InvNorm => Some(NaryOp::InvNorm),
+            Token::BinomPdf => Some(NaryOp::BinomPdf),
+            Token::PoissonPdf => Some(NaryOp::PoissonPdf),
+            Token::FromBase => Some(NaryOp::FromBase),
+            Token::ToBase => Some(NaryOp::ToBase),
+            Token::Cfrac => Some(NaryOp::Cfrac),
+            Token::Convergent => Some(NaryOp::Convergent),
+            Token::Sample => Some(NaryOp::Sample),
+            Token::Shuffle => Some(NaryOp::Shuffle),
+            Token::Map => Some(NaryOp::Map),
+            Token::Filter => Some(NaryOp::Filter),
+            _ => None,
+        };
+
+        if let Some(op) = nary_op {
+            self.advance();
+            let mut negative = false;
+            let first = |parser: &mut Self| match parser.peek().cloned() {
+                Some(Token::Text(text)) if op == NaryOp::FromBase => {
+                    let (sign, digits) = parser.digit_string(&text)?;
+                    negative = sign;
+                    Ok(digits)
+                }
+                _ => parser.expression(),
+            };
+            let args = self.builtin_arguments(start, &token, op.arity()..=op.arity(), first)?;
+            let call = self.node(start, Expr::nary(op, args));
+            return Ok(if negative { self.node(start, Expr::unary(UnaryOp::Negate, call)) } else { call });
         }
 
         self.primary()
     }
 
-    // primary -> NUMBER | '(' expression ')' | CONSTANT | array
+    // primary -> NUMBER | '(' expression ')' | '|' expression '|' | CONSTANT | array
     fn primary(&mut self) -> Result<Expr, ParseError> {
         let token = match self.peek().cloned() {
             Some(t) => t,
@@ -251,31 +580,33 @@ impl Parser {
                 return Err(ParseError {
                     message: "Unexpected end of input".to_string(),
                     position: self.position,
+                    kind: ParseErrorKind::Syntax,
                 })
             }
         };
 
+        let start = self.position;
         match token {
             Token::Number(n) => {
                 self.advance();
-                Ok(Expr::number(n))
+                Ok(self.node(start, Expr::number(n)))
             }
             Token::Pi => {
                 self.advance();
-                Ok(Expr::number(std::f64::consts::PI))
+                Ok(self.node(start, Expr::number(core::f64::consts::PI)))
             }
             Token::E => {
                 self.advance();
-                Ok(Expr::number(std::f64::consts::E))
+                Ok(self.node(start, Expr::number(core::f64::consts::E)))
             }
             Token::Tau => {
                 self.advance();
-                Ok(Expr::number(std::f64::consts::TAU))
+                Ok(self.node(start, Expr::number(core::f64::consts::TAU)))
             }
             Token::Phi => {
                 self.advance();
                 // Golden ratio: (1 + sqrt(5)) / 2
-                Ok(Expr::number(1.618033988749895))
+                Ok(self.node(start, Expr::number(1.618033988749895)))
             }
             Token::LParen => {
                 self.advance();
@@ -283,18 +614,121 @@ impl Parser {
                 self.expect(&Token::RParen)?;
                 Ok(expr)
             }
+            Token::Pipe => {
+                self.advance();
+                let expr = self.nested(|parser| parser.expression())?;
+                self.expect(&Token::Pipe)?;
+                Ok(self.node(start, Expr::unary(UnaryOp::Abs, expr)))
+            }
             Token::LBracket => {
                 self.parse_array()
             }
+            Token::Identifier(name) => {
+                self.advance();
+                if let Some(Token::LParen) = self.peek() {
+                    return self.parse_call(start, name.to_string());
+                }
+                Ok(self.node(start, Expr::variable(name)))
+            }
             _ => Err(ParseError {
                 message: format!("Unexpected token: {:?}", token),
                 position: self.position,
+                kind: ParseErrorKind::Syntax,
             }),
         }
     }
 
+    /// The digits of a `frombase` string as an array of their values, and
+    /// whether it had a leading minus sign
+    fn digit_string(&mut self, text: &str) -> Result<(bool, Expr), ParseError> {
+        let error = |message: String| ParseError { message, position: self.position, kind: ParseErrorKind::Syntax };
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        if digits.is_empty() {
+            return Err(error("frombase needs at least one digit".to_string()));
+        }
+        let values = digits
+            .chars()
+            .map(|ch| match ch.to_digit(36) {
+                Some(value) => Ok(Expr::number(value as f64)),
+                None => Err(error(format!("'{}' is not a digit", ch))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let start = self.position;
+        self.advance();
+        // Every digit, and the array of them, comes from the one string
+        self.spans.extend(core::iter::repeat_n(start..self.position, values.len()));
+        Ok((negative, self.node(start, Expr::array(values))))
+    }
+
+    /// Consume `'(' (argument (',' expression)*)? ')'`, the first argument
+    /// parsed by `first`
+    fn arguments(
+        &mut self,
+        first: impl FnOnce(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Vec<Expr>, ParseError> {
+        self.expect(&Token::LParen)?;
+
+        let mut args = Vec::new();
+        if let Some(Token::RParen) = self.peek() {
+            self.advance();
+            return Ok(args);
+        }
+
+        args.push(first(self)?);
+        while let Some(Token::Comma) = self.peek() {
+            self.advance();
+            args.push(self.expression()?);
+        }
+
+        self.expect(&Token::RParen)?;
+        Ok(args)
+    }
+
+    /// Arguments of the builtin `token` named at `start`, failing there
+    /// unless their number is within `arity`
+    fn builtin_arguments(
+        &mut self,
+        start: usize,
+        token: &Token,
+        arity: RangeInclusive<usize>,
+        first: impl FnOnce(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Vec<Expr>, ParseError> {
+        let args = self.arguments(first)?;
+        if arity.contains(&args.len()) {
+            return Ok(args);
+        }
+        let name = builtins::for_token(token).map_or("function", |builtin| builtin.name);
+        let expected = match (*arity.start(), *arity.end()) {
+            (1, 1) => "1 argument".to_string(),
+            (min, max) if min == max => format!("{} arguments", min),
+            (min, max) => format!("{} or {} arguments", min, max),
+        };
+        Err(ParseError {
+            message: format!("{} expects {}, found {}", name, expected, args.len()),
+            position: start,
+            kind: ParseErrorKind::Syntax,
+        })
+    }
+
+    // call -> IDENT '(' (expression (',' expression)*)? ')'
+    fn parse_call(&mut self, start: usize, name: String) -> Result<Expr, ParseError> {
+        let args = self.arguments(Self::expression)?;
+        if args.len() > u8::MAX as usize {
+            return Err(ParseError {
+                message: format!("Too many arguments in call to {}", name),
+                position: start,
+                kind: ParseErrorKind::Syntax,
+            });
+        }
+        Ok(self.node(start, Expr::call(name, args)))
+    }
+
     // array -> '[' (expression (',' expression)*)? ']'
     fn parse_array(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position;
         self.expect(&Token::LBracket)?;
         
         let mut elements = Vec::new();
@@ -302,7 +736,7 @@ impl Parser {
         // Check for empty array
         if let Some(Token::RBracket) = self.peek() {
             self.advance();
-            return Ok(Expr::array(elements));
+            return Ok(self.node(start, Expr::array(elements)));
         }
 
         // Parse first element
@@ -315,10 +749,39 @@ impl Parser {
         }
 
         self.expect(&Token::RBracket)?;
-        Ok(Expr::array(elements))
+        Ok(self.node(start, Expr::array(elements)))
     }
 }
 
+/// `postorder`, the spans of `expr`'s nodes with children before parents,
+/// reordered to put every node before its children
+///
+/// Reversed, children before parents is parents before children with each
+/// node's children taken right to left, which pairs every node with its
+/// span without knowing subtree sizes.
+fn preorder(expr: &Expr, postorder: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut spans = BTreeMap::new();
+    let mut remaining = postorder.iter().rev();
+    let mut pending = vec![expr];
+    while let Some(node) = pending.pop() {
+        spans.insert(node as *const Expr, remaining.next().cloned().unwrap_or_default());
+        pending.extend(node.children());
+    }
+
+    let mut ordered = Vec::with_capacity(spans.len());
+    let mut pending = vec![expr];
+    while let Some(node) = pending.pop() {
+        ordered.push(spans[&(node as *const Expr)].clone());
+        pending.extend(node.children().into_iter().rev());
+    }
+    ordered
+}
+
+/// A parameter named more than once in `params`
+fn duplicate(params: &[String]) -> Option<&String> {
+    params.iter().enumerate().find_map(|(i, param)| params[..i].contains(param).then_some(param))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -425,4 +888,180 @@ mod tests {
             Expr::modulo(Expr::number(10.0), Expr::number(3.0))
         );
     }
+
+    #[test]
+    fn test_assignment_and_variables() {
+        let expr = parse("x = 2; x * y").unwrap();
+        assert_eq!(
+            expr,
+            Expr::Sequence(vec![
+                Expr::assign("x", Expr::number(2.0)),
+                Expr::multiply(Expr::variable("x"), Expr::variable("y")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_function_definition_and_call() {
+        let expr = parse("hyp(a, b) = sqrt(a^2 + b^2); hyp(3, 4);").unwrap();
+        let Expr::Sequence(statements) = &expr else {
+            panic!("expected a sequence");
+        };
+        assert!(matches!(
+            &statements[0],
+            Expr::FunctionDef { name, params, .. } if name == "hyp" && params == &["a", "b"]
+        ));
+        assert_eq!(
+            statements[1],
+            Expr::call("hyp", vec![Expr::number(3.0), Expr::number(4.0)])
+        );
+
+        // A call that isn't followed by '=' is an expression
+        assert_eq!(parse("f(x) + 1").unwrap(), Expr::add(Expr::call("f", vec![Expr::variable("x")]), Expr::number(1.0)));
+        assert!(parse("f(x, x) = x").is_err());
+    }
+
+    #[test]
+    fn test_lambda() {
+        let square = Expr::lambda(vec!["x".into()], Expr::power(Expr::variable("x"), Expr::number(2.0)));
+        assert_eq!(
+            parse("map(x -> x^2, [1, 2])").unwrap(),
+            Expr::nary(NaryOp::Map, vec![square, Expr::array(vec![Expr::number(1.0), Expr::number(2.0)])])
+        );
+        let add = parse("f = (a, b) -> a + b").unwrap();
+        assert_eq!(add.to_string(), "f = (a, b) -> (a + b)");
+        assert_eq!(parse("() -> 1").unwrap(), Expr::lambda(vec![], Expr::number(1.0)));
+
+        // A parenthesized expression isn't a parameter list
+        assert_eq!(parse("(x) * 2").unwrap(), Expr::multiply(Expr::variable("x"), Expr::number(2.0)));
+        assert!(parse("(x, x) -> x").is_err());
+    }
+
+    #[test]
+    fn test_let() {
+        let expr = parse("let x = 2 in let y = x + 1 in x * y").unwrap();
+        let inner = Expr::let_in(
+            "y",
+            Expr::add(Expr::variable("x"), Expr::number(1.0)),
+            Expr::multiply(Expr::variable("x"), Expr::variable("y")),
+        );
+        assert_eq!(expr, Expr::let_in("x", Expr::number(2.0), inner));
+        assert_eq!(expr.to_string(), "(let x = 2 in (let y = (x + 1) in (x * y)))");
+        // The body extends as far right as it can
+        assert_eq!(parse(&expr.to_string()).unwrap(), expr);
+        assert_eq!(parse("1 + (let x = 2 in x) * 3").unwrap().to_string(), "(1 + ((let x = 2 in x) * 3))");
+
+        assert!(parse("let 2 = 1 in 3").unwrap_err().message.contains("Expected a name after let"));
+        assert!(parse("let x = 1; x").is_err());
+    }
+
+    #[test]
+    fn test_parse_spanned() {
+        let parse_spanned = |input: &str| Parser::new(Tokenizer::new(input).tokenize().unwrap()).parse_spanned().unwrap();
+
+        // Tokens: 1 + 2 * x
+        let (expr, spans) = parse_spanned("1 + 2 * x");
+        assert_eq!(expr, parse("1 + 2 * x").unwrap());
+        assert_eq!(spans, [0..5, 0..1, 2..5, 2..3, 4..5]);
+
+        // Brackets aren't nodes; tokens: ( 1 + 2 ) ! ; y = - x
+        let (_, spans) = parse_spanned("(1 + 2)!; y = -x");
+        assert_eq!(spans, [0..11, 0..6, 1..4, 1..2, 3..4, 7..11, 9..11, 10..11]);
+
+        // Every node of a rewrite spans the call
+        let (expr, spans) = parse_spanned("expand((x + 1)^2)");
+        assert_eq!(spans.len(), expr.size());
+        assert!(spans.iter().all(|span| *span == (0..10)));
+    }
+
+    #[test]
+    fn test_arity_errors() {
+        let error = |input: &str| parse(input).unwrap_err();
+        let found = error("1 + nCr(5, 2, 1)");
+        assert_eq!(found.position, 2);
+        assert_eq!(found.to_string(), "Parse error at position 2: nCr expects 2 arguments, found 3");
+        assert_eq!(error("choose(5)").message, "nCr expects 2 arguments, found 1");
+        assert_eq!(error("sin(1, 2)").message, "sin expects 1 argument, found 2");
+        assert_eq!(error("sqrt()").message, "sqrt expects 1 argument, found 0");
+        assert_eq!(error("round(1, 2, 3)").message, "round expects 1 or 2 arguments, found 3");
+        assert_eq!(error("linspace(0, 1)").message, "linspace expects 3 arguments, found 2");
+        assert_eq!(error("expand(x, 1)").message, "expand expects 1 argument, found 2");
+
+        assert_eq!(parse("round(1.25, 1)").unwrap(), Expr::binary(BinaryOp::RoundTo, Expr::number(1.25), Expr::number(1.0)));
+        // A missing bracket is still reported as one
+        assert!(error("nCr(5, 2").message.contains("found end of input"));
+    }
+
+    #[test]
+    fn test_absolute_value_bars() {
+        let abs = |expr| Expr::unary(UnaryOp::Abs, expr);
+        assert_eq!(parse("|x|").unwrap(), abs(Expr::variable("x")));
+        assert_eq!(parse("2 * |-3|").unwrap(), Expr::multiply(Expr::number(2.0), abs(Expr::negate(Expr::number(3.0)))));
+        let nested = abs(Expr::subtract(abs(Expr::variable("x")), Expr::number(1.0)));
+        assert_eq!(parse("| |x| - 1 |").unwrap(), nested);
+        assert_eq!(parse("||x| - 1|").unwrap(), nested);
+        assert_eq!(parse("||x||").unwrap(), abs(abs(Expr::variable("x"))));
+        assert_eq!(parse("|x|^2").unwrap(), Expr::power(abs(Expr::variable("x")), Expr::number(2.0)));
+
+        assert!(parse("|x").unwrap_err().message.contains("Expected Pipe"));
+        assert!(parse("||").is_err());
+    }
+
+    #[test]
+    fn test_degree_suffix() {
+        assert_eq!(parse("45 deg").unwrap(), Expr::number(45.0));
+        assert_eq!(parse("sin(30deg) + 1").unwrap(), Expr::add(Expr::unary(UnaryOp::Sin, Expr::number(30.0)), Expr::number(1.0)));
+        assert_eq!(parse("30°15'").unwrap(), Expr::number(30.25));
+        // Still the conversion when called
+        assert_eq!(parse("2 * deg(1)").unwrap(), Expr::multiply(Expr::number(2.0), Expr::unary(UnaryOp::ToDeg, Expr::number(1.0))));
+        assert!(parse("x deg").is_err());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let parse_within = |input: &str, max_depth| {
+            Parser::new(Tokenizer::new(input).tokenize().unwrap()).with_max_depth(max_depth).parse()
+        };
+        assert!(parse_within("((1))", 3).is_ok());
+        assert!(parse_within("(((1)))", 3).is_err());
+        assert!(parse_within("2^2^2^2", 3).is_err());
+        assert!(parse_within("-sqrt([1])", 3).is_err());
+        // Chains loop rather than recurse
+        assert!(parse_within("1 + 2 * 3 - 4", 1).is_ok());
+        assert_eq!(parse_within("1 +", 1).unwrap_err().kind, ParseErrorKind::Syntax);
+        // Reported where the nesting went too deep
+        let error = parse_within("1 + (2 * (3 - 4))", 2).unwrap_err();
+        assert_eq!((error.kind, error.position), (ParseErrorKind::TooDeep, 6));
+    }
+
+    #[test]
+    fn test_default_max_depth() {
+        let nested = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        let error = parse(&nested).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::TooDeep);
+        assert_eq!(error.message, format!("Expression nested more than {} levels deep", DEFAULT_MAX_DEPTH));
+
+        let negations = format!("{}1", "-".repeat(100_000));
+        assert_eq!(parse(&negations).unwrap_err().kind, ParseErrorKind::TooDeep);
+        let factorials = format!("3{}", "!".repeat(100_000));
+        let error = parse(&factorials).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::TooDeep);
+        assert_eq!(error.position, DEFAULT_MAX_DEPTH);
+
+        // Anything within the limit parses and compiles
+        let brackets = format!("{}1{}", "(".repeat(DEFAULT_MAX_DEPTH - 1), ")".repeat(DEFAULT_MAX_DEPTH - 1));
+        assert!(parse(&brackets).is_ok());
+        let ast = parse(&format!("{}1", "-".repeat(DEFAULT_MAX_DEPTH - 1))).unwrap();
+        assert_eq!(ast.depth(), DEFAULT_MAX_DEPTH);
+        assert!(crate::CodeGenerator::new().compile(&ast).is_ok());
+        let ast = parse(&format!("0{}", "!".repeat(DEFAULT_MAX_DEPTH - 1))).unwrap();
+        assert_eq!(ast.depth(), DEFAULT_MAX_DEPTH);
+        assert!(crate::CodeGenerator::new().compile(&ast).is_ok());
+
+        // Chains of any length are compiled and dropped in a loop
+        let chain = vec!["1"; 100_000].join("+");
+        assert_eq!(crate::evaluate(&chain), Ok(100_000.0));
+        let ast = parse(&chain).unwrap();
+        assert!(crate::CodeGenerator::new().compile_registers(&ast).is_ok());
+    }
 }
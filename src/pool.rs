@@ -0,0 +1,166 @@
+//! Evaluator pool for servers
+//!
+//! An `EvaluatorPool` is shared by reference between threads. Each formula
+//! is compiled once and its chunk cached, while execution happens on a VM
+//! belonging to the calling thread, so threads never wait on each other's
+//! execution. The VMs work within the pool's `Limits`, as
+//! `evaluate_sandboxed` does, and their variables, functions and heap are
+//! cleared after every evaluation, so nothing one defines is visible to the
+//! next.
+//!
+//! A thread keeps its VM, and the heap that comes with it, until it exits.
+
+use crate::sandbox::{compile_sandboxed, limited_vm};
+use crate::vm::VmError;
+use crate::{Chunk, Limits, VirtualMachine};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Compiled formulas kept by default
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+thread_local! {
+    /// This thread's VM, with the limits it was made for
+    static VM: RefCell<Option<(Limits, VirtualMachine)>> = const { RefCell::new(None) };
+}
+
+/// Compiles formulas once and evaluates them on per-thread VMs
+pub struct EvaluatorPool {
+    limits: Limits,
+    /// Compiled chunks by source text
+    chunks: Mutex<HashMap<String, Arc<Chunk>>>,
+    cache_capacity: usize,
+}
+
+/// Lock `mutex`, ignoring a panic in another thread; the pool's state is
+/// valid between any two operations
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl EvaluatorPool {
+    /// A pool evaluating within `Limits::default()`
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        EvaluatorPool {
+            limits,
+            chunks: Mutex::new(HashMap::new()),
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+        }
+    }
+
+    /// Keep at most `capacity` compiled formulas; the cache starts over once it is full
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// The chunk for `input`, compiled on first use
+    pub fn compile(&self, input: &str) -> Result<Arc<Chunk>, String> {
+        if let Some(chunk) = lock(&self.chunks).get(input) {
+            return Ok(chunk.clone());
+        }
+        // Compiled outside the lock; two threads may both compile a new formula
+        let chunk = Arc::new(compile_sandboxed(input, self.limits)?);
+        let mut chunks = lock(&self.chunks);
+        if chunks.len() >= self.cache_capacity {
+            chunks.clear();
+        }
+        if self.cache_capacity > 0 {
+            chunks.insert(input.to_string(), chunk.clone());
+        }
+        Ok(chunk)
+    }
+
+    /// Evaluate `input` on this thread's VM
+    pub fn evaluate(&self, input: &str) -> Result<f64, String> {
+        let chunk = self.compile(input)?;
+        self.execute(&chunk).map_err(|e| e.to_string())
+    }
+
+    /// Execute a compiled chunk on this thread's VM
+    ///
+    /// A thread has one VM, remade whenever a pool with other limits uses it.
+    pub fn execute(&self, chunk: &Chunk) -> Result<f64, VmError> {
+        VM.with(|slot| {
+            let Ok(mut slot) = slot.try_borrow_mut() else {
+                // This thread's VM is busy further up the stack
+                return limited_vm(self.limits).execute(chunk);
+            };
+            if !matches!(&*slot, Some((limits, _)) if *limits == self.limits) {
+                *slot = Some((self.limits, limited_vm(self.limits)));
+            }
+            let (_, vm) = slot.as_mut().expect("VM was just made");
+            let result = vm.execute(chunk);
+            vm.reset_heap();
+            result
+        })
+    }
+
+    /// Number of compiled formulas in the cache
+    pub fn cached(&self) -> usize {
+        lock(&self.chunks).len()
+    }
+}
+
+impl Default for EvaluatorPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_concurrent_evaluation() {
+        fn shared<T: Send + Sync>(_: &T) {}
+        let pool = EvaluatorPool::new();
+        shared(&pool);
+
+        thread::scope(|scope| {
+            for n in 0..8 {
+                let pool = &pool;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        assert_eq!(pool.evaluate("f(x) = x^2; f(3) + sum([1, 2])"), Ok(12.0));
+                        let n = n as f64;
+                        assert_eq!(pool.evaluate(&format!("{} * 2", n)), Ok(n * 2.0));
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.cached(), 9);
+
+        // Definitions don't leak between evaluations, nor does the heap
+        assert!(pool.evaluate("f(3)").is_err());
+        assert_eq!(pool.evaluate("sum([1, 2, 3])"), Ok(6.0));
+        VM.with(|slot| assert!(slot.borrow().as_ref().unwrap().1.heap_objects().is_empty()));
+    }
+
+    #[test]
+    fn test_limits_and_cache() {
+        let limits = Limits { max_instructions: 10, ..Limits::default() };
+        let pool = EvaluatorPool::with_limits(limits).with_cache_capacity(2);
+        assert_eq!(pool.evaluate(&vec!["1"; 20].join("+")).unwrap_err(), "Instruction limit of 10 exceeded");
+        assert!(pool.evaluate("1 +").is_err());
+
+        let chunk = pool.compile("2 + 3").unwrap();
+        assert!(Arc::ptr_eq(&chunk, &pool.compile("2 + 3").unwrap()));
+        pool.compile("4").unwrap();
+        assert_eq!(pool.cached(), 1);
+        assert_eq!(pool.execute(&chunk).unwrap(), 5.0);
+
+        // Pools with other limits on the same thread get a VM of their own
+        let small = EvaluatorPool::with_limits(Limits { max_memory: 512, ..Limits::default() });
+        let array = format!("sum([{}])", vec!["1"; 200].join(","));
+        assert_eq!(small.evaluate(&array).unwrap_err(), "Out of memory");
+        assert_eq!(EvaluatorPool::new().evaluate(&array), Ok(200.0));
+    }
+}
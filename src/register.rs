@@ -0,0 +1,202 @@
+//! Register IR - Experimental register-machine backend
+//!
+//! An alternative to the stack bytecode in which every instruction names
+//! its operands explicitly. Constants are folded into operands instead of
+//! being pushed, so the same expression usually needs fewer instructions:
+//!
+//!   Input:    "sin(90) + 2^3"
+//!   Stack:    PUSH 90, SIN, PUSH 2, PUSH 3, POW, ADD, HALT   (7 instructions)
+//!   Register:
+//!     0000: r0 = SIN 90
+//!     0001: r1 = POW 2, 3
+//!     0002: r0 = ADD r0, r1
+//!     0003: RET r0
+//!
+//! Registers are allocated by expression depth, so `register_count` is the
+//! register-file size the VM needs to run the chunk.
+
+use crate::prelude::*;
+use crate::bytecode::{Function, OpCode};
+use crate::intern::Symbol;
+use core::fmt;
+use alloc::sync::Arc;
+
+/// Index into the VM register file
+pub type Register = usize;
+
+/// Instruction operand: a register or an inline constant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Reg(Register),
+    Const(f64),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Reg(r) => write!(f, "r{}", r),
+            Source::Const(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Register-machine instruction
+///
+/// Operations reuse `OpCode` so both backends share one definition of
+/// what each operation means.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegInstr {
+    /// dst = src
+    Move { dst: Register, src: Source },
+    /// dst = op(src)
+    Unary { op: OpCode, dst: Register, src: Source },
+    /// dst = op(lhs, rhs)
+    Binary { op: OpCode, dst: Register, lhs: Source, rhs: Source },
+    /// dst = [start, start + count)
+    MakeArray { dst: Register, start: Register, count: usize },
+    /// dst = op(start, ..., start + count - 1)
+    Nary { op: OpCode, dst: Register, start: Register, count: usize },
+    /// dst = global variable `name`
+    LoadGlobal { dst: Register, name: Symbol },
+    /// global variable `name` = src
+    StoreGlobal { name: Symbol, src: Source },
+    /// Bind a function from the chunk's function table
+    Define { function: usize },
+    /// dst = function value of a lambda from the chunk's function table,
+    /// capturing start, ..., start + count - 1
+    Closure { dst: Register, function: usize, start: Register, count: usize },
+    /// dst = name(start, ..., start + count - 1)
+    Call { dst: Register, name: Symbol, start: Register, count: usize },
+    /// Finish execution with the value of src
+    Return { src: Source },
+}
+
+impl fmt::Display for RegInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegInstr::Move { dst, src } => write!(f, "r{} = {}", dst, src),
+            RegInstr::Unary { op, dst, src } => write!(f, "r{} = {} {}", dst, op, src),
+            RegInstr::Binary { op, dst, lhs, rhs } => write!(f, "r{} = {} {}, {}", dst, op, lhs, rhs),
+            RegInstr::MakeArray { dst, start, count } => {
+                write!(f, "r{} = ARRAY r{}..r{}", dst, start, start + count)
+            }
+            RegInstr::Nary { op, dst, start, count } => {
+                write!(f, "r{} = {} r{}..r{}", dst, op, start, start + count)
+            }
+            RegInstr::LoadGlobal { dst, name } => write!(f, "r{} = {}", dst, name),
+            RegInstr::StoreGlobal { name, src } => write!(f, "{} = {}", name, src),
+            RegInstr::Define { function } => write!(f, "DEF #{}", function),
+            RegInstr::Closure { dst, function, start, count } => {
+                write!(f, "r{} = CLOSURE #{} r{}..r{}", dst, function, start, start + count)
+            }
+            RegInstr::Call { dst, name, start, count } => {
+                write!(f, "r{} = CALL {} r{}..r{}", dst, name, start, start + count)
+            }
+            RegInstr::Return { src } => write!(f, "RET {}", src),
+        }
+    }
+}
+
+/// A compiled register-machine program
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegisterChunk {
+    instructions: Vec<RegInstr>,
+    register_count: usize,
+    /// Functions bound by `Define`, compiled for the stack machine
+    functions: Vec<Arc<Function>>,
+}
+
+impl RegisterChunk {
+    pub fn new() -> Self {
+        RegisterChunk::default()
+    }
+
+    /// Append an instruction, growing the register file to cover it
+    pub fn emit(&mut self, instr: RegInstr) {
+        let highest = match &instr {
+            RegInstr::Move { dst, .. }
+            | RegInstr::Unary { dst, .. }
+            | RegInstr::Binary { dst, .. }
+            | RegInstr::LoadGlobal { dst, .. } => Some(*dst),
+            RegInstr::MakeArray { dst, start, count }
+            | RegInstr::Closure { dst, start, count, .. }
+            | RegInstr::Nary { dst, start, count, .. }
+            | RegInstr::Call { dst, start, count, .. } => {
+                Some((*dst).max(start + count.saturating_sub(1)))
+            }
+            RegInstr::StoreGlobal { .. } | RegInstr::Define { .. } | RegInstr::Return { .. } => None,
+        };
+        if let Some(reg) = highest {
+            self.register_count = self.register_count.max(reg + 1);
+        }
+        self.instructions.push(instr);
+    }
+
+    pub fn instructions(&self) -> &[RegInstr] {
+        &self.instructions
+    }
+
+    /// Add a function to the function table, returning its index
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.functions.push(Arc::new(function));
+        self.functions.len() - 1
+    }
+
+    pub fn functions(&self) -> &[Arc<Function>] {
+        &self.functions
+    }
+
+    /// Number of registers the program touches
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl fmt::Display for RegisterChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "; {} registers", self.register_count)?;
+        for (index, instr) in self.instructions.iter().enumerate() {
+            writeln!(f, "{:04}: {}", index, instr)?;
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            writeln!(f, "; #{}: {}", index, function)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_count_tracks_destinations() {
+        let mut chunk = RegisterChunk::new();
+        chunk.emit(RegInstr::Move { dst: 1, src: Source::Const(2.0) });
+        chunk.emit(RegInstr::MakeArray { dst: 0, start: 0, count: 3 });
+        chunk.emit(RegInstr::Return { src: Source::Reg(0) });
+        assert_eq!(chunk.register_count(), 3);
+        assert_eq!(chunk.len(), 3);
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut chunk = RegisterChunk::new();
+        chunk.emit(RegInstr::Binary {
+            op: OpCode::Add,
+            dst: 0,
+            lhs: Source::Const(1.0),
+            rhs: Source::Const(2.5),
+        });
+        chunk.emit(RegInstr::Return { src: Source::Reg(0) });
+        assert_eq!(chunk.to_string(), "; 1 registers\n0000: r0 = ADD 1, 2.5\n0001: RET r0\n");
+    }
+}
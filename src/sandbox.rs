@@ -0,0 +1,128 @@
+//! Sandboxed evaluation of untrusted input
+//!
+//! `evaluate_sandboxed` runs the same pipeline as `evaluate`, but every
+//! stage is bounded, so a formula submitted by a user can't exhaust the
+//! stack, the heap or the CPU of the process evaluating it:
+//!
+//!   tokens       at most `max_tokens`
+//!   parse / AST  nested at most `max_ast_depth` levels
+//!   VM           at most `max_instructions` executed, counting function
+//!                bodies, calls nested at most `max_call_depth` deep,
+//!                and `max_memory` bytes of arrays
+//!
+//! The VM is fresh for every call, so nothing one input defines is visible
+//! to the next.
+
+use crate::prelude::*;
+use crate::{Chunk, CodeGenerator, Optimizer, Parser, Tokenizer, VirtualMachine};
+
+/// Resource limits for `evaluate_sandboxed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_tokens: usize,
+    pub max_ast_depth: usize,
+    pub max_instructions: usize,
+    pub max_call_depth: usize,
+    /// Bytes of GC heap available to arrays
+    pub max_memory: usize,
+}
+
+impl Default for Limits {
+    /// Generous for anything typed into a calculator
+    fn default() -> Self {
+        Limits {
+            max_tokens: 1_000,
+            max_ast_depth: 64,
+            max_instructions: 100_000,
+            max_call_depth: 64,
+            max_memory: 1 << 20,
+        }
+    }
+}
+
+/// Evaluate an expression string within `limits`
+pub fn evaluate_sandboxed(input: &str, limits: Limits) -> Result<f64, String> {
+    let chunk = compile_sandboxed(input, limits)?;
+
+    // Execute
+    let mut vm = limited_vm(limits);
+    vm.execute(&chunk).map_err(|e| e.to_string())
+}
+
+/// Compile an expression string, refusing input beyond the token and depth limits
+pub(crate) fn compile_sandboxed(input: &str, limits: Limits) -> Result<Chunk, String> {
+    // Tokenize
+    let tokens = Tokenizer::new(input).tokenize().map_err(|e| e.to_string())?;
+    if tokens.len() > limits.max_tokens {
+        return Err(format!("Too many tokens: {} (limit {})", tokens.len(), limits.max_tokens));
+    }
+
+    // Parse; operator chains like 1+1+1 deepen the tree without nesting
+    let ast = Parser::new(tokens)
+        .with_max_depth(limits.max_ast_depth)
+        .parse()
+        .map_err(|e| e.to_string())?;
+    let depth = ast.depth();
+    if depth > limits.max_ast_depth {
+        return Err(format!("Expression too deep: {} levels (limit {})", depth, limits.max_ast_depth));
+    }
+
+    // Compile
+    Ok(Optimizer::fuse_superinstructions(&CodeGenerator::new().compile(&ast).map_err(|e| e.to_string())?.0))
+}
+
+/// A VM held to the instruction, call depth and memory limits
+pub(crate) fn limited_vm(limits: Limits) -> VirtualMachine {
+    let mut vm = VirtualMachine::with_memory_limit(limits.max_memory);
+    vm.set_instruction_limit(limits.max_instructions);
+    vm.set_recursion_limit(limits.max_call_depth);
+    vm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_within_limits() {
+        assert_eq!(evaluate_sandboxed("f(x) = x^2; f(3) + sum([1, 2])", Limits::default()), Ok(12.0));
+    }
+
+    #[test]
+    fn test_each_limit() {
+        let limits = Limits::default();
+
+        let long = vec!["1"; 600].join("+");
+        let err = evaluate_sandboxed(&long, limits).unwrap_err();
+        assert_eq!(err, "Too many tokens: 1199 (limit 1000)");
+        // Names in refused input are never interned
+        let names = vec!["sandbox_test_unseen"; 600].join("+");
+        assert!(evaluate_sandboxed(&names, limits).is_err());
+        assert_eq!(crate::Symbol::lookup("sandbox_test_unseen"), None);
+
+        // Nesting is refused while parsing, chains once parsed
+        let nested = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert!(evaluate_sandboxed(&nested, limits).unwrap_err().contains("nested more than 64 levels"));
+        let chain = vec!["1"; 100].join("+");
+        assert_eq!(
+            evaluate_sandboxed(&chain, limits).unwrap_err(),
+            "Expression too deep: 100 levels (limit 64)"
+        );
+
+        // Each call doubles the work
+        let calls = "a(x) = x + x; b(x) = a(x) + a(x); c(x) = b(x) + b(x); d(x) = c(x) + c(x); d(1)";
+        assert_eq!(evaluate_sandboxed(calls, limits), Ok(16.0));
+        let tight = Limits { max_instructions: 50, ..limits };
+        assert_eq!(evaluate_sandboxed(calls, tight).unwrap_err(), "Instruction limit of 50 exceeded");
+        let shallow = Limits { max_call_depth: 3, ..limits };
+        assert_eq!(
+            evaluate_sandboxed(calls, shallow).unwrap_err(),
+            "Recursion limit exceeded: calls nested more than 3 deep"
+        );
+
+        let array = format!("sum([{}])", vec!["1"; 200].join(","));
+        assert_eq!(evaluate_sandboxed(&array, limits), Ok(200.0));
+        let small = Limits { max_memory: 512, ..limits };
+        assert_eq!(evaluate_sandboxed(&array, small).unwrap_err(), "Out of memory");
+    }
+}
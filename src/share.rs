@@ -0,0 +1,144 @@
+//! Shareable links
+//!
+//! The web build reads an expression, and optionally the result format, from
+//! the URL fragment, so a link like
+//!
+//!   https://example.com/#fmt=Fixed.4.1000&expr=sqrt(2)
+//!
+//! opens the calculator with `sqrt(2)` entered and evaluated to 4 decimals.
+//! The expression always comes last and runs to the end of the fragment, so
+//! it may contain `&` and `=` once the browser has percent-decoded it.
+
+use crate::format::{Notation, NumberFormat};
+
+/// An expression, and optionally how to format its result, carried in a URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedLink {
+    pub expression: String,
+    pub format: Option<NumberFormat>,
+}
+
+impl SharedLink {
+    pub fn new(expression: impl Into<String>) -> Self {
+        SharedLink {
+            expression: expression.into(),
+            format: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: NumberFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Percent-encoded fragment, without the leading `#`
+    pub fn to_fragment(&self) -> String {
+        let mut fragment = String::new();
+        if let Some(format) = &self.format {
+            fragment.push_str(&format!(
+                "fmt={}.{}.{}&",
+                format.notation.name(),
+                format.digits,
+                format.max_denominator
+            ));
+        }
+        fragment.push_str("expr=");
+        fragment.push_str(&percent_encode(&self.expression));
+        fragment
+    }
+
+    /// `to_fragment`'s output once the browser has percent-decoded it
+    ///
+    /// The leading `#` is optional. Returns `None` without an expression;
+    /// an unreadable format is ignored.
+    pub fn from_fragment(fragment: &str) -> Option<Self> {
+        let mut rest = fragment.strip_prefix('#').unwrap_or(fragment);
+        let mut format = None;
+        loop {
+            if let Some(expression) = rest.strip_prefix("expr=") {
+                let expression = expression.trim();
+                return (!expression.is_empty()).then(|| SharedLink {
+                    expression: expression.to_string(),
+                    format,
+                });
+            }
+            let (parameter, next) = rest.split_once('&')?;
+            if let Some(value) = parameter.strip_prefix("fmt=") {
+                format = parse_format(value);
+            }
+            rest = next;
+        }
+    }
+
+    /// `url` with its fragment replaced by this link's
+    pub fn to_url(&self, url: &str) -> String {
+        let base = url.split_once('#').map_or(url, |(base, _)| base);
+        format!("{}#{}", base, self.to_fragment())
+    }
+}
+
+/// Read "Notation.digits.max_denominator"
+fn parse_format(value: &str) -> Option<NumberFormat> {
+    let mut parts = value.split('.');
+    let name = parts.next()?;
+    let notation = Notation::ALL.into_iter().find(|notation| notation.name() == name)?;
+    let digits = parts.next()?.parse().ok()?;
+    let max_denominator = parts.next()?.parse().ok()?;
+    Some(NumberFormat::new(notation).with_digits(digits).with_max_denominator(max_denominator))
+}
+
+/// Escape everything but unreserved characters and a few that read well in URLs
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~()*!,".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the browser hands the app
+    fn percent_decode(text: &str) -> String {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                decoded.push(u8::from_str_radix(&text[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            } else {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let link = SharedLink::new("f(x) = x % 3 & 1 + π").with_format(NumberFormat::new(Notation::Fixed).with_digits(4));
+        let fragment = link.to_fragment();
+        assert!(fragment.starts_with("fmt=Fixed.4.1000&expr=f(x)%20%3D%20x%20%25%203"));
+        assert_eq!(SharedLink::from_fragment(&percent_decode(&format!("#{}", fragment))), Some(link));
+    }
+
+    #[test]
+    fn test_from_fragment() {
+        assert_eq!(SharedLink::from_fragment("#expr=1+2"), Some(SharedLink::new("1+2")));
+        assert_eq!(SharedLink::from_fragment("fmt=Bogus.1.1&expr=2"), Some(SharedLink::new("2")));
+        assert_eq!(SharedLink::from_fragment(""), None);
+        assert_eq!(SharedLink::from_fragment("#expr= "), None);
+        assert_eq!(SharedLink::from_fragment("#section=3"), None);
+        assert_eq!(
+            SharedLink::new("2^10").to_url("http://localhost:8080/index.html#old"),
+            "http://localhost:8080/index.html#expr=2%5E10"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Probability distributions for the statistics builtins
+//!
+//! Argument order follows graphing calculators: `normpdf(x, μ, σ)`,
+//! `normcdf(x, μ, σ)`, `invnorm(p, μ, σ)`, `binompdf(n, p, k)` and
+//! `poissonpdf(λ, k)`.
+
+#[cfg(not(any(feature = "std", test)))]
+use crate::prelude::Float;
+use crate::vm::VmError;
+use core::f64::consts::{FRAC_1_SQRT_2, PI, SQRT_2};
+
+fn check_sigma(sigma: f64) -> Result<(), VmError> {
+    if sigma > 0.0 && sigma.is_finite() {
+        Ok(())
+    } else {
+        Err(VmError::MathError("Standard deviation must be positive".into()))
+    }
+}
+
+/// Density of the normal distribution with mean `mu` and standard deviation `sigma`
+pub fn normal_pdf(x: f64, mu: f64, sigma: f64) -> Result<f64, VmError> {
+    check_sigma(sigma)?;
+    let z = (x - mu) / sigma;
+    Ok((-z * z / 2.0).exp() / (sigma * (2.0 * PI).sqrt()))
+}
+
+/// Probability that a normal variable is at most `x`
+pub fn normal_cdf(x: f64, mu: f64, sigma: f64) -> Result<f64, VmError> {
+    check_sigma(sigma)?;
+    Ok(erfc(-(x - mu) / sigma * FRAC_1_SQRT_2) / 2.0)
+}
+
+/// The `x` with `normal_cdf(x, mu, sigma) == p`
+pub fn inverse_normal(p: f64, mu: f64, sigma: f64) -> Result<f64, VmError> {
+    check_sigma(sigma)?;
+    if !(p > 0.0 && p < 1.0) {
+        return Err(VmError::MathError("invnorm probability must be between 0 and 1".into()));
+    }
+    Ok(mu + sigma * standard_quantile(p))
+}
+
+/// Probability of exactly `k` successes in `n` trials of probability `p`
+pub fn binomial_pdf(n: f64, p: f64, k: f64) -> Result<f64, VmError> {
+    if n < 0.0 || n.fract() != 0.0 || !n.is_finite() {
+        return Err(VmError::MathError("binompdf trials must be a whole number".into()));
+    }
+    if !(0.0..=1.0).contains(&p) {
+        return Err(VmError::MathError("binompdf probability must be between 0 and 1".into()));
+    }
+    if k < 0.0 || k > n || k.fract() != 0.0 {
+        return Ok(0.0);
+    }
+    // ln(0) is fine on its own, but not times zero
+    if p == 0.0 || p == 1.0 {
+        let certain = if p == 0.0 { 0.0 } else { n };
+        return Ok(if k == certain { 1.0 } else { 0.0 });
+    }
+    // ln C(n, k) = Σ ln((n - s + i) / i) for i up to s = min(k, n - k)
+    let s = k.min(n - k);
+    let ln_choose: f64 = (1..=s as u64).map(|i| ((n - s + i as f64) / i as f64).ln()).sum();
+    Ok((ln_choose + k * p.ln() + (n - k) * (1.0 - p).ln()).exp())
+}
+
+/// Probability of exactly `k` events when `lambda` are expected
+pub fn poisson_pdf(lambda: f64, k: f64) -> Result<f64, VmError> {
+    if lambda < 0.0 || !lambda.is_finite() {
+        return Err(VmError::MathError("poissonpdf mean must be non-negative".into()));
+    }
+    if k < 0.0 || k.fract() != 0.0 {
+        return Ok(0.0);
+    }
+    if lambda == 0.0 {
+        return Ok(if k == 0.0 { 1.0 } else { 0.0 });
+    }
+    let ln_factorial: f64 = (2..=(k as u64)).map(|i| (i as f64).ln()).sum();
+    Ok((k * lambda.ln() - lambda - ln_factorial).exp())
+}
+
+/// Complementary error function, accurate to about 1e-15 relative
+pub fn erfc(x: f64) -> f64 {
+    if x.is_nan() {
+        return f64::NAN;
+    }
+    if x < 0.0 {
+        return 2.0 - erfc(-x);
+    }
+    if x < 3.0 {
+        return 1.0 - erf_series(x);
+    }
+    if x > 27.0 {
+        return 0.0;
+    }
+    // Continued fraction x + 1/2 / (x + 1 / (x + 3/2 / (x + ...))), by Lentz's method
+    let tiny = 1e-300;
+    let mut f = x;
+    let (mut c, mut d) = (x, 0.0);
+    for n in 1..200 {
+        let a = n as f64 / 2.0;
+        d = x + a * d;
+        d = if d == 0.0 { tiny } else { 1.0 / d };
+        c = x + a / c;
+        if c == 0.0 {
+            c = tiny;
+        }
+        let delta = c * d;
+        f *= delta;
+        if (delta - 1.0).abs() < 1e-16 {
+            break;
+        }
+    }
+    (-x * x).exp() / (f * PI.sqrt())
+}
+
+/// erf(x) = 2/√π e^(-x²) Σ 2ⁿ x^(2n+1) / (1·3·…·(2n+1)), whose terms are all
+/// positive, so nothing cancels
+fn erf_series(x: f64) -> f64 {
+    let mut term = x;
+    let mut sum = x;
+    for n in 1..200 {
+        term *= 2.0 * x * x / (2 * n + 1) as f64;
+        sum += term;
+        if term < sum * 1e-17 {
+            break;
+        }
+    }
+    2.0 / PI.sqrt() * (-x * x).exp() * sum
+}
+
+/// Quantile of the standard normal distribution, for 0 < p < 1
+///
+/// Acklam's rational approximation, good to about 1e-9, then one Halley
+/// step against `erfc` for full precision.
+fn standard_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1, 2.209460984245205e2, -2.759285104469687e2,
+        1.38357751867269e2, -3.066479806614716e1, 2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1, 1.615858368580409e2, -1.556989798598866e2,
+        6.680131188771972e1, -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3, -3.223964580411365e-1, -2.400758277161838,
+        -2.549732539343734, 4.374664141464968, 2.938163982698783,
+    ];
+    const D: [f64; 4] = [7.784695709041462e-3, 3.224671290700398e-1, 2.445134137142996, 3.754408661907416];
+    const LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        let q = (-2.0 * q.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    let x = if p < LOW {
+        tail(p)
+    } else if p > 1.0 - LOW {
+        -tail(1.0 - p)
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    };
+
+    let error = erfc(-x / SQRT_2) / 2.0 - p;
+    let u = error * (2.0 * PI).sqrt() * (x * x / 2.0).exp();
+    x - u / (1.0 + x * u / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-12 * b.abs().max(1e-300)
+    }
+
+    #[test]
+    fn test_normal() {
+        assert!(close(normal_pdf(0.0, 0.0, 1.0).unwrap(), 0.3989422804014327));
+        assert!(close(normal_pdf(7.0, 5.0, 2.0).unwrap(), 0.12098536225957168));
+        assert_eq!(normal_cdf(0.0, 0.0, 1.0).unwrap(), 0.5);
+        assert!(close(normal_cdf(1.96, 0.0, 1.0).unwrap(), 0.9750021048517796));
+        assert!(close(normal_cdf(-5.0, 0.0, 1.0).unwrap(), 2.866515718791946e-7));
+        assert!(close(normal_cdf(-10.0, 0.0, 1.0).unwrap(), 7.619853024160593e-24));
+        assert!(close(erfc(0.5), 0.4795001221869535));
+        assert!(normal_pdf(0.0, 0.0, 0.0).is_err());
+
+        for p in [1e-10, 0.01, 0.3, 0.5, 0.975, 0.999] {
+            let x = inverse_normal(p, 0.0, 1.0).unwrap();
+            assert!(close(normal_cdf(x, 0.0, 1.0).unwrap(), p), "{} -> {}", p, x);
+        }
+        assert!(close(inverse_normal(0.975, 100.0, 15.0).unwrap(), 129.3994597681008));
+        assert!(inverse_normal(1.0, 0.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_discrete() {
+        assert!(close(binomial_pdf(10.0, 0.5, 5.0).unwrap(), 0.24609375));
+        assert!(close(binomial_pdf(5.0, 0.3, 0.0).unwrap(), 0.16807));
+        assert_eq!(binomial_pdf(5.0, 0.3, 6.0).unwrap(), 0.0);
+        assert_eq!(binomial_pdf(5.0, 1.0, 5.0).unwrap(), 1.0);
+        assert!(binomial_pdf(5.5, 0.3, 1.0).is_err());
+        assert!(binomial_pdf(5.0, 1.5, 1.0).is_err());
+
+        assert!(close(poisson_pdf(3.0, 2.0).unwrap(), 0.22404180765538775));
+        assert!(close(poisson_pdf(2.5, 0.0).unwrap(), (-2.5f64).exp()));
+        assert_eq!(poisson_pdf(0.0, 0.0).unwrap(), 1.0);
+        assert_eq!(poisson_pdf(3.0, 1.5).unwrap(), 0.0);
+        assert!(poisson_pdf(-1.0, 1.0).is_err());
+    }
+}
@@ -0,0 +1,475 @@
+//! Symbolic rewrites of polynomial expressions
+//!
+//! The parser applies `expand((x + 1)^2)` and `factor(x^2 - 1)` to the tree
+//! of their argument before anything is compiled, so what comes out is an
+//! ordinary expression: the GUI shows it, and it evaluates once its
+//! variables are set, e.g. `f(x) = expand((x + 1)^3)`.
+//!
+//! Both take polynomials: numbers and variables combined with `+`, `-`, `*`,
+//! division by a number and whole powers. `factor` also needs a single
+//! variable and whole coefficients, and splits off the rational roots.
+
+use crate::prelude::*;
+use crate::ast::{BinaryOp, Expr, UnaryOp};
+use alloc::collections::BTreeMap;
+use core::fmt::Write;
+
+/// Highest power of a variable a rewrite will build
+const MAX_DEGREE: u32 = 64;
+
+/// Most terms an expansion may have
+const MAX_TERMS: usize = 1_000;
+
+/// Largest coefficient `factor` will search the divisors of
+const MAX_COEFFICIENT: f64 = 1e12;
+
+/// Variables and their powers, sorted by name
+type Monomial = Vec<(String, u32)>;
+
+/// Sum of monomials times their coefficients, none of which is zero
+#[derive(Debug, Clone, Default)]
+struct Polynomial {
+    terms: BTreeMap<Monomial, f64>,
+}
+
+impl Polynomial {
+    fn constant(value: f64) -> Self {
+        let mut polynomial = Polynomial::default();
+        polynomial.add_term(Vec::new(), value);
+        polynomial
+    }
+
+    fn variable(name: &str) -> Self {
+        let mut polynomial = Polynomial::default();
+        polynomial.add_term(vec![(name.to_string(), 1)], 1.0);
+        polynomial
+    }
+
+    /// Of one variable, from coefficients by ascending power
+    fn from_coefficients(variable: &str, coefficients: &[i128]) -> Self {
+        let mut polynomial = Polynomial::default();
+        for (power, &coefficient) in coefficients.iter().enumerate() {
+            let monomial = if power == 0 { Vec::new() } else { vec![(variable.to_string(), power as u32)] };
+            polynomial.add_term(monomial, coefficient as f64);
+        }
+        polynomial
+    }
+
+    fn add_term(&mut self, monomial: Monomial, coefficient: f64) {
+        let sum = self.terms.get(&monomial).copied().unwrap_or(0.0) + coefficient;
+        if sum == 0.0 {
+            self.terms.remove(&monomial);
+        } else {
+            self.terms.insert(monomial, sum);
+        }
+    }
+
+    fn as_constant(&self) -> Option<f64> {
+        match self.terms.len() {
+            0 => Some(0.0),
+            1 => self.terms.get(&Vec::new()).copied(),
+            _ => None,
+        }
+    }
+
+    fn add(mut self, other: Polynomial) -> Self {
+        for (monomial, coefficient) in other.terms {
+            self.add_term(monomial, coefficient);
+        }
+        self
+    }
+
+    fn scale(mut self, factor: f64) -> Self {
+        if factor == 0.0 {
+            return Polynomial::default();
+        }
+        self.terms.values_mut().for_each(|coefficient| *coefficient *= factor);
+        self
+    }
+
+    fn multiply(&self, other: &Polynomial) -> Result<Self, String> {
+        let mut product = Polynomial::default();
+        for (left, a) in &self.terms {
+            for (right, b) in &other.terms {
+                product.add_term(merge(left, right)?, a * b);
+            }
+            if product.terms.len() > MAX_TERMS {
+                return Err(format!("Expansion has more than {} terms", MAX_TERMS));
+            }
+        }
+        Ok(product)
+    }
+
+    fn power(&self, exponent: u32) -> Result<Self, String> {
+        let mut result = Polynomial::constant(1.0);
+        for _ in 0..exponent {
+            result = result.multiply(self)?;
+        }
+        Ok(result)
+    }
+
+    /// Names of the variables, sorted
+    fn variables(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.terms.keys().flatten().map(|(name, _)| name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+}
+
+/// Product of two monomials
+fn merge(left: &Monomial, right: &Monomial) -> Result<Monomial, String> {
+    let mut powers: BTreeMap<&str, u32> = BTreeMap::new();
+    for (name, power) in left.iter().chain(right) {
+        let total = powers.entry(name).or_insert(0);
+        *total += power;
+        if *total > MAX_DEGREE {
+            return Err(format!("Powers above {} are not expanded", MAX_DEGREE));
+        }
+    }
+    Ok(powers.into_iter().map(|(name, power)| (name.to_string(), power)).collect())
+}
+
+/// Read `expr` as a polynomial in its variables
+fn to_polynomial(expr: &Expr) -> Result<Polynomial, String> {
+    match expr {
+        Expr::Number(value) => Ok(Polynomial::constant(*value)),
+        Expr::Variable(name) => Ok(Polynomial::variable(name)),
+        Expr::UnaryOp { op: UnaryOp::Negate, operand } => Ok(to_polynomial(operand)?.scale(-1.0)),
+        Expr::BinaryOp { op, left, right } => {
+            let left = to_polynomial(left)?;
+            let right = to_polynomial(right)?;
+            match op {
+                BinaryOp::Add => Ok(left.add(right)),
+                BinaryOp::Subtract => Ok(left.add(right.scale(-1.0))),
+                BinaryOp::Multiply => left.multiply(&right),
+                BinaryOp::Divide => match right.as_constant() {
+                    Some(divisor) if divisor != 0.0 => Ok(left.scale(1.0 / divisor)),
+                    _ => Err("Only division by a nonzero number is supported".to_string()),
+                },
+                BinaryOp::Power => match (left.as_constant(), right.as_constant()) {
+                    (Some(base), Some(exponent)) => Ok(Polynomial::constant(base.powf(exponent))),
+                    (_, Some(exponent))
+                        if exponent >= 0.0 && exponent.fract() == 0.0 && exponent <= MAX_DEGREE as f64 =>
+                    {
+                        left.power(exponent as u32)
+                    }
+                    _ => Err(format!("Exponents must be whole numbers up to {}", MAX_DEGREE)),
+                },
+                _ => Err(format!("{} is not a polynomial operation", op)),
+            }
+        }
+        _ => Err(format!("{} is not a polynomial", expr)),
+    }
+}
+
+/// Write `polynomial` as a sum of terms, highest degree first
+fn from_polynomial(polynomial: &Polynomial) -> Expr {
+    // By total degree, then by the powers of the variables in name order
+    let names = polynomial.variables();
+    let order = |monomial: &Monomial| {
+        let powers: Vec<u32> = names
+            .iter()
+            .map(|name| monomial.iter().find(|(n, _)| n == name).map_or(0, |(_, power)| *power))
+            .collect();
+        (powers.iter().sum::<u32>(), powers)
+    };
+    let mut terms: Vec<(&Monomial, f64)> = polynomial.terms.iter().map(|(m, c)| (m, *c)).collect();
+    terms.sort_by_cached_key(|(monomial, _)| core::cmp::Reverse(order(monomial)));
+
+    let mut sum: Option<Expr> = None;
+    for (monomial, coefficient) in terms {
+        sum = Some(match sum {
+            None if coefficient == -1.0 && !monomial.is_empty() => Expr::negate(term(1.0, monomial)),
+            None => term(coefficient, monomial),
+            Some(sum) if coefficient < 0.0 => Expr::subtract(sum, term(-coefficient, monomial)),
+            Some(sum) => Expr::add(sum, term(coefficient, monomial)),
+        });
+    }
+    sum.unwrap_or(Expr::number(0.0))
+}
+
+/// `coefficient * x^a * y^b ...`, leaving out a coefficient of 1
+fn term(coefficient: f64, monomial: &Monomial) -> Expr {
+    let mut factors = Vec::new();
+    if coefficient != 1.0 || monomial.is_empty() {
+        factors.push(Expr::number(coefficient));
+    }
+    factors.extend(monomial.iter().map(|(name, power)| power_of(Expr::variable(name.as_str()), *power)));
+    product(factors)
+}
+
+fn power_of(base: Expr, power: u32) -> Expr {
+    if power == 1 {
+        base
+    } else {
+        Expr::power(base, Expr::number(power as f64))
+    }
+}
+
+fn product(factors: Vec<Expr>) -> Expr {
+    factors.into_iter().reduce(Expr::multiply).unwrap_or(Expr::number(1.0))
+}
+
+/// Multiply out every product and power in `expr`
+pub fn expand(expr: &Expr) -> Result<Expr, String> {
+    Ok(from_polynomial(&to_polynomial(expr)?))
+}
+
+/// Write `expr` as a product of a number, its rational linear factors and
+/// whatever is left
+pub fn factor(expr: &Expr) -> Result<Expr, String> {
+    let polynomial = to_polynomial(expr)?;
+    let variable = match polynomial.variables()[..] {
+        [] => return Ok(from_polynomial(&polynomial)),
+        [name] => name.to_string(),
+        [first, second, ..] => {
+            return Err(format!("factor needs a single variable, found {} and {}", first, second));
+        }
+    };
+
+    // Coefficients by ascending power
+    let degree = polynomial.terms.keys().flatten().map(|(_, power)| *power).max().unwrap_or(0);
+    let mut coefficients = vec![0i128; degree as usize + 1];
+    for (monomial, &coefficient) in &polynomial.terms {
+        if coefficient.fract() != 0.0 || coefficient.abs() > MAX_COEFFICIENT {
+            return Err(format!("factor needs whole coefficients up to {}", MAX_COEFFICIENT));
+        }
+        let power = monomial.first().map_or(0, |(_, power)| *power);
+        coefficients[power as usize] = coefficient as i128;
+    }
+
+    // Common factor, taking the sign of the leading coefficient
+    let mut content = coefficients.iter().fold(0, |g, &c| gcd(g, c.abs()));
+    if coefficients[degree as usize] < 0 {
+        content = -content;
+    }
+    coefficients.iter_mut().for_each(|c| *c /= content);
+
+    let mut factors = Vec::new();
+    let lowest = coefficients.iter().position(|&c| c != 0).unwrap_or(0);
+    if lowest > 0 {
+        factors.push(power_of(Expr::variable(variable.as_str()), lowest as u32));
+        coefficients.drain(..lowest);
+    }
+
+    // Each rational root p/q splits off a factor q x - p
+    let mut roots: Vec<((i128, i128), u32)> = Vec::new();
+    while coefficients.len() > 1 {
+        let Some((p, q)) = rational_root(&coefficients) else {
+            break;
+        };
+        coefficients = deflate(&coefficients, p, q);
+        match roots.iter_mut().find(|(root, _)| *root == (p, q)) {
+            Some((_, multiplicity)) => *multiplicity += 1,
+            None => roots.push(((p, q), 1)),
+        }
+    }
+    for ((p, q), multiplicity) in roots {
+        let linear = from_polynomial(&Polynomial::from_coefficients(&variable, &[-p, q]));
+        factors.push(power_of(linear, multiplicity));
+    }
+    if coefficients.len() > 1 {
+        factors.push(from_polynomial(&Polynomial::from_coefficients(&variable, &coefficients)));
+    }
+
+    match content {
+        1 => {}
+        // -(x - 2)*(x + 2) reads better than -1*(x - 2)*(x + 2)
+        -1 => factors[0] = Expr::negate(factors[0].clone()),
+        _ => factors.insert(0, Expr::number(content as f64)),
+    }
+    Ok(product(factors))
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Positive divisors of `n`, ascending
+fn divisors(n: i128) -> Vec<i128> {
+    let n = n.abs();
+    let (mut small, mut large) = (Vec::new(), Vec::new());
+    let mut d = 1;
+    while d * d <= n {
+        if n % d == 0 {
+            small.push(d);
+            if d * d != n {
+                large.push(n / d);
+            }
+        }
+        d += 1;
+    }
+    small.extend(large.into_iter().rev());
+    small
+}
+
+/// A root p/q, q > 0, of the primitive polynomial with `coefficients`,
+/// trying the smallest first and positive before negative
+fn rational_root(coefficients: &[i128]) -> Option<(i128, i128)> {
+    let (constant, leading) = (coefficients[0], coefficients[coefficients.len() - 1]);
+    let mut candidates: Vec<(i128, i128)> = Vec::new();
+    for p in divisors(constant) {
+        for q in divisors(leading) {
+            if gcd(p, q) == 1 {
+                candidates.extend([(p, q), (-p, q)]);
+            }
+        }
+    }
+    candidates.sort_by(|a, b| (a.0.abs() * b.1).cmp(&(b.0.abs() * a.1)).then(b.0.cmp(&a.0)));
+    candidates.into_iter().find(|&(p, q)| is_root(coefficients, p, q))
+}
+
+/// Whether q^n P(p/q) = Σ a_i p^i q^(n-i) is zero; overflow counts as no
+fn is_root(coefficients: &[i128], p: i128, q: i128) -> bool {
+    // Horner's rule on the homogenized form
+    let mut value: i128 = 0;
+    let mut q_power: i128 = 1;
+    for &a in coefficients.iter().rev() {
+        let next = value.checked_mul(p).and_then(|v| a.checked_mul(q_power).and_then(|t| v.checked_add(t)));
+        match (next, q_power.checked_mul(q)) {
+            (Some(next), Some(power)) => (value, q_power) = (next, power),
+            _ => return false,
+        }
+    }
+    value == 0
+}
+
+/// P / (q x - p) for a root p/q of P, exact since P is primitive
+fn deflate(coefficients: &[i128], p: i128, q: i128) -> Vec<i128> {
+    let n = coefficients.len() - 1;
+    let mut quotient = vec![0; n];
+    quotient[n - 1] = coefficients[n] / q;
+    for k in (1..n).rev() {
+        quotient[k - 1] = (coefficients[k] + p * quotient[k]) / q;
+    }
+    quotient
+}
+
+/// `expr` as it would be typed, bracketed only where precedence needs it
+///
+/// Negation binds tighter than `^` here, so `-(x^2)` keeps its brackets.
+pub fn to_infix(expr: &Expr) -> String {
+    let mut text = String::new();
+    write_infix(expr, 0, &mut text);
+    text
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::BinaryOp { op: BinaryOp::Add | BinaryOp::Subtract, .. } => 1,
+        Expr::BinaryOp { op: BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo, .. } => 2,
+        Expr::BinaryOp { op: BinaryOp::Power, .. } => 3,
+        Expr::UnaryOp { op: UnaryOp::Negate, .. } => 4,
+        Expr::Number(value) if *value < 0.0 => 4,
+        _ => 5,
+    }
+}
+
+fn write_infix(expr: &Expr, lowest: u8, text: &mut String) {
+    let own = precedence(expr);
+    if own < lowest {
+        text.push('(');
+    }
+    match expr {
+        Expr::BinaryOp { op, left, right } if own < 4 => {
+            // Power groups to the right, everything else to the left
+            let (left_lowest, right_lowest) = if *op == BinaryOp::Power { (own + 1, own) } else { (own, own + 1) };
+            write_infix(left, left_lowest, text);
+            let spaced = matches!(op, BinaryOp::Add | BinaryOp::Subtract);
+            let _ = if spaced { write!(text, " {} ", op) } else { write!(text, "{}", op) };
+            write_infix(right, right_lowest, text);
+        }
+        Expr::UnaryOp { op: UnaryOp::Negate, operand } => {
+            text.push('-');
+            write_infix(operand, own, text);
+        }
+        Expr::Assign { name, value } => {
+            let _ = write!(text, "{} = ", name);
+            write_infix(value, 0, text);
+        }
+        Expr::FunctionDef { name, params, body } => {
+            let _ = write!(text, "{}({}) = ", name, params.join(", "));
+            write_infix(body, 0, text);
+        }
+        Expr::Sequence(statements) => {
+            for (i, statement) in statements.iter().enumerate() {
+                if i > 0 {
+                    text.push_str("; ");
+                }
+                write_infix(statement, 0, text);
+            }
+        }
+        _ => {
+            let _ = write!(text, "{}", expr);
+        }
+    }
+    if own < lowest {
+        text.push(')');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Parser, Tokenizer};
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(Tokenizer::new(input).tokenize().unwrap()).parse().unwrap()
+    }
+
+    fn expanded(input: &str) -> String {
+        to_infix(&expand(&parse(input)).unwrap())
+    }
+
+    fn factored(input: &str) -> String {
+        to_infix(&factor(&parse(input)).unwrap())
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expanded("(x + 1)^2"), "x^2 + 2*x + 1");
+        assert_eq!(expanded("(x - y)*(x + y)"), "x^2 - y^2");
+        assert_eq!(expanded("(a + b)^3 - a^3"), "3*a^2*b + 3*a*b^2 + b^3");
+        assert_eq!(expanded("-(x^2) + x/2 - 4"), "-(x^2) + 0.5*x - 4");
+        assert_eq!(expanded("(x + 1) - (1 + x)"), "0");
+        assert_eq!(expanded("2^3 * x"), "8*x");
+
+        assert!(expand(&parse("sin(x) + 1")).is_err());
+        assert!(expand(&parse("1 / x")).is_err());
+        assert!(expand(&parse("x^0.5")).is_err());
+        assert!(expand(&parse("(x + 1)^100")).is_err());
+
+        // The rewrite is what gets compiled
+        assert_eq!(crate::evaluate("f(x) = expand((x + 1)^3); f(2)"), Ok(27.0));
+        assert!(crate::evaluate("expand(sin(x))").unwrap_err().contains("sin(x) is not a polynomial"));
+    }
+
+    #[test]
+    fn test_factor() {
+        assert_eq!(factored("x^2 - 1"), "(x - 1)*(x + 1)");
+        assert_eq!(factored("x^2 + 2*x + 1"), "(x + 1)^2");
+        assert_eq!(factored("2*x^3 - 2*x"), "2*x*(x - 1)*(x + 1)");
+        assert_eq!(factored("6*x^2 - x - 1"), "(3*x + 1)*(2*x - 1)");
+        assert_eq!(factored("4 - x^2"), "-(x - 2)*(x + 2)");
+        assert_eq!(factored("x^3 - 1"), "(x - 1)*(x^2 + x + 1)");
+        assert_eq!(factored("x^2 + 1"), "x^2 + 1");
+        assert_eq!(factored("7"), "7");
+
+        assert!(factor(&parse("x*y - 1")).is_err());
+        assert!(factor(&parse("x^2 - 0.5")).is_err());
+    }
+
+    #[test]
+    fn test_to_infix() {
+        assert_eq!(to_infix(&parse("(1 - (2 - 3)) * 4")), "(1 - (2 - 3))*4");
+        assert_eq!(to_infix(&parse("2^3^2")), "2^3^2");
+        assert_eq!(to_infix(&parse("(2^3)^2")), "(2^3)^2");
+        assert_eq!(to_infix(&parse("-(x^2)")), "-(x^2)");
+        assert_eq!(to_infix(&parse("sqrt(x) + 1")), "sqrt(x) + 1");
+        assert_eq!(to_infix(&parse("f(x) = expand((x + 1)^2); f(1)")), "f(x) = x^2 + 2*x + 1; f(1)");
+    }
+}
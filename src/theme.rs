@@ -0,0 +1,245 @@
+//! GUI theme settings
+//!
+//! A preset (dark, light or high-contrast), an accent color, the monospace
+//! font and the UI scale make up the persisted `ThemeSettings`. Applying them
+//! sets egui's visuals, fonts and zoom; the detail views take their colors
+//! from the matching `Palette` instead of hard-coding them.
+
+use eframe::egui::{self, Color32, FontFamily, FontId, TextStyle};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThemePreset {
+    Dark,
+    Light,
+    HighContrast,
+}
+
+impl ThemePreset {
+    pub const ALL: [ThemePreset; 3] = [ThemePreset::Dark, ThemePreset::Light, ThemePreset::HighContrast];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ThemePreset::Dark => "Dark",
+            ThemePreset::Light => "Light",
+            ThemePreset::HighContrast => "High contrast",
+        }
+    }
+
+    /// Accent that reads well on this preset's background
+    pub fn default_accent(self) -> [u8; 3] {
+        match self {
+            ThemePreset::Dark => [144, 238, 144],
+            ThemePreset::Light => [0, 128, 64],
+            ThemePreset::HighContrast => [0, 255, 0],
+        }
+    }
+}
+
+/// Font used for expressions, bytecode and values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MonospaceFont {
+    /// egui's bundled monospace font
+    Hack,
+    /// egui's proportional font, for those who prefer it for expressions
+    Ubuntu,
+}
+
+impl MonospaceFont {
+    pub const ALL: [MonospaceFont; 2] = [MonospaceFont::Hack, MonospaceFont::Ubuntu];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MonospaceFont::Hack => "Hack",
+            MonospaceFont::Ubuntu => "Ubuntu",
+        }
+    }
+
+    /// Name of the font in egui's default font definitions
+    fn font_key(self) -> &'static str {
+        match self {
+            MonospaceFont::Hack => "Hack",
+            MonospaceFont::Ubuntu => "Ubuntu-Light",
+        }
+    }
+}
+
+/// User-chosen look of the GUI
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeSettings {
+    pub preset: ThemePreset,
+    /// sRGB accent for selections and highlighted values
+    pub accent: [u8; 3],
+    pub monospace_font: MonospaceFont,
+    /// Monospace text size in points
+    pub monospace_size: f32,
+    /// Zoom applied to the whole interface
+    pub ui_scale: f32,
+    /// Taller, touch-friendly calculator buttons
+    pub large_buttons: bool,
+}
+
+impl Default for ThemeSettings {
+    fn default() -> Self {
+        ThemeSettings {
+            preset: ThemePreset::Dark,
+            accent: ThemePreset::Dark.default_accent(),
+            monospace_font: MonospaceFont::Hack,
+            monospace_size: 14.0,
+            ui_scale: 1.0,
+            large_buttons: false,
+        }
+    }
+}
+
+impl ThemeSettings {
+    pub fn accent(&self) -> Color32 {
+        let [r, g, b] = self.accent;
+        Color32::from_rgb(r, g, b)
+    }
+
+    /// Colors for the detail views under this theme
+    pub fn palette(&self) -> Palette {
+        let accent = self.accent();
+        match self.preset {
+            ThemePreset::Dark => Palette {
+                accent,
+                error: Color32::from_rgb(255, 90, 90),
+                current: Color32::YELLOW,
+                muted: Color32::GRAY,
+                secondary: Color32::LIGHT_GRAY,
+                code_background: Color32::from_gray(40),
+                number: Color32::LIGHT_BLUE,
+                function: Color32::from_rgb(230, 200, 110),
+                constant: Color32::from_rgb(200, 150, 255),
+                variable: Color32::from_rgb(140, 220, 160),
+                operator: Color32::from_rgb(255, 160, 90),
+            },
+            ThemePreset::Light => Palette {
+                accent,
+                error: Color32::from_rgb(200, 0, 0),
+                current: Color32::from_rgb(160, 100, 0),
+                muted: Color32::from_gray(120),
+                secondary: Color32::from_gray(70),
+                code_background: Color32::from_gray(225),
+                number: Color32::from_rgb(0, 80, 180),
+                function: Color32::from_rgb(140, 90, 0),
+                constant: Color32::from_rgb(120, 40, 180),
+                variable: Color32::from_rgb(0, 120, 60),
+                operator: Color32::from_rgb(190, 70, 0),
+            },
+            ThemePreset::HighContrast => Palette {
+                accent,
+                error: Color32::from_rgb(255, 60, 60),
+                current: Color32::from_rgb(255, 255, 0),
+                muted: Color32::from_gray(200),
+                secondary: Color32::WHITE,
+                code_background: Color32::BLACK,
+                number: Color32::from_rgb(0, 255, 255),
+                function: Color32::from_rgb(255, 255, 0),
+                constant: Color32::from_rgb(255, 128, 255),
+                variable: Color32::from_rgb(0, 255, 0),
+                operator: Color32::from_rgb(255, 170, 0),
+            },
+        }
+    }
+
+    /// egui visuals for the preset, tinted with the accent
+    pub fn visuals(&self) -> egui::Visuals {
+        let mut visuals = match self.preset {
+            ThemePreset::Dark => egui::Visuals::dark(),
+            ThemePreset::Light => egui::Visuals::light(),
+            ThemePreset::HighContrast => {
+                let mut visuals = egui::Visuals::dark();
+                visuals.override_text_color = Some(Color32::WHITE);
+                visuals.panel_fill = Color32::BLACK;
+                visuals.window_fill = Color32::BLACK;
+                visuals.extreme_bg_color = Color32::BLACK;
+                visuals.faint_bg_color = Color32::from_gray(24);
+                for widget in [
+                    &mut visuals.widgets.noninteractive,
+                    &mut visuals.widgets.inactive,
+                    &mut visuals.widgets.hovered,
+                    &mut visuals.widgets.active,
+                ] {
+                    widget.bg_stroke = egui::Stroke::new(1.5, Color32::WHITE);
+                    widget.fg_stroke.color = Color32::WHITE;
+                }
+                visuals
+            }
+        };
+        let accent = self.accent();
+        visuals.selection.bg_fill = accent.gamma_multiply(0.5);
+        visuals.selection.stroke.color = accent;
+        visuals.hyperlink_color = accent;
+        visuals
+    }
+
+    /// Apply visuals, fonts and scale to `ctx`
+    ///
+    /// Rebuilding fonts is expensive, so call this only when the settings change.
+    pub fn apply(&self, ctx: &egui::Context) {
+        ctx.set_visuals(self.visuals());
+        ctx.set_zoom_factor(self.ui_scale.clamp(0.5, 3.0));
+
+        let mut fonts = egui::FontDefinitions::default();
+        let monospace = fonts.families.entry(FontFamily::Monospace).or_default();
+        monospace.retain(|name| name != self.monospace_font.font_key());
+        monospace.insert(0, self.monospace_font.font_key().to_string());
+        ctx.set_fonts(fonts);
+
+        ctx.style_mut(|style| {
+            style
+                .text_styles
+                .insert(TextStyle::Monospace, FontId::monospace(self.monospace_size));
+        });
+    }
+}
+
+/// Semantic colors of the detail views
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub accent: Color32,
+    pub error: Color32,
+    /// The instruction the debugger is on
+    pub current: Color32,
+    /// Placeholders such as "[empty]"
+    pub muted: Color32,
+    /// Values that are shown but not emphasized
+    pub secondary: Color32,
+    pub code_background: Color32,
+    pub number: Color32,
+    pub function: Color32,
+    pub constant: Color32,
+    pub variable: Color32,
+    pub operator: Color32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let dark = ThemeSettings::default();
+        let light = ThemeSettings {
+            preset: ThemePreset::Light,
+            accent: ThemePreset::Light.default_accent(),
+            ..ThemeSettings::default()
+        };
+        assert!(dark.visuals().dark_mode);
+        assert!(!light.visuals().dark_mode);
+        assert_ne!(dark.palette(), light.palette());
+        assert_eq!(light.visuals().selection.stroke.color, Color32::from_rgb(0, 128, 64));
+    }
+
+    #[test]
+    fn test_settings_fill_missing_fields() {
+        let settings: ThemeSettings = serde_json::from_str(r#"{"preset": "HighContrast"}"#).unwrap();
+        assert_eq!(settings.preset, ThemePreset::HighContrast);
+        assert_eq!(settings.monospace_size, 14.0);
+        assert_eq!(settings.ui_scale, 1.0);
+        assert!(!settings.large_buttons);
+    }
+}
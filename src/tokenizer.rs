@@ -7,10 +7,26 @@
 //!   - Arrays: [1, 2, 3]
 //!   - Modulo: 10 % 3
 //!   - Factorial: 5!
-//!   - More functions: exp, sinh, cosh, tanh, round, sign, min, max, sum, avg, len, gcd, lcm
+//!   - More functions: exp, sec, csc, cot, sinh, cosh, tanh, asinh, acosh, atanh, round, sign, min, max, sum, avg, len, gcd, lcm
 //!   - Permutations/Combinations: nPr(5,2), nCr(5,2)
+//!   - Variables and user functions: x = 2; f(n) = n^2; f(x)
+//!   - Angles: 45°, 30°15' and 30°15'36" read as one number of degrees
+//!   - `e` after a number: `2e3` is 2000, or 2 * e * 3 with `ENotation::Euler`;
+//!     either way `2e` alone is 2 * e, the multiplication implied
+//!
+//! `retokenize` applies an edit to the input and reuses the tokens it can.
+//! Tokens are read in steps (one token, or a number with its implied `* e`),
+//! each remembering how far ahead it looked. Steps that looked only at
+//! characters before the edit come out the same, and so does everything
+//! after it from the first step that starts where an old one did, moved by
+//! the edit, as reading a step depends on nothing before it.
 
-use std::fmt;
+use crate::prelude::*;
+use crate::builtins;
+use core::cell::Cell;
+use core::fmt;
+use core::ops::Range;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -28,7 +44,20 @@ pub enum Token {
     RParen,
     LBracket,
     RBracket,
+    /// `|`, on either side of an absolute value
+    Pipe,
     Comma,
+    // Definitions
+    Identifier(String),
+    /// A double-quoted string, which only `frombase` takes
+    Text(String),
+    Assign,
+    Semicolon,
+    /// `->` between a lambda's parameters and its body
+    Arrow,
+    /// `let` and `in` around a local binding
+    Let,
+    In,
     // Trigonometric functions
     Sin,
     Cos,
@@ -36,10 +65,16 @@ pub enum Token {
     Asin,
     Acos,
     Atan,
+    Sec,
+    Csc,
+    Cot,
     // Hyperbolic functions
     Sinh,
     Cosh,
     Tanh,
+    Asinh,
+    Acosh,
+    Atanh,
     // Mathematical functions
     Sqrt,
     Cbrt,       // Cube root
@@ -51,6 +86,8 @@ pub enum Token {
     Floor,
     Ceil,
     Round,
+    Trunc,
+    Frac,
     Sign,
     // Array functions
     Sum,
@@ -58,11 +95,48 @@ pub enum Token {
     Min,
     Max,
     Len,
+    Multinomial,
+    // Array constructors
+    Linspace,
+    Zeros,
+    Ones,
+    Repeat,
+    Hist,
+    HistEdges,
+    Solve,
+    Eigvals,
+    Linfit,
+    // Distributions
+    NormPdf,
+    NormCdf,
+    InvNorm,
+    BinomPdf,
+    PoissonPdf,
+    // Bases
+    FromBase,
+    ToBase,
+    // Continued fractions
+    Cfrac,
+    Convergent,
+    // Random
+    Sample,
+    Shuffle,
+    // Higher-order
+    Map,
+    Filter,
+    // Symbolic rewrites
+    Expand,
+    Factor,
     // Combinatorics
     Gcd,
     Lcm,
     Npr,        // Permutations
     Ncr,        // Combinations
+    Derange,    // Derangements (subfactorial)
+    Stirling2,  // Stirling numbers of the second kind
+    // Number theory
+    Totient,    // Euler's phi
+    PrimePi,    // Prime-counting function
     // Conversion
     ToRad,      // Degrees to radians
     ToDeg,      // Radians to degrees
@@ -88,16 +162,30 @@ impl fmt::Display for Token {
             Token::RParen => write!(f, ")"),
             Token::LBracket => write!(f, "["),
             Token::RBracket => write!(f, "]"),
+            Token::Pipe => write!(f, "|"),
             Token::Comma => write!(f, ","),
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::Text(text) => write!(f, "\"{}\"", text),
+            Token::Assign => write!(f, "="),
+            Token::Semicolon => write!(f, ";"),
+            Token::Arrow => write!(f, "->"),
+            Token::Let => write!(f, "let"),
+            Token::In => write!(f, "in"),
             Token::Sin => write!(f, "sin"),
             Token::Cos => write!(f, "cos"),
             Token::Tan => write!(f, "tan"),
             Token::Asin => write!(f, "asin"),
             Token::Acos => write!(f, "acos"),
             Token::Atan => write!(f, "atan"),
+            Token::Sec => write!(f, "sec"),
+            Token::Csc => write!(f, "csc"),
+            Token::Cot => write!(f, "cot"),
             Token::Sinh => write!(f, "sinh"),
             Token::Cosh => write!(f, "cosh"),
             Token::Tanh => write!(f, "tanh"),
+            Token::Asinh => write!(f, "asinh"),
+            Token::Acosh => write!(f, "acosh"),
+            Token::Atanh => write!(f, "atanh"),
             Token::Sqrt => write!(f, "sqrt"),
             Token::Cbrt => write!(f, "cbrt"),
             Token::Log => write!(f, "log"),
@@ -108,16 +196,47 @@ impl fmt::Display for Token {
             Token::Floor => write!(f, "floor"),
             Token::Ceil => write!(f, "ceil"),
             Token::Round => write!(f, "round"),
+            Token::Trunc => write!(f, "trunc"),
+            Token::Frac => write!(f, "frac"),
             Token::Sign => write!(f, "sign"),
             Token::Sum => write!(f, "sum"),
             Token::Avg => write!(f, "avg"),
             Token::Min => write!(f, "min"),
             Token::Max => write!(f, "max"),
             Token::Len => write!(f, "len"),
+            Token::Linspace => write!(f, "linspace"),
+            Token::Zeros => write!(f, "zeros"),
+            Token::Ones => write!(f, "ones"),
+            Token::Repeat => write!(f, "repeat"),
+            Token::Hist => write!(f, "hist"),
+            Token::HistEdges => write!(f, "histedges"),
+            Token::Solve => write!(f, "solve"),
+            Token::Eigvals => write!(f, "eigvals"),
+            Token::Linfit => write!(f, "linfit"),
+            Token::NormPdf => write!(f, "normpdf"),
+            Token::NormCdf => write!(f, "normcdf"),
+            Token::InvNorm => write!(f, "invnorm"),
+            Token::BinomPdf => write!(f, "binompdf"),
+            Token::PoissonPdf => write!(f, "poissonpdf"),
+            Token::FromBase => write!(f, "frombase"),
+            Token::ToBase => write!(f, "tobase"),
+            Token::Cfrac => write!(f, "cfrac"),
+            Token::Convergent => write!(f, "convergent"),
+            Token::Sample => write!(f, "sample"),
+            Token::Shuffle => write!(f, "shuffle"),
+            Token::Map => write!(f, "map"),
+            Token::Filter => write!(f, "filter"),
+            Token::Expand => write!(f, "expand"),
+            Token::Factor => write!(f, "factor"),
             Token::Gcd => write!(f, "gcd"),
             Token::Lcm => write!(f, "lcm"),
             Token::Npr => write!(f, "nPr"),
             Token::Ncr => write!(f, "nCr"),
+            Token::Derange => write!(f, "derange"),
+            Token::Stirling2 => write!(f, "stirling2"),
+            Token::Totient => write!(f, "totient"),
+            Token::PrimePi => write!(f, "primepi"),
+            Token::Multinomial => write!(f, "multinomial"),
             Token::ToRad => write!(f, "rad"),
             Token::ToDeg => write!(f, "deg"),
             Token::Pi => write!(f, "pi"),
@@ -140,9 +259,58 @@ impl fmt::Display for TokenizerError {
     }
 }
 
+/// How an `e` written right after a number is read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ENotation {
+    /// An exponent when digits follow: `2e3` is 2000 and `1.5e-3` is 0.0015
+    #[default]
+    Scientific,
+    /// Always Euler's number: `2e3` is 2 * e * 3
+    Euler,
+}
+
+/// Replacement of the characters in `range` by `text`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+impl Edit {
+    pub fn new(range: Range<usize>, text: impl Into<String>) -> Self {
+        Edit { range, text: text.into() }
+    }
+
+    /// The edit turning `old` into `new`, replacing what lies between their
+    /// common prefix and suffix
+    pub fn between(old: &str, new: &str) -> Self {
+        let (old, new): (Vec<char>, Vec<char>) = (old.chars().collect(), new.chars().collect());
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+        Edit {
+            range: prefix..old.len() - suffix,
+            text: new[prefix..new.len() - suffix].iter().collect(),
+        }
+    }
+}
+
+/// Tokens read together, from `first` on, and the end of the characters
+/// looked at to read them
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    first: usize,
+    looked: usize,
+}
+
 pub struct Tokenizer {
     input: Vec<char>,
     position: usize,
+    e_notation: ENotation,
+    /// End of the characters looked at in the current step
+    looked: Cell<usize>,
+    /// Tokens read so far, kept for `retokenize`
+    tokens: Vec<(Token, Range<usize>)>,
+    steps: Vec<Step>,
 }
 
 impl Tokenizer {
@@ -150,11 +318,31 @@ impl Tokenizer {
         Tokenizer {
             input: input.chars().collect(),
             position: 0,
+            e_notation: ENotation::default(),
+            looked: Cell::new(0),
+            tokens: Vec::new(),
+            steps: Vec::new(),
         }
     }
 
+    /// Read `e` after a number as `e_notation` says
+    pub fn with_e_notation(mut self, e_notation: ENotation) -> Self {
+        self.e_notation = e_notation;
+        self
+    }
+
+    pub fn e_notation(&self) -> ENotation {
+        self.e_notation
+    }
+
+    /// The character at `index`, noting that the current step looked at it
+    fn char_at(&self, index: usize) -> Option<char> {
+        self.looked.set(self.looked.get().max(index + 1));
+        self.input.get(index).copied()
+    }
+
     fn peek(&self) -> Option<char> {
-        self.input.get(self.position).copied()
+        self.char_at(self.position)
     }
 
     fn advance(&mut self) -> Option<char> {
@@ -184,7 +372,7 @@ impl Tokenizer {
             } else if ch == '.' && !has_dot && !has_e {
                 has_dot = true;
                 self.advance();
-            } else if (ch == 'e' || ch == 'E') && !has_e {
+            } else if (ch == 'e' || ch == 'E') && !has_e && self.exponent_follows() {
                 has_e = true;
                 self.advance();
                 // Handle optional sign after e
@@ -205,6 +393,60 @@ impl Tokenizer {
         })
     }
 
+    /// Whether the `e` at the current position starts an exponent
+    fn exponent_follows(&self) -> bool {
+        let digit_at = |offset| self.char_at(self.position + offset).is_some_and(|ch| ch.is_ascii_digit());
+        self.e_notation == ENotation::Scientific
+            && match self.char_at(self.position + 1) {
+                Some('+' | '-') => digit_at(2),
+                _ => digit_at(1),
+            }
+    }
+
+    /// Whether the number just read is followed by `e` standing alone for
+    /// Euler's number, rather than starting a longer name like `exp`
+    fn euler_follows(&self) -> bool {
+        matches!(self.peek(), Some('e' | 'E'))
+            && !self.char_at(self.position + 1).is_some_and(|ch| ch.is_alphabetic() || ch == '_')
+    }
+
+    /// A number, or an angle written in degrees, minutes and seconds as
+    /// `d°`, `d°m'` or `d°m's"` and read as its value in degrees
+    ///
+    /// Trigonometric functions take degrees, so that is the value used.
+    fn read_angle(&mut self) -> Result<f64, TokenizerError> {
+        let mut angle = self.read_number()?;
+        if self.peek() != Some('°') {
+            return Ok(angle);
+        }
+        self.advance();
+
+        for (marks, per_degree) in [(['\'', '′'], 60.0), (['"', '″'], 3600.0)] {
+            // A part counts only if its mark follows, so `30° 2` is 30 then 2
+            let before = self.position;
+            self.skip_whitespace();
+            if !self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+                self.position = before;
+                break;
+            }
+            let start = self.position;
+            let part = self.read_number()?;
+            if !self.peek().is_some_and(|ch| marks.contains(&ch)) {
+                self.position = before;
+                break;
+            }
+            if part >= 60.0 {
+                return Err(TokenizerError {
+                    message: format!("Minutes and seconds must be less than 60, found {}", part),
+                    position: start,
+                });
+            }
+            self.advance();
+            angle += part / per_degree;
+        }
+        Ok(angle)
+    }
+
     fn read_identifier(&mut self) -> String {
         let start = self.position;
         while let Some(ch) = self.peek() {
@@ -217,105 +459,171 @@ impl Tokenizer {
         self.input[start..self.position].iter().collect()
     }
 
+    fn read_text(&mut self) -> Result<String, TokenizerError> {
+        let start = self.position;
+        self.advance(); // Opening quote
+        let mut text = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(text),
+                Some(ch) => text.push(ch),
+                None => {
+                    return Err(TokenizerError {
+                        message: "Unterminated string".to_string(),
+                        position: start,
+                    })
+                }
+            }
+        }
+    }
+
     pub fn tokenize(&mut self) -> Result<Vec<Token>, TokenizerError> {
-        let mut tokens = Vec::new();
+        Ok(self.tokenize_spanned()?.into_iter().map(|(token, _)| token).collect())
+    }
 
-        while self.position < self.input.len() {
-            self.skip_whitespace();
+    /// Tokenize, pairing each token with the range of characters it was read from
+    ///
+    /// Ranges index characters, not bytes, like `TokenizerError::position`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err, fields(chars = self.input.len())))]
+    pub fn tokenize_spanned(&mut self) -> Result<Vec<(Token, Range<usize>)>, TokenizerError> {
+        self.tokens.clear();
+        self.steps.clear();
+        while self.skip_to_token() {
+            self.step()?;
+        }
+        self.steps.clear();
+        debug_event!(tokens = self.tokens.len(), "tokenized");
+        Ok(core::mem::take(&mut self.tokens))
+    }
 
-            if self.position >= self.input.len() {
-                break;
+    /// Apply `edit` to the input and tokenize it again, reusing the tokens
+    /// of the last `retokenize` that the edit can't have changed
+    ///
+    /// The first call, with nothing to reuse, reads the whole input. After
+    /// an error the tokens before it are still reused.
+    pub fn retokenize(&mut self, edit: &Edit) -> Result<&[(Token, Range<usize>)], TokenizerError> {
+        let Edit { range, text } = edit;
+        let (removed, inserted) = (range.len(), text.chars().count());
+        self.input.splice(range.clone(), text.chars());
+        let old_tokens = core::mem::take(&mut self.tokens);
+        let old_steps = core::mem::take(&mut self.steps);
+
+        // Steps that didn't look as far as the edit are kept
+        let kept = old_steps.iter().take_while(|step| step.looked <= range.start).count();
+        let first_read = old_steps.get(kept).map_or(old_tokens.len(), |step| step.first);
+        self.position = first_read.checked_sub(1).map_or(0, |last| old_tokens[last].1.end);
+        self.tokens.extend_from_slice(&old_tokens[..first_read]);
+        self.steps.extend_from_slice(&old_steps[..kept]);
+
+        // Past the edit, the rest is reused from the first step starting where one did
+        let resume = range.start + inserted;
+        let moved = |old: usize| old + inserted - removed;
+        while self.skip_to_token() {
+            if self.position >= resume {
+                let old = self.position + removed - inserted;
+                let later = &old_steps[kept..];
+                if let Ok(index) = later.binary_search_by_key(&old, |step| old_tokens[step.first].1.start) {
+                    let (from, to) = (later[index].first, self.tokens.len());
+                    self.tokens.extend(
+                        old_tokens[from..].iter().map(|(token, span)| (token.clone(), moved(span.start)..moved(span.end))),
+                    );
+                    self.steps.extend(later[index..].iter().map(|step| Step {
+                        first: step.first - from + to,
+                        looked: moved(step.looked),
+                    }));
+                    break;
+                }
             }
+            self.step()?;
+        }
+        Ok(&self.tokens)
+    }
 
-            let ch = self.peek().unwrap();
-
-            let token = if ch.is_ascii_digit() || (ch == '.' && self.input.get(self.position + 1).map_or(false, |c| c.is_ascii_digit())) {
-                Token::Number(self.read_number()?)
-            } else if ch.is_alphabetic() {
-                let ident = self.read_identifier().to_lowercase();
-                match ident.as_str() {
-                    // Trig functions
-                    "sin" => Token::Sin,
-                    "cos" => Token::Cos,
-                    "tan" => Token::Tan,
-                    "asin" | "arcsin" => Token::Asin,
-                    "acos" | "arccos" => Token::Acos,
-                    "atan" | "arctan" => Token::Atan,
-                    // Hyperbolic
-                    "sinh" => Token::Sinh,
-                    "cosh" => Token::Cosh,
-                    "tanh" => Token::Tanh,
-                    // Math functions
-                    "sqrt" => Token::Sqrt,
-                    "cbrt" => Token::Cbrt,
-                    "log" | "log10" => Token::Log,
-                    "log2" => Token::Log2,
-                    "ln" => Token::Ln,
-                    "exp" => Token::Exp,
-                    "abs" => Token::Abs,
-                    "floor" => Token::Floor,
-                    "ceil" => Token::Ceil,
-                    "round" => Token::Round,
-                    "sign" | "sgn" => Token::Sign,
-                    // Array functions
-                    "sum" => Token::Sum,
-                    "avg" | "mean" | "average" => Token::Avg,
-                    "min" => Token::Min,
-                    "max" => Token::Max,
-                    "len" | "length" | "count" => Token::Len,
-                    // Combinatorics
-                    "gcd" => Token::Gcd,
-                    "lcm" => Token::Lcm,
-                    "npr" | "perm" => Token::Npr,
-                    "ncr" | "comb" | "choose" => Token::Ncr,
-                    // Conversion
-                    "rad" | "torad" => Token::ToRad,
-                    "deg" | "todeg" => Token::ToDeg,
-                    // Constants
-                    "pi" => Token::Pi,
-                    "e" => Token::E,
-                    "tau" => Token::Tau,
-                    "phi" | "golden" => Token::Phi,
-                    _ => return Err(TokenizerError {
-                        message: format!("Unknown identifier: {}", ident),
-                        position: self.position - ident.len(),
-                    }),
+    /// Skip whitespace, returning whether a token follows
+    fn skip_to_token(&mut self) -> bool {
+        self.skip_whitespace();
+        self.position < self.input.len()
+    }
+
+    /// Read the tokens of one step at the current position
+    fn step(&mut self) -> Result<(), TokenizerError> {
+        self.looked.set(self.position);
+        let first = self.tokens.len();
+        self.read_step()?;
+        self.steps.push(Step { first, looked: self.looked.get() });
+        Ok(())
+    }
+
+    /// Read a step's tokens onto `tokens`
+    fn read_step(&mut self) -> Result<(), TokenizerError> {
+        let ch = self.peek().unwrap();
+        let start = self.position;
+
+        let token = if ch.is_ascii_digit() || (ch == '.' && self.char_at(self.position + 1).is_some_and(|c| c.is_ascii_digit())) {
+            let number = self.read_angle()?;
+            if self.euler_follows() {
+                // `2e` is 2 * e, and `2e3` 2 * e * 3; the implied
+                // multiplications span no characters
+                let e = self.position;
+                self.tokens.push((Token::Number(number), start..e));
+                self.tokens.push((Token::Multiply, e..e));
+                self.advance();
+                self.tokens.push((Token::E, e..e + 1));
+                if self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+                    self.tokens.push((Token::Multiply, e + 1..e + 1));
                 }
-            } else {
+                return Ok(());
+            }
+            Token::Number(number)
+        } else if ch == '"' {
+            Token::Text(self.read_text()?)
+        } else if ch.is_alphabetic() {
+            let name = self.read_identifier();
+            match builtins::lookup(&name) {
+                Some(builtin) => builtin.token.clone(),
+                // Anything else names a variable or user function (case-sensitive)
+                None => Token::Identifier(name),
+            }
+        } else {
+            self.advance();
+            // Check for ** (power operator)
+            if ch == '*' && self.peek() == Some('*') {
                 self.advance();
-                // Check for ** (power operator)
-                if ch == '*' && self.peek() == Some('*') {
-                    self.advance();
-                    Token::Power
-                } else {
-                    match ch {
-                        '+' => Token::Plus,
-                        '-' => Token::Minus,
-                        '*' | '×' => Token::Multiply,
-                        '/' | '÷' => Token::Divide,
-                        '^' => Token::Power,
-                        '%' => Token::Modulo,
-                        '!' => Token::Factorial,
-                        '(' => Token::LParen,
-                        ')' => Token::RParen,
-                        '[' => Token::LBracket,
-                        ']' => Token::RBracket,
-                        ',' => Token::Comma,
-                        'π' => Token::Pi,
-                        'τ' => Token::Tau,
-                        'φ' => Token::Phi,
-                        _ => return Err(TokenizerError {
-                            message: format!("Unexpected character: {}", ch),
-                            position: self.position - 1,
-                        }),
-                    }
+                Token::Power
+            } else if ch == '-' && self.peek() == Some('>') {
+                self.advance();
+                Token::Arrow
+            } else {
+                match ch {
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' | '×' => Token::Multiply,
+                    '/' | '÷' => Token::Divide,
+                    '^' => Token::Power,
+                    '%' => Token::Modulo,
+                    '!' => Token::Factorial,
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    '|' => Token::Pipe,
+                    ',' => Token::Comma,
+                    '=' => Token::Assign,
+                    ';' => Token::Semicolon,
+                    'π' => Token::Pi,
+                    'τ' => Token::Tau,
+                    'φ' => Token::Phi,
+                    '→' => Token::Arrow,
+                    _ => return Err(TokenizerError {
+                        message: format!("Unexpected character: {}", ch),
+                        position: self.position - 1,
+                    }),
                 }
-            };
-
-            tokens.push(token);
-        }
+            }
+        };
 
-        Ok(tokens)
+        self.tokens.push((token, start..self.position));
+        Ok(())
     }
 }
 
@@ -364,6 +672,118 @@ mod tests {
         assert_eq!(tokens, vec![Token::Number(5.0), Token::Factorial]);
     }
 
+    #[test]
+    fn test_text() {
+        let tokens = Tokenizer::new("frombase(\"1F\", 16)").tokenize().unwrap();
+        assert_eq!(tokens[2], Token::Text("1F".into()));
+        assert!(Tokenizer::new("frombase(\"1F").tokenize().is_err());
+    }
+
+    #[test]
+    fn test_arrow() {
+        let tokens = Tokenizer::new("x->x-1").tokenize().unwrap();
+        assert_eq!(tokens, vec![
+            Token::Identifier("x".into()),
+            Token::Arrow,
+            Token::Identifier("x".into()),
+            Token::Minus,
+            Token::Number(1.0),
+        ]);
+        assert_eq!(Tokenizer::new("x → 2").tokenize().unwrap()[1], Token::Arrow);
+    }
+
+    #[test]
+    fn test_definitions() {
+        let mut tokenizer = Tokenizer::new("Rate = 2; f(x_1) = Sin(x_1)");
+        let tokens = tokenizer.tokenize().unwrap();
+        assert_eq!(tokens, vec![
+            Token::Identifier("Rate".into()),
+            Token::Assign,
+            Token::Number(2.0),
+            Token::Semicolon,
+            Token::Identifier("f".into()),
+            Token::LParen,
+            Token::Identifier("x_1".into()),
+            Token::RParen,
+            Token::Assign,
+            Token::Sin,
+            Token::LParen,
+            Token::Identifier("x_1".into()),
+            Token::RParen,
+        ]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let mut tokenizer = Tokenizer::new("sqrt(2) ** pi");
+        let spans: Vec<_> = tokenizer.tokenize_spanned().unwrap().into_iter().map(|(_, span)| span).collect();
+        assert_eq!(spans, vec![0..4, 4..5, 5..6, 6..7, 8..10, 11..13]);
+    }
+
+    #[test]
+    fn test_e_notation() {
+        let tokens = |input: &str, e_notation| Tokenizer::new(input).with_e_notation(e_notation).tokenize().unwrap();
+        let euler_product = vec![Token::Number(2.0), Token::Multiply, Token::E, Token::Multiply, Token::Number(3.0)];
+        assert_eq!(tokens("2e3", ENotation::Scientific), vec![Token::Number(2000.0)]);
+        assert_eq!(tokens("2e3", ENotation::Euler), euler_product);
+        assert_eq!(tokens("1.5e-3", ENotation::Scientific), vec![Token::Number(0.0015)]);
+
+        // Without an exponent's digits, `e` is the constant in either mode
+        for e_notation in [ENotation::Scientific, ENotation::Euler] {
+            assert_eq!(tokens("1e", e_notation), vec![Token::Number(1.0), Token::Multiply, Token::E]);
+            assert_eq!(tokens("1e+x", e_notation)[..4], [Token::Number(1.0), Token::Multiply, Token::E, Token::Plus]);
+            // A longer name is left alone
+            assert_eq!(tokens("2exp(1)", e_notation)[..2], [Token::Number(2.0), Token::Exp]);
+        }
+
+        let spans: Vec<_> = Tokenizer::new("2e").tokenize_spanned().unwrap().into_iter().map(|(_, span)| span).collect();
+        assert_eq!(spans, vec![0..1, 1..1, 1..2]);
+    }
+
+    #[test]
+    fn test_angles() {
+        let number = |input: &str| Tokenizer::new(input).tokenize().unwrap()[0].clone();
+        assert_eq!(number("45°"), Token::Number(45.0));
+        assert_eq!(number("30°15'"), Token::Number(30.25));
+        assert_eq!(number("30° 15′ 36″"), Token::Number(30.26));
+        assert_eq!(number("1°0'36\""), Token::Number(1.01));
+
+        // A number without a mark isn't a part of the angle
+        let tokens = Tokenizer::new("30° 2").tokenize().unwrap();
+        assert_eq!(tokens, vec![Token::Number(30.0), Token::Number(2.0)]);
+        let tokens = Tokenizer::new("30°15' + 1").tokenize().unwrap();
+        assert_eq!(tokens, vec![Token::Number(30.25), Token::Plus, Token::Number(1.0)]);
+
+        let error = Tokenizer::new("10°75'").tokenize().unwrap_err();
+        assert_eq!((error.message.as_str(), error.position), ("Minutes and seconds must be less than 60, found 75", 3));
+    }
+
+    #[test]
+    fn test_retokenize() {
+        let fresh = |input: &str| Tokenizer::new(input).tokenize_spanned().map_err(|e| e.position);
+        let script = "f(x) = 2e3 * x; 30°15' + sin(x)**2 -> \"1F\"; g = 1.5e-3";
+
+        // Typed a character at a time, then edited in the middle
+        let mut tokenizer = Tokenizer::new("");
+        let mut input = String::new();
+        for ch in script.chars() {
+            let end = input.chars().count();
+            input.push(ch);
+            let tokens = tokenizer.retokenize(&Edit::new(end..end, ch)).map(<[_]>::to_vec).map_err(|e| e.position);
+            assert_eq!(tokens, fresh(&input), "{}", input);
+        }
+        for (old, new) in [("2e3", "2e"), ("2e", "2 e3"), ("°15'", "°15"), ("**", "*"), ("sin", "sinh"), ("\"1F", "1F"), ("1F", "\"1F")] {
+            let edited = input.replacen(old, new, 1);
+            let tokens = tokenizer.retokenize(&Edit::between(&input, &edited)).map(<[_]>::to_vec).map_err(|e| e.position);
+            assert_eq!(tokens, fresh(&edited), "{}", edited);
+            input = edited;
+        }
+
+        assert_eq!(Edit::between("1 + 2", "1 - 2"), Edit::new(2..3, "-"));
+        assert_eq!(Edit::between("aa", "aaa"), Edit::new(2..2, "a"));
+        assert_eq!(Edit::between("x = 1", "x"), Edit::new(1..5, ""));
+    }
+
     #[test]
     fn test_scientific_notation() {
         let mut tokenizer = Tokenizer::new("1.5e10 + 2E-3");
@@ -3,33 +3,228 @@
 //! Stack-based VM that interprets bytecode generated by the compiler.
 //! Uses a fixed-size stack for operands and supports all calculator operations.
 //! Supports scalar values and arrays for aggregate operations.
+//!
+//! Besides one-shot `execute`, a chunk can be `load`ed and driven with
+//! `step`/`step_back`. Reverse stepping restores the nearest periodic
+//! checkpoint and replays forward, so live debugging can move in both
+//! directions without recording a full trace.
+//!
+//! Variables assigned and functions defined by a program are kept by the
+//! VM across executions until removed. A CALL runs the function's own
+//! chunk in a nested dispatch loop with its arguments as the frame's locals.
+//!
+//! A `HostContext` lets the embedding application supply variables and
+//! functions the program doesn't define itself, looked up by name as the
+//! program runs.
+//!
+//! `execute_registers` runs the experimental register IR instead; both
+//! modes share the same per-opcode arithmetic. With the `jit` feature,
+//! `execute_jit` runs straight-line arithmetic as native code.
+
+use crate::allocator::Allocator;
+use crate::bytecode::{Chunk, Function, Instruction, OpCode, Operand};
+use crate::codegen::Program;
+use crate::gc::{GarbageCollector, GcArray, GcPhase, GcValue, Traceable};
+use crate::linker::LinkedProgram;
+use crate::intern::Symbol;
+use crate::register::{RegInstr, RegisterChunk, Source};
+use crate::stats;
+use crate::prelude::*;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+use web_time::Instant;
+
+pub(crate) const STACK_MAX: usize = 256;
+
+/// Default maximum depth of nested function calls
+const DEFAULT_RECURSION_LIMIT: usize = 64;
+
+/// Number of executed instructions between state snapshots used by `step_back`
+const CHECKPOINT_INTERVAL: usize = 32;
 
-use crate::bytecode::{Chunk, OpCode};
-use crate::gc::GarbageCollector;
-use std::fmt;
+/// Number of executed instructions between cancellation/deadline checks
+const INTERRUPT_CHECK_INTERVAL: usize = 256;
 
-const STACK_MAX: usize = 256;
+/// Collection work done after each execution, in GC work units
+const GC_STEP_BUDGET: usize = 1024;
+
+/// Largest x `primepi` counts up to, taking about x^(3/4) steps
+const PRIME_PI_MAX: f64 = 1e11;
+
+/// Largest n `stirling2` works through, row by row
+const STIRLING_MAX: usize = 1_000;
+
+/// Most elements an array constructor like `zeros` or `hist` will build
+const GENERATED_MAX: usize = 1_000_000;
+
+/// Seed of a new VM's random number generator, so runs repeat until reseeded
+const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+/// Smallest magnitude integer mode rejects: 2^53, past which `f64` skips integers
+const INTEGER_LIMIT: f64 = 9_007_199_254_740_992.0;
+
+/// Arrays at least this long are reduced on the rayon thread pool
+///
+/// Below this, splitting the work costs more than it saves.
+#[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+pub const PARALLEL_THRESHOLD: usize = 32_768;
 
 /// Stack value - can be a scalar or an array
+///
+/// Arrays live on the VM's garbage-collected heap, so a `StackValue`
+/// borrowed from the VM must not be used after the VM is dropped.
 #[derive(Debug, Clone)]
 pub enum StackValue {
     Scalar(f64),
-    Array(Vec<f64>),
+    Array(GcArray),
+    /// A function value, made by running a lambda
+    Closure(GcValue<Closure>),
+    /// A string, such as `tobase` writes; shared rather than on the heap,
+    /// since it never refers to other values
+    Text(Arc<str>),
 }
 
 impl StackValue {
     pub fn as_scalar(&self) -> Result<f64, VmError> {
         match self {
             StackValue::Scalar(v) => Ok(*v),
-            StackValue::Array(arr) if arr.len() == 1 => Ok(arr[0]),
+            StackValue::Array(arr) if arr.len() == 1 => Ok(arr.as_slice()[0]),
             StackValue::Array(_) => Err(VmError::InvalidOperation("Expected scalar, got array".into())),
+            StackValue::Closure(_) => Err(VmError::InvalidOperation("Expected scalar, got function".into())),
+            StackValue::Text(_) => Err(VmError::InvalidOperation("Expected scalar, got string".into())),
         }
     }
 
     pub fn as_array(&self) -> Vec<f64> {
+        self.as_slice().to_vec()
+    }
+
+    /// View the value as elements (a scalar is a one-element array, a
+    /// function or string has none)
+    pub fn as_slice(&self) -> &[f64] {
+        match self {
+            StackValue::Scalar(v) => core::slice::from_ref(v),
+            StackValue::Array(arr) => arr.as_slice(),
+            StackValue::Closure(_) | StackValue::Text(_) => &[],
+        }
+    }
+
+    /// Copy the value off the GC heap
+    pub fn to_value(&self) -> Value {
+        self.view().to_value()
+    }
+
+    /// Borrow the value where it is
+    pub fn view(&self) -> ValueRef<'_> {
+        match self {
+            StackValue::Scalar(v) => ValueRef::Scalar(*v),
+            StackValue::Array(arr) => ValueRef::Array(arr.as_slice()),
+            StackValue::Closure(closure) => ValueRef::Function(&closure.get().function),
+            StackValue::Text(text) => ValueRef::Text(text),
+        }
+    }
+}
+
+/// A lambda's function together with the values it captured
+#[derive(Debug, Clone)]
+pub struct Closure {
+    pub function: Arc<Function>,
+    /// Captured values, by LOAD_UPVALUE slot
+    pub upvalues: Vec<StackValue>,
+}
+
+impl Traceable for Closure {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        self.upvalues.trace(gc);
+    }
+}
+
+/// A value borrowed from the VM, valid until it next runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueRef<'a> {
+    Scalar(f64),
+    Array(&'a [f64]),
+    Function(&'a Function),
+    Text(&'a str),
+}
+
+impl ValueRef<'_> {
+    pub fn to_value(&self) -> Value {
+        match self {
+            ValueRef::Scalar(v) => Value::Scalar(*v),
+            ValueRef::Array(elements) => Value::Array(elements.to_vec()),
+            ValueRef::Function(function) => Value::Function(function.to_string()),
+            ValueRef::Text(text) => Value::Text(text.to_string()),
+        }
+    }
+}
+
+/// What `VirtualMachine::heap_objects` tells of an object on the heap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapObject {
+    /// Address of the object's data, fixed while it lives
+    pub address: usize,
+    pub kind: HeapKind,
+    /// Bytes the object takes, its header and padding included
+    pub bytes: usize,
+    /// Whether the stack, a global or a step-back snapshot refers to it;
+    /// anything else is garbage the next collection frees
+    pub reachable: bool,
+}
+
+/// Type of a heap object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapKind {
+    /// An array of numbers
+    Array { len: usize },
+    /// A function value holding `captures` captured values
+    Closure { captures: usize },
+}
+
+/// A value owned outside the VM, e.g. the contents of a global variable
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Scalar(f64),
+    Array(Vec<f64>),
+    /// A function value, by its source, e.g. "x -> (k * x)"
+    ///
+    /// Only a lambda running in the VM makes one; the source can be
+    /// compiled into a new one, but not whatever it captured.
+    Function(String),
+    /// A string, e.g. the digits `tobase` writes
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Scalar(v) => write!(f, "{}", v),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, v) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Function(source) | Value::Text(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+impl Traceable for StackValue {
+    fn trace(&self, gc: &mut GarbageCollector) {
         match self {
-            StackValue::Scalar(v) => vec![*v],
-            StackValue::Array(arr) => arr.clone(),
+            StackValue::Scalar(_) | StackValue::Text(_) => {}
+            StackValue::Array(arr) => arr.trace(gc),
+            StackValue::Closure(closure) => closure.trace(gc),
         }
     }
 }
@@ -42,6 +237,26 @@ pub enum VmError {
     DivisionByZero,
     InvalidOperation(String),
     MathError(String),
+    Timeout(Duration),
+    Cancelled,
+    OutOfMemory,
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    /// A `HostContext` function failed
+    HostError(String),
+    /// More instructions ran than `set_instruction_limit` allows
+    InstructionLimit(usize),
+    /// In integer mode, a value with a fractional part
+    NotAnInteger(f64),
+    /// In integer mode, a value beyond the exactly representable integers
+    IntegerOverflow,
+    /// In checked mode, an instruction the stack doesn't fit, which means
+    /// the bytecode is wrong
+    CheckFailed(String),
+    /// `solve` was given a matrix with no inverse
+    SingularMatrix,
+    /// A call nested deeper than `set_recursion_limit` allows
+    RecursionLimit { depth: usize },
 }
 
 impl fmt::Display for VmError {
@@ -53,12 +268,105 @@ impl fmt::Display for VmError {
             VmError::DivisionByZero => write!(f, "Division by zero"),
             VmError::InvalidOperation(msg) => write!(f, "Invalid operation: {}", msg),
             VmError::MathError(msg) => write!(f, "Math error: {}", msg),
+            VmError::Timeout(limit) => write!(f, "Execution timed out after {:?}", limit),
+            VmError::Cancelled => write!(f, "Execution cancelled"),
+            VmError::OutOfMemory => write!(f, "Out of memory"),
+            VmError::UndefinedVariable(name) => write!(f, "Undefined variable: {}", name),
+            VmError::UndefinedFunction(name) => write!(f, "Undefined function: {}", name),
+            VmError::HostError(msg) => write!(f, "Host error: {}", msg),
+            VmError::InstructionLimit(limit) => write!(f, "Instruction limit of {} exceeded", limit),
+            VmError::NotAnInteger(value) => write!(f, "Not an integer: {}", value),
+            VmError::CheckFailed(msg) => write!(f, "Check failed: {}", msg),
+            VmError::IntegerOverflow => write!(f, "Integer overflow"),
+            VmError::SingularMatrix => write!(f, "Singular matrix"),
+            VmError::RecursionLimit { depth } => {
+                write!(f, "Recursion limit exceeded: calls nested more than {} deep", depth)
+            }
+        }
+    }
+}
+
+/// Variables and functions provided by the embedding application
+///
+/// The VM asks its host only for names the program hasn't defined itself,
+/// each time they are used, so the values can come straight from the
+/// application's own data. Both methods default to knowing nothing.
+pub trait HostContext: Send + Sync {
+    /// The value of the variable `name`, or `None` if the host has none
+    fn resolve_variable(&self, _name: &str) -> Option<Value> {
+        None
+    }
+
+    /// Call the function `name`, or return `None` if the host has none
+    fn call_function(&self, _name: &str, _args: &[Value]) -> Option<Result<Value, String>> {
+        None
+    }
+}
+
+/// When a timed execution has to stop, and the timeout it was given
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    limit: Duration,
+}
+
+/// Timeouts need a clock, so without `std` there are no deadlines
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+enum Deadline {}
+
+impl Deadline {
+    /// `timeout` from now
+    #[cfg(feature = "std")]
+    fn after(timeout: Duration) -> Self {
+        Deadline {
+            at: Instant::now() + timeout,
+            limit: timeout,
         }
     }
+
+    #[cfg(feature = "std")]
+    fn check(self) -> Result<(), VmError> {
+        if Instant::now() >= self.at {
+            return Err(VmError::Timeout(self.limit));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn check(self) -> Result<(), VmError> {
+        match self {}
+    }
+}
+
+/// Cooperative cancellation flag for a running VM
+///
+/// Clones share the same flag, so one can be handed to the VM while another
+/// stays with the caller (e.g. the GUI thread) to request an abort.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of any execution observing this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
 }
 
 /// Execution trace for debugging/display
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ExecutionStep {
     pub ip: usize,
     pub opcode: OpCode,
@@ -67,18 +375,317 @@ pub struct ExecutionStep {
     pub stack_after: Vec<f64>,
 }
 
+impl ExecutionStep {
+    /// Whether two steps did the same, counting NaN as equal to NaN
+    fn same_as(&self, other: &ExecutionStep) -> bool {
+        let same = |a: &f64, b: &f64| a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()) || a == b;
+        let same_stack = |a: &[f64], b: &[f64]| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b));
+        self.ip == other.ip
+            && self.opcode == other.opcode
+            && match (self.operand, other.operand) {
+                (Some(a), Some(b)) => same(&a, &b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+            && same_stack(&self.stack_before, &other.stack_before)
+            && same_stack(&self.stack_after, &other.stack_after)
+    }
+
+    fn new(instruction: &Instruction, stack_before: Vec<f64>, stack_after: Vec<f64>) -> Self {
+        let operand = match instruction.operand {
+            Operand::Number(value) => Some(value),
+            _ => None,
+        };
+        ExecutionStep {
+            ip: instruction.offset,
+            opcode: instruction.opcode,
+            operand,
+            stack_before,
+            stack_after,
+        }
+    }
+}
+
+/// The first step at which `VirtualMachine::replay` differed from its trace
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Index of the step within the trace
+    pub step: usize,
+    /// The recorded step, or `None` if the replay ran past the end of the trace
+    pub expected: Option<ExecutionStep>,
+    /// The replayed step, or `None` if the replay stopped first
+    pub actual: Option<ExecutionStep>,
+    /// Why the replay stopped, if an instruction failed
+    pub error: Option<VmError>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Replay diverged at step {}: ", self.step)?;
+        let describe = |step: &ExecutionStep| format!("{} at 0x{:04X} leaving {:?}", step.opcode, step.ip, step.stack_after);
+        match (&self.expected, &self.actual, &self.error) {
+            (Some(expected), Some(actual), _) => {
+                write!(f, "expected {}, got {}", describe(expected), describe(actual))
+            }
+            (Some(expected), None, Some(error)) => write!(f, "expected {}, failed with {}", describe(expected), error),
+            (Some(expected), None, None) => write!(f, "expected {}, but the program ended", describe(expected)),
+            (None, Some(actual), _) => write!(f, "the trace ended, but the program ran {}", describe(actual)),
+            (None, None, _) => write!(f, "no steps to compare"),
+        }
+    }
+}
+
+/// The loaded program's remaining steps, each executed as it is pulled
+///
+/// Made by `VirtualMachine::steps`. Iteration ends when the program halts
+/// or an instruction fails, leaving the VM as `step` would.
+pub struct Steps<'a> {
+    vm: &'a mut VirtualMachine,
+    error: Option<VmError>,
+}
+
+impl Steps<'_> {
+    /// The error that ended iteration early, if any
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
+    }
+}
+
+impl Iterator for Steps<'_> {
+    type Item = ExecutionStep;
+
+    fn next(&mut self) -> Option<ExecutionStep> {
+        if self.error.is_some() {
+            return None;
+        }
+        let instruction = self.vm.program.as_ref().and_then(|chunk| chunk.instructions().get(self.vm.pc).copied());
+        let stack_before = self.vm.current_stack();
+        match self.vm.step() {
+            Ok(true) => Some(ExecutionStep::new(&instruction?, stack_before, self.vm.current_stack())),
+            Ok(false) => None,
+            Err(error) => {
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a mut VirtualMachine {
+    type Item = ExecutionStep;
+    type IntoIter = Steps<'a>;
+
+    fn into_iter(self) -> Steps<'a> {
+        self.steps()
+    }
+}
+
+/// Whether a hook runs before or after its instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPhase {
+    Before,
+    After,
+}
+
+/// What a hook set with `VirtualMachine::set_hook` is shown of an instruction
+#[derive(Debug, Clone, Copy)]
+pub struct HookEvent<'a> {
+    pub phase: HookPhase,
+    /// Byte offset of the instruction within its chunk
+    pub ip: usize,
+    pub opcode: OpCode,
+    pub operand: Operand,
+    /// Number of function calls the instruction is nested in
+    pub depth: usize,
+    /// The operand stack, top last
+    pub stack: &'a [StackValue],
+}
+
+type Hook = Box<dyn FnMut(&HookEvent) + Send>;
+
+/// Implementation of an extension opcode, registered with
+/// `VirtualMachine::register_extension`
+///
+/// It is given the top stack values, deepest first, and its result replaces them.
+type ExtensionHandler = Arc<dyn Fn(&[Value]) -> Result<Value, VmError> + Send + Sync>;
+
+/// A registered extension opcode
+#[derive(Clone)]
+struct Extension {
+    arity: usize,
+    handler: ExtensionHandler,
+}
+
+/// Opcode handler, looked up by opcode byte in `HANDLERS`
+type Handler = fn(&mut VirtualMachine, Operand) -> Result<(), VmError>;
+
+/// Dispatch table indexed by opcode byte
+static HANDLERS: [Handler; 256] = VirtualMachine::handler_table();
+
+/// SplitMix64 generator: small and fast, fine for sampling but not for secrets
+#[derive(Debug, Clone, Copy)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in 0..n, for n > 0, without modulo bias
+    fn below(&mut self, n: usize) -> usize {
+        let n = n as u64;
+        // Largest multiple of n representable; draws at or above it are redrawn
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return (x % n) as usize;
+            }
+        }
+    }
+}
+
+/// Snapshot of VM state used for reverse stepping
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    step: usize,
+    ip: usize,
+    pc: usize,
+    halted: bool,
+    stack: Vec<StackValue>,
+    locals: Vec<StackValue>,
+    globals: BTreeMap<Symbol, StackValue>,
+    functions: BTreeMap<Symbol, Arc<Function>>,
+    rng: Rng,
+}
+
+impl Traceable for Checkpoint {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        self.stack.trace(gc);
+        self.locals.trace(gc);
+        for value in self.globals.values() {
+            value.trace(gc);
+        }
+    }
+}
+
+/// Everything in the VM that can reference heap objects
+struct Roots<'a> {
+    stack: &'a [StackValue],
+    locals: &'a [StackValue],
+    checkpoints: &'a [Checkpoint],
+    globals: &'a BTreeMap<Symbol, StackValue>,
+    /// Registers of a register program waiting on a CALL
+    parked: &'a [StackValue],
+    closures: &'a [GcValue<Closure>],
+    registers: &'a [StackValue],
+}
+
+impl Traceable for Roots<'_> {
+    fn trace(&self, gc: &mut GarbageCollector) {
+        self.stack.trace(gc);
+        self.locals.trace(gc);
+        self.checkpoints.trace(gc);
+        for value in self.globals.values() {
+            value.trace(gc);
+        }
+        self.parked.trace(gc);
+        self.closures.trace(gc);
+        self.registers.trace(gc);
+    }
+}
+
+/// Name and function tables of the chunk currently being executed
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    names: Arc<Vec<Symbol>>,
+    functions: Arc<Vec<Arc<Function>>>,
+}
+
+impl Scope {
+    fn of(chunk: &Chunk) -> Self {
+        let (names, functions) = chunk.tables();
+        Scope { names, functions }
+    }
+
+    fn name(&self, index: u16) -> Result<Symbol, VmError> {
+        self.names
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| VmError::InvalidOperation(format!("Name #{} out of range", index)))
+    }
+}
+
 /// Virtual Machine for executing calculator bytecode
 pub struct VirtualMachine {
     /// Operand stack - using StackValue to support arrays
     stack: Vec<StackValue>,
-    /// Instruction pointer
+    /// Instruction pointer (byte offset into the chunk)
     ip: usize,
+    /// Index of the next instruction in the chunk's decoded instruction list
+    pc: usize,
     /// Garbage collector for memory management
     gc: GarbageCollector,
     /// Execution trace for debugging
     trace: Vec<ExecutionStep>,
     /// Whether to record execution trace
     tracing_enabled: bool,
+    /// Whether HALT has been executed
+    halted: bool,
+    /// Number of instructions executed since reset
+    step_count: usize,
+    /// Program loaded for step-by-step execution
+    program: Option<Chunk>,
+    /// Periodic snapshots of the loaded program's state, oldest first
+    checkpoints: Vec<Checkpoint>,
+    /// Token polled during execution to support cooperative cancellation
+    cancellation: Option<CancellationToken>,
+    /// Deadline of the running execution, also observed by nested calls
+    deadline: Option<Deadline>,
+    /// Global variables, kept across executions
+    globals: BTreeMap<Symbol, StackValue>,
+    /// User-defined functions, kept across executions
+    functions: BTreeMap<Symbol, Arc<Function>>,
+    /// Tables of the chunk being executed, for resolving index operands
+    scope: Scope,
+    /// Stack index of the first argument, and the argument count, of each active call
+    frame_bases: Vec<(usize, usize)>,
+    /// Callee of a TAILCALL, to run in the frame of the function that made it
+    tail_call: Option<Arc<Function>>,
+    /// Closures being called, innermost last, whose captures LOAD_UPVALUE reads
+    closures: Vec<GcValue<Closure>>,
+    /// Values of let-bindings, the current frame's from `local_base`
+    locals: Vec<StackValue>,
+    local_base: usize,
+    /// Registers of a register program suspended in a CALL
+    parked: Vec<StackValue>,
+    /// Application data consulted for undefined names
+    host: Option<Arc<dyn HostContext>>,
+    /// Instructions executed since reset, including those of called functions
+    executed: usize,
+    /// Most instructions one execution may run
+    instruction_limit: Option<usize>,
+    /// Deepest calls may nest
+    recursion_limit: usize,
+    /// Whether every value must be an integer (see `enable_integer_mode`)
+    integer_mode: bool,
+    /// Whether instructions are checked against the stack (see `enable_checked_mode`)
+    checked_mode: bool,
+    /// Source of `shuffle` and `sample`
+    rng: Rng,
+    /// Handlers of the extension opcodes, by slot
+    extensions: [Option<Extension>; 16],
+    /// Called around every instruction
+    hook: Option<Hook>,
 }
 
 impl VirtualMachine {
@@ -86,12 +693,57 @@ impl VirtualMachine {
         VirtualMachine {
             stack: Vec::with_capacity(STACK_MAX),
             ip: 0,
+            pc: 0,
             gc: GarbageCollector::new(),
             trace: Vec::new(),
             tracing_enabled: false,
+            halted: false,
+            step_count: 0,
+            program: None,
+            checkpoints: Vec::new(),
+            cancellation: None,
+            deadline: None,
+            globals: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            scope: Scope::default(),
+            frame_bases: Vec::new(),
+            tail_call: None,
+            closures: Vec::new(),
+            locals: Vec::new(),
+            local_base: 0,
+            parked: Vec::new(),
+            host: None,
+            executed: 0,
+            instruction_limit: None,
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            integer_mode: false,
+            checked_mode: false,
+            rng: Rng::new(DEFAULT_SEED),
+            extensions: Default::default(),
+            hook: None,
         }
     }
 
+    /// Create a VM whose heap is capped at `limit` bytes
+    ///
+    /// Allocations past the cap force a full collection; if that doesn't
+    /// free enough, execution fails with `VmError::OutOfMemory`.
+    pub fn with_memory_limit(limit: usize) -> Self {
+        let mut vm = Self::new();
+        vm.gc = GarbageCollector::with_limit(limit);
+        vm
+    }
+
+    /// Create a VM whose heap takes its memory from `allocator`
+    ///
+    /// With a `FixedBuffer`, running out of it fails execution with
+    /// `VmError::OutOfMemory` like a memory limit does.
+    pub fn with_allocator(allocator: impl Allocator + 'static) -> Self {
+        let mut vm = Self::new();
+        vm.gc = GarbageCollector::with_allocator(allocator);
+        vm
+    }
+
     /// Enable execution tracing
     pub fn enable_tracing(&mut self) {
         self.tracing_enabled = true;
@@ -112,11 +764,175 @@ impl VirtualMachine {
         self.trace.clear();
     }
 
+    /// Call `hook` before and after every instruction, including those of
+    /// called functions
+    ///
+    /// Tracers, coverage tools and debuggers can watch execution this way
+    /// without the VM recording anything itself. An instruction that fails
+    /// gets no `After` event.
+    pub fn set_hook(&mut self, hook: impl FnMut(&HookEvent) + Send + 'static) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    /// Stop calling the hook
+    pub fn clear_hook(&mut self) {
+        self.hook = None;
+    }
+
+    /// Report `instruction` to the hook, if there is one
+    #[inline(always)]
+    fn run_hook(&mut self, phase: HookPhase, instruction: &Instruction) {
+        if let Some(hook) = self.hook.as_mut() {
+            hook(&HookEvent {
+                phase,
+                ip: instruction.offset,
+                opcode: instruction.opcode,
+                operand: instruction.operand,
+                depth: self.frame_bases.len(),
+                stack: &self.stack,
+            });
+        }
+    }
+
+    /// Observe `token` during execution and abort with `VmError::Cancelled` once it fires
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// Stop observing a cancellation token
+    pub fn clear_cancellation_token(&mut self) {
+        self.cancellation = None;
+    }
+
+    /// Resolve names the program doesn't define through `host`
+    pub fn set_host_context(&mut self, host: Arc<dyn HostContext>) {
+        self.host = Some(host);
+    }
+
+    /// Stop consulting the host context
+    pub fn clear_host_context(&mut self) {
+        self.host = None;
+    }
+
+    /// Fail with `VmError::InstructionLimit` once an execution has run
+    /// `limit` instructions, counting those in called functions
+    pub fn set_instruction_limit(&mut self, limit: usize) {
+        self.instruction_limit = Some(limit);
+    }
+
+    /// Let executions run any number of instructions
+    pub fn clear_instruction_limit(&mut self) {
+        self.instruction_limit = None;
+    }
+
+    /// Fail with `VmError::RecursionLimit` when calls nest more than
+    /// `depth` deep (64 by default)
+    ///
+    /// Every nested call also nests the dispatch loop on the host thread's
+    /// stack, so raise `depth` only as far as that stack allows. Tail calls
+    /// reuse their caller's frame and take no more of that stack, but still
+    /// count against the limit, which is all that stops `f(x) = f(x)`.
+    pub fn set_recursion_limit(&mut self, depth: usize) {
+        self.recursion_limit = depth;
+    }
+
+    /// How deep calls may nest
+    pub fn recursion_limit(&self) -> usize {
+        self.recursion_limit
+    }
+
+    /// How many calls are running, innermost included
+    pub fn call_depth(&self) -> usize {
+        self.frame_bases.len()
+    }
+
+    /// Require every value to be an integer
+    ///
+    /// Each value an instruction produces is checked: a fractional part
+    /// fails with `VmError::NotAnInteger`, a magnitude of 2^53 or more, where
+    /// `f64` starts skipping integers, with `VmError::IntegerOverflow`, and a
+    /// division with a remainder with a math error.
+    pub fn enable_integer_mode(&mut self) {
+        self.integer_mode = true;
+    }
+
+    /// Allow fractional values again
+    pub fn disable_integer_mode(&mut self) {
+        self.integer_mode = false;
+    }
+
+    /// Run the extension `opcode` (one of `OpCode::EXTENSIONS`) with `handler`,
+    /// which takes the top `arity` stack values
+    ///
+    /// Hosts and optional modules use this to add instructions of their
+    /// own; executing an extension with no handler is an error.
+    pub fn register_extension(
+        &mut self,
+        opcode: OpCode,
+        arity: usize,
+        handler: impl Fn(&[Value]) -> Result<Value, VmError> + Send + Sync + 'static,
+    ) -> Result<(), VmError> {
+        let slot = opcode
+            .extension_slot()
+            .ok_or_else(|| VmError::InvalidOperation(format!("{} is not an extension opcode", opcode)))?;
+        self.extensions[slot] = Some(Extension {
+            arity,
+            handler: Arc::new(handler),
+        });
+        Ok(())
+    }
+
+    /// Remove the handler of the extension `opcode`
+    pub fn unregister_extension(&mut self, opcode: OpCode) {
+        if let Some(slot) = opcode.extension_slot() {
+            self.extensions[slot] = None;
+        }
+    }
+
+    /// Check every instruction against the stack before running it
+    ///
+    /// An instruction needing more operands than its frame holds, a
+    /// LOAD_LOCAL past the frame's arguments, or a HALT leaving other than
+    /// exactly one result fails with `VmError::CheckFailed` naming the
+    /// instruction, so bugs in generated bytecode show up as errors rather
+    /// than wrong answers. Popped values leave the stack's `Vec`, so there
+    /// are no stale slots to poison; a read outside the frame is caught
+    /// instead. Meant for testing compilers and optimizer passes.
+    pub fn enable_checked_mode(&mut self) {
+        self.checked_mode = true;
+    }
+
+    /// Run instructions unchecked again
+    pub fn disable_checked_mode(&mut self) {
+        self.checked_mode = false;
+    }
+
+    /// Restart the random number generator from `seed`, so `shuffle` and
+    /// `sample` repeat the results they gave after the same seed
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
     /// Reset VM state
+    ///
+    /// Global variables and functions are kept; see `clear_definitions`.
     pub fn reset(&mut self) {
         self.stack.clear();
         self.ip = 0;
+        self.pc = 0;
         self.trace.clear();
+        self.halted = false;
+        self.step_count = 0;
+        self.program = None;
+        self.checkpoints.clear();
+        self.deadline = None;
+        self.scope = Scope::default();
+        self.frame_bases.clear();
+        self.tail_call = None;
+        self.closures.clear();
+        self.locals.clear();
+        self.local_base = 0;
+        self.executed = 0;
     }
 
     /// Push value onto stack
@@ -156,29 +972,6 @@ impl VirtualMachine {
         self.stack.iter().filter_map(|v| v.as_scalar().ok()).collect()
     }
 
-    /// Read byte at current IP and advance
-    fn read_byte(&mut self, chunk: &Chunk) -> u8 {
-        let byte = chunk.code()[self.ip];
-        self.ip += 1;
-        byte
-    }
-
-    /// Read f64 constant from bytecode
-    fn read_constant(&mut self, chunk: &Chunk) -> f64 {
-        let value = chunk.read_f64(self.ip);
-        self.ip += 8;
-        value
-    }
-
-    /// Read u64 from bytecode
-    fn read_u64(&mut self, chunk: &Chunk) -> u64 {
-        let bytes: [u8; 8] = chunk.code()[self.ip..self.ip + 8]
-            .try_into()
-            .expect("Invalid u64 bytes");
-        self.ip += 8;
-        u64::from_le_bytes(bytes)
-    }
-
     /// Calculate factorial
     fn factorial(n: f64) -> Result<f64, VmError> {
         if n < 0.0 {
@@ -222,482 +1015,3351 @@ impl VirtualMachine {
     }
 
     /// Calculate nPr (Permutations)
+    ///
+    /// Integer arguments multiply out n (n-1) ... (n-r+1), exactly while the
+    /// product fits in a u128, so large n doesn't overflow through n!.
     fn npr(n: f64, r: f64) -> Result<f64, VmError> {
         if n < 0.0 || r < 0.0 || r > n {
             return Err(VmError::MathError("Invalid nPr arguments".into()));
         }
-        let n_fact = Self::factorial(n)?;
-        let nr_fact = Self::factorial(n - r)?;
-        Ok(n_fact / nr_fact)
+        if n.fract() != 0.0 || r.fract() != 0.0 {
+            return Ok(Self::factorial(n)? / Self::factorial(n - r)?);
+        }
+        let (n, r) = (n as u64, r as u64);
+        let factors = (n - r + 1..=n).map(|factor| (factor, 1));
+        Self::checked_product(factors).ok_or_else(|| VmError::MathError("nPr overflow".into()))
     }
 
     /// Calculate nCr (Combinations)
+    ///
+    /// Integer arguments use the multiplicative formula over the smaller of
+    /// r and n-r, exactly while the running value fits in a u128.
     fn ncr(n: f64, r: f64) -> Result<f64, VmError> {
         if n < 0.0 || r < 0.0 || r > n {
             return Err(VmError::MathError("Invalid nCr arguments".into()));
         }
-        let n_fact = Self::factorial(n)?;
-        let r_fact = Self::factorial(r)?;
-        let nr_fact = Self::factorial(n - r)?;
-        Ok(n_fact / (r_fact * nr_fact))
+        if n.fract() != 0.0 || r.fract() != 0.0 {
+            let n_fact = Self::factorial(n)?;
+            let r_fact = Self::factorial(r)?;
+            let nr_fact = Self::factorial(n - r)?;
+            return Ok(n_fact / (r_fact * nr_fact));
+        }
+        let (n, r) = (n as u64, r as u64);
+        let r = r.min(n - r);
+        // C(n, i) = C(n, i-1) * (n-r+i) / i, an integer at every step
+        let factors = (1..=r).map(|i| (n - r + i, i));
+        Self::checked_product(factors).ok_or_else(|| VmError::MathError("nCr overflow".into()))
     }
 
-    /// Execute a chunk of bytecode
-    pub fn execute(&mut self, chunk: &Chunk) -> Result<f64, VmError> {
-        self.reset();
-
-        while self.ip < chunk.len() {
-            let instruction_ip = self.ip;
-            let stack_before = if self.tracing_enabled {
-                self.current_stack()
-            } else {
-                Vec::new()
-            };
-
-            let byte = self.read_byte(chunk);
-            let opcode = OpCode::from_byte(byte).ok_or(VmError::InvalidOpcode(byte))?;
+    /// Multinomial coefficient (k1 + k2 + ...)! / (k1! k2! ...)
+    ///
+    /// Built up as C(k1, k1) C(k1 + k2, k2) ..., exact while it fits in a u128.
+    fn multinomial(counts: &[f64]) -> Result<f64, VmError> {
+        if counts.iter().any(|&k| k < 0.0 || k.fract() != 0.0 || k > u32::MAX as f64) {
+            return Err(VmError::MathError("multinomial counts must be non-negative integers".into()));
+        }
+        let mut total = 0u64;
+        let factors = counts.iter().flat_map(|&k| {
+            let start = total;
+            total += k as u64;
+            (1..=k as u64).map(move |i| (start + i, i))
+        });
+        Self::checked_product(factors).ok_or_else(|| VmError::MathError("multinomial overflow".into()))
+    }
 
-            let operand = if opcode == OpCode::Push {
-                Some(self.read_constant(chunk))
-            } else {
-                None
+    /// Number of derangements !n, by D(n) = n D(n-1) + (-1)^n, exact while it
+    /// fits in a u128
+    fn derange(n: f64) -> Result<f64, VmError> {
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(VmError::MathError("derange needs a non-negative integer".into()));
+        }
+        let (mut exact, mut approximate) = (Some(1u128), 1.0f64);
+        // Past 170 the count overflows f64 anyway
+        for i in 1..=n.min(171.0) as u32 {
+            let odd = i % 2 == 1;
+            exact = exact
+                .and_then(|d| d.checked_mul(i as u128))
+                .and_then(|d| if odd { d.checked_sub(1) } else { d.checked_add(1) });
+            approximate = match exact {
+                Some(d) => d as f64,
+                None => approximate * i as f64,
             };
+        }
+        if approximate.is_finite() {
+            Ok(approximate)
+        } else {
+            Err(VmError::MathError("derange overflow".into()))
+        }
+    }
 
-            match opcode {
-                OpCode::Push => {
-                    self.push_scalar(operand.unwrap())?;
-                }
-                OpCode::Pop => {
-                    self.pop()?;
-                }
-                OpCode::Dup => {
-                    let value = self.peek(0)?.clone();
-                    self.push(value)?;
-                }
-                OpCode::PushArray => {
-                    let count = self.read_u64(chunk) as usize;
-                    let mut elements = Vec::with_capacity(count);
-                    // Pop elements in reverse order (they were pushed in order)
-                    for _ in 0..count {
-                        elements.push(self.pop_scalar()?);
-                    }
-                    elements.reverse();
-                    self.push(StackValue::Array(elements))?;
-                }
-                OpCode::Add => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a + b)?;
-                }
-                OpCode::Sub => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a - b)?;
-                }
-                OpCode::Mul => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a * b)?;
-                }
-                OpCode::Div => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    if b == 0.0 {
-                        return Err(VmError::DivisionByZero);
-                    }
-                    self.push_scalar(a / b)?;
-                }
-                OpCode::Pow => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.powf(b))?;
-                }
-                OpCode::Neg => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(-a)?;
-                }
-                OpCode::Mod => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    if b == 0.0 {
-                        return Err(VmError::DivisionByZero);
-                    }
-                    self.push_scalar(a % b)?;
-                }
-                OpCode::Factorial => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(Self::factorial(a)?)?;
-                }
-                OpCode::Sin => {
-                    let a = self.pop_scalar()?;
-                    // Convert degrees to radians
-                    self.push_scalar((a * std::f64::consts::PI / 180.0).sin())?;
-                }
-                OpCode::Cos => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar((a * std::f64::consts::PI / 180.0).cos())?;
-                }
-                OpCode::Tan => {
-                    let a = self.pop_scalar()?;
-                    let rad = a * std::f64::consts::PI / 180.0;
-                    let result = rad.tan();
-                    if !result.is_finite() {
-                        return Err(VmError::MathError("tan undefined at this angle".into()));
-                    }
-                    self.push_scalar(result)?;
-                }
-                OpCode::Asin => {
-                    let a = self.pop_scalar()?;
-                    if a < -1.0 || a > 1.0 {
-                        return Err(VmError::MathError("asin domain error".into()));
-                    }
-                    // Return degrees
-                    self.push_scalar(a.asin() * 180.0 / std::f64::consts::PI)?;
-                }
-                OpCode::Acos => {
-                    let a = self.pop_scalar()?;
-                    if a < -1.0 || a > 1.0 {
-                        return Err(VmError::MathError("acos domain error".into()));
-                    }
-                    self.push_scalar(a.acos() * 180.0 / std::f64::consts::PI)?;
+    /// Euler's totient: how many of 1..=n share no factor with n
+    ///
+    /// Factors n by trial division with the 6k ± 1 wheel, at most √n steps.
+    fn totient(n: f64) -> Result<f64, VmError> {
+        if n < 1.0 || n.fract() != 0.0 || n >= INTEGER_LIMIT {
+            return Err(VmError::MathError("totient needs a positive integer below 2^53".into()));
+        }
+        let (mut rest, mut phi) = (n as u64, n as u64);
+        let mut divide_out = |p: u64, rest: &mut u64| {
+            if rest.is_multiple_of(p) {
+                phi -= phi / p;
+                while rest.is_multiple_of(p) {
+                    *rest /= p;
                 }
-                OpCode::Atan => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.atan() * 180.0 / std::f64::consts::PI)?;
+            }
+        };
+        divide_out(2, &mut rest);
+        divide_out(3, &mut rest);
+        let mut p = 5;
+        while p * p <= rest {
+            divide_out(p, &mut rest);
+            divide_out(p + 2, &mut rest);
+            p += 6;
+        }
+        if rest > 1 {
+            phi -= phi / rest;
+        }
+        Ok(phi as f64)
+    }
+
+    /// Number of primes up to x, by the Lucy Hedgehog sieve
+    ///
+    /// S(v) counts the numbers in 2..=v not yet sieved out, for each of the
+    /// values v = n / i; sieving by each prime p ≤ √n removes the multiples
+    /// of p with no smaller prime factor.
+    fn prime_pi(x: f64) -> Result<f64, VmError> {
+        if x.is_nan() || x > PRIME_PI_MAX {
+            return Err(VmError::MathError(format!("primepi supports x up to {:e}", PRIME_PI_MAX)));
+        }
+        if x < 2.0 {
+            return Ok(0.0);
+        }
+        let n = x as u64;
+        let root = n.isqrt() as usize;
+        // small[v] = S(v) for v ≤ √n, large[i] = S(n / i) for i ≤ √n
+        let mut small: Vec<u64> = (0..=root as u64).map(|v| v.saturating_sub(1)).collect();
+        let mut large: Vec<u64> = (0..=root as u64).map(|i| (n / i.max(1)).saturating_sub(1)).collect();
+        for p in 2..=root {
+            if small[p] == small[p - 1] {
+                continue; // Not prime
+            }
+            let below = small[p - 1];
+            let square = p * p;
+            for i in 1..=root {
+                let v = (n / i as u64) as usize;
+                if v < square {
+                    break;
                 }
-                OpCode::Sinh => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.sinh())?;
+                let multiples = if i * p <= root { large[i * p] } else { small[v / p] };
+                large[i] -= multiples - below;
+            }
+            for v in (square..=root).rev() {
+                small[v] -= small[v / p] - below;
+            }
+        }
+        Ok(large[1] as f64)
+    }
+
+    /// Stirling number of the second kind S(n, k), by the recurrence
+    /// S(n, k) = k S(n-1, k) + S(n-1, k-1), exact while it fits in a u128
+    fn stirling2(n: f64, k: f64) -> Result<f64, VmError> {
+        if n < 0.0 || k < 0.0 || n.fract() != 0.0 || k.fract() != 0.0 {
+            return Err(VmError::MathError("Invalid stirling2 arguments".into()));
+        }
+        if k > n {
+            return Ok(0.0);
+        }
+        if k == n || k == 1.0 {
+            return Ok(1.0);
+        }
+        if k == 0.0 {
+            return Ok(0.0);
+        }
+        if n > STIRLING_MAX as f64 {
+            return Err(VmError::MathError(format!("stirling2 supports n up to {}", STIRLING_MAX)));
+        }
+        let (n, k) = (n as usize, k as usize);
+        // One row of the triangle at a time, S(i, 0..=k)
+        let mut exact: Vec<Option<u128>> = vec![Some(0); k + 1];
+        let mut approximate = vec![0.0f64; k + 1];
+        exact[0] = Some(1);
+        approximate[0] = 1.0;
+        for i in 1..=n {
+            for j in (1..=k.min(i)).rev() {
+                exact[j] = match (exact[j], exact[j - 1]) {
+                    (Some(same), Some(fewer)) => (j as u128).checked_mul(same).and_then(|s| s.checked_add(fewer)),
+                    _ => None,
+                };
+                approximate[j] = match exact[j] {
+                    Some(value) => value as f64,
+                    None => j as f64 * approximate[j] + approximate[j - 1],
+                };
+            }
+            exact[0] = Some(0);
+            approximate[0] = 0.0;
+        }
+        if approximate[k].is_finite() {
+            Ok(approximate[k])
+        } else {
+            Err(VmError::MathError("stirling2 overflow".into()))
+        }
+    }
+
+    /// Fold `value = value * numerator / denominator` from 1, in u128 while it
+    /// stays exact and in f64 after that; `None` if the result is infinite
+    fn checked_product(factors: impl Iterator<Item = (u64, u64)>) -> Option<f64> {
+        let mut exact = Some(1u128);
+        let mut approximate = 1.0f64;
+        for (numerator, denominator) in factors {
+            exact = exact.and_then(|value| value.checked_mul(numerator as u128)).map(|value| value / denominator as u128);
+            approximate = match exact {
+                Some(value) => value as f64,
+                None => approximate * numerator as f64 / denominator as f64,
+            };
+            if !approximate.is_finite() {
+                return None;
+            }
+        }
+        Some(approximate)
+    }
+
+    /// Round `x` to `digits` decimals, or to tens, hundreds, ... if negative
+    fn round_to(x: f64, digits: f64) -> Result<f64, VmError> {
+        if digits.fract() != 0.0 {
+            return Err(VmError::MathError("round digits must be an integer".into()));
+        }
+        // An f64 has at most 17 significant and 308 integral digits
+        let digits = digits.clamp(-308.0, 308.0) as i32;
+        let scale = 10f64.powi(digits.abs());
+        let rounded = if digits >= 0 { (x * scale).round() / scale } else { (x / scale).round() * scale };
+        // Too many decimals to matter, or scaled past the f64 range
+        Ok(if rounded.is_finite() { rounded } else { x })
+    }
+
+    /// Execute a chunk of bytecode
+    pub fn execute(&mut self, chunk: &Chunk) -> Result<f64, VmError> {
+        self.run_to_completion(chunk, None)?;
+        self.finish()
+    }
+
+    /// Execute a chunk of bytecode, aborting with `VmError::Timeout` once
+    /// `timeout` of wall-clock time has elapsed
+    #[cfg(feature = "std")]
+    pub fn execute_with_timeout(&mut self, chunk: &Chunk, timeout: Duration) -> Result<f64, VmError> {
+        self.run_to_completion(chunk, Some(Deadline::after(timeout)))?;
+        self.finish()
+    }
+
+    /// Execute a chunk of bytecode whose result may be an array, as
+    /// `linspace(0, 1, 5)` is, rather than failing unless it is a number
+    pub fn execute_value(&mut self, chunk: &Chunk) -> Result<Value, VmError> {
+        self.run_to_completion(chunk, None)?;
+        Ok(self.finish_value())
+    }
+
+    /// `execute_value` with the timeout of `execute_with_timeout`
+    #[cfg(feature = "std")]
+    pub fn execute_value_with_timeout(&mut self, chunk: &Chunk, timeout: Duration) -> Result<Value, VmError> {
+        self.run_to_completion(chunk, Some(Deadline::after(timeout)))?;
+        Ok(self.finish_value())
+    }
+
+    /// Run a chunk from the start, polling for cancellation and the optional deadline
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "execute", level = "debug", skip_all, err, fields(bytes = chunk.len()))
+    )]
+    fn run_to_completion(
+        &mut self,
+        chunk: &Chunk,
+        deadline: Option<Deadline>,
+    ) -> Result<(), VmError> {
+        self.reset();
+        self.check_interrupts(deadline)?;
+        self.deadline = deadline;
+        self.scope = Scope::of(chunk);
+
+        if self.tracing_enabled {
+            while self.execute_instruction(chunk)? {
+                if self.step_count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+                    self.check_interrupts(deadline)?;
+                }
+            }
+        } else {
+            self.dispatch(chunk, deadline)?;
+        }
+
+        debug_event!(instructions = self.executed, "executed");
+        Ok(())
+    }
+
+    /// Hot dispatch loop over the chunk's cached instructions, used when tracing is off
+    fn dispatch(&mut self, chunk: &Chunk, deadline: Option<Deadline>) -> Result<(), VmError> {
+        let instructions = chunk.instructions();
+
+        while let Some(instruction) = instructions.get(self.pc) {
+            self.count_instruction()?;
+            if self.checked_mode {
+                self.check_instruction(instruction)?;
+            }
+            self.run_hook(HookPhase::Before, instruction);
+            self.pc += 1;
+            self.ip = instruction.next_offset();
+            HANDLERS[instruction.opcode as usize](self, instruction.operand)?;
+            self.run_hook(HookPhase::After, instruction);
+            self.step_count += 1;
+            if self.integer_mode {
+                self.check_integer_top()?;
+            }
+
+            if self.halted {
+                return Ok(());
+            }
+            if self.step_count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+                self.check_interrupts(deadline)?;
+            }
+        }
+
+        if self.ip < chunk.len() {
+            return Err(Self::decode_error(chunk, self.ip));
+        }
+        Ok(())
+    }
+
+    /// Fail if cancellation was requested or the deadline has passed
+    fn check_interrupts(&self, deadline: Option<Deadline>) -> Result<(), VmError> {
+        if self.cancellation.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Err(VmError::Cancelled);
+        }
+        deadline.map_or(Ok(()), Deadline::check)
+    }
+
+    /// In integer mode, fail unless `value` is an integer of at most 53 bits
+    fn check_integer(value: f64) -> Result<f64, VmError> {
+        if value.is_nan() || (value.is_finite() && value.fract() != 0.0) {
+            Err(VmError::NotAnInteger(value))
+        } else if value.abs() >= INTEGER_LIMIT {
+            Err(VmError::IntegerOverflow)
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Check the value the last instruction left on top of the stack
+    fn check_integer_top(&self) -> Result<(), VmError> {
+        match self.stack.last() {
+            Some(value) => Self::check_integer_value(value),
+            None => Ok(()),
+        }
+    }
+
+    fn check_integer_value(value: &StackValue) -> Result<(), VmError> {
+        value.as_slice().iter().try_for_each(|&v| Self::check_integer(v).map(|_| ()))
+    }
+
+    /// `binary_scalar`, refusing divisions with a remainder in integer mode
+    #[inline(always)]
+    fn binary_scalar_in_mode(integer_mode: bool, op: OpCode, a: f64, b: f64) -> Result<f64, VmError> {
+        if integer_mode && op == OpCode::Div && b != 0.0 && a % b != 0.0 {
+            return Err(VmError::MathError(format!("{} is not divisible by {}", a, b)));
+        }
+        Self::binary_scalar(op, a, b)
+    }
+
+    /// Count one more instruction against the instruction limit
+    fn count_instruction(&mut self) -> Result<(), VmError> {
+        self.executed += 1;
+        match self.instruction_limit {
+            Some(limit) if self.executed > limit => Err(VmError::InstructionLimit(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Execute the instruction at the current IP
+    ///
+    /// Returns `Ok(false)` once the program has halted or run off the end of the chunk.
+    fn execute_instruction(&mut self, chunk: &Chunk) -> Result<bool, VmError> {
+        if self.halted || self.ip >= chunk.len() {
+            return Ok(false);
+        }
+
+        let instruction = match chunk.instructions().get(self.pc) {
+            Some(instruction) => *instruction,
+            None => return Err(Self::decode_error(chunk, self.ip)),
+        };
+        self.count_instruction()?;
+        let stack_before = if self.tracing_enabled {
+            self.current_stack()
+        } else {
+            Vec::new()
+        };
+
+        if self.checked_mode {
+            self.check_instruction(&instruction)?;
+        }
+        self.run_hook(HookPhase::Before, &instruction);
+        self.pc += 1;
+        self.ip = instruction.next_offset();
+        HANDLERS[instruction.opcode as usize](self, instruction.operand)?;
+        self.run_hook(HookPhase::After, &instruction);
+        if self.integer_mode {
+            self.check_integer_top()?;
+        }
+
+        if self.tracing_enabled {
+            let step = ExecutionStep::new(&instruction, stack_before, self.current_stack());
+            self.trace.push(step);
+        }
+
+        self.step_count += 1;
+        Ok(true)
+    }
+
+    /// In checked mode, make sure the current frame fits `instruction`
+    fn check_instruction(&self, instruction: &Instruction) -> Result<(), VmError> {
+        let fail = |message: String| {
+            Err(VmError::CheckFailed(format!(
+                "{} at 0x{:04X} {}",
+                instruction.opcode, instruction.offset, message
+            )))
+        };
+        let (base, argc) = self.frame_bases.last().copied().unwrap_or((0, 0));
+        let available = self.stack.len().saturating_sub(base + argc);
+        match (instruction.opcode, instruction.operand) {
+            (OpCode::Halt, _) if available != 1 => {
+                return fail(format!("leaves {} values, expected 1", available));
+            }
+            (OpCode::LoadLocal, Operand::Index(slot)) if slot as usize >= argc + self.locals.len() - self.local_base => {
+                return fail(format!(
+                    "reads slot {} of a frame with {} arguments and {} let-bindings",
+                    slot,
+                    argc,
+                    self.locals.len() - self.local_base
+                ));
+            }
+            _ => {}
+        }
+        let needed = self.operands_needed(instruction);
+        if available < needed {
+            return fail(format!("needs {} operands, the frame has {}", needed, available));
+        }
+        Ok(())
+    }
+
+    /// Number of stack values `instruction` consumes
+    fn operands_needed(&self, instruction: &Instruction) -> usize {
+        let op = instruction.opcode;
+        match instruction.operand {
+            // The callee sits below its arguments
+            Operand::Count(argc) if op == OpCode::CallValue => return argc as usize + 1,
+            Operand::Count(count) => return count as usize,
+            Operand::Closure { captures, .. } => return captures as usize,
+            Operand::Call { argc, .. } => return argc as usize,
+            _ => {}
+        }
+        if let Some(slot) = op.extension_slot() {
+            return self.extensions[slot].as_ref().map_or(0, |extension| extension.arity);
+        }
+        match op {
+            OpCode::Push | OpCode::LoadGlobal | OpCode::LoadLocal | OpCode::LoadUpvalue | OpCode::DefineFunction => 0,
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Pow | OpCode::Mod
+            | OpCode::Gcd | OpCode::Lcm | OpCode::Npr | OpCode::Ncr | OpCode::RoundTo
+            | OpCode::Stirling2 => 2,
+            // One for the remaining unary, reducing and stack opcodes
+            _ => Self::nary_arity(op),
+        }
+    }
+
+    /// Describe why the bytes at `offset` could not be decoded
+    fn decode_error(chunk: &Chunk, offset: usize) -> VmError {
+        let byte = chunk.code()[offset];
+        match OpCode::from_byte(byte) {
+            Some(opcode) => VmError::InvalidOperation(format!(
+                "Truncated {} instruction at 0x{:04X}",
+                opcode, offset
+            )),
+            None => VmError::InvalidOpcode(byte),
+        }
+    }
+
+    /// Execute a chunk as native code, falling back to the interpreter
+    ///
+    /// The chunk is compiled on first use and the native code is cached on
+    /// it. Chunks with opcodes the JIT doesn't support and runs that hit a
+    /// runtime error use `execute` instead, as do VMs that have to see each
+    /// instruction: with a hook, tracing, an instruction limit, or integer or
+    /// checked mode.
+    #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err))]
+    pub fn execute_jit(&mut self, chunk: &Chunk) -> Result<f64, VmError> {
+        let interpreted = self.hook.is_some()
+            || self.tracing_enabled
+            || self.instruction_limit.is_some()
+            || self.integer_mode
+            || self.checked_mode;
+        if interpreted {
+            return self.execute(chunk);
+        }
+        if let Ok(function) = chunk.jitted() {
+            self.reset();
+            self.check_interrupts(None)?;
+            if let Some(result) = function.call() {
+                debug_event!("ran native code");
+                self.halted = true;
+                return Ok(result);
+            }
+        }
+        self.execute(chunk)
+    }
+
+    /// Execute a register-machine program
+    ///
+    /// Uses a register file sized by the chunk instead of the operand stack.
+    /// `step_count` afterwards holds the number of executed instructions.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, err))]
+    pub fn execute_registers(&mut self, chunk: &RegisterChunk) -> Result<f64, VmError> {
+        self.reset();
+        self.check_interrupts(None)?;
+        let mut registers = vec![StackValue::Scalar(0.0); chunk.register_count()];
+
+        for instr in chunk.instructions() {
+            self.count_instruction()?;
+            self.step_count += 1;
+            if self.step_count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+                self.check_interrupts(None)?;
+            }
+            match *instr {
+                RegInstr::Move { dst, src } => {
+                    registers[dst] = match src {
+                        Source::Const(value) => StackValue::Scalar(value),
+                        Source::Reg(reg) => Self::register(&registers, reg)?.clone(),
+                    };
                 }
-                OpCode::Cosh => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.cosh())?;
+                RegInstr::Unary { op, dst, src } => {
+                    let result = match op {
+                        OpCode::Sum | OpCode::Avg | OpCode::Min | OpCode::Max | OpCode::Len | OpCode::Multinomial => {
+                            match src {
+                                Source::Const(value) => Self::reduce_array(op, &[value])?,
+                                Source::Reg(reg) => Self::reduce_array(op, Self::numbers(Self::register(&registers, reg)?)?)?,
+                            }
+                        }
+                        _ => Self::unary_scalar(op, Self::register_scalar(&registers, src)?)?,
+                    };
+                    registers[dst] = StackValue::Scalar(result);
                 }
-                OpCode::Tanh => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.tanh())?;
+                RegInstr::Binary { op, dst, lhs, rhs } => {
+                    let a = Self::register_scalar(&registers, lhs)?;
+                    let b = Self::register_scalar(&registers, rhs)?;
+                    registers[dst] = StackValue::Scalar(Self::binary_scalar_in_mode(self.integer_mode, op, a, b)?);
                 }
-                OpCode::Sqrt => {
-                    let a = self.pop_scalar()?;
-                    if a < 0.0 {
-                        return Err(VmError::MathError("sqrt of negative number".into()));
+                RegInstr::MakeArray { dst, start, count } => {
+                    let elements = registers
+                        .get(start..start + count)
+                        .ok_or_else(|| VmError::InvalidOperation(format!("Register r{} out of range", start + count)))?
+                        .iter()
+                        .map(Self::numbers)
+                        .collect::<Result<Vec<_>, _>>()?
+                        .concat();
+                    registers[dst] = self.alloc_array(&elements, &registers, OpCode::PushArray.name())?;
+                }
+                RegInstr::Nary { op: op @ (OpCode::Map | OpCode::Filter | OpCode::CallValue), dst, start, count } => {
+                    // The function values run on the stack machine, as with a call
+                    for reg in start..start + count {
+                        let arg = Self::register(&registers, reg)?.clone();
+                        self.push(arg)?;
                     }
-                    self.push_scalar(a.sqrt())?;
+                    // CALL_VALUE's first register is the callee, the rest its arguments
+                    let operand = match op {
+                        OpCode::CallValue => Operand::Count(count.saturating_sub(1) as u64),
+                        _ => Operand::None,
+                    };
+                    self.parked = core::mem::take(&mut registers);
+                    let result = HANDLERS[op as usize](self, operand);
+                    registers = core::mem::take(&mut self.parked);
+                    result?;
+                    registers[dst] = self.pop()?;
                 }
-                OpCode::Cbrt => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.cbrt())?;
+                RegInstr::Nary { op, dst, start, count } => {
+                    let args = registers
+                        .get(start..start + count)
+                        .ok_or_else(|| VmError::InvalidOperation(format!("Register r{} out of range", start + count)))?;
+                    Self::nary_arguments(op, args)?;
+                    let result = Self::nary(op, args, &mut self.rng)?;
+                    registers[dst] = self.heap_value(result, &registers, op.name())?;
                 }
-                OpCode::Log => {
-                    let a = self.pop_scalar()?;
-                    if a <= 0.0 {
-                        return Err(VmError::MathError("log of non-positive number".into()));
-                    }
-                    self.push_scalar(a.log10())?;
+                RegInstr::LoadGlobal { dst, ref name } => {
+                    registers[dst] = self.load_global(name, &registers)?;
                 }
-                OpCode::Log2 => {
-                    let a = self.pop_scalar()?;
-                    if a <= 0.0 {
-                        return Err(VmError::MathError("log2 of non-positive number".into()));
+                RegInstr::StoreGlobal { ref name, src } => {
+                    let value = match src {
+                        Source::Const(value) => StackValue::Scalar(value),
+                        Source::Reg(reg) => Self::register(&registers, reg)?.clone(),
+                    };
+                    self.globals.insert(name.clone(), value);
+                }
+                RegInstr::Define { function } => {
+                    let function = chunk.functions().get(function).cloned().ok_or_else(|| {
+                        VmError::InvalidOperation(format!("Function #{} out of range", function))
+                    })?;
+                    self.functions.insert(Symbol::intern(&function.name), function);
+                }
+                RegInstr::Closure { dst, function, start, count } => {
+                    let function = chunk.functions().get(function).cloned().ok_or_else(|| {
+                        VmError::InvalidOperation(format!("Function #{} out of range", function))
+                    })?;
+                    let upvalues = registers
+                        .get(start..start + count)
+                        .ok_or_else(|| VmError::InvalidOperation(format!("Register r{} out of range", start + count)))?
+                        .to_vec();
+                    registers[dst] = self.alloc_closure(Closure { function, upvalues }, &registers, OpCode::Closure.name())?;
+                }
+                RegInstr::Call { dst, ref name, start, count } => {
+                    for reg in start..start + count {
+                        let arg = Self::register(&registers, reg)?.clone();
+                        self.push(arg)?;
                     }
-                    self.push_scalar(a.log2())?;
+                    // Keep the registers rooted while the callee runs
+                    self.parked = core::mem::take(&mut registers);
+                    let result = self.call_named(name, count);
+                    registers = core::mem::take(&mut self.parked);
+                    result?;
+                    registers[dst] = self.pop()?;
                 }
-                OpCode::Ln => {
-                    let a = self.pop_scalar()?;
-                    if a <= 0.0 {
-                        return Err(VmError::MathError("ln of non-positive number".into()));
+                RegInstr::Return { src } => {
+                    self.halted = true;
+                    if let Source::Reg(reg) = src {
+                        if let Some(value @ StackValue::Closure(_)) = registers.get(reg) {
+                            return Self::result_of(value);
+                        }
                     }
-                    self.push_scalar(a.ln())?;
+                    let value = Self::register_scalar(&registers, src)?;
+                    return if self.integer_mode { Self::check_integer(value) } else { Ok(value) };
+                }
+            }
+            if self.integer_mode {
+                let written = match instr {
+                    RegInstr::Move { dst, .. }
+                    | RegInstr::Unary { dst, .. }
+                    | RegInstr::Binary { dst, .. }
+                    | RegInstr::MakeArray { dst, .. }
+                    | RegInstr::Nary { dst, .. }
+                    | RegInstr::LoadGlobal { dst, .. }
+                    | RegInstr::Closure { dst, .. }
+                    | RegInstr::Call { dst, .. } => Some(*dst),
+                    RegInstr::StoreGlobal { .. } | RegInstr::Define { .. } | RegInstr::Return { .. } => None,
+                };
+                if let Some(dst) = written {
+                    Self::check_integer_value(&registers[dst])?;
+                }
+            }
+        }
+
+        Ok(0.0)
+    }
+
+    /// Execute a program produced by `CodeGenerator::compile_program`
+    pub fn execute_program(&mut self, program: &Program) -> Result<f64, VmError> {
+        match program {
+            Program::Stack(chunk) => self.execute(chunk),
+            Program::Register(chunk) => self.execute_registers(chunk),
+        }
+    }
+
+    /// Bind every function of a linked program, then run its main chunk
+    pub fn execute_linked(&mut self, program: &LinkedProgram) -> Result<f64, VmError> {
+        for function in program.symbols() {
+            self.define_function(function.clone());
+        }
+        self.execute(program.main())
+    }
+
+    fn register(registers: &[StackValue], reg: usize) -> Result<&StackValue, VmError> {
+        registers
+            .get(reg)
+            .ok_or_else(|| VmError::InvalidOperation(format!("Register r{} out of range", reg)))
+    }
+
+    #[inline(always)]
+    fn register_scalar(registers: &[StackValue], src: Source) -> Result<f64, VmError> {
+        match src {
+            Source::Const(value) => Ok(value),
+            Source::Reg(reg) => Self::register(registers, reg)?.as_scalar(),
+        }
+    }
+
+    /// Build the opcode dispatch table
+    const fn handler_table() -> [Handler; 256] {
+        let mut table = [Self::op_unassigned as Handler; 256];
+        table[OpCode::Push as usize] = Self::op_push;
+        table[OpCode::Pop as usize] = Self::op_pop;
+        table[OpCode::Dup as usize] = Self::op_dup;
+        table[OpCode::PushArray as usize] = Self::op_push_array;
+        table[OpCode::Add as usize] = Self::op_binary::<{ OpCode::Add as u8 }>;
+        table[OpCode::Sub as usize] = Self::op_binary::<{ OpCode::Sub as u8 }>;
+        table[OpCode::Mul as usize] = Self::op_binary::<{ OpCode::Mul as u8 }>;
+        table[OpCode::Div as usize] = Self::op_binary::<{ OpCode::Div as u8 }>;
+        table[OpCode::Pow as usize] = Self::op_binary::<{ OpCode::Pow as u8 }>;
+        table[OpCode::Neg as usize] = Self::op_unary::<{ OpCode::Neg as u8 }>;
+        table[OpCode::Mod as usize] = Self::op_binary::<{ OpCode::Mod as u8 }>;
+        table[OpCode::Factorial as usize] = Self::op_unary::<{ OpCode::Factorial as u8 }>;
+        table[OpCode::Derange as usize] = Self::op_unary::<{ OpCode::Derange as u8 }>;
+        table[OpCode::Totient as usize] = Self::op_unary::<{ OpCode::Totient as u8 }>;
+        table[OpCode::PrimePi as usize] = Self::op_unary::<{ OpCode::PrimePi as u8 }>;
+        table[OpCode::Sin as usize] = Self::op_unary::<{ OpCode::Sin as u8 }>;
+        table[OpCode::Cos as usize] = Self::op_unary::<{ OpCode::Cos as u8 }>;
+        table[OpCode::Tan as usize] = Self::op_unary::<{ OpCode::Tan as u8 }>;
+        table[OpCode::Asin as usize] = Self::op_unary::<{ OpCode::Asin as u8 }>;
+        table[OpCode::Acos as usize] = Self::op_unary::<{ OpCode::Acos as u8 }>;
+        table[OpCode::Atan as usize] = Self::op_unary::<{ OpCode::Atan as u8 }>;
+        table[OpCode::Sinh as usize] = Self::op_unary::<{ OpCode::Sinh as u8 }>;
+        table[OpCode::Cosh as usize] = Self::op_unary::<{ OpCode::Cosh as u8 }>;
+        table[OpCode::Tanh as usize] = Self::op_unary::<{ OpCode::Tanh as u8 }>;
+        table[OpCode::Sec as usize] = Self::op_unary::<{ OpCode::Sec as u8 }>;
+        table[OpCode::Csc as usize] = Self::op_unary::<{ OpCode::Csc as u8 }>;
+        table[OpCode::Cot as usize] = Self::op_unary::<{ OpCode::Cot as u8 }>;
+        table[OpCode::Asinh as usize] = Self::op_unary::<{ OpCode::Asinh as u8 }>;
+        table[OpCode::Acosh as usize] = Self::op_unary::<{ OpCode::Acosh as u8 }>;
+        table[OpCode::Atanh as usize] = Self::op_unary::<{ OpCode::Atanh as u8 }>;
+        table[OpCode::Sqrt as usize] = Self::op_unary::<{ OpCode::Sqrt as u8 }>;
+        table[OpCode::Log as usize] = Self::op_unary::<{ OpCode::Log as u8 }>;
+        table[OpCode::Ln as usize] = Self::op_unary::<{ OpCode::Ln as u8 }>;
+        table[OpCode::Abs as usize] = Self::op_unary::<{ OpCode::Abs as u8 }>;
+        table[OpCode::Floor as usize] = Self::op_unary::<{ OpCode::Floor as u8 }>;
+        table[OpCode::Ceil as usize] = Self::op_unary::<{ OpCode::Ceil as u8 }>;
+        table[OpCode::Cbrt as usize] = Self::op_unary::<{ OpCode::Cbrt as u8 }>;
+        table[OpCode::Log2 as usize] = Self::op_unary::<{ OpCode::Log2 as u8 }>;
+        table[OpCode::Exp as usize] = Self::op_unary::<{ OpCode::Exp as u8 }>;
+        table[OpCode::Round as usize] = Self::op_unary::<{ OpCode::Round as u8 }>;
+        table[OpCode::Trunc as usize] = Self::op_unary::<{ OpCode::Trunc as u8 }>;
+        table[OpCode::Frac as usize] = Self::op_unary::<{ OpCode::Frac as u8 }>;
+        table[OpCode::Sign as usize] = Self::op_unary::<{ OpCode::Sign as u8 }>;
+        table[OpCode::ToRad as usize] = Self::op_unary::<{ OpCode::ToRad as u8 }>;
+        table[OpCode::ToDeg as usize] = Self::op_unary::<{ OpCode::ToDeg as u8 }>;
+        table[OpCode::Sum as usize] = Self::op_reduce::<{ OpCode::Sum as u8 }>;
+        table[OpCode::Avg as usize] = Self::op_reduce::<{ OpCode::Avg as u8 }>;
+        table[OpCode::Min as usize] = Self::op_reduce::<{ OpCode::Min as u8 }>;
+        table[OpCode::Max as usize] = Self::op_reduce::<{ OpCode::Max as u8 }>;
+        table[OpCode::Len as usize] = Self::op_reduce::<{ OpCode::Len as u8 }>;
+        table[OpCode::Multinomial as usize] = Self::op_reduce::<{ OpCode::Multinomial as u8 }>;
+        table[OpCode::Linspace as usize] = Self::op_nary::<{ OpCode::Linspace as u8 }>;
+        table[OpCode::Zeros as usize] = Self::op_nary::<{ OpCode::Zeros as u8 }>;
+        table[OpCode::Ones as usize] = Self::op_nary::<{ OpCode::Ones as u8 }>;
+        table[OpCode::Repeat as usize] = Self::op_nary::<{ OpCode::Repeat as u8 }>;
+        table[OpCode::Hist as usize] = Self::op_nary::<{ OpCode::Hist as u8 }>;
+        table[OpCode::HistEdges as usize] = Self::op_nary::<{ OpCode::HistEdges as u8 }>;
+        table[OpCode::Solve as usize] = Self::op_nary::<{ OpCode::Solve as u8 }>;
+        table[OpCode::Eigvals as usize] = Self::op_nary::<{ OpCode::Eigvals as u8 }>;
+        table[OpCode::Linfit as usize] = Self::op_nary::<{ OpCode::Linfit as u8 }>;
+        table[OpCode::FromBase as usize] = Self::op_nary::<{ OpCode::FromBase as u8 }>;
+        table[OpCode::ToBase as usize] = Self::op_nary::<{ OpCode::ToBase as u8 }>;
+        table[OpCode::Cfrac as usize] = Self::op_nary::<{ OpCode::Cfrac as u8 }>;
+        table[OpCode::Convergent as usize] = Self::op_nary::<{ OpCode::Convergent as u8 }>;
+        table[OpCode::Sample as usize] = Self::op_nary::<{ OpCode::Sample as u8 }>;
+        table[OpCode::Shuffle as usize] = Self::op_nary::<{ OpCode::Shuffle as u8 }>;
+        table[OpCode::NormPdf as usize] = Self::op_nary::<{ OpCode::NormPdf as u8 }>;
+        table[OpCode::NormCdf as usize] = Self::op_nary::<{ OpCode::NormCdf as u8 }>;
+        table[OpCode::InvNorm as usize] = Self::op_nary::<{ OpCode::InvNorm as u8 }>;
+        table[OpCode::BinomPdf as usize] = Self::op_nary::<{ OpCode::BinomPdf as u8 }>;
+        table[OpCode::PoissonPdf as usize] = Self::op_nary::<{ OpCode::PoissonPdf as u8 }>;
+        table[OpCode::Gcd as usize] = Self::op_binary::<{ OpCode::Gcd as u8 }>;
+        table[OpCode::Lcm as usize] = Self::op_binary::<{ OpCode::Lcm as u8 }>;
+        table[OpCode::Npr as usize] = Self::op_binary::<{ OpCode::Npr as u8 }>;
+        table[OpCode::Ncr as usize] = Self::op_binary::<{ OpCode::Ncr as u8 }>;
+        table[OpCode::RoundTo as usize] = Self::op_binary::<{ OpCode::RoundTo as u8 }>;
+        table[OpCode::Stirling2 as usize] = Self::op_binary::<{ OpCode::Stirling2 as u8 }>;
+        table[OpCode::PushAdd as usize] = Self::op_push_add;
+        table[OpCode::PushMul as usize] = Self::op_push_mul;
+        table[OpCode::DupMul as usize] = Self::op_dup_mul;
+        table[OpCode::LoadGlobal as usize] = Self::op_load_global;
+        table[OpCode::StoreGlobal as usize] = Self::op_store_global;
+        table[OpCode::LoadLocal as usize] = Self::op_load_local;
+        table[OpCode::StoreLocal as usize] = Self::op_store_local;
+        table[OpCode::DefineFunction as usize] = Self::op_define_function;
+        table[OpCode::Call as usize] = Self::op_call;
+        table[OpCode::TailCall as usize] = Self::op_tail_call;
+        table[OpCode::Closure as usize] = Self::op_closure;
+        table[OpCode::LoadUpvalue as usize] = Self::op_load_upvalue;
+        table[OpCode::CallValue as usize] = Self::op_call_value;
+        table[OpCode::Map as usize] = Self::op_higher_order::<{ OpCode::Map as u8 }>;
+        table[OpCode::Filter as usize] = Self::op_higher_order::<{ OpCode::Filter as u8 }>;
+        table[OpCode::Halt as usize] = Self::op_halt;
+        table[OpCode::Ext0 as usize] = Self::op_extension::<0>;
+        table[OpCode::Ext1 as usize] = Self::op_extension::<1>;
+        table[OpCode::Ext2 as usize] = Self::op_extension::<2>;
+        table[OpCode::Ext3 as usize] = Self::op_extension::<3>;
+        table[OpCode::Ext4 as usize] = Self::op_extension::<4>;
+        table[OpCode::Ext5 as usize] = Self::op_extension::<5>;
+        table[OpCode::Ext6 as usize] = Self::op_extension::<6>;
+        table[OpCode::Ext7 as usize] = Self::op_extension::<7>;
+        table[OpCode::Ext8 as usize] = Self::op_extension::<8>;
+        table[OpCode::Ext9 as usize] = Self::op_extension::<9>;
+        table[OpCode::Ext10 as usize] = Self::op_extension::<10>;
+        table[OpCode::Ext11 as usize] = Self::op_extension::<11>;
+        table[OpCode::Ext12 as usize] = Self::op_extension::<12>;
+        table[OpCode::Ext13 as usize] = Self::op_extension::<13>;
+        table[OpCode::Ext14 as usize] = Self::op_extension::<14>;
+        table[OpCode::Ext15 as usize] = Self::op_extension::<15>;
+        table
+    }
+
+    /// Scalar semantics of a one-operand opcode
+    ///
+    /// Shared by the stack handlers and the register machine so both
+    /// backends compute identical results.
+    #[inline(always)]
+    fn unary_scalar(op: OpCode, a: f64) -> Result<f64, VmError> {
+        match op {
+            OpCode::Neg => Ok(-a),
+            OpCode::Factorial => Self::factorial(a),
+            OpCode::Derange => Self::derange(a),
+            OpCode::Totient => Self::totient(a),
+            OpCode::PrimePi => Self::prime_pi(a),
+            // Convert degrees to radians
+            OpCode::Sin => Ok((a * core::f64::consts::PI / 180.0).sin()),
+            OpCode::Cos => Ok((a * core::f64::consts::PI / 180.0).cos()),
+            OpCode::Tan => {
+                let rad = a * core::f64::consts::PI / 180.0;
+                let result = rad.tan();
+                if !result.is_finite() {
+                    return Err(VmError::MathError("tan undefined at this angle".into()));
                 }
-                OpCode::Exp => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.exp())?;
+                Ok(result)
+            }
+            OpCode::Asin => {
+                if !(-1.0..=1.0).contains(&a) {
+                    return Err(VmError::MathError("asin domain error".into()));
+                }
+                // Return degrees
+                Ok(a.asin() * 180.0 / core::f64::consts::PI)
+            }
+            OpCode::Acos => {
+                if !(-1.0..=1.0).contains(&a) {
+                    return Err(VmError::MathError("acos domain error".into()));
+                }
+                Ok(a.acos() * 180.0 / core::f64::consts::PI)
+            }
+            OpCode::Atan => Ok(a.atan() * 180.0 / core::f64::consts::PI),
+            // Poles are checked in degrees, where they are exact
+            OpCode::Sec => {
+                if (a - 90.0) % 180.0 == 0.0 {
+                    return Err(VmError::MathError("sec undefined at this angle".into()));
                 }
-                OpCode::Abs => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.abs())?;
+                Ok(1.0 / (a * core::f64::consts::PI / 180.0).cos())
+            }
+            OpCode::Csc => {
+                if a % 180.0 == 0.0 {
+                    return Err(VmError::MathError("csc undefined at this angle".into()));
                 }
-                OpCode::Floor => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.floor())?;
+                Ok(1.0 / (a * core::f64::consts::PI / 180.0).sin())
+            }
+            OpCode::Cot => {
+                if a % 180.0 == 0.0 {
+                    return Err(VmError::MathError("cot undefined at this angle".into()));
                 }
-                OpCode::Ceil => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.ceil())?;
+                let rad = a * core::f64::consts::PI / 180.0;
+                Ok(rad.cos() / rad.sin())
+            }
+            OpCode::Sinh => Ok(a.sinh()),
+            OpCode::Cosh => Ok(a.cosh()),
+            OpCode::Tanh => Ok(a.tanh()),
+            OpCode::Asinh => Ok(a.asinh()),
+            OpCode::Acosh => {
+                if a < 1.0 {
+                    return Err(VmError::MathError("acosh domain error".into()));
                 }
-                OpCode::Round => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.round())?;
+                Ok(a.acosh())
+            }
+            OpCode::Atanh => {
+                if a <= -1.0 || a >= 1.0 {
+                    return Err(VmError::MathError("atanh domain error".into()));
                 }
-                OpCode::Sign => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a.signum())?;
+                Ok(a.atanh())
+            }
+            OpCode::Sqrt => {
+                if a < 0.0 {
+                    return Err(VmError::MathError("sqrt of negative number".into()));
                 }
-                OpCode::ToRad => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a * std::f64::consts::PI / 180.0)?;
+                Ok(a.sqrt())
+            }
+            OpCode::Cbrt => Ok(a.cbrt()),
+            OpCode::Log => {
+                if a <= 0.0 {
+                    return Err(VmError::MathError("log of non-positive number".into()));
                 }
-                OpCode::ToDeg => {
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(a * 180.0 / std::f64::consts::PI)?;
+                Ok(a.log10())
+            }
+            OpCode::Log2 => {
+                if a <= 0.0 {
+                    return Err(VmError::MathError("log2 of non-positive number".into()));
                 }
-                // Array operations
-                OpCode::Sum => {
-                    let arr = self.pop()?.as_array();
-                    self.push_scalar(arr.iter().sum())?;
+                Ok(a.log2())
+            }
+            OpCode::Ln => {
+                if a <= 0.0 {
+                    return Err(VmError::MathError("ln of non-positive number".into()));
                 }
-                OpCode::Avg => {
-                    let arr = self.pop()?.as_array();
-                    if arr.is_empty() {
-                        return Err(VmError::MathError("Average of empty array".into()));
-                    }
-                    self.push_scalar(arr.iter().sum::<f64>() / arr.len() as f64)?;
+                Ok(a.ln())
+            }
+            OpCode::Exp => Ok(a.exp()),
+            OpCode::Abs => Ok(a.abs()),
+            OpCode::Floor => Ok(a.floor()),
+            OpCode::Ceil => Ok(a.ceil()),
+            OpCode::Round => Ok(a.round()),
+            OpCode::Trunc => Ok(a.trunc()),
+            OpCode::Frac => Ok(a.fract()),
+            OpCode::Sign => Ok(a.signum()),
+            OpCode::ToRad => Ok(a * core::f64::consts::PI / 180.0),
+            OpCode::ToDeg => Ok(a * 180.0 / core::f64::consts::PI),
+            _ => Err(VmError::InvalidOperation(format!("{} is not a unary operation", op))),
+        }
+    }
+
+    /// Scalar semantics of a two-operand opcode (`a` is the second operand, `b` the top)
+    #[inline(always)]
+    fn binary_scalar(op: OpCode, a: f64, b: f64) -> Result<f64, VmError> {
+        match op {
+            OpCode::Add => Ok(a + b),
+            OpCode::Sub => Ok(a - b),
+            OpCode::Mul => Ok(a * b),
+            OpCode::Div => {
+                if b == 0.0 {
+                    return Err(VmError::DivisionByZero);
                 }
-                OpCode::Min => {
-                    let arr = self.pop()?.as_array();
-                    if arr.is_empty() {
-                        return Err(VmError::MathError("Min of empty array".into()));
-                    }
-                    self.push_scalar(arr.iter().cloned().fold(f64::INFINITY, f64::min))?;
+                Ok(a / b)
+            }
+            OpCode::Pow => Ok(a.powf(b)),
+            OpCode::Mod => {
+                if b == 0.0 {
+                    return Err(VmError::DivisionByZero);
                 }
-                OpCode::Max => {
-                    let arr = self.pop()?.as_array();
-                    if arr.is_empty() {
-                        return Err(VmError::MathError("Max of empty array".into()));
-                    }
-                    self.push_scalar(arr.iter().cloned().fold(f64::NEG_INFINITY, f64::max))?;
-                }
-                OpCode::Len => {
-                    let arr = self.pop()?.as_array();
-                    self.push_scalar(arr.len() as f64)?;
-                }
-                // Binary functions
-                OpCode::Gcd => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(Self::gcd(a, b)?)?;
-                }
-                OpCode::Lcm => {
-                    let b = self.pop_scalar()?;
-                    let a = self.pop_scalar()?;
-                    self.push_scalar(Self::lcm(a, b)?)?;
-                }
-                OpCode::Npr => {
-                    let r = self.pop_scalar()?;
-                    let n = self.pop_scalar()?;
-                    self.push_scalar(Self::npr(n, r)?)?;
-                }
-                OpCode::Ncr => {
-                    let r = self.pop_scalar()?;
-                    let n = self.pop_scalar()?;
-                    self.push_scalar(Self::ncr(n, r)?)?;
-                }
-                OpCode::Halt => {
-                    if self.tracing_enabled {
-                        self.trace.push(ExecutionStep {
-                            ip: instruction_ip,
-                            opcode,
-                            operand: None,
-                            stack_before,
-                            stack_after: self.current_stack(),
-                        });
-                    }
-                    break;
+                Ok(a % b)
+            }
+            OpCode::Gcd => Self::gcd(a, b),
+            OpCode::Lcm => Self::lcm(a, b),
+            OpCode::Npr => Self::npr(a, b),
+            OpCode::Ncr => Self::ncr(a, b),
+            OpCode::RoundTo => Self::round_to(a, b),
+            OpCode::Stirling2 => Self::stirling2(a, b),
+            _ => Err(VmError::InvalidOperation(format!("{} is not a binary operation", op))),
+        }
+    }
+
+    /// Semantics of an array aggregate opcode
+    #[inline(always)]
+    fn reduce_array(op: OpCode, arr: &[f64]) -> Result<f64, VmError> {
+        match op {
+            OpCode::Sum => Ok(array_sum(arr)),
+            OpCode::Avg => {
+                if arr.is_empty() {
+                    return Err(VmError::MathError("Average of empty array".into()));
+                }
+                Ok(array_sum(arr) / arr.len() as f64)
+            }
+            OpCode::Min => {
+                if arr.is_empty() {
+                    return Err(VmError::MathError("Min of empty array".into()));
+                }
+                Ok(array_fold(arr, f64::INFINITY, f64::min))
+            }
+            OpCode::Max => {
+                if arr.is_empty() {
+                    return Err(VmError::MathError("Max of empty array".into()));
+                }
+                Ok(array_fold(arr, f64::NEG_INFINITY, f64::max))
+            }
+            OpCode::Len => Ok(arr.len() as f64),
+            OpCode::Multinomial => Self::multinomial(arr),
+            _ => Err(VmError::InvalidOperation(format!("{} is not an array operation", op))),
+        }
+    }
+
+    /// Elements of the array a generator opcode builds from `args`
+    fn generate_array(op: OpCode, args: &[StackValue]) -> Result<Vec<f64>, VmError> {
+        let length = |n: f64| {
+            if n < 0.0 || n.fract() != 0.0 || n > GENERATED_MAX as f64 {
+                return Err(VmError::MathError(format!(
+                    "Array length must be a whole number up to {}",
+                    GENERATED_MAX
+                )));
+            }
+            Ok(n as usize)
+        };
+        let scalar = |index: usize| args.get(index).ok_or(VmError::StackUnderflow)?.as_scalar();
+        match op {
+            OpCode::Linspace => {
+                let (a, b, n) = (scalar(0)?, scalar(1)?, length(scalar(2)?)?);
+                let step = (b - a) / (n.max(2) - 1) as f64;
+                // The last value is exactly b, not a + (n - 1) * step
+                Ok((0..n).map(|i| if i + 1 == n && n > 1 { b } else { a + i as f64 * step }).collect())
+            }
+            OpCode::Zeros => Ok(vec![0.0; length(scalar(0)?)?]),
+            OpCode::Ones => Ok(vec![1.0; length(scalar(0)?)?]),
+            OpCode::Repeat => Ok(vec![scalar(0)?; length(scalar(1)?)?]),
+            OpCode::Hist | OpCode::HistEdges => {
+                let data = args.first().ok_or(VmError::StackUnderflow)?.as_slice();
+                let bins = length(scalar(1)?)?;
+                if bins == 0 {
+                    return Err(VmError::MathError("hist needs at least one bin".into()));
+                }
+                let (low, width) = histogram_range(data, bins)?;
+                if op == OpCode::HistEdges {
+                    return Ok((0..=bins).map(|i| low + i as f64 * width).collect());
+                }
+                let mut counts = vec![0.0; bins];
+                for &x in data {
+                    // The top edge belongs to the last bin
+                    let bin = (((x - low) / width) as usize).min(bins - 1);
+                    counts[bin] += 1.0;
                 }
+                Ok(counts)
+            }
+            OpCode::Solve => {
+                let a = args.first().ok_or(VmError::StackUnderflow)?.as_slice();
+                let b = args.get(1).ok_or(VmError::StackUnderflow)?.as_slice();
+                crate::linalg::solve(a, b)
+            }
+            OpCode::Eigvals => crate::linalg::eigvals(args.first().ok_or(VmError::StackUnderflow)?.as_slice()),
+            OpCode::Cfrac => continued_fraction(scalar(0)?, scalar(1)?).map(|(terms, _)| terms),
+            OpCode::Linfit => {
+                let xs = args.first().ok_or(VmError::StackUnderflow)?.as_slice();
+                let ys = args.get(1).ok_or(VmError::StackUnderflow)?.as_slice();
+                linear_fit(xs, ys).map(Vec::from)
+            }
+            _ => Err(VmError::InvalidOperation(format!("{} is not an array constructor", op))),
+        }
+    }
+
+    /// Result of an n-ary builtin opcode applied to `args`
+    fn nary(op: OpCode, args: &[StackValue], rng: &mut Rng) -> Result<Value, VmError> {
+        let scalar = |index: usize| args.get(index).ok_or(VmError::StackUnderflow)?.as_scalar();
+        let value = match op {
+            OpCode::NormPdf => stats::normal_pdf(scalar(0)?, scalar(1)?, scalar(2)?)?,
+            OpCode::NormCdf => stats::normal_cdf(scalar(0)?, scalar(1)?, scalar(2)?)?,
+            OpCode::InvNorm => stats::inverse_normal(scalar(0)?, scalar(1)?, scalar(2)?)?,
+            OpCode::BinomPdf => stats::binomial_pdf(scalar(0)?, scalar(1)?, scalar(2)?)?,
+            OpCode::PoissonPdf => stats::poisson_pdf(scalar(0)?, scalar(1)?)?,
+            OpCode::Convergent => continued_fraction(scalar(0)?, scalar(1)?)?.1,
+            OpCode::FromBase => match args.first().ok_or(VmError::StackUnderflow)? {
+                StackValue::Text(text) => from_text(text, scalar(1)?)?,
+                digits => from_digits(digits.as_slice(), scalar(1)?)?,
+            },
+            OpCode::ToBase => return to_text(scalar(0)?, scalar(1)?).map(Value::Text),
+            OpCode::Shuffle | OpCode::Sample => return Self::random_array(op, args, rng).map(Value::Array),
+            _ => return Self::generate_array(op, args).map(Value::Array),
+        };
+        Ok(Value::Scalar(value))
+    }
+
+    /// `shuffle(array)`, or the `sample(array, k)` of k elements without replacement
+    fn random_array(op: OpCode, args: &[StackValue], rng: &mut Rng) -> Result<Vec<f64>, VmError> {
+        let mut elements = args.first().ok_or(VmError::StackUnderflow)?.as_slice().to_vec();
+        let count = if op == OpCode::Sample {
+            let k = args.get(1).ok_or(VmError::StackUnderflow)?.as_scalar()?;
+            if k < 0.0 || k.fract() != 0.0 || k > elements.len() as f64 {
+                return Err(VmError::MathError(format!(
+                    "sample size must be a whole number up to the {} elements",
+                    elements.len()
+                )));
+            }
+            k as usize
+        } else {
+            elements.len()
+        };
+        // Fisher-Yates, stopping once the first `count` places are drawn
+        for i in 0..count {
+            let j = i + rng.below(elements.len() - i);
+            elements.swap(i, j);
+        }
+        elements.truncate(count);
+        Ok(elements)
+    }
+
+    /// Number of arguments of an n-ary builtin opcode
+    fn nary_arity(op: OpCode) -> usize {
+        match op {
+            OpCode::Linspace | OpCode::NormPdf | OpCode::NormCdf | OpCode::InvNorm | OpCode::BinomPdf => 3,
+            OpCode::Repeat | OpCode::Hist | OpCode::HistEdges | OpCode::Solve | OpCode::Linfit
+            | OpCode::PoissonPdf | OpCode::FromBase | OpCode::ToBase | OpCode::Cfrac | OpCode::Convergent
+            | OpCode::Sample => 2,
+            _ => 1,
+        }
+    }
+
+    /// Pop one scalar, apply `f`, and push the result
+    #[inline(always)]
+    fn unary_op(&mut self, f: impl FnOnce(f64) -> Result<f64, VmError>) -> Result<(), VmError> {
+        // Fast path: rewrite a scalar on top of the stack in place
+        if let Some(StackValue::Scalar(a)) = self.stack.last_mut() {
+            *a = f(*a)?;
+            return Ok(());
+        }
+        let a = self.pop_scalar()?;
+        self.push_scalar(f(a)?)
+    }
+
+    /// Pop two scalars, apply `f(second, top)`, and push the result
+    #[inline(always)]
+    fn binary_op(&mut self, f: impl FnOnce(f64, f64) -> Result<f64, VmError>) -> Result<(), VmError> {
+        // Fast path: combine two scalars without moving them off the stack
+        if let [.., StackValue::Scalar(a), StackValue::Scalar(b)] = self.stack.as_mut_slice() {
+            *a = f(*a, *b)?;
+            self.stack.pop();
+            return Ok(());
+        }
+        let b = self.pop_scalar()?;
+        let a = self.pop_scalar()?;
+        self.push_scalar(f(a, b)?)
+    }
+
+    fn op_unary<const OP: u8>(&mut self, _: Operand) -> Result<(), VmError> {
+        let op = const { OpCode::from_byte(OP).expect("handler registered for a valid opcode") };
+        self.unary_op(|a| Self::unary_scalar(op, a))
+    }
+
+    fn op_binary<const OP: u8>(&mut self, _: Operand) -> Result<(), VmError> {
+        let op = const { OpCode::from_byte(OP).expect("handler registered for a valid opcode") };
+        let integer_mode = self.integer_mode;
+        self.binary_op(|a, b| Self::binary_scalar_in_mode(integer_mode, op, a, b))
+    }
+
+    fn op_reduce<const OP: u8>(&mut self, _: Operand) -> Result<(), VmError> {
+        let op = const { OpCode::from_byte(OP).expect("handler registered for a valid opcode") };
+        let value = self.pop()?;
+        self.push_scalar(Self::reduce_array(op, Self::numbers(&value)?)?)
+    }
+
+    fn op_nary<const OP: u8>(&mut self, _: Operand) -> Result<(), VmError> {
+        let op = const { OpCode::from_byte(OP).expect("handler registered for a valid opcode") };
+        let base = self.stack.len().checked_sub(Self::nary_arity(op)).ok_or(VmError::StackUnderflow)?;
+        Self::nary_arguments(op, &self.stack[base..])?;
+        // The arguments stay on the stack, rooted, until the result is allocated
+        let result = Self::nary(op, &self.stack[base..], &mut self.rng)?;
+        let result = self.heap_value(result, &[], op.name())?;
+        self.stack.truncate(base);
+        self.push(result)
+    }
+
+    fn op_extension<const SLOT: usize>(&mut self, _: Operand) -> Result<(), VmError> {
+        let Extension { arity, handler } = self.extensions[SLOT].clone().ok_or_else(|| {
+            VmError::InvalidOperation(format!("{} has no registered handler", OpCode::EXTENSIONS[SLOT]))
+        })?;
+        let base = self.stack.len().checked_sub(arity).ok_or(VmError::StackUnderflow)?;
+        let args: Vec<Value> = self.stack[base..].iter().map(StackValue::to_value).collect();
+        let value = handler(&args)?;
+        // The arguments stay rooted until the result is on the heap
+        let value = self.heap_value(value, &[], OpCode::EXTENSIONS[SLOT].name())?;
+        self.stack.truncate(base);
+        self.push(value)
+    }
+
+    fn op_unassigned(&mut self, _: Operand) -> Result<(), VmError> {
+        Err(VmError::InvalidOperation("Opcode has no handler".into()))
+    }
+
+    fn op_push(&mut self, operand: Operand) -> Result<(), VmError> {
+        match operand {
+            Operand::Number(value) => self.push_scalar(value),
+            _ => Err(VmError::InvalidOperation("PUSH without constant".into())),
+        }
+    }
+
+    fn op_pop(&mut self, _: Operand) -> Result<(), VmError> {
+        self.pop().map(|_| ())
+    }
+
+    fn op_dup(&mut self, _: Operand) -> Result<(), VmError> {
+        let value = self.peek(0)?.clone();
+        self.push(value)
+    }
+
+    fn op_push_array(&mut self, operand: Operand) -> Result<(), VmError> {
+        let count = match operand {
+            Operand::Count(count) => count as usize,
+            _ => return Err(VmError::InvalidOperation("PUSH_ARRAY without count".into())),
+        };
+        // Nested arrays are spliced in row by row, so [[1, 2], [3, 4]] is a
+        // flat 2x2 matrix
+        let base = self.stack.len().checked_sub(count).ok_or(VmError::StackUnderflow)?;
+        let elements = self.stack[base..].iter().map(Self::numbers).collect::<Result<Vec<_>, _>>()?.concat();
+        self.stack.truncate(base);
+        let array = self.alloc_array(&elements, &[], OpCode::PushArray.name())?;
+        self.push(array)
+    }
+
+    /// Allocate an array on the GC heap, collecting first if due
+    ///
+    /// `registers` are extra roots for the register machine, and `site` is
+    /// what the allocation is counted under when site tracking is on.
+    #[track_caller]
+    fn alloc_array(&mut self, elements: &[f64], registers: &[StackValue], site: &'static str) -> Result<StackValue, VmError> {
+        self.reserve(GcArray::size_for(elements.len()), registers)?;
+        self.gc.set_allocation_site(site);
+        GcArray::new(&mut self.gc, elements)
+            .map(StackValue::Array)
+            .ok_or(VmError::OutOfMemory)
+    }
+
+    /// Allocate a closure on the GC heap, collecting first if due
+    ///
+    /// The captured values must be rooted elsewhere until this returns.
+    #[track_caller]
+    fn alloc_closure(&mut self, closure: Closure, registers: &[StackValue], site: &'static str) -> Result<StackValue, VmError> {
+        self.reserve(core::mem::size_of::<Closure>(), registers)?;
+        self.gc.set_allocation_site(site);
+        GcValue::with_drop(&mut self.gc, closure)
+            .map(StackValue::Closure)
+            .ok_or(VmError::OutOfMemory)
+    }
+
+    /// Let the collector run, and make sure `size` bytes fit under the memory cap
+    fn reserve(&mut self, size: usize, registers: &[StackValue]) -> Result<(), VmError> {
+        self.gc_safepoint(registers, GC_STEP_BUDGET);
+        if !self.gc.fits(size) {
+            // Over the memory cap: reclaim everything unreachable before giving up
+            let roots = Roots {
+                stack: &self.stack,
+                locals: &self.locals,
+                checkpoints: &self.checkpoints,
+                globals: &self.globals,
+                parked: &self.parked,
+                closures: &self.closures,
+                registers,
+            };
+            self.gc.collect_with(&roots);
+            if !self.gc.fits(size) {
+                return Err(VmError::OutOfMemory);
             }
+        }
+        Ok(())
+    }
+
+    /// Let the collector run, scanning the VM's roots if a new cycle starts
+    fn gc_safepoint(&mut self, registers: &[StackValue], budget: usize) -> GcPhase {
+        if self.gc.phase() == GcPhase::Idle {
+            if !self.gc.should_collect() {
+                return GcPhase::Idle;
+            }
+            let roots = Roots {
+                stack: &self.stack,
+                locals: &self.locals,
+                checkpoints: &self.checkpoints,
+                globals: &self.globals,
+                parked: &self.parked,
+                closures: &self.closures,
+                registers,
+            };
+            self.gc.start_cycle_with(&roots);
+        }
+        self.gc.step(budget)
+    }
+
+    /// Extract the constant of a fused PUSH_* instruction
+    fn fused_constant(operand: Operand) -> Result<f64, VmError> {
+        match operand {
+            Operand::Number(value) => Ok(value),
+            _ => Err(VmError::InvalidOperation("Fused instruction without constant".into())),
+        }
+    }
+
+    fn op_push_add(&mut self, operand: Operand) -> Result<(), VmError> {
+        let c = Self::fused_constant(operand)?;
+        self.unary_op(|a| Ok(a + c))
+    }
+
+    fn op_push_mul(&mut self, operand: Operand) -> Result<(), VmError> {
+        let c = Self::fused_constant(operand)?;
+        self.unary_op(|a| Ok(a * c))
+    }
+
+    fn op_dup_mul(&mut self, _: Operand) -> Result<(), VmError> {
+        self.unary_op(|a| Ok(a * a))
+    }
+
+    /// Extract the table index of a LOAD_*/STORE_*/DEF_FUNC instruction
+    fn index_operand(operand: Operand) -> Result<u16, VmError> {
+        match operand {
+            Operand::Index(index) => Ok(index),
+            _ => Err(VmError::InvalidOperation("Instruction without index".into())),
+        }
+    }
+
+    /// The global `name`, or else the host's variable of that name
+    ///
+    /// `registers` are extra GC roots for the register machine.
+    fn load_global(&mut self, name: &Symbol, registers: &[StackValue]) -> Result<StackValue, VmError> {
+        if let Some(value) = self.globals.get(name) {
+            return Ok(value.clone());
+        }
+        if let Some(function) = self.functions.get(name).cloned() {
+            // A defined function used as a value, e.g. in map(f, xs)
+            return self.alloc_closure(Closure { function, upvalues: Vec::new() }, registers, OpCode::LoadGlobal.name());
+        }
+        let value = self
+            .host
+            .as_ref()
+            .and_then(|host| host.resolve_variable(name.as_str()))
+            .ok_or_else(|| VmError::UndefinedVariable(name.to_string()))?;
+        self.heap_value(value, registers, "host")
+    }
+
+    /// Move a value from the host, or a builtin's result, onto the VM's heap
+    fn heap_value(&mut self, value: Value, registers: &[StackValue], site: &'static str) -> Result<StackValue, VmError> {
+        match value {
+            Value::Scalar(v) => Ok(StackValue::Scalar(v)),
+            Value::Array(elements) => self.alloc_array(&elements, registers, site),
+            Value::Function(_) => Err(VmError::InvalidOperation("Function values can only be made by a lambda".into())),
+            Value::Text(text) => Ok(StackValue::Text(text.into())),
+        }
+    }
+
+    /// Call the user-defined or else the host function `name` with the top
+    /// `argc` stack values, replacing them with the result
+    fn call_named(&mut self, name: &Symbol, argc: usize) -> Result<(), VmError> {
+        if let Some(function) = self.functions.get(name).cloned() {
+            return self.call_function(function, argc);
+        }
+        if let Some(StackValue::Closure(closure)) = self.globals.get(name) {
+            let closure = closure.clone();
+            return self.call_closure(closure, argc);
+        }
+        let host = self.host.clone().ok_or_else(|| VmError::UndefinedFunction(name.to_string()))?;
+        let base = self.stack.len().checked_sub(argc).ok_or(VmError::StackUnderflow)?;
+        let args: Vec<Value> = self.stack[base..].iter().map(StackValue::to_value).collect();
+        let value = host
+            .call_function(name.as_str(), &args)
+            .ok_or_else(|| VmError::UndefinedFunction(name.to_string()))?
+            .map_err(VmError::HostError)?;
+        // The arguments stay rooted until the result is on the heap
+        let value = self.heap_value(value, &[], "host")?;
+        self.stack.truncate(base);
+        self.push(value)
+    }
+
+    fn op_load_global(&mut self, operand: Operand) -> Result<(), VmError> {
+        let name = self.scope.name(Self::index_operand(operand)?)?;
+        let value = self.load_global(&name, &[])?;
+        self.push(value)
+    }
+
+    fn op_store_global(&mut self, operand: Operand) -> Result<(), VmError> {
+        let name = self.scope.name(Self::index_operand(operand)?)?;
+        // The assigned value stays on the stack as the statement's result
+        let value = self.peek(0)?.clone();
+        self.globals.insert(name, value);
+        Ok(())
+    }
+
+    fn op_load_local(&mut self, operand: Operand) -> Result<(), VmError> {
+        let slot = Self::index_operand(operand)? as usize;
+        // Parameters are on the stack, let-bindings after them in `locals`
+        let (base, argc) = self.frame_bases.last().copied().unwrap_or((0, 0));
+        let value = if slot < argc {
+            self.stack.get(base + slot)
+        } else {
+            self.locals.get(self.local_base + slot - argc)
+        };
+        let value = value.cloned().ok_or_else(|| VmError::InvalidOperation(format!("Local slot {} is unset", slot)))?;
+        self.push(value)
+    }
+
+    fn op_store_local(&mut self, operand: Operand) -> Result<(), VmError> {
+        let slot = Self::index_operand(operand)? as usize;
+        let (_, argc) = self.frame_bases.last().copied().unwrap_or((0, 0));
+        let index = slot
+            .checked_sub(argc)
+            .map(|offset| self.local_base + offset)
+            .ok_or_else(|| VmError::InvalidOperation(format!("STORE_LOCAL to parameter slot {}", slot)))?;
+        let value = self.pop()?;
+        if index >= self.locals.len() {
+            self.locals.resize(index + 1, StackValue::Scalar(0.0));
+        }
+        self.locals[index] = value;
+        Ok(())
+    }
+
+    fn op_define_function(&mut self, operand: Operand) -> Result<(), VmError> {
+        let index = Self::index_operand(operand)?;
+        let function = self
+            .scope
+            .functions
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| VmError::InvalidOperation(format!("Function #{} out of range", index)))?;
+        self.functions.insert(Symbol::intern(&function.name), function);
+        Ok(())
+    }
+
+    fn op_call(&mut self, operand: Operand) -> Result<(), VmError> {
+        let (name, argc) = match operand {
+            Operand::Call { name, argc } => (name, argc as usize),
+            _ => return Err(VmError::InvalidOperation("CALL without target".into())),
+        };
+        let name = self.scope.name(name)?;
+        self.call_named(&name, argc)
+    }
+
+    /// Call a user-defined function in place of the one running
+    ///
+    /// Its arguments replace the current ones and it runs in the same frame,
+    /// once the current body has halted, so tail recursion needs no more
+    /// frames however deep it goes. Anything else is called as by CALL.
+    fn op_tail_call(&mut self, operand: Operand) -> Result<(), VmError> {
+        let (name, argc) = match operand {
+            Operand::Call { name, argc } => (name, argc as usize),
+            _ => return Err(VmError::InvalidOperation("TAILCALL without target".into())),
+        };
+        let name = self.scope.name(name)?;
+        let (Some(frame), Some(function)) = (self.frame_bases.last_mut(), self.functions.get(&name)) else {
+            return self.call_named(&name, argc);
+        };
+        Self::check_arity(function, argc)?;
+        let args = self.stack.len().checked_sub(argc).ok_or(VmError::StackUnderflow)?;
+        let base = frame.0;
+        *frame = (base, argc);
+        self.tail_call = Some(function.clone());
+        self.stack.drain(base..args);
+        self.halted = true;
+        Ok(())
+    }
+
+    fn op_closure(&mut self, operand: Operand) -> Result<(), VmError> {
+        let (index, captures) = match operand {
+            Operand::Closure { function, captures } => (function, captures as usize),
+            _ => return Err(VmError::InvalidOperation("CLOSURE without function".into())),
+        };
+        let function = self
+            .scope
+            .functions
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| VmError::InvalidOperation(format!("Function #{} out of range", index)))?;
+        let base = self.stack.len().checked_sub(captures).ok_or(VmError::StackUnderflow)?;
+        let upvalues = self.stack[base..].to_vec();
+        // The captured values stay on the stack, rooted, until the closure holds them
+        let closure = self.alloc_closure(Closure { function, upvalues }, &[], OpCode::Closure.name())?;
+        self.stack.truncate(base);
+        self.push(closure)
+    }
+
+    fn op_load_upvalue(&mut self, operand: Operand) -> Result<(), VmError> {
+        let slot = Self::index_operand(operand)? as usize;
+        let closure = self
+            .closures
+            .last()
+            .ok_or_else(|| VmError::InvalidOperation("LOAD_UPVALUE outside a closure".into()))?;
+        let value = closure
+            .get()
+            .upvalues
+            .get(slot)
+            .cloned()
+            .ok_or_else(|| VmError::InvalidOperation(format!("Upvalue {} out of range", slot)))?;
+        self.push(value)
+    }
+
+    fn op_call_value(&mut self, operand: Operand) -> Result<(), VmError> {
+        let argc = match operand {
+            Operand::Count(argc) => argc as usize,
+            _ => return Err(VmError::InvalidOperation("CALL_VALUE without argument count".into())),
+        };
+        let closure = Self::closure(self.peek(argc)?)?;
+        self.call_closure(closure, argc)?;
+        // The result takes the place of the callee below it
+        let value = self.pop()?;
+        *self.stack.last_mut().ok_or(VmError::StackUnderflow)? = value;
+        Ok(())
+    }
+
+    /// MAP or FILTER: call the function value below the array on each element
+    fn op_higher_order<const OP: u8>(&mut self, _: Operand) -> Result<(), VmError> {
+        let op = const { OpCode::from_byte(OP).expect("handler registered for a valid opcode") };
+        let closure = Self::closure(self.peek(1)?)?;
+        let elements = Self::numbers(self.peek(0)?)?.to_vec();
+        let mut results = Vec::with_capacity(elements.len());
+        for element in elements {
+            self.push_scalar(element)?;
+            self.call_closure(closure.clone(), 1)?;
+            let result = self.pop_scalar()?;
+            if op == OpCode::Map {
+                results.push(result);
+            } else if result != 0.0 {
+                results.push(element);
+            }
+        }
+        // The function and the array stay rooted until the result is allocated
+        let array = self.alloc_array(&results, &[], op.name())?;
+        self.stack.truncate(self.stack.len() - 2);
+        self.push(array)
+    }
+
+    /// The function value `value` holds
+    fn closure(value: &StackValue) -> Result<GcValue<Closure>, VmError> {
+        match value {
+            StackValue::Closure(closure) => Ok(closure.clone()),
+            StackValue::Scalar(_) => Err(VmError::InvalidOperation("Expected function, got scalar".into())),
+            StackValue::Array(_) => Err(VmError::InvalidOperation("Expected function, got array".into())),
+            StackValue::Text(_) => Err(VmError::InvalidOperation("Expected function, got string".into())),
+        }
+    }
+
+    /// The elements of `value`, which must not be a function or string
+    fn numbers(value: &StackValue) -> Result<&[f64], VmError> {
+        match value {
+            StackValue::Closure(_) => Err(VmError::InvalidOperation("Expected number, got function".into())),
+            StackValue::Text(_) => Err(VmError::InvalidOperation("Expected number, got string".into())),
+            _ => Ok(value.as_slice()),
+        }
+    }
+
+    /// Check the arguments of an n-ary builtin are numbers, but for the
+    /// string of digits `frombase` also reads
+    fn nary_arguments(op: OpCode, args: &[StackValue]) -> Result<(), VmError> {
+        args.iter().enumerate().try_for_each(|(i, arg)| match arg {
+            StackValue::Text(_) if op == OpCode::FromBase && i == 0 => Ok(()),
+            _ => Self::numbers(arg).map(drop),
+        })
+    }
+
+    /// Call `closure` with the top `argc` stack values as arguments
+    fn call_closure(&mut self, closure: GcValue<Closure>, argc: usize) -> Result<(), VmError> {
+        let function = closure.get().function.clone();
+        self.closures.push(closure);
+        let result = self.call_function(function, argc);
+        self.closures.pop();
+        result
+    }
+
+    fn check_arity(function: &Function, argc: usize) -> Result<(), VmError> {
+        if argc != function.arity() {
+            return Err(VmError::InvalidOperation(format!(
+                "{} expects {} arguments, got {}",
+                function.name,
+                function.arity(),
+                argc
+            )));
+        }
+        Ok(())
+    }
+
+    /// Call `function` with the top `argc` stack values as arguments
+    ///
+    /// The body runs untraced to completion and counts as a single step,
+    /// so stepping and checkpoints only ever see the caller's instructions.
+    /// The arguments are replaced by the result.
+    fn call_function(&mut self, function: Arc<Function>, argc: usize) -> Result<(), VmError> {
+        Self::check_arity(&function, argc)?;
+        if self.frame_bases.len() >= self.recursion_limit {
+            return Err(VmError::RecursionLimit { depth: self.recursion_limit });
+        }
+        self.check_interrupts(self.deadline)?;
+        let base = self.stack.len().checked_sub(argc).ok_or(VmError::StackUnderflow)?;
+
+        let caller = core::mem::replace(&mut self.scope, Scope::of(&function.chunk));
+        let (ip, pc, step_count, local_base) = (self.ip, self.pc, self.step_count, self.local_base);
+        self.frame_bases.push((base, argc));
+        self.local_base = self.locals.len();
+        self.ip = 0;
+        self.pc = 0;
+
+        let mut function = function;
+        let mut tail_calls = 0;
+        let result = loop {
+            let result = self.dispatch(&function.chunk, self.deadline);
+            match self.tail_call.take() {
+                Some(callee) if result.is_ok() => {
+                    // Nothing can end a tail recursion without a conditional,
+                    // so a tail call still counts as one level deeper
+                    tail_calls += 1;
+                    if self.frame_bases.len() + tail_calls > self.recursion_limit {
+                        break Err(VmError::RecursionLimit { depth: self.recursion_limit });
+                    }
+                    self.scope = Scope::of(&callee.chunk);
+                    self.locals.truncate(self.local_base);
+                    self.ip = 0;
+                    self.pc = 0;
+                    self.halted = false;
+                    function = callee;
+                }
+                _ => break result,
+            }
+        };
+
+        self.frame_bases.pop();
+        self.locals.truncate(self.local_base);
+        self.local_base = local_base;
+        self.scope = caller;
+        self.ip = ip;
+        self.pc = pc;
+        self.step_count = step_count;
+        self.halted = false;
+        result?;
+
+        let value = self.pop()?;
+        self.stack.truncate(base);
+        self.push(value)
+    }
+
+    fn op_halt(&mut self, _: Operand) -> Result<(), VmError> {
+        self.halted = true;
+        Ok(())
+    }
+
+    /// Run post-execution housekeeping and return the top of the stack
+    fn finish(&mut self) -> Result<f64, VmError> {
+        // Advance any pending collection by a bounded amount
+        self.gc_safepoint(&[], GC_STEP_BUDGET);
+
+        // Return top of stack as result
+        match self.stack.last() {
+            Some(value) => Self::result_of(value),
+            None => Ok(0.0),
+        }
+    }
+
+    /// `finish`, returning an array result as it is
+    fn finish_value(&mut self) -> Value {
+        self.gc_safepoint(&[], GC_STEP_BUDGET);
+        match self.stack.last() {
+            // Like a definition, `f = x -> 2 * x` evaluates to 0
+            Some(StackValue::Closure(_)) | None => Value::Scalar(0.0),
+            Some(value) => value.to_value(),
+        }
+    }
+
+    /// The number a program leaving `value` evaluates to
+    fn result_of(value: &StackValue) -> Result<f64, VmError> {
+        match value {
+            // Like a definition, `f = x -> 2 * x` evaluates to 0
+            StackValue::Closure(_) => Ok(0.0),
+            _ => value.as_scalar(),
+        }
+    }
+
+    /// Load a chunk for step-by-step execution
+    ///
+    /// The VM keeps its own copy of the chunk so it can be driven one
+    /// instruction at a time with `step()` and rewound with `step_back()`.
+    pub fn load(&mut self, chunk: &Chunk) {
+        self.reset();
+        self.program = Some(chunk.clone());
+        self.checkpoints.push(self.checkpoint());
+    }
+
+    /// Execute a single instruction of the loaded program
+    ///
+    /// Returns `Ok(false)` once the program has halted. If the instruction
+    /// fails, the VM is left in the state just before it.
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        let chunk = self.take_program()?;
+        let result = self.execute_instruction(&chunk);
+        self.program = Some(chunk);
+
+        match result {
+            Ok(executed) => {
+                if executed {
+                    self.record_checkpoint();
+                }
+                Ok(executed)
+            }
+            Err(e) => {
+                self.seek(self.step_count)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Iterate over the loaded program's remaining steps, executing each
+    /// only when it is pulled
+    pub fn steps(&mut self) -> Steps<'_> {
+        Steps { vm: self, error: None }
+    }
+
+    /// Load `chunk` and step through it, checking each step against a
+    /// recorded `trace`, e.g. one from `trace()` saved by an earlier version,
+    /// and return the first step that differs
+    ///
+    /// Execution starts from the VM's current variables, functions and
+    /// random seed, which should match those the trace was recorded with.
+    /// The trace may end where the recorded run failed; the replay must
+    /// then fail at the same instruction.
+    pub fn replay(&mut self, chunk: &Chunk, trace: &[ExecutionStep]) -> Option<Divergence> {
+        self.load(chunk);
+        let mut steps = self.steps();
+        for (index, expected) in trace.iter().enumerate() {
+            match steps.next() {
+                Some(actual) if actual.same_as(expected) => {}
+                actual => {
+                    return Some(Divergence {
+                        step: index,
+                        expected: Some(expected.clone()),
+                        actual,
+                        error: steps.error().cloned(),
+                    })
+                }
+            }
+        }
+        steps.next().map(|actual| Divergence {
+            step: trace.len(),
+            expected: None,
+            actual: Some(actual),
+            error: None,
+        })
+    }
+
+    /// Undo the most recently executed instruction of the loaded program
+    ///
+    /// Returns `Ok(false)` if the VM is already at the first instruction.
+    pub fn step_back(&mut self) -> Result<bool, VmError> {
+        if self.step_count == 0 {
+            return Ok(false);
+        }
+        self.seek(self.step_count - 1)?;
+        Ok(true)
+    }
+
+    /// Move the loaded program to the state after `step` instructions
+    ///
+    /// Restores the nearest checkpoint at or before `step` and replays
+    /// forward from there, so both directions cost at most
+    /// `CHECKPOINT_INTERVAL` instructions plus any steps not yet visited.
+    pub fn seek(&mut self, step: usize) -> Result<(), VmError> {
+        let chunk = self.take_program()?;
+
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|c| c.step <= step)
+            .cloned()
+            .expect("checkpoint for step 0 is taken on load");
+        self.ip = checkpoint.ip;
+        self.pc = checkpoint.pc;
+        self.halted = checkpoint.halted;
+        self.step_count = checkpoint.step;
+        self.stack = checkpoint.stack;
+        self.locals = checkpoint.locals;
+        self.globals = checkpoint.globals;
+        self.functions = checkpoint.functions;
+        self.rng = checkpoint.rng;
+        self.trace.truncate(self.step_count);
+
+        let mut result = Ok(());
+        while self.step_count < step {
+            match self.execute_instruction(&chunk) {
+                Ok(true) => self.record_checkpoint(),
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        self.program = Some(chunk);
+        result
+    }
+
+    /// Run the loaded program until it halts and return the result
+    pub fn run(&mut self) -> Result<f64, VmError> {
+        while self.step()? {}
+        self.finish()
+    }
+
+    /// Run at most `budget` more steps of the loaded program
+    ///
+    /// Returns the result once the program has halted and `None` while it
+    /// is still running, so a single-threaded caller such as a web page can
+    /// hand control back between slices and resume with another call. A
+    /// call of a user-defined function counts as one step.
+    pub fn run_for(&mut self, budget: usize) -> Result<Option<f64>, VmError> {
+        self.check_interrupts(None)?;
+        for _ in 0..budget {
+            if !self.step()? {
+                return self.finish().map(Some);
+            }
+        }
+        if self.halted {
+            self.finish().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Take the loaded program out of the VM while it is being executed
+    fn take_program(&mut self) -> Result<Chunk, VmError> {
+        let chunk = self
+            .program
+            .take()
+            .ok_or_else(|| VmError::InvalidOperation("No program loaded".into()))?;
+        // `execute` may have run another chunk since this one was loaded
+        self.scope = Scope::of(&chunk);
+        Ok(chunk)
+    }
+
+    /// Snapshot the current machine state
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            step: self.step_count,
+            ip: self.ip,
+            pc: self.pc,
+            halted: self.halted,
+            stack: self.stack.clone(),
+            locals: self.locals.clone(),
+            globals: self.globals.clone(),
+            functions: self.functions.clone(),
+            rng: self.rng,
+        }
+    }
+
+    /// Take a checkpoint if one is due and not already recorded
+    fn record_checkpoint(&mut self) {
+        let due = self.step_count.is_multiple_of(CHECKPOINT_INTERVAL);
+        let recorded = self
+            .checkpoints
+            .last()
+            .is_some_and(|c| c.step >= self.step_count);
+        if due && !recorded {
+            self.checkpoints.push(self.checkpoint());
+        }
+    }
+
+    /// Current instruction pointer
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Number of instructions executed since the program was loaded
+    pub fn step_count(&self) -> usize {
+        self.step_count
+    }
+
+    /// Whether the program has executed HALT
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Current operand stack, bottom first
+    pub fn stack(&self) -> &[StackValue] {
+        &self.stack
+    }
+
+    /// The values on the stack, bottom first, borrowed rather than copied
+    pub fn stack_values(&self) -> impl Iterator<Item = ValueRef<'_>> + '_ {
+        self.stack.iter().map(StackValue::view)
+    }
+
+    /// Value of the global variable `name`
+    pub fn global(&self, name: &str) -> Option<Value> {
+        // A name never interned can't be bound
+        self.globals.get(&Symbol::lookup(name)?).map(StackValue::to_value)
+    }
+
+    /// All global variables, sorted by name
+    pub fn globals(&self) -> impl Iterator<Item = (&str, ValueRef<'_>)> + '_ {
+        // The map is ordered by symbol, which follows addresses rather than names
+        let mut globals: Vec<_> = self.globals.iter().map(|(name, value)| (name.as_str(), value.view())).collect();
+        globals.sort_unstable_by_key(|&(name, _)| name);
+        globals.into_iter()
+    }
+
+    /// Bind the global variable `name`, replacing any previous value
+    ///
+    /// A `Value::Function` is only a description and can't be bound back.
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), VmError> {
+        let value = self.heap_value(value, &[], "globals")?;
+        self.globals.insert(Symbol::intern(name), value);
+        Ok(())
+    }
+
+    /// Unbind the global variable `name`, returning its value
+    pub fn remove_global(&mut self, name: &str) -> Option<Value> {
+        self.globals.remove(&Symbol::lookup(name)?).map(|value| value.to_value())
+    }
+
+    /// The user-defined function `name`
+    pub fn function(&self, name: &str) -> Option<&Arc<Function>> {
+        self.functions.get(&Symbol::lookup(name)?)
+    }
+
+    /// All user-defined functions, sorted by name
+    pub fn functions(&self) -> impl Iterator<Item = &Arc<Function>> + '_ {
+        let mut functions: Vec<_> = self.functions.values().collect();
+        functions.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        functions.into_iter()
+    }
+
+    /// Define a function, replacing any previous one of the same name
+    pub fn define_function(&mut self, function: Arc<Function>) {
+        self.functions.insert(Symbol::intern(&function.name), function);
+    }
+
+    /// Remove the user-defined function `name`
+    pub fn remove_function(&mut self, name: &str) -> Option<Arc<Function>> {
+        self.functions.remove(&Symbol::lookup(name)?)
+    }
+
+    /// Remove all global variables and user-defined functions
+    pub fn clear_definitions(&mut self) {
+        self.globals.clear();
+        self.functions.clear();
+    }
+
+    /// `reset` and `clear_definitions`, then free the whole heap at once,
+    /// nothing being left to refer to it
+    pub fn reset_heap(&mut self) {
+        self.reset();
+        self.clear_definitions();
+        self.parked.clear();
+        self.gc.reset();
+    }
+
+    /// Get GC statistics
+    pub fn gc_stats(&self) -> &crate::gc::GcStats {
+        self.gc.stats()
+    }
+
+    /// Record where every heap object is allocated (see `leak_report`)
+    pub fn enable_leak_detection(&mut self) {
+        self.gc.enable_leak_detection();
+    }
+
+    /// Count heap objects and bytes by the opcode or subsystem that
+    /// allocated them, in `memory_stats().sites`
+    pub fn enable_site_tracking(&mut self) {
+        self.gc.enable_site_tracking();
+    }
+
+    /// Keep the last `capacity` freed heap slots from being reused, to catch
+    /// use after free while debugging
+    pub fn enable_quarantine(&mut self, capacity: usize) {
+        self.gc.enable_quarantine(capacity);
+    }
+
+    /// Heap objects still alive, grouped by the code that allocated them
+    pub fn leak_report(&self) -> Option<String> {
+        self.gc.leak_report()
+    }
+
+    /// Run a full garbage collection over the VM's roots
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = Roots {
+            stack: &self.stack,
+            locals: &self.locals,
+            checkpoints: &self.checkpoints,
+            globals: &self.globals,
+            parked: &self.parked,
+            closures: &self.closures,
+            registers: &[],
+        };
+        self.gc.collect_with(&roots)
+    }
+
+    /// Every object on the heap, in address order within each arena block
+    ///
+    /// Objects nothing refers to any more stay listed, as unreachable,
+    /// until a collection frees them.
+    pub fn heap_objects(&self) -> Vec<HeapObject> {
+        let snapshots = self.checkpoints.iter().flat_map(|c| c.stack.iter().chain(&c.locals).chain(c.globals.values()));
+        let running = self.closures.iter().cloned().map(StackValue::Closure);
+        let mut pending: Vec<StackValue> = self
+            .stack
+            .iter()
+            .chain(&self.locals)
+            .chain(&self.parked)
+            .chain(self.globals.values())
+            .chain(snapshots)
+            .cloned()
+            .chain(running)
+            .collect();
+        // Follow the values closures capture
+        let mut reachable = BTreeSet::new();
+        while let Some(value) = pending.pop() {
+            match value {
+                StackValue::Array(array) => {
+                    reachable.insert(array.as_ptr().as_ptr() as usize);
+                }
+                StackValue::Closure(closure) => {
+                    if reachable.insert(closure.as_ptr().as_ptr() as usize) {
+                        pending.extend(closure.get().upvalues.iter().cloned());
+                    }
+                }
+                StackValue::Scalar(_) | StackValue::Text(_) => {}
+            }
+        }
+
+        self.gc
+            .objects()
+            .map(|object| {
+                let address = object.ptr.as_ptr() as usize;
+                let kind = if object.finalizer {
+                    // SAFETY: only closures are given finalizers, and an object
+                    // not yet swept is still intact
+                    let closure = unsafe { object.ptr.cast::<Closure>().as_ref() };
+                    HeapKind::Closure { captures: closure.upvalues.len() }
+                } else {
+                    // Everything else is an array, sized by `GcArray::size_for`
+                    HeapKind::Array { len: (object.size - GcArray::size_for(0)) / core::mem::size_of::<f64>() }
+                };
+                HeapObject {
+                    address,
+                    kind,
+                    bytes: object.footprint,
+                    reachable: reachable.contains(&address),
+                }
+            })
+            .collect()
+    }
+
+    /// Advance garbage collection by at most `budget` units of work
+    pub fn gc_step(&mut self, budget: usize) -> GcPhase {
+        self.gc_safepoint(&[], budget)
+    }
+
+    /// Get memory statistics
+    pub fn memory_stats(&self) -> &crate::memory::MemoryStats {
+        self.gc.memory_stats()
+    }
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Up to `terms` continued fraction terms of `x`, and the fraction they make
+///
+/// The expansion stops early once that fraction is `x` itself, rather than
+/// spelling out the rounding error of `x` in further terms.
+fn continued_fraction(x: f64, terms: f64) -> Result<(Vec<f64>, f64), VmError> {
+    if !x.is_finite() {
+        return Err(VmError::MathError("Continued fraction of a non-finite value".into()));
+    }
+    if terms < 1.0 || terms.fract() != 0.0 {
+        return Err(VmError::MathError("Continued fraction needs a whole number of terms".into()));
+    }
+    let mut expansion = Vec::new();
+    // Convergents p/q, starting from 1/0 and 0/1
+    let (mut p, mut p_previous, mut q, mut q_previous) = (1.0, 0.0, 0.0, 1.0);
+    let mut rest = x;
+    while (expansion.len() as f64) < terms {
+        let term = rest.floor();
+        expansion.push(term);
+        (p, p_previous) = (term * p + p_previous, p);
+        (q, q_previous) = (term * q + q_previous, q);
+        if p / q == x || rest == term {
+            break;
+        }
+        rest = 1.0 / (rest - term);
+    }
+    Ok((expansion, p / q))
+}
+
+/// The number whose digits in `base`, most significant first, are `digits`
+/// The number the string `text` writes in `base`, with an optional leading minus
+fn from_text(text: &str, base: f64) -> Result<f64, VmError> {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, text),
+    };
+    if digits.is_empty() {
+        return Err(VmError::MathError("frombase needs at least one digit".into()));
+    }
+    let digits = digits
+        .chars()
+        .map(|ch| ch.to_digit(36).map(f64::from).ok_or_else(|| VmError::MathError(format!("'{}' is not a digit", ch))))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(sign * from_digits(&digits, base)?)
+}
+
+/// `x` written in `base` as a string
+fn to_text(x: f64, base: f64) -> Result<String, VmError> {
+    if !(2.0..=36.0).contains(&base) || base.fract() != 0.0 {
+        return Err(VmError::MathError(format!("Base must be a whole number from 2 to 36, got {}", base)));
+    }
+    crate::format::to_base(x, base as u32)
+        .ok_or_else(|| VmError::MathError(format!("tobase needs an integer below 2^53, got {}", x)))
+}
+
+fn from_digits(digits: &[f64], base: f64) -> Result<f64, VmError> {
+    if !(2.0..=36.0).contains(&base) || base.fract() != 0.0 {
+        return Err(VmError::MathError(format!("Base must be a whole number from 2 to 36, got {}", base)));
+    }
+    digits.iter().try_fold(0.0, |value, &digit| {
+        if digit < 0.0 || digit >= base || digit.fract() != 0.0 {
+            return Err(VmError::MathError(format!("{} is not a digit in base {}", digit, base)));
+        }
+        Ok(value * base + digit)
+    })
+}
+
+/// Least-squares line through the points (xs[i], ys[i]): slope, intercept
+/// and the coefficient of determination r²
+fn linear_fit(xs: &[f64], ys: &[f64]) -> Result<[f64; 3], VmError> {
+    if xs.len() != ys.len() {
+        return Err(VmError::MathError(format!("linfit of {} x and {} y values", xs.len(), ys.len())));
+    }
+    if xs.len() < 2 {
+        return Err(VmError::MathError("linfit needs at least two points".into()));
+    }
+    let n = xs.len() as f64;
+    let (mean_x, mean_y) = (array_sum(xs) / n, array_sum(ys) / n);
+    let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(ys) {
+        let (dx, dy) = (x - mean_x, y - mean_y);
+        sxx += dx * dx;
+        sxy += dx * dy;
+        syy += dy * dy;
+    }
+    if sxx == 0.0 {
+        return Err(VmError::MathError("linfit needs at least two distinct x values".into()));
+    }
+    let slope = sxy / sxx;
+    // Constant y lies exactly on the horizontal line
+    let r2 = if syy == 0.0 { 1.0 } else { sxy * sxy / (sxx * syy) };
+    Ok([slope, mean_y - slope * mean_x, r2])
+}
+
+/// Sum of an array, split across threads when it is large enough
+///
+/// The parallel sum adds in a different order, so the last bits of the
+/// result can differ from the sequential one.
+fn array_sum(arr: &[f64]) -> f64 {
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    if arr.len() >= PARALLEL_THRESHOLD {
+        use rayon::prelude::*;
+        return arr.par_iter().sum();
+    }
+    arr.iter().sum()
+}
+
+/// Fold an array with an associative `f`, split across threads when it is large enough
+fn array_fold(arr: &[f64], identity: f64, f: fn(f64, f64) -> f64) -> f64 {
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    if arr.len() >= PARALLEL_THRESHOLD {
+        use rayon::prelude::*;
+        return arr.par_iter().copied().reduce(|| identity, f);
+    }
+    arr.iter().copied().fold(identity, f)
+}
+
+/// Lowest edge and bin width of a histogram of `data` with `bins` bins
+///
+/// The bins span the data; if it is all one value, a unit-wide range around it.
+fn histogram_range(data: &[f64], bins: usize) -> Result<(f64, f64), VmError> {
+    if data.is_empty() {
+        return Err(VmError::MathError("hist of empty array".into()));
+    }
+    if data.iter().any(|x| !x.is_finite()) {
+        return Err(VmError::MathError("hist of non-finite values".into()));
+    }
+    let low = array_fold(data, f64::INFINITY, f64::min);
+    let high = array_fold(data, f64::NEG_INFINITY, f64::max);
+    let (low, high) = if low == high { (low - 0.5, high + 0.5) } else { (low, high) };
+    Ok((low, (high - low) / bins as f64))
+}
+
+/// Gamma function approximation using Lanczos approximation
+fn gamma(x: f64) -> f64 {
+    // Lanczos approximation constants
+    let g = 7;
+    let coefficients = [
+        0.999_999_999_999_809_9,
+        676.5203681218851,
+        -1259.1392167224028,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507343278686905,
+        -0.13857109526572012,
+        9.984_369_578_019_572e-6,
+        1.5056327351493116e-7,
+    ];
+
+    if x < 0.5 {
+        // Reflection formula
+        core::f64::consts::PI / ((core::f64::consts::PI * x).sin() * gamma(1.0 - x))
+    } else {
+        let x = x - 1.0;
+        let mut a = coefficients[0];
+        for (i, c) in coefficients.iter().enumerate().skip(1) {
+            a += c / (x + i as f64);
+        }
+        let t = x + g as f64 + 0.5;
+        (2.0 * core::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{Backend, CodeGenerator};
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+
+    fn compile(input: &str) -> Chunk {
+        let mut tokenizer = Tokenizer::new(input);
+        let tokens = tokenizer.tokenize().expect("Tokenization failed");
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().expect("Parsing failed");
+        CodeGenerator::new().compile(&ast).unwrap().0
+    }
+
+    fn evaluate(input: &str) -> Result<f64, VmError> {
+        let chunk = compile(input);
+        let mut vm = VirtualMachine::new();
+        // Any bytecode the compiler emits has to pass the checks
+        vm.enable_checked_mode();
+        vm.execute(&chunk)
+    }
+
+    fn scalars(vm: &VirtualMachine) -> Vec<f64> {
+        vm.stack().iter().map(|v| v.as_scalar().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_simple_addition() {
+        let result = evaluate("1 + 2").unwrap();
+        assert!((result - 3.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_complex_expression() {
+        let result = evaluate("sin(90) + 2^3").unwrap();
+        assert!((result - 9.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_nested_expression() {
+        let result = evaluate("(1 + 2) * (3 + 4)").unwrap();
+        assert!((result - 21.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_power() {
+        let result = evaluate("2^3^2").unwrap();
+        // 2^(3^2) = 2^9 = 512 (right associative)
+        assert!((result - 512.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let result = evaluate("1 / 0");
+        assert!(matches!(result, Err(VmError::DivisionByZero)));
+    }
+
+    #[test]
+    fn test_factorial() {
+        let result = evaluate("5!").unwrap();
+        assert!((result - 120.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_modulo() {
+        let result = evaluate("10 % 3").unwrap();
+        assert!((result - 1.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_gcd() {
+        let result = evaluate("gcd(12, 8)").unwrap();
+        assert!((result - 4.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_lcm() {
+        let result = evaluate("lcm(4, 6)").unwrap();
+        assert!((result - 12.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_ncr() {
+        let result = evaluate("nCr(5, 2)").unwrap();
+        assert!((result - 10.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_npr() {
+        let result = evaluate("nPr(5, 2)").unwrap();
+        assert!((result - 20.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_large_ncr_npr() {
+        // Exact beyond 170!, and past 2^53 while the result fits
+        assert_eq!(evaluate("nCr(200, 3)").unwrap(), 1_313_400.0);
+        assert_eq!(evaluate("nCr(60, 30)").unwrap(), 118_264_581_564_861_424.0);
+        assert_eq!(evaluate("nPr(1000, 2)").unwrap(), 999_000.0);
+
+        let result = evaluate("nCr(1000, 500)").unwrap();
+        assert!((result / 2.702_882_409_454_365e299 - 1.0).abs() < 1e-12, "{}", result);
+        assert_eq!(evaluate("nCr(1000, 999)").unwrap(), 1000.0);
+        assert!(evaluate("nPr(1000, 500)").unwrap_err().to_string().contains("overflow"));
+        assert!((evaluate("nCr(4.5, 2)").unwrap() - 7.875).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_totient_primepi() {
+        assert_eq!(evaluate("totient(1)").unwrap(), 1.0);
+        assert_eq!(evaluate("totient(36)").unwrap(), 12.0);
+        assert_eq!(evaluate("totient(97)").unwrap(), 96.0);
+        assert_eq!(evaluate("totient(2^31 - 1)").unwrap(), 2_147_483_646.0);
+        assert_eq!(evaluate("totient(1000000000039 * 2)").unwrap(), 1_000_000_000_038.0);
+        assert!(evaluate("totient(0)").is_err());
+        assert!(evaluate("totient(2.5)").is_err());
+
+        assert_eq!(evaluate("primepi(1)").unwrap(), 0.0);
+        assert_eq!(evaluate("primepi(10.5)").unwrap(), 4.0);
+        assert_eq!(evaluate("primepi(100)").unwrap(), 25.0);
+        assert_eq!(evaluate("primepi(10^6)").unwrap(), 78_498.0);
+        assert_eq!(evaluate("primepi(10^9)").unwrap(), 50_847_534.0);
+        assert!(evaluate("primepi(10^12)").is_err());
+    }
+
+    #[test]
+    fn test_sample_shuffle() {
+        let draw = |seed: u64| {
+            let mut vm = VirtualMachine::new();
+            vm.set_seed(seed);
+            vm.execute(&compile("s = shuffle([1, 2, 3, 4, 5, 6]); t = sample([10, 20, 30, 40], 3); 0")).unwrap();
+            match (vm.global("s"), vm.global("t")) {
+                (Some(Value::Array(s)), Some(Value::Array(t))) => (s, t),
+                other => panic!("{:?}", other),
+            }
+        };
+        let (s, t) = draw(7);
+        assert_eq!(draw(7), (s.clone(), t.clone()));
+
+        let mut sorted = s.clone();
+        sorted.sort_by(f64::total_cmp);
+        assert_eq!(sorted, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(t.len(), 3);
+        assert!(t.iter().all(|x| [10.0, 20.0, 30.0, 40.0].contains(x)));
+        assert!(t.iter().enumerate().all(|(i, x)| !t[..i].contains(x)));
+        assert!((0..20).any(|seed| draw(seed).0 != s));
+
+        assert_eq!(evaluate("sum(sample([1, 2, 3], 3))").unwrap(), 6.0);
+        assert_eq!(evaluate("len(sample([1, 2, 3], 0))").unwrap(), 0.0);
+        assert!(evaluate("sample([1, 2, 3], 4)").is_err());
+        assert!(evaluate("sample([1, 2, 3], 1.5)").is_err());
+        assert_eq!(evaluate_registers("sum(shuffle([1, 2, 3]))").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn test_continued_fractions() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("terms = cfrac(pi, 5); short = cfrac(0.75, 10); negative = cfrac(-1.5, 3); 0")).unwrap();
+        assert_eq!(vm.global("terms"), Some(Value::Array(vec![3.0, 7.0, 15.0, 1.0, 292.0])));
+        assert_eq!(vm.global("short"), Some(Value::Array(vec![0.0, 1.0, 3.0])));
+        assert_eq!(vm.global("negative"), Some(Value::Array(vec![-2.0, 2.0])));
+
+        assert_eq!(evaluate("convergent(pi, 2)").unwrap(), 22.0 / 7.0);
+        assert_eq!(evaluate("convergent(pi, 4)").unwrap(), 355.0 / 113.0);
+        assert_eq!(evaluate_registers("convergent(0.1, 50)").unwrap(), 0.1);
+        assert!(evaluate("cfrac(2, 0)").is_err());
+    }
+
+    #[test]
+    fn test_multinomial_derange_stirling() {
+        assert_eq!(evaluate("multinomial([2, 3, 5])").unwrap(), 2520.0);
+        assert_eq!(evaluate("multinomial([20, 20, 20])").unwrap(), 577_831_214_478_475_823_831_865_900.0);
+        assert_eq!(evaluate("multinomial([])").unwrap(), 1.0);
+        assert!(evaluate("multinomial([1, 1.5])").is_err());
+
+        assert_eq!(evaluate("derange(0)").unwrap(), 1.0);
+        assert_eq!(evaluate("derange(1)").unwrap(), 0.0);
+        assert_eq!(evaluate("derange(10)").unwrap(), 1_334_961.0);
+        assert_eq!(evaluate("derange(25)").unwrap(), 5_706_255_282_633_466_762_357_224.0);
+        assert!(evaluate("derange(171)").unwrap_err().to_string().contains("overflow"));
+        assert!(evaluate("derange(2.5)").is_err());
+
+        assert_eq!(evaluate("stirling2(10, 3)").unwrap(), 9330.0);
+        assert_eq!(evaluate("stirling2(4, 0) + stirling2(0, 0) + stirling2(3, 5)").unwrap(), 1.0);
+        let result = evaluate("stirling2(50, 10)").unwrap();
+        assert!((result / 2.615_471_651_586_288e43 - 1.0).abs() < 1e-12, "{}", result);
+        assert!(evaluate("stirling2(5000, 2)").is_err());
+    }
+
+    #[test]
+    fn test_array_sum() {
+        let result = evaluate("sum([1, 2, 3, 4])").unwrap();
+        assert!((result - 10.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_array_avg() {
+        let result = evaluate("avg([1, 2, 3, 4])").unwrap();
+        assert!((result - 2.5).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_array_min_max() {
+        let min = evaluate("min([3, 1, 4, 1, 5])").unwrap();
+        let max = evaluate("max([3, 1, 4, 1, 5])").unwrap();
+        assert!((min - 1.0).abs() < 1e-10);
+        assert!((max - 5.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_hyperbolic() {
+        let sinh_result = evaluate("sinh(0)").unwrap();
+        let cosh_result = evaluate("cosh(0)").unwrap();
+        assert!((sinh_result - 0.0).abs() < 1e-10);
+        assert!((cosh_result - 1.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_array_constructors() {
+        let mut vm = VirtualMachine::new();
+        let array = |vm: &mut VirtualMachine, input: &str| vm.execute_value(&compile(input)).unwrap();
+        assert_eq!(array(&mut vm, "linspace(0, 1, 5)"), Value::Array(vec![0.0, 0.25, 0.5, 0.75, 1.0]));
+        assert_eq!(array(&mut vm, "linspace(0.1, 0.3, 3)"), Value::Array(vec![0.1, 0.2, 0.3]));
+        assert_eq!(array(&mut vm, "linspace(2, 5, 1)"), Value::Array(vec![2.0]));
+        assert_eq!(array(&mut vm, "zeros(2)"), Value::Array(vec![0.0, 0.0]));
+        assert_eq!(array(&mut vm, "ones(0)"), Value::Array(vec![]));
+        assert_eq!(array(&mut vm, "repeat(-1.5, 3)"), Value::Array(vec![-1.5; 3]));
+        assert_eq!(vm.execute(&compile("sum(linspace(1, 100, 100)) + len(zeros(4))")).unwrap(), 5054.0);
+        // Only `execute_value` has an array to return
+        assert!(vm.execute(&compile("zeros(2)")).is_err());
+        assert_eq!(array(&mut vm, "len(zeros(2))"), Value::Scalar(2.0));
+        assert_eq!(array(&mut vm, "f = x -> x"), Value::Scalar(0.0));
+
+        assert!(vm.execute(&compile("zeros(-1)")).is_err());
+        assert!(vm.execute(&compile("ones(2.5)")).is_err());
+        assert!(vm.execute(&compile("repeat(1, 1e9)")).is_err());
+        assert!(Parser::new(Tokenizer::new("linspace(0, 1)").tokenize().unwrap()).parse().is_err());
+
+        let ast = Parser::new(Tokenizer::new("sum(repeat(2, 3)) + avg(linspace(0, 4, 3))").tokenize().unwrap()).parse().unwrap();
+        assert_eq!(vm.execute_registers(&CodeGenerator::new().compile_registers(&ast).unwrap()).unwrap(), 8.0);
+    }
+
+    #[test]
+    fn test_hist() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("data = [1, 2, 2, 3, 3, 3, 4, 5]; 0")).unwrap();
+        vm.execute(&compile("counts = hist(data, 4); edges = histedges(data, 4); 0")).unwrap();
+        assert_eq!(vm.global("counts"), Some(Value::Array(vec![1.0, 2.0, 3.0, 2.0])));
+        assert_eq!(vm.global("edges"), Some(Value::Array(vec![1.0, 2.0, 3.0, 4.0, 5.0])));
+        assert_eq!(vm.execute(&compile("sum(hist(linspace(0, 1, 101), 7))")).unwrap(), 101.0);
+
+        vm.execute(&compile("same = hist([2, 2], 2); edges = histedges([2, 2], 2); 0")).unwrap();
+        assert_eq!(vm.global("same"), Some(Value::Array(vec![0.0, 2.0])));
+        assert_eq!(vm.global("edges"), Some(Value::Array(vec![1.5, 2.0, 2.5])));
+        assert!(vm.execute(&compile("hist([], 3)")).is_err());
+        assert!(vm.execute(&compile("hist(data, 0)")).is_err());
+    }
+
+    #[test]
+    fn test_solve() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("m = [[1, 2], [3, 4]]; x = solve([[2, 1], [1, 3]], [3, 5]); 0")).unwrap();
+        assert_eq!(vm.global("m"), Some(Value::Array(vec![1.0, 2.0, 3.0, 4.0])));
+        let Some(Value::Array(x)) = vm.global("x") else { panic!("solve returned a scalar") };
+        assert!((x[0] - 0.8).abs() < 1e-12 && (x[1] - 1.4).abs() < 1e-12, "{:?}", x);
+        assert_eq!(evaluate_registers("sum(solve([[0, 1], [1, 0]], [4, 7]))").unwrap(), 11.0);
+
+        assert!(matches!(vm.execute(&compile("solve([[1, 2], [2, 4]], [1, 2])")), Err(VmError::SingularMatrix)));
+        assert!(vm.execute(&compile("solve([1, 2, 3], [1, 2])")).is_err());
+    }
+
+    #[test]
+    fn test_linfit() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("fit = linfit([1, 2, 3, 4], [3, 5, 7, 9]); 0")).unwrap();
+        assert_eq!(vm.global("fit"), Some(Value::Array(vec![2.0, 1.0, 1.0])));
+        vm.execute(&compile("fit = linfit([0, 1, 2], [1, 0, 2]); flat = linfit([1, 2], [4, 4]); 0")).unwrap();
+        assert_eq!(vm.global("fit"), Some(Value::Array(vec![0.5, 0.5, 0.25])));
+        assert_eq!(vm.global("flat"), Some(Value::Array(vec![0.0, 4.0, 1.0])));
+        assert_eq!(evaluate_registers("sum(linfit([1, 2, 3], [2, 4, 6]))").unwrap(), 3.0);
+
+        assert!(vm.execute(&compile("linfit([1, 2], [1, 2, 3])")).is_err());
+        assert!(vm.execute(&compile("linfit([1], [1])")).is_err());
+        assert!(vm.execute(&compile("linfit([2, 2], [1, 3])")).is_err());
+    }
+
+    #[test]
+    fn test_frombase() {
+        assert_eq!(evaluate("frombase(\"ff\", 16)").unwrap(), 255.0);
+        assert_eq!(evaluate("frombase(\"-101\", 2) + 1").unwrap(), -4.0);
+        assert_eq!(evaluate("frombase(\"Zz\", 36)").unwrap(), 1295.0);
+        assert_eq!(evaluate_registers("frombase([1, 0, 1], 2)").unwrap(), 5.0);
+        assert!(evaluate("frombase(\"12\", 2)").unwrap_err().to_string().contains("2 is not a digit in base 2"));
+        assert!(evaluate("frombase(\"ff\", 40)").is_err());
+        // String literals are read while parsing, and only as frombase's digits
+        assert!(crate::evaluate("frombase(\"f.f\", 16)").is_err());
+        assert!(crate::evaluate("\"ff\" + 1").is_err());
+    }
+
+    #[test]
+    fn test_tobase() {
+        let mut vm = VirtualMachine::new();
+        let mut text = |input: &str| vm.execute_value(&compile(input));
+        assert_eq!(text("tobase(255, 16)").unwrap(), Value::Text("ff".into()));
+        assert_eq!(text("tobase(-5, 2)").unwrap(), Value::Text("-101".into()));
+        assert_eq!(text("tobase(1295, 36)").unwrap(), Value::Text("zz".into()));
+        assert!(text("tobase(2.5, 16)").is_err());
+        assert!(text("tobase(255, 37)").is_err());
+
+        // The string is a value like any other, which frombase reads back
+        vm.execute(&compile("hex = tobase(48879, 16); 0")).unwrap();
+        assert_eq!(vm.global("hex"), Some(Value::Text("beef".into())));
+        assert_eq!(evaluate("frombase(tobase(-255, 2), 2)").unwrap(), -255.0);
+        assert_eq!(evaluate("frombase(tobase(123456, 7), 7)").unwrap(), 123456.0);
+        assert!(evaluate("tobase(255, 16) + 1").unwrap_err().to_string().contains("got string"));
+        assert!(evaluate("sum(tobase(255, 16))").is_err());
+        assert!(evaluate("frombase(tobase(255, 16), 2)").is_err());
+    }
+
+    #[test]
+    fn test_distributions() {
+        assert!((evaluate("normcdf(1.96, 0, 1)").unwrap() - 0.975).abs() < 1e-4);
+        assert!((evaluate("normpdf(0, 0, 1) * sqrt(2 * pi)").unwrap() - 1.0).abs() < 1e-12);
+        assert!((evaluate("normcdf(invnorm(0.3, 10, 2), 10, 2)").unwrap() - 0.3).abs() < 1e-12);
+        assert_eq!(evaluate("binompdf(4, 0.5, 2)").unwrap(), 0.375);
+        assert!((evaluate_registers("poissonpdf(2, 0) * e^2").unwrap() - 1.0).abs() < 1e-12);
+        assert!(evaluate("normpdf(0, 0, -1)").is_err());
+        assert!(evaluate("invnorm(0, 0, 1)").is_err());
+    }
+
+    #[test]
+    fn test_eigvals() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("values = eigvals([[2, 1], [1, 2]]); 0")).unwrap();
+        let Some(Value::Array(values)) = vm.global("values") else { panic!("eigvals returned a scalar") };
+        assert!((values[0] - 1.0).abs() < 1e-12 && (values[1] - 3.0).abs() < 1e-12, "{:?}", values);
+        assert!((evaluate_registers("max(eigvals([[4, 1], [2, 3]]))").unwrap() - 5.0).abs() < 1e-12);
+        assert!(vm.execute(&compile("eigvals([[0, -1], [1, 0]])")).is_err());
+    }
+
+    #[test]
+    fn test_round_trunc_frac() {
+        assert_eq!(evaluate("round(1.23456, 2)").unwrap(), 1.23);
+        assert_eq!(evaluate("round(-2.675, 1)").unwrap(), -2.7);
+        assert_eq!(evaluate("round(1234.5, -2)").unwrap(), 1200.0);
+        assert_eq!(evaluate("round(2.5)").unwrap(), 3.0);
+        assert_eq!(evaluate("round(1e300, 20)").unwrap(), 1e300);
+        assert!(evaluate("round(1, 0.5)").is_err());
+        assert_eq!(evaluate("trunc(-2.7)").unwrap(), -2.0);
+        assert!((evaluate("frac(-2.75)").unwrap() + 0.75).abs() < 1e-12);
+
+        let ast = Parser::new(Tokenizer::new("round(x / 3, n + 1)").tokenize().unwrap()).parse().unwrap();
+        assert_eq!(ast.to_string(), "round((x / 3), (n + 1))");
+    }
+
+    #[test]
+    fn test_reciprocal_trig_and_inverse_hyperbolic() {
+        assert!((evaluate("sec(60)").unwrap() - 2.0).abs() < 1e-10);
+        assert!((evaluate("csc(30)").unwrap() - 2.0).abs() < 1e-10);
+        assert!((evaluate("cot(45)").unwrap() - 1.0).abs() < 1e-10);
+        assert!(evaluate("sec(270)").is_err());
+        assert!(evaluate("csc(-180)").is_err());
+        assert!(evaluate("cot(0)").is_err());
+
+        assert!((evaluate("asinh(sinh(1.5))").unwrap() - 1.5).abs() < 1e-10);
+        assert!((evaluate("acosh(cosh(2))").unwrap() - 2.0).abs() < 1e-10);
+        assert!((evaluate("atanh(tanh(0.5))").unwrap() - 0.5).abs() < 1e-10);
+        assert!(evaluate("acosh(0.5)").is_err());
+        assert!(evaluate("atanh(1)").is_err());
+    }
+
+    #[test]
+    fn test_cbrt() {
+        let result = evaluate("cbrt(27)").unwrap();
+        assert!((result - 3.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_exp() {
+        let result = evaluate("exp(0)").unwrap();
+        assert!((result - 1.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_step_and_step_back() {
+        let mut vm = VirtualMachine::new();
+        vm.load(&compile("1 + 2"));
+
+        assert!(vm.step().unwrap()); // PUSH 1
+        assert!(vm.step().unwrap()); // PUSH 2
+        assert_eq!(scalars(&vm), vec![1.0, 2.0]);
+        assert!(vm.step().unwrap()); // ADD
+        assert_eq!(scalars(&vm), vec![3.0]);
+
+        assert!(vm.step_back().unwrap());
+        assert_eq!(scalars(&vm), vec![1.0, 2.0]);
+        assert_eq!(vm.step_count(), 2);
+        assert_eq!(vm.ip(), 18);
 
-            if self.tracing_enabled {
-                self.trace.push(ExecutionStep {
-                    ip: instruction_ip,
-                    opcode,
-                    operand,
-                    stack_before,
-                    stack_after: self.current_stack(),
-                });
-            }
-        }
+        assert!(vm.step_back().unwrap());
+        assert!(vm.step_back().unwrap());
+        assert!(!vm.step_back().unwrap());
+        assert!(vm.stack().is_empty());
+
+        assert!((vm.run().unwrap() - 3.0).abs() < 1e-10);
+        assert!(vm.is_halted());
+        assert!(!vm.step().unwrap());
+    }
 
-        // Check if GC should run
-        if self.gc.should_collect() {
-            self.gc.collect();
+    #[test]
+    fn test_step_back_across_checkpoints() {
+        let input = (1..=40).map(|n| n.to_string()).collect::<Vec<_>>().join(" + ");
+        let mut vm = VirtualMachine::new();
+        vm.load(&compile(&input));
+        vm.enable_tracing();
+
+        let mut states = vec![scalars(&vm)];
+        while vm.step().unwrap() {
+            states.push(scalars(&vm));
         }
+        assert!(states.len() > 2 * CHECKPOINT_INTERVAL);
+        assert_eq!(vm.trace().len(), states.len() - 1);
 
-        // Return top of stack as result
-        if self.stack.is_empty() {
-            Ok(0.0)
-        } else {
-            self.stack.last().unwrap().as_scalar()
+        while vm.step_back().unwrap() {
+            assert_eq!(scalars(&vm), states[vm.step_count()]);
+            assert_eq!(vm.trace().len(), vm.step_count());
         }
+        assert_eq!(vm.step_count(), 0);
     }
 
-    /// Get GC statistics
-    pub fn gc_stats(&self) -> &crate::gc::GcStats {
-        self.gc.stats()
+    #[test]
+    fn test_step_error_keeps_prior_state() {
+        let mut vm = VirtualMachine::new();
+        vm.load(&compile("1 / 0"));
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert!(matches!(vm.step(), Err(VmError::DivisionByZero)));
+        assert_eq!(scalars(&vm), vec![1.0, 0.0]);
+        assert_eq!(vm.step_count(), 2);
     }
 
-    /// Get memory statistics
-    pub fn memory_stats(&self) -> &crate::memory::MemoryStats {
-        self.gc.memory_stats()
+    #[test]
+    fn test_steps_iterator() {
+        let mut vm = VirtualMachine::new();
+        vm.load(&compile("1 + 2 * 3"));
+        let first: Vec<_> = vm.steps().take(2).collect();
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[1].operand, Some(2.0));
+        assert_eq!(first[1].stack_after, vec![1.0, 2.0]);
+        assert_eq!(vm.step_count(), 2);
+
+        let rest: Vec<_> = (&mut vm).into_iter().collect();
+        assert_eq!(rest.last().unwrap().opcode, OpCode::Halt);
+        assert_eq!(rest[rest.len() - 2].stack_after, vec![7.0]);
+        assert!(vm.is_halted());
+        assert_eq!(vm.steps().count(), 0);
+
+        vm.load(&compile("1 / 0"));
+        let mut steps = vm.steps();
+        assert_eq!(steps.by_ref().count(), 2);
+        assert!(matches!(steps.error(), Some(VmError::DivisionByZero)));
+        assert_eq!(steps.next().map(|step| step.ip), None);
     }
-}
 
-impl Default for VirtualMachine {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_run_for() {
+        let mut vm = VirtualMachine::new();
+        vm.load(&compile("x = 2; x * 3 + 1"));
+        let mut slices = 1;
+        let result = loop {
+            match vm.run_for(2).unwrap() {
+                Some(result) => break result,
+                None => slices += 1,
+            }
+        };
+        assert_eq!(result, 7.0);
+        assert_eq!(slices, vm.step_count().div_ceil(2));
+        assert_eq!(vm.run_for(2).unwrap(), Some(7.0));
+
+        let token = CancellationToken::new();
+        vm.set_cancellation_token(token.clone());
+        vm.load(&compile("1 + 2"));
+        assert_eq!(vm.run_for(1).unwrap(), None);
+        token.cancel();
+        assert!(matches!(vm.run_for(1), Err(VmError::Cancelled)));
     }
-}
 
-/// Gamma function approximation using Lanczos approximation
-fn gamma(x: f64) -> f64 {
-    // Lanczos approximation constants
-    let g = 7;
-    let coefficients = [
-        0.99999999999980993,
-        676.5203681218851,
-        -1259.1392167224028,
-        771.32342877765313,
-        -176.61502916214059,
-        12.507343278686905,
-        -0.13857109526572012,
-        9.9843695780195716e-6,
-        1.5056327351493116e-7,
-    ];
+    #[test]
+    fn test_replay() {
+        let chunk = compile("x = 1.5; x * 2 + 3");
+        let mut vm = VirtualMachine::new();
+        vm.enable_tracing();
+        vm.execute(&chunk).unwrap();
+        let trace = vm.trace().to_vec();
+        vm.disable_tracing();
 
-    if x < 0.5 {
-        // Reflection formula
-        std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x))
-    } else {
-        let x = x - 1.0;
-        let mut a = coefficients[0];
-        for i in 1..g + 2 {
-            a += coefficients[i] / (x + i as f64);
+        assert!(vm.replay(&chunk, &trace).is_none());
+
+        let mut changed = trace.clone();
+        changed[3].stack_after = vec![4.0];
+        let divergence = vm.replay(&chunk, &changed).unwrap();
+        assert_eq!(divergence.step, 3);
+        assert_eq!(divergence.actual.as_ref(), Some(&trace[3]));
+
+        let divergence = vm.replay(&chunk, &trace[..2]).unwrap();
+        assert_eq!((divergence.step, divergence.expected.is_none()), (2, true));
+        assert!(divergence.to_string().starts_with("Replay diverged at step 2: the trace ended"));
+
+        // A trace ending where the recorded run failed
+        let failing = compile("1 / 0");
+        vm.enable_tracing();
+        assert!(vm.execute(&failing).is_err());
+        let trace = vm.trace().to_vec();
+        assert!(vm.replay(&failing, &trace).is_none());
+        vm.execute(&chunk).unwrap();
+        let trace = vm.trace().to_vec();
+        vm.set_instruction_limit(3);
+        let divergence = vm.replay(&chunk, &trace).unwrap();
+        assert!(matches!(divergence.error, Some(VmError::InstructionLimit(3))));
+        assert!(divergence.to_string().ends_with("failed with Instruction limit of 3 exceeded"));
+    }
+
+    #[test]
+    fn test_step_without_program() {
+        let mut vm = VirtualMachine::new();
+        assert!(matches!(vm.step(), Err(VmError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn test_cancellation_token() {
+        let chunk = compile("1 + 2");
+        let token = CancellationToken::new();
+        let mut vm = VirtualMachine::new();
+        vm.set_cancellation_token(token.clone());
+
+        assert!((vm.execute(&chunk).unwrap() - 3.0).abs() < 1e-10);
+        token.cancel();
+        assert!(matches!(vm.execute(&chunk), Err(VmError::Cancelled)));
+
+        vm.clear_cancellation_token();
+        assert!(vm.execute(&chunk).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_execute_with_timeout() {
+        let input = vec!["1"; 300].join(" + ");
+        let chunk = compile(&input);
+        let mut vm = VirtualMachine::new();
+
+        let result = vm.execute_with_timeout(&chunk, Duration::from_secs(60)).unwrap();
+        assert!((result - 300.0).abs() < 1e-10);
+
+        let result = vm.execute_with_timeout(&chunk, Duration::ZERO);
+        assert!(matches!(result, Err(VmError::Timeout(_))));
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        let mut chunk = Chunk::new();
+        chunk.write_push(1.0);
+        chunk.write_byte(0xDD);
+        let mut vm = VirtualMachine::new();
+        assert!(matches!(vm.execute(&chunk), Err(VmError::InvalidOpcode(0xDD))));
+    }
+
+    #[test]
+    fn test_extension_opcodes() {
+        let mut chunk = Chunk::new();
+        chunk.write_push(3.0);
+        chunk.write_push(4.0);
+        chunk.write_op(OpCode::Ext0);
+        chunk.write_op(OpCode::Ext15);
+        chunk.write_op(OpCode::Sum);
+        chunk.write_op(OpCode::Halt);
+        assert!(crate::Disassembler::format(&chunk).contains("EXT15"));
+
+        let mut vm = VirtualMachine::new();
+        let error = vm.execute(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid operation: EXT0 has no registered handler");
+
+        vm.register_extension(OpCode::Ext0, 2, |args| match args {
+            [Value::Scalar(a), Value::Scalar(b)] => Ok(Value::Scalar(a.hypot(*b))),
+            _ => Err(VmError::MathError("hypot takes two numbers".into())),
+        })
+        .unwrap();
+        vm.register_extension(OpCode::Ext15, 1, |args| match args {
+            [Value::Scalar(x)] => Ok(Value::Array(vec![*x; 3])),
+            _ => Err(VmError::MathError("expected a number".into())),
+        })
+        .unwrap();
+        assert_eq!(vm.execute(&chunk).unwrap(), 15.0);
+
+        assert!(vm.register_extension(OpCode::Add, 2, |_| Ok(Value::Scalar(0.0))).is_err());
+        vm.unregister_extension(OpCode::Ext15);
+        assert!(vm.execute(&chunk).is_err());
+    }
+
+    #[test]
+    fn test_hook() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let mut vm = VirtualMachine::new();
+        vm.set_hook(move |event| {
+            let top = event.stack.last().map(|value| value.as_slice()[0]);
+            seen.lock().unwrap().push((event.phase, event.opcode, event.depth, top));
+        });
+        vm.execute(&compile("f(x) = -x; f(2)")).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len() % 2, 0);
+        assert!(events.contains(&(HookPhase::After, OpCode::Neg, 1, Some(-2.0))));
+        let calls: Vec<_> = events.iter().filter(|event| event.1 == OpCode::Call).collect();
+        assert_eq!(calls[0], &(HookPhase::Before, OpCode::Call, 0, Some(2.0)));
+        assert_eq!(calls[1], &(HookPhase::After, OpCode::Call, 0, Some(-2.0)));
+        drop(events);
+
+        // Stepping reports instructions the same way
+        let count = Arc::new(Mutex::new(0));
+        let counter = count.clone();
+        vm.set_hook(move |_| *counter.lock().unwrap() += 1);
+        vm.enable_tracing();
+        vm.execute(&compile("1 + 2")).unwrap();
+        assert_eq!(*count.lock().unwrap(), 2 * vm.trace().len());
+        vm.clear_hook();
+    }
+
+    #[test]
+    fn test_checked_mode() {
+        let mut vm = VirtualMachine::new();
+        vm.enable_checked_mode();
+        let inputs = ["f(x, y) = x * y + 1; f(2, 3) - [1, 2, 3] * 0 + ncr(5, 2)", "linspace(0, 1, 3)", "x = 2; x!"];
+        for input in inputs {
+            let chunk = compile(input);
+            let mut unchecked = VirtualMachine::new();
+            assert_eq!(
+                vm.execute(&chunk).map_err(|e| e.to_string()),
+                unchecked.execute(&chunk).map_err(|e| e.to_string()),
+                "{}",
+                input
+            );
         }
-        let t = x + g as f64 + 0.5;
-        (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * a
+
+        // A binary operator short of an operand
+        let mut chunk = Chunk::new();
+        chunk.write_push(1.0);
+        chunk.write_op(OpCode::Add);
+        chunk.write_op(OpCode::Halt);
+        let error = vm.execute(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "Check failed: ADD at 0x0009 needs 2 operands, the frame has 1");
+
+        // A value left behind
+        let mut chunk = Chunk::new();
+        chunk.write_push(1.0);
+        chunk.write_push(2.0);
+        chunk.write_op(OpCode::Halt);
+        assert_eq!(VirtualMachine::new().execute(&chunk).unwrap(), 2.0);
+        let error = vm.execute(&chunk).unwrap_err();
+        assert_eq!(error.to_string(), "Check failed: HALT at 0x0012 leaves 2 values, expected 1");
+
+        // A function body reading past its arguments
+        let mut body = Chunk::new();
+        body.write_op_u16(OpCode::LoadLocal, 1);
+        body.write_op(OpCode::Halt);
+        vm.define_function(Arc::new(Function { name: "g".into(), params: vec!["x".into()], chunk: body, body: "?".into() }));
+        let error = vm.execute(&compile("g(1) + 5")).unwrap_err();
+        assert_eq!(error.to_string(), "Check failed: LOAD_LOCAL at 0x0000 reads slot 1 of a frame with 1 arguments and 0 let-bindings");
+        vm.disable_checked_mode();
+        assert!(vm.execute(&compile("g(1) + 5")).is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::codegen::CodeGenerator;
-    use crate::parser::Parser;
-    use crate::tokenizer::Tokenizer;
+    #[test]
+    fn test_inspection() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("v = [4, 5]; v = [1, 2, 3]; w = 5")).unwrap();
+        let globals: Vec<_> = vm.globals().collect();
+        assert_eq!(globals, [("v", ValueRef::Array(&[1.0, 2.0, 3.0])), ("w", ValueRef::Scalar(5.0))]);
 
-    fn evaluate(input: &str) -> Result<f64, VmError> {
+        vm.load(&compile("[7, 8] + 1"));
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.stack_values().collect::<Vec<_>>(), [ValueRef::Array(&[7.0, 8.0])]);
+
+        let objects = vm.heap_objects();
+        let live: Vec<_> = objects.iter().filter(|object| object.reachable).map(|object| object.kind).collect();
+        assert_eq!(live, [HeapKind::Array { len: 3 }, HeapKind::Array { len: 2 }]);
+        assert!(objects.iter().any(|object| !object.reachable));
+        assert!(objects.iter().all(|object| object.bytes >= GcArray::size_for(2)));
+
+        vm.collect_garbage();
+        assert!(vm.heap_objects().iter().all(|object| object.reachable));
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::Push);
+        chunk.write_byte(0);
+        let mut vm = VirtualMachine::new();
+        assert!(matches!(vm.execute(&chunk), Err(VmError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn test_decoded_cache_invalidated_on_write() {
+        let mut chunk = Chunk::new();
+        chunk.write_push(2.0);
+        let mut vm = VirtualMachine::new();
+        assert!((vm.execute(&chunk).unwrap() - 2.0).abs() < 1e-10);
+
+        chunk.write_op(OpCode::Neg);
+        assert_eq!(chunk.instructions().len(), 2);
+        assert!((vm.execute(&chunk).unwrap() + 2.0).abs() < 1e-10);
+    }
+
+    fn evaluate_registers(input: &str) -> Result<f64, VmError> {
         let mut tokenizer = Tokenizer::new(input);
-        let tokens = tokenizer.tokenize().expect("Tokenization failed");
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().expect("Parsing failed");
-        let chunk = CodeGenerator::new().compile(&ast);
+        let tokens = tokenizer.tokenize().unwrap();
+        let ast = Parser::new(tokens).parse().unwrap();
+        let chunk = CodeGenerator::new().compile_registers(&ast).unwrap();
+        VirtualMachine::new().execute_registers(&chunk)
+    }
+
+    #[test]
+    fn test_register_backend_matches_stack() {
+        for input in [
+            "1 + 2 * 3",
+            "sin(90) + 2^3",
+            "2^3^2 - 10 % 4",
+            "5! / nCr(5, 2)",
+            "sum([1, 2, 3]) + max([4, -5, 6])",
+            "avg([1, sqrt(16), 2^2 + 1])",
+            "len([]) + gcd(12, 8)",
+            "-(-(3)!)",
+        ] {
+            assert_eq!(evaluate_registers(input).unwrap(), evaluate(input).unwrap(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_register_backend_errors() {
+        assert!(matches!(evaluate_registers("1 / 0"), Err(VmError::DivisionByZero)));
+        assert!(matches!(evaluate_registers("sqrt(-1)"), Err(VmError::MathError(_))));
+        assert!(matches!(evaluate_registers("[1, 2] + 1"), Err(VmError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn test_register_backend_uses_fewer_instructions() {
+        let input = "(1 + 2) * (3 + 4)";
         let mut vm = VirtualMachine::new();
-        vm.execute(&chunk)
+        vm.execute(&compile(input)).unwrap();
+        let stack_steps = vm.step_count();
+
+        let ast = Parser::new(Tokenizer::new(input).tokenize().unwrap())
+            .parse()
+            .unwrap();
+        let program = CodeGenerator::with_backend(Backend::Register).compile_program(&ast).unwrap();
+        assert_eq!(vm.execute_program(&program).unwrap(), 21.0);
+        assert!(vm.step_count() < stack_steps);
     }
 
     #[test]
-    fn test_simple_addition() {
-        let result = evaluate("1 + 2").unwrap();
-        assert!((result - 3.0).abs() < 1e-10);
+    fn test_arrays_survive_collection_while_reachable() {
+        let mut vm = VirtualMachine::new();
+        vm.gc = GarbageCollector::with_threshold(1);
+
+        // Every array allocation is a safepoint; consumed arrays get freed
+        assert_eq!(vm.execute(&compile("sum([1, 2]) + sum([3, 4]) + len([5, 6, 7])")).unwrap(), 13.0);
+        while vm.gc_step(usize::MAX) != GcPhase::Idle {}
+        assert!(vm.gc_stats().total_objects_freed >= 2);
+
+        // An array left on the stack is a root
+        let chunk = compile("[1, 2, 3]");
+        vm.execute(&chunk).unwrap_err();
+        assert_eq!(vm.collect_garbage(), 0);
+        assert_eq!(vm.stack()[0].as_slice(), &[1.0, 2.0, 3.0]);
+
+        vm.reset();
+        assert_eq!(vm.collect_garbage(), 1);
     }
 
     #[test]
-    fn test_complex_expression() {
-        let result = evaluate("sin(90) + 2^3").unwrap();
-        assert!((result - 9.0).abs() < 1e-10);
+    fn test_memory_limit() {
+        // Room for a couple of small arrays, but not a large one
+        let mut vm = VirtualMachine::with_memory_limit(256);
+        for _ in 0..100 {
+            assert_eq!(vm.execute(&compile("sum([1, 2, 3]) + len([4, 5])")).unwrap(), 8.0);
+        }
+        let large = format!("sum([{}])", vec!["1"; 64].join(", "));
+        assert!(matches!(vm.execute(&compile(&large)), Err(VmError::OutOfMemory)));
+
+        // The VM stays usable after running out of memory
+        vm.reset();
+        assert_eq!(vm.execute(&compile("sum([1, 2, 3])")).unwrap(), 6.0);
     }
 
     #[test]
-    fn test_nested_expression() {
-        let result = evaluate("(1 + 2) * (3 + 4)").unwrap();
-        assert!((result - 21.0).abs() < 1e-10);
+    fn test_fixed_buffer_allocator() {
+        use crate::allocator::FixedBuffer;
+        // One arena block's worth of memory
+        let buffer = FixedBuffer::new(Box::leak(vec![0u8; 64 * 1024].into_boxed_slice()));
+        let mut vm = VirtualMachine::with_allocator(buffer);
+        assert_eq!(vm.execute(&compile("sum([1, 2, 3])")).unwrap(), 6.0);
+        assert!(matches!(vm.execute(&compile("sum(ones(10000))")), Err(VmError::OutOfMemory)));
+        vm.reset();
+        assert_eq!(vm.execute(&compile("len([1, 2])")).unwrap(), 2.0);
     }
 
     #[test]
-    fn test_power() {
-        let result = evaluate("2^3^2").unwrap();
-        // 2^(3^2) = 2^9 = 512 (right associative)
-        assert!((result - 512.0).abs() < 1e-10);
+    fn test_leak_report_names_opcode_handler() {
+        let mut vm = VirtualMachine::new();
+        vm.enable_leak_detection();
+        vm.execute(&compile("sum([1, 2])")).unwrap();
+        vm.execute(&compile("[1, 2, 3]")).unwrap_err();
+        vm.collect_garbage();
+
+        let report = vm.leak_report().unwrap();
+        assert!(report.starts_with("1 live blocks"), "{}", report);
+        assert!(report.contains("allocated at src/vm.rs:"), "{}", report);
     }
 
+    #[cfg(all(feature = "tracing", feature = "std"))]
     #[test]
-    fn test_division_by_zero() {
-        let result = evaluate("1 / 0");
-        assert!(matches!(result, Err(VmError::DivisionByZero)));
+    fn test_tracing_spans() {
+        use std::sync::{Arc, Mutex};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        /// Names of the spans entered and the events seen, in order
+        #[derive(Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut names = self.0.lock().unwrap();
+                names.push(span.metadata().name().to_string());
+                Id::from_u64(names.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                self.0.lock().unwrap().push(format!("event in {}", event.metadata().module_path().unwrap_or_default()));
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorder = Recorder::default();
+        let seen = Arc::clone(&recorder.0);
+        tracing::subscriber::with_default(recorder, || {
+            let mut vm = VirtualMachine::new();
+            vm.execute(&compile("sum([1, 2])")).unwrap();
+            vm.collect_garbage();
+            assert!(vm.execute(&compile("1 / 0")).is_err());
+        });
+        let seen = seen.lock().unwrap();
+        for name in ["tokenize_spanned", "parse", "compile", "execute", "gc", "event in calculator::gc"] {
+            assert!(seen.iter().any(|seen| seen == name), "no {} in {:?}", name, seen);
+        }
     }
 
     #[test]
-    fn test_factorial() {
-        let result = evaluate("5!").unwrap();
-        assert!((result - 120.0).abs() < 1e-10);
+    fn test_allocation_sites() {
+        let mut vm = VirtualMachine::new();
+        vm.enable_site_tracking();
+        vm.execute(&compile("f = x -> x + 1; sum(map(f, [1, 2, 3])) + len(ones(4))")).unwrap();
+        let sites = &vm.memory_stats().sites;
+        assert_eq!(sites["PUSH_ARR"].allocations, 1);
+        assert_eq!(sites["CLOSURE"].allocations, 1);
+        assert_eq!(sites["MAP"].allocations, 1);
+        assert_eq!(sites["ONES"].allocations, 1);
+
+        // Only the closure bound to f is still alive
+        vm.collect_garbage();
+        let live: Vec<_> = vm.memory_stats().sites_by_bytes().into_iter().filter(|(_, site)| site.objects > 0).collect();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].0, "CLOSURE");
     }
 
     #[test]
-    fn test_modulo() {
-        let result = evaluate("10 % 3").unwrap();
-        assert!((result - 1.0).abs() < 1e-10);
+    fn test_register_arrays_survive_collection() {
+        let ast = Parser::new(Tokenizer::new("sum([1, 2]) + max([len([1, 2, 3]), 4])").tokenize().unwrap())
+            .parse()
+            .unwrap();
+        let mut vm = VirtualMachine::new();
+        vm.gc = GarbageCollector::with_threshold(1);
+        let chunk = CodeGenerator::new().compile_registers(&ast).unwrap();
+        assert_eq!(vm.execute_registers(&chunk).unwrap(), 7.0);
     }
 
     #[test]
-    fn test_gcd() {
-        let result = evaluate("gcd(12, 8)").unwrap();
-        assert!((result - 4.0).abs() < 1e-10);
+    fn test_globals_persist_across_executions() {
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(&compile("x = 2; y = x * 3")).unwrap(), 6.0);
+        assert_eq!(vm.execute(&compile("x + y")).unwrap(), 8.0);
+        assert_eq!(vm.global("y"), Some(Value::Scalar(6.0)));
+
+        assert!(matches!(vm.execute(&compile("z + 1")), Err(VmError::UndefinedVariable(name)) if name == "z"));
+        assert_eq!(vm.remove_global("x"), Some(Value::Scalar(2.0)));
+        assert!(vm.execute(&compile("x")).is_err());
+
+        vm.set_global("xs", Value::Array(vec![1.0, 2.0, 3.0])).unwrap();
+        assert_eq!(vm.execute(&compile("sum(xs)")).unwrap(), 6.0);
+        let names: Vec<_> = vm.globals().map(|(name, _)| name.to_string()).collect();
+        assert_eq!(names, ["xs", "y"]);
     }
 
     #[test]
-    fn test_lcm() {
-        let result = evaluate("lcm(4, 6)").unwrap();
-        assert!((result - 12.0).abs() < 1e-10);
+    fn test_tail_calls() {
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(&compile("g(x) = 2 * x; f(a, b) = g(a + b); h(x) = f(x, 1); h(3)")).unwrap(), 8.0);
+
+        // Tail recursion runs in one frame, but still counts against the recursion limit
+        let result = vm.execute(&compile("r(n) = r(n + 1); r(0)"));
+        assert!(matches!(result, Err(VmError::RecursionLimit { depth: 64 })));
+        assert!(matches!(vm.execute(&compile("s(x) = s(x); s(1)")), Err(VmError::RecursionLimit { depth: 64 })));
+        vm.set_recursion_limit(100_000);
+        assert!(matches!(vm.execute(&compile("r(0)")), Err(VmError::RecursionLimit { depth: 100_000 })));
+        vm.set_recursion_limit(3);
+        assert_eq!(vm.execute(&compile("c(x) = x + 1; b(x) = c(x); a(x) = b(x); a(1)")).unwrap(), 2.0);
+        assert!(matches!(vm.execute(&compile("z(x) = a(x); z(1)")), Err(VmError::RecursionLimit { depth: 3 })));
+        vm.set_recursion_limit(64);
+
+        assert!(matches!(vm.execute(&compile("t(x) = f(x); t(1)")), Err(VmError::InvalidOperation(msg)) if msg.contains("expects 2")));
+        assert_eq!(vm.execute(&compile("t(x) = sqrt(x); t(9)")).unwrap(), 3.0);
     }
 
     #[test]
-    fn test_ncr() {
-        let result = evaluate("nCr(5, 2)").unwrap();
-        assert!((result - 10.0).abs() < 1e-10);
+    fn test_closures() {
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(&compile("k = 3; f = x -> k * x; f(2)")).unwrap(), 6.0);
+        assert_eq!(vm.global("f").unwrap().to_string(), "x -> (k * x)");
+
+        // A lambda captures the parameters of the function it is made in
+        assert_eq!(vm.execute(&compile("scale(k, xs) = map(x -> k * x, xs); sum(scale(2, [1, 2, 3]))")).unwrap(), 12.0);
+        assert_eq!(vm.execute(&compile("adder(n) = (a, b) -> a + b + n; g = adder(10); g(1, 2)")).unwrap(), 13.0);
+        assert_eq!(vm.execute(&compile("apply(f, x) = f(x); apply(x -> x ^ 2, 4)")).unwrap(), 16.0);
+        assert_eq!(vm.execute(&compile("sum(filter(x -> x % 2, [1, 2, 3, 4]))")).unwrap(), 4.0);
+        assert_eq!(vm.execute(&compile("sq(x) = x * x; sum(map(sq, [1, 2, 3]))")).unwrap(), 14.0);
+
+        // The captures survive a collection while the closure is reachable
+        vm.execute(&compile("make(a) = x -> x + sum(a); h = make([1, 2, 3])")).unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.execute(&compile("h(1)")).unwrap(), 7.0);
+        assert!(vm.heap_objects().iter().any(|object| object.kind == HeapKind::Closure { captures: 1 } && object.reachable));
+
+        let mut error = |source: &str| match vm.execute(&compile(source)) {
+            Err(VmError::InvalidOperation(msg)) => msg,
+            other => panic!("expected an error, got {:?}", other),
+        };
+        assert_eq!(error("f + 1"), "Expected scalar, got function");
+        assert_eq!(error("map(2, [1])"), "Expected function, got scalar");
+        assert!(error("f(1, 2)").contains("expects 1"));
+
+        // The register backend hands function values to the stack machine
+        let ast = Parser::new(Tokenizer::new("sum(map(x -> 2 * x + k, [1, 2]))").tokenize().unwrap()).parse().unwrap();
+        assert_eq!(vm.execute_registers(&CodeGenerator::new().compile_registers(&ast).unwrap()).unwrap(), 12.0);
     }
 
     #[test]
-    fn test_npr() {
-        let result = evaluate("nPr(5, 2)").unwrap();
-        assert!((result - 20.0).abs() < 1e-10);
+    fn test_let_bindings() {
+        let cases = [
+            ("let x = 2 in x * x", 4.0),
+            ("let x = 1 in let x = x + 1 in x", 2.0),
+            ("1 + (let a = 2 in a * 3) + (let b = 4 in b)", 11.0),
+            ("let v = [1, 2, 3] in sum(v) + len(v)", 9.0),
+            ("let k = 3 in sum(map(x -> k * x, [1, 2]))", 9.0),
+            ("let f = x -> x + 1 in f(1)", 2.0),
+            // Each frame has its own slots
+            ("k(n) = let y = n in y; h(n) = let y = 10 * n in y + k(n); h(1)", 11.0),
+            ("f(a) = let b = 2 * a in let c = b + a in c * b; f(1)", 6.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(evaluate(input).unwrap(), expected, "{}", input);
+            assert_eq!(evaluate_registers(input).unwrap(), expected, "{} on the register machine", input);
+        }
+
+        // Bindings never reach the globals
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("let t = 5 in t")).unwrap();
+        assert_eq!(vm.global("t"), None);
+        assert!(matches!(vm.execute(&compile("t")), Err(VmError::UndefinedVariable(name)) if name == "t"));
     }
 
     #[test]
-    fn test_array_sum() {
-        let result = evaluate("sum([1, 2, 3, 4])").unwrap();
-        assert!((result - 10.0).abs() < 1e-10);
+    fn test_recursion_limit() {
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.recursion_limit(), 64);
+        vm.set_recursion_limit(10);
+        let program = compile("r(n) = 1 + r(n + 1); r(0)");
+        let error = vm.execute(&program).unwrap_err();
+        assert!(matches!(error, VmError::RecursionLimit { depth: 10 }));
+        assert_eq!(error.to_string(), "Recursion limit exceeded: calls nested more than 10 deep");
+        assert_eq!(vm.call_depth(), 0);
+
+        // Ten nested calls fit, eleven don't
+        assert_eq!(vm.execute(&compile("d(n) = 1 + n; c(n) = d(n) * 1; b(n) = c(c(n)) * 1; b(1)")).unwrap(), 3.0);
+        let chain = "f1(n) = 1 + f2(n); f2(n) = 1 + f3(n); f3(n) = 1 + f4(n); f4(n) = 1 + f5(n); \
+            f5(n) = 1 + f6(n); f6(n) = 1 + f7(n); f7(n) = 1 + f8(n); f8(n) = 1 + f9(n); f9(n) = 1 + f10(n); \
+            f10(n) = 1 + f11(n); f11(n) = n";
+        vm.execute(&compile(chain)).unwrap();
+        assert_eq!(vm.execute(&compile("f2(0)")).unwrap(), 9.0);
+        assert!(matches!(vm.execute(&compile("f1(0)")), Err(VmError::RecursionLimit { depth: 10 })));
     }
 
     #[test]
-    fn test_array_avg() {
-        let result = evaluate("avg([1, 2, 3, 4])").unwrap();
-        assert!((result - 2.5).abs() < 1e-10);
+    fn test_function_calls() {
+        let mut vm = VirtualMachine::new();
+        assert_eq!(vm.execute(&compile("f(x) = x^2 + 1; g(a, b) = f(a) - b")).unwrap(), 0.0);
+        assert_eq!(vm.execute(&compile("g(3, 4) * 2")).unwrap(), 12.0);
+        assert_eq!(vm.functions().map(|f| f.name.as_str()).collect::<Vec<_>>(), ["f", "g"]);
+
+        // Globals are visible inside functions, parameters shadow them
+        assert_eq!(vm.execute(&compile("k = 10; h(x) = x + k; x = 100; h(1)")).unwrap(), 11.0);
+
+        assert!(matches!(vm.execute(&compile("nope(1)")), Err(VmError::UndefinedFunction(name)) if name == "nope"));
+        assert!(matches!(vm.execute(&compile("f(1, 2)")), Err(VmError::InvalidOperation(msg)) if msg.contains("expects 1")));
+        assert!(matches!(vm.execute(&compile("r(n) = 1 + r(n + 1); r(0)")), Err(VmError::RecursionLimit { depth: 64 })));
+
+        // Register backend shares the same definitions
+        let ast = Parser::new(Tokenizer::new("g(5, 6) + f(2)").tokenize().unwrap()).parse().unwrap();
+        let chunk = CodeGenerator::new().compile_registers(&ast).unwrap();
+        assert_eq!(vm.execute_registers(&chunk).unwrap(), 25.0);
     }
 
     #[test]
-    fn test_array_min_max() {
-        let min = evaluate("min([3, 1, 4, 1, 5])").unwrap();
-        let max = evaluate("max([3, 1, 4, 1, 5])").unwrap();
-        assert!((min - 1.0).abs() < 1e-10);
-        assert!((max - 5.0).abs() < 1e-10);
+    fn test_step_back_undoes_assignment() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("x = 1")).unwrap();
+        vm.load(&compile("x = 5"));
+        // PUSH, STORE_GLOBAL
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.global("x"), Some(Value::Scalar(5.0)));
+        vm.step_back().unwrap();
+        assert_eq!(vm.global("x"), Some(Value::Scalar(1.0)));
+    }
+
+    /// A spreadsheet-like host: cells by name and a `lookup` function
+    struct Sheet;
+
+    impl HostContext for Sheet {
+        fn resolve_variable(&self, name: &str) -> Option<Value> {
+            match name {
+                "price" => Some(Value::Scalar(2.5)),
+                "sales" => Some(Value::Array(vec![1.0, 2.0, 3.0])),
+                _ => None,
+            }
+        }
+
+        fn call_function(&self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+            (name == "lookup").then(|| match args {
+                [Value::Scalar(row)] if *row >= 1.0 => Ok(Value::Scalar(row * 10.0)),
+                _ => Err("lookup expects a row number".to_string()),
+            })
+        }
     }
 
     #[test]
-    fn test_hyperbolic() {
-        let sinh_result = evaluate("sinh(0)").unwrap();
-        let cosh_result = evaluate("cosh(0)").unwrap();
-        assert!((sinh_result - 0.0).abs() < 1e-10);
-        assert!((cosh_result - 1.0).abs() < 1e-10);
+    fn test_instruction_limit() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("f(x) = x * 2")).unwrap();
+        let chunk = compile("f(f(f(1)))");
+        vm.set_instruction_limit(100);
+        assert_eq!(vm.execute(&chunk).unwrap(), 8.0);
+
+        // The caller alone runs fewer than 8, the calls' bodies push it over
+        vm.set_instruction_limit(8);
+        assert!(matches!(vm.execute(&chunk), Err(VmError::InstructionLimit(8))));
+        vm.clear_instruction_limit();
+        assert_eq!(vm.execute(&chunk).unwrap(), 8.0);
     }
 
     #[test]
-    fn test_cbrt() {
-        let result = evaluate("cbrt(27)").unwrap();
-        assert!((result - 3.0).abs() < 1e-10);
+    fn test_integer_mode() {
+        let mut vm = VirtualMachine::new();
+        vm.enable_integer_mode();
+        assert_eq!(vm.execute(&compile("(2^52 + 7) % 10 + 12 / 4 * 3!")).unwrap(), 21.0);
+        assert!(matches!(vm.execute(&compile("x = 1.5")), Err(VmError::NotAnInteger(v)) if v == 1.5));
+        assert!(matches!(vm.execute(&compile("2^53")), Err(VmError::IntegerOverflow)));
+        assert!(matches!(vm.execute(&compile("-(2^52) * 4")), Err(VmError::IntegerOverflow)));
+        assert!(matches!(vm.execute(&compile("sqrt(2)")), Err(VmError::NotAnInteger(_))));
+        assert!(matches!(vm.execute(&compile("1 / 0")), Err(VmError::DivisionByZero)));
+        let err = vm.execute(&compile("7 / 2")).unwrap_err();
+        assert_eq!(err.to_string(), "Math error: 7 is not divisible by 2");
+
+        let ast = Parser::new(Tokenizer::new("x = 7; x / 2").tokenize().unwrap()).parse().unwrap();
+        assert!(vm.execute_registers(&CodeGenerator::new().compile_registers(&ast).unwrap()).is_err());
+
+        vm.disable_integer_mode();
+        assert_eq!(vm.execute(&compile("7 / 2")).unwrap(), 3.5);
     }
 
     #[test]
-    fn test_exp() {
-        let result = evaluate("exp(0)").unwrap();
-        assert!((result - 1.0).abs() < 1e-10);
+    fn test_host_context() {
+        let mut vm = VirtualMachine::new();
+        vm.set_host_context(Arc::new(Sheet));
+        assert_eq!(vm.execute(&compile("price * sum(sales) + lookup(2)")).unwrap(), 35.0);
+
+        // Program definitions shadow the host's
+        assert_eq!(vm.execute(&compile("price = 1; lookup(n) = n; price + lookup(2)")).unwrap(), 3.0);
+        vm.clear_definitions();
+
+        let ast = Parser::new(Tokenizer::new("lookup(1) + price").tokenize().unwrap()).parse().unwrap();
+        assert_eq!(vm.execute_registers(&CodeGenerator::new().compile_registers(&ast).unwrap()).unwrap(), 12.5);
+
+        assert!(matches!(vm.execute(&compile("lookup(0)")), Err(VmError::HostError(_))));
+        assert!(matches!(vm.execute(&compile("cost")), Err(VmError::UndefinedVariable(_))));
+        assert!(matches!(vm.execute(&compile("total(1)")), Err(VmError::UndefinedFunction(_))));
+        vm.clear_host_context();
+        assert!(vm.execute(&compile("price")).is_err());
+    }
+
+    #[test]
+    fn test_globals_survive_collection() {
+        let mut vm = VirtualMachine::new();
+        vm.execute(&compile("xs = [1, 2, 3]; f(a) = sum([a, a])")).unwrap();
+        vm.execute(&compile("len([4, 5])")).unwrap();
+        vm.reset();
+        vm.collect_garbage();
+        assert_eq!(vm.execute(&compile("sum(xs) + f(2)")).unwrap(), 10.0);
+        assert_eq!(vm.global("xs"), Some(Value::Array(vec![1.0, 2.0, 3.0])));
+    }
+
+    #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_execute_jit() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        let mut vm = VirtualMachine::new();
+        // Native path
+        assert_eq!(vm.execute_jit(&compile("(1 + 2) * 3")).unwrap(), 9.0);
+        // Unsupported opcode falls back to the interpreter
+        assert_eq!(vm.execute_jit(&compile("sin(90) + 1")).unwrap(), 2.0);
+        // Runtime errors come from the interpreter
+        assert!(matches!(vm.execute_jit(&compile("1 / 0")), Err(VmError::DivisionByZero)));
+
+        // Settings that watch each instruction are honoured
+        vm.enable_tracing();
+        assert_eq!(vm.execute_jit(&compile("1 + 2")).unwrap(), 3.0);
+        assert!(!vm.trace().is_empty());
+        vm.disable_tracing();
+        vm.set_instruction_limit(1);
+        assert!(matches!(vm.execute_jit(&compile("1 + 2")), Err(VmError::InstructionLimit(1))));
+        vm.clear_instruction_limit();
+        let events = Arc::new(AtomicUsize::new(0));
+        let counter = events.clone();
+        vm.set_hook(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(vm.execute_jit(&compile("1 + 2")).unwrap(), 3.0);
+        assert!(events.load(Ordering::Relaxed) > 0);
+    }
+
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    #[test]
+    fn test_parallel_reductions() {
+        let data: Vec<f64> = (0..PARALLEL_THRESHOLD * 4).map(|i| (i % 1000) as f64 - 250.0).collect();
+        let sequential: f64 = data.iter().sum();
+
+        let sum = VirtualMachine::reduce_array(OpCode::Sum, &data).unwrap();
+        assert!((sum - sequential).abs() < 1e-6);
+        let avg = VirtualMachine::reduce_array(OpCode::Avg, &data).unwrap();
+        assert!((avg - sequential / data.len() as f64).abs() < 1e-9);
+        assert_eq!(VirtualMachine::reduce_array(OpCode::Min, &data).unwrap(), -250.0);
+        assert_eq!(VirtualMachine::reduce_array(OpCode::Max, &data).unwrap(), 749.0);
     }
 }